@@ -8,19 +8,62 @@ create table books (
     creator text,
     description text,
     publisher text,
-    hash text not null
+    series text,
+    hash text not null,
+-- set when metadata was missing and had to be guessed during import
+-- it's only a flag, which of the identifier, language, or title were guessed isn't recorded
+    metadata_review boolean not null,
+    -- sum of the chapter word counts
+    words integer not null,
+    -- the fimfiction story the book is, so an updated version of it replaces the book instead of being a new one
+    story_id integer,
+    -- when the book was imported, null for books imported before it was recorded
+    added datetime
 );
 
 -- these will be used for searching books
 create index book_titles_idx on books(title);
 create index book_creators_idx on books(creator);
 create index book_publishers_idx on books(publisher);
+create index book_story_ids_idx on books(story_id);
+
+-- every path a book has been found at, a book can have multiple when there are copies or symlinks
+create table book_paths (
+    book_id text not null,
+    path text not null,
+    primary key(book_id, path),
+    foreign key (book_id) references books(id)
+);
+
+-- the files of older versions of fimfiction stories whose book has a newer version,
+-- kept so finding one again skips it instead of importing it or replacing the newer version
+create table superseded_hashes (
+    hash text not null primary key,
+    book_id text not null,
+    foreign key (book_id) references books(id)
+);
+
+-- the files books were imported from compressed with zstd, only kept with the keep originals setting
+-- they're keyed by the book's hash so copies of a file are only stored once
+create table originals (
+    hash text not null primary key,
+    -- the file's name, its extension picks the importer when it's parsed again
+    name text not null,
+    content blob not null
+);
 
 create table chapters (
     id text not null primary key,
     book_id text not null,
     `index` integer not null,
     content blob not null,
+    words integer not null,
+    -- false for spine items marked linear="no", which are skipped by next/previous chapter
+    linear boolean not null default true,
+    -- from the toc or the chapter's first heading
+    title text,
+    -- blake3 hash of the stored content, null for chapters imported before it was stored
+    checksum text,
     unique(book_id, `index`)
     foreign key (book_id) references books(id)
 );
@@ -31,19 +74,212 @@ create table table_of_contents (
     `index` integer not null,
     chapter_id text not null,
     title text not null,
+    -- how deeply the entry is nested, top level entries are 0
+    depth integer not null,
+    -- the id of the element in the chapter the entry links to, if it doesn't link to the top
+    fragment text,
     unique(book_id, `index`)
     foreign key (book_id) references books(id),
     foreign key (chapter_id) references chapters(id)
 );
 
+-- landmarks from an epub3 nav document, kind is the link's epub:type like cover, toc, or bodymatter
+create table landmarks (
+    book_id text not null,
+    kind text not null,
+    title text not null,
+    chapter_id text not null,
+    fragment text,
+    foreign key (book_id) references books(id),
+    foreign key (chapter_id) references chapters(id)
+);
+
+-- where the pages of the print edition start, from an epub3 nav document
+create table page_list (
+    book_id text not null,
+    `index` integer not null,
+    label text not null,
+    chapter_id text not null,
+    fragment text,
+    unique(book_id, `index`)
+    foreign key (book_id) references books(id),
+    foreign key (chapter_id) references chapters(id)
+);
+
+-- the SMIL files read-aloud epubs use to sync each chapter's text to audio
+create table media_overlays (
+    chapter_id text not null primary key,
+    book_id text not null,
+    -- zstd compressed
+    smil blob not null,
+    -- the clock value from the media:duration metadata, like 0:32:29.1
+    duration text,
+    foreign key (book_id) references books(id),
+    foreign key (chapter_id) references chapters(id)
+);
+
 create table bookmarks (
     id integer not null primary key autoincrement,
     book_id text not null,
     chapter_id text not null,
     progress real not null,
     created datetime not null,
--- only one bookmark per story, use 'insert or replace' to set a bookmark
-    unique(book_id),
+-- a book can have any number of bookmarks, the most recent one is where the book is opened
     foreign key (book_id) references books(id),
     foreign key (chapter_id) references chapters(id)
 );
+
+-- bookmarks that were deleted, kept so syncing deletes them on other machines instead of adding them back
+create table deleted_bookmarks (
+    book_id text not null,
+    -- the deleted bookmark's created time, which is what identifies it between libraries
+    created datetime not null,
+    deleted datetime not null,
+    primary key(book_id, created),
+    foreign key (book_id) references books(id)
+);
+
+create table settings (
+    key text not null primary key,
+    value text not null
+);
+
+-- content filters are enabled for every book unless they are listed here
+create table disabled_content_filters (
+    book_id text not null,
+    name text not null,
+    primary key(book_id, name),
+    foreign key (book_id) references books(id)
+);
+
+-- free-form notes, one per book
+create table notes (
+    book_id text not null primary key,
+    content text not null,
+    updated datetime not null,
+    foreign key (book_id) references books(id)
+);
+
+-- a passage selected in the reader with a note attached to it
+create table annotations (
+    id integer not null primary key autoincrement,
+    book_id text not null,
+    chapter_id text not null,
+    progress real not null,
+    passage text not null,
+    note text not null,
+    created datetime not null,
+    foreign key (book_id) references books(id),
+    foreign key (chapter_id) references chapters(id)
+);
+
+-- the people in each book's creator field, split so books by several authors show up under each of them
+create table authors (
+    id integer primary key,
+    name text not null unique
+);
+
+create table book_authors (
+    book_id text not null,
+    author_id integer not null,
+    primary key(book_id, author_id),
+    foreign key (book_id) references books(id),
+    foreign key (author_id) references authors(id)
+);
+
+create table book_tags (
+    book_id text not null,
+    tag text not null,
+    primary key(book_id, tag),
+    foreign key (book_id) references books(id)
+);
+
+-- saved library filters that show up as collections in the library
+create table collections (
+    name text not null primary key,
+    query text not null
+);
+
+-- books added to a collection by a rule, shown along with the books matching its filter
+create table collection_books (
+    collection text not null,
+    book_id text not null,
+    primary key(collection, book_id),
+    foreign key (book_id) references books(id)
+);
+
+-- applied to books when they're imported and on demand, e.g. "if author contains X, add tag Y"
+create table rules (
+    id integer primary key,
+    -- one of author, title, publisher, series, or language
+    field text not null,
+    pattern text not null,
+    -- the field has to equal the pattern instead of containing it
+    exact boolean not null,
+    -- tag or collection
+    action text not null,
+    target text not null
+);
+
+-- star ratings from 1 to 5
+create table ratings (
+    book_id text not null primary key,
+    rating integer not null,
+    foreign key (book_id) references books(id)
+);
+
+-- where each book was last read, saved automatically while reading
+create table positions (
+    book_id text not null primary key,
+    chapter_id text not null,
+    progress real not null,
+    updated datetime not null,
+    foreign key (book_id) references books(id),
+    foreign key (chapter_id) references chapters(id)
+);
+
+-- per-book fixes for badly formatted epubs
+create table render_overrides (
+    book_id text not null primary key,
+    disable_italics boolean not null,
+    paragraph_spacing boolean not null,
+    -- css selectors of elements to remove, one per line
+    strip_selectors text not null,
+    foreign key (book_id) references books(id)
+);
+
+-- background jobs, kind is the job as json and state is queued, running, done, failed, or cancelled
+create table jobs (
+    id integer primary key,
+    kind text not null,
+    state text not null,
+    -- the report of a job that's done or the error of one that failed
+    message text,
+    -- the pid of the process running the job, several processes can work on the same queue
+    owner integer,
+    created datetime not null,
+    updated datetime not null
+);
+
+-- prequels and sequels of fimfiction stories, by story id, saved from the fimfarchive index
+-- the title is kept so the link still shows without the index
+create table related_stories (
+    story_id integer not null,
+    related_id integer not null,
+    -- prequel or sequel
+    relation text not null,
+    title text not null,
+    primary key (story_id, related_id)
+);
+
+-- books and fimfarchive stories to read next, in the order they were added
+create table read_later (
+    id integer primary key,
+    -- null for a story from the fimfarchive that wasn't in the library when it was added
+    book_id text,
+    -- the fimfiction id, how a story finds its book once it's imported
+    story_id integer,
+    title text not null,
+    added datetime not null,
+    foreign key (book_id) references books(id)
+);
@@ -0,0 +1,303 @@
+use crate::jobs::scan_report;
+use crate::library::{self, Bookmark};
+use crate::scan::{import_file, text};
+use crate::settings::Settings;
+use crate::text_index::TextIndex;
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+/// Where the daemon serves the api, separate from `daemon::SOCKET_PATH` so the interface's
+/// protocol can change without breaking scripts.
+pub const API_SOCKET_PATH: &str = "ereader-api.sock";
+
+// the error codes from the JSON-RPC 2.0 spec
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// anything that went wrong in ereader itself, like a book that isn't in the library
+const SERVER_ERROR: i64 = -32000;
+
+/// The results of `search` are capped at this unless the request asks for a different limit.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    /// None for notifications, they don't get a response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        RpcError::new(SERVER_ERROR, e)
+    }
+}
+
+#[derive(Serialize)]
+struct ApiBook {
+    id: String,
+    title: String,
+    creator: Option<String>,
+    language: String,
+    series: Option<String>,
+    words: i64,
+}
+
+#[derive(Serialize)]
+struct ApiChapter {
+    id: String,
+    book_id: String,
+    index: i64,
+    title: Option<String>,
+    words: i64,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct ApiMatch {
+    book_id: String,
+    chapter_id: String,
+    snippet: String,
+    progress: f32,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    query: String,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ChapterFormat {
+    Html,
+    Text,
+}
+
+impl Default for ChapterFormat {
+    fn default() -> Self {
+        ChapterFormat::Html
+    }
+}
+
+#[derive(Deserialize)]
+struct ChapterParams {
+    book_id: String,
+    /// starts at 1 like the chapter numbers shown in the reader
+    index: i64,
+    #[serde(default)]
+    format: ChapterFormat,
+}
+
+#[derive(Deserialize)]
+struct BookmarkParams {
+    book_id: String,
+    index: i64,
+    /// how far into the chapter the bookmark is, from 0 to 1
+    progress: f32,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    path: String,
+}
+
+/// Serves the library to other programs as JSON-RPC 2.0 with a request or response on each line.
+///
+/// The methods are `search`, `list_books`, `get_chapter`, `add_bookmark`, and `import_file`,
+/// see the README for their params.
+pub async fn listen<P: AsRef<Path>>(
+    path: P,
+    pool: SqlitePool,
+    text_index: TextIndex,
+) -> Result<(), Error> {
+    // a socket left behind by a daemon that didn't exit cleanly would stop the bind
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let pool = pool.clone();
+        let text_index = text_index.clone();
+        tokio::spawn(async move {
+            let _ = handle(stream, pool, text_index).await;
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, pool: SqlitePool, text_index: TextIndex) -> Result<(), Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) if request.jsonrpc != "2.0" => Some(response(
+                request.id.unwrap_or(Value::Null),
+                Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")),
+            )),
+            Ok(request) => {
+                let result = call(&pool, &text_index, &request.method, request.params).await;
+                request.id.map(|id| response(id, result))
+            }
+            Err(e) => Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e)))),
+        };
+
+        if let Some(response) = response {
+            let mut line = serde_json::to_string(&response)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn book_id(id: &str) -> Result<Hyphenated, RpcError> {
+    Uuid::parse_str(id)
+        .map(Hyphenated::from)
+        .map_err(|_| RpcError::new(INVALID_PARAMS, format!("invalid book id {}", id)))
+}
+
+async fn call(
+    pool: &SqlitePool,
+    text_index: &TextIndex,
+    method: &str,
+    params_value: Value,
+) -> Result<Value, RpcError> {
+    let result = match method {
+        "search" => {
+            let params: SearchParams = params(params_value)?;
+            // books imported since the last search aren't in the index yet
+            text_index.update(pool).await?;
+            let matches = text_index
+                .search(&params.query, params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT))?
+                .into_iter()
+                .map(|text_match| ApiMatch {
+                    book_id: text_match.book_id.to_string(),
+                    chapter_id: text_match.chapter_id.to_string(),
+                    snippet: text_match.snippet,
+                    progress: text_match.progress,
+                })
+                .collect::<Vec<ApiMatch>>();
+            json!(matches)
+        }
+        "list_books" => {
+            let books = library::get_books(pool)
+                .await?
+                .into_iter()
+                .map(|book| ApiBook {
+                    id: book.id.to_string(),
+                    title: book.title,
+                    creator: book.creator,
+                    language: book.language,
+                    series: book.series,
+                    words: book.words,
+                })
+                .collect::<Vec<ApiBook>>();
+            json!(books)
+        }
+        "get_chapter" => {
+            let params: ChapterParams = params(params_value)?;
+            let chapter =
+                library::get_chapter(pool, book_id(&params.book_id)?, params.index).await?;
+            let html = library::decompress_chapter(&chapter.content)?;
+            let html = String::from_utf8_lossy(&html);
+            let content = match params.format {
+                ChapterFormat::Html => html.to_string(),
+                ChapterFormat::Text => text(&html),
+            };
+            json!(ApiChapter {
+                id: chapter.id.to_string(),
+                book_id: chapter.book_id.to_string(),
+                index: chapter.index,
+                title: chapter.title,
+                words: chapter.words,
+                content,
+            })
+        }
+        "add_bookmark" => {
+            let params: BookmarkParams = params(params_value)?;
+            if !(0.0..=1.0).contains(&params.progress) {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "progress must be from 0 to 1",
+                ));
+            }
+            let book_id = book_id(&params.book_id)?;
+            let chapter = library::get_chapter(pool, book_id, params.index).await?;
+            library::insert_bookmark(
+                pool,
+                &Bookmark {
+                    id: 0,
+                    book_id,
+                    chapter_id: chapter.id,
+                    progress: params.progress,
+                    created: chrono::Utc::now(),
+                },
+            )
+            .await?;
+            let pruning = Settings::load(pool).await?.bookmark_pruning;
+            library::prune_bookmarks(pool, &pruning).await?;
+            json!(null)
+        }
+        "import_file" => {
+            let params: ImportParams = params(params_value)?;
+            let (book_ids, report) = import_file(pool, &params.path).await?;
+            report.forget_updated(text_index)?;
+            json!({
+                "book_ids": book_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>(),
+                "report": scan_report(&report, false),
+            })
+        }
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", method),
+            ))
+        }
+    };
+
+    Ok(result)
+}
@@ -0,0 +1,65 @@
+/// Where a passage came from.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub title: String,
+    pub author: Option<String>,
+    pub publisher: Option<String>,
+    pub chapter: String,
+}
+
+/// Formats a passage as a markdown block quote followed by its source.
+pub fn markdown(passage: &str, source: &Source) -> String {
+    let quote = passage
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                ">".to_string()
+            } else {
+                format!("> {}", line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let mut attribution = String::from("— ");
+    if let Some(author) = &source.author {
+        attribution.push_str(&format!("{}, ", author));
+    }
+    attribution.push_str(&format!("*{}*, {}", source.title, source.chapter));
+
+    format!("{}\n>\n> {}\n", quote, attribution)
+}
+
+/// Formats a passage as a BibTeX entry with the passage as a note.
+pub fn bibtex(passage: &str, source: &Source) -> String {
+    let mut fields = vec![format!("  title = {{{}}}", source.title)];
+    if let Some(author) = &source.author {
+        fields.push(format!("  author = {{{}}}", author));
+    }
+    if let Some(publisher) = &source.publisher {
+        fields.push(format!("  publisher = {{{}}}", publisher));
+    }
+    fields.push(format!("  chapter = {{{}}}", source.chapter));
+    fields.push(format!(
+        "  note = {{``{}''}}",
+        passage.split_whitespace().collect::<Vec<&str>>().join(" ")
+    ));
+
+    format!("@book{{{},\n{}\n}}\n", key(source), fields.join(",\n"))
+}
+
+/// A citation key made from the author's last name and the first word of the title.
+fn key(source: &Source) -> String {
+    let author = source
+        .author
+        .as_deref()
+        .and_then(|author| author.split_whitespace().last())
+        .unwrap_or("anonymous");
+    let title = source.title.split_whitespace().next().unwrap_or("untitled");
+
+    format!("{}{}", author, title)
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,102 @@
+use clap::{ArgEnum, Parser, Subcommand};
+
+/// A terminal epub reader with fimfarchive search.
+#[derive(Parser, Debug)]
+#[clap(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    /// An epub to open, it is imported into the library first if it isn't already there
+    pub path: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Import the books in a directory, or see what would be imported with --dry-run
+    Scan {
+        path: String,
+        /// Only report what would be imported and why the other files would be skipped
+        #[clap(long)]
+        dry_run: bool,
+        /// Also list the files that are already in the library
+        #[clap(short, long)]
+        verbose: bool,
+    },
+    /// Import books again from their original files, keeping their bookmarks and progress,
+    /// e.g. after the html cleanup or table of contents parsing improves
+    Reprocess {
+        /// The books to reprocess, the whole library if none are given
+        book_ids: Vec<String>,
+    },
+    /// Check the chapters for corruption and offer to re-import the books they're from
+    Verify {
+        /// Re-import books with corrupt chapters without asking
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Build the fimfarchive search index again from the index.json in the fimfarchive zip
+    RebuildIndex,
+    /// Search the fimfarchive index and print the results
+    Search {
+        query: String,
+        /// How many results to print
+        #[clap(short, long, default_value_t = 50)]
+        limit: usize,
+        /// json and csv print the title, author, words, wilson score, path, and tags of each result
+        #[clap(short, long, arg_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Pick a book with fzf or another picker and open it, or list the books for a picker
+    Pick {
+        /// Only print a line for each book, the title, author, and id separated by tabs
+        #[clap(long)]
+        list: bool,
+        /// Open the book of a line printed by --list instead of running the picker
+        #[clap(long, conflicts_with = "list")]
+        open: Option<String>,
+        /// The picker to run, it's given the lines on stdin and prints the chosen one
+        #[clap(long, default_value = crate::pick::DEFAULT_PICKER)]
+        picker: String,
+    },
+    /// Write the bash, zsh, and fish completions and a man page into a directory
+    GenerateCompletions {
+        #[clap(default_value = ".")]
+        dir: String,
+    },
+    /// Write a .desktop entry and register ereader as the handler for epub files
+    InstallDesktop,
+    /// Write the settings and keybindings to a TOML file
+    ExportSettings { path: String },
+    /// Load the settings and keybindings from a TOML file written by export-settings
+    ImportSettings { path: String },
+    /// Write the file a book was imported from, from the stored original if it was kept
+    ExportOriginal { book_id: String, path: String },
+    /// Write the reading positions, bookmarks, annotations, and notes to a sync file
+    ExportSync { path: String },
+    /// Merge a sync file from another machine, keeping whichever changes are newer
+    ImportSync { path: String },
+    /// Run scans and other tasks in the background so they keep going after the interface is closed
+    Daemon,
+    /// Stop a running daemon, a task it was running starts over the next time jobs are run
+    StopDaemon,
+    /// Use the ratatui interface instead of the default one
+    #[cfg(feature = "ratatui-frontend")]
+    Ratatui,
+    /// Serve a minimal web reader for the library, e.g. to read on a tablet
+    Web {
+        /// The address to listen on, use 0.0.0.0:8080 to allow other devices on the network
+        #[clap(default_value = crate::web::DEFAULT_ADDRESS)]
+        address: String,
+        /// Require this token, one is made up if the address isn't only reachable from this machine
+        #[clap(long)]
+        token: Option<String>,
+    },
+}
+
+/// How the search subcommand prints its results.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
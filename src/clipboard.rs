@@ -0,0 +1,48 @@
+use crate::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Copies text to the system clipboard.
+///
+/// wl-copy and xclip are used when there is a display to talk to.
+/// Otherwise the text is sent to the terminal with OSC 52, which also works over ssh
+/// as long as the terminal supports it.
+pub fn copy(text: &str) -> Result<(), Error> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() && pipe_to("wl-copy", &[], text).is_ok() {
+        return Ok(());
+    }
+
+    if std::env::var_os("DISPLAY").is_some()
+        && pipe_to("xclip", &["-selection", "clipboard"], text).is_ok()
+    {
+        return Ok(());
+    }
+
+    osc52(text)
+}
+
+fn pipe_to(program: &str, args: &[&str], text: &str) -> Result<(), Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+
+    if child.wait()?.success() {
+        Ok(())
+    } else {
+        Err(Error::CommandFailed(program.to_string()))
+    }
+}
+
+fn osc52(text: &str) -> Result<(), Error> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", base64::encode(text))?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use crate::cli::Cli;
+use crate::Error;
+use clap::IntoApp;
+use clap_complete::{generate_to, Shell};
+use std::path::{Path, PathBuf};
+
+/// Writes the bash, zsh, and fish completions and the man page for the command line into a directory.
+///
+/// They're generated from the clap definitions so they can't fall behind the subcommands,
+/// returns the paths of the files that were written.
+pub fn generate<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, Error> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+
+    let mut app = Cli::into_app();
+    let mut written = Vec::new();
+    for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+        written.push(generate_to(shell, &mut app, "ereader", dir)?);
+    }
+
+    let mut man = Vec::new();
+    clap_mangen::Man::new(app).render(&mut man)?;
+    let man_path = dir.join("ereader.1");
+    std::fs::write(&man_path, man)?;
+    written.push(man_path);
+
+    Ok(written)
+}
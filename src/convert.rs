@@ -0,0 +1,82 @@
+use crate::formats::BookFile;
+use crate::Error;
+use std::collections::BTreeMap;
+use std::process::Command;
+use uuid::Uuid;
+
+/// The converters used until the settings say otherwise, keyed by extension.
+///
+/// `{input}` is replaced with the file to convert and `{output}` with the epub to write.
+pub fn default_converters() -> BTreeMap<String, String> {
+    [
+        ("djvu", "ebook-convert {input} {output}"),
+        ("doc", "ebook-convert {input} {output}"),
+        ("rtf", "pandoc {input} -o {output}"),
+    ]
+    .iter()
+    .map(|(extension, command)| (extension.to_string(), command.to_string()))
+    .collect()
+}
+
+/// Whether a template has everything it needs to be run.
+pub fn valid_template(template: &str) -> bool {
+    template.split_whitespace().next().is_some()
+        && template.contains("{input}")
+        && template.contains("{output}")
+}
+
+/// Converts a file into an epub with an external tool.
+///
+/// The file is written to a temporary file first since it may have come out of a zip.
+/// The converted file keeps the original's path, hash, and id so it's recognized on the next scan.
+pub fn convert(template: &str, file: &BookFile) -> Result<BookFile, Error> {
+    let path = file.path.to_string_lossy().to_string();
+    let failed = |reason: String| Error::ConversionFailed(path.clone(), reason);
+
+    let name = format!("ereader-{}", Uuid::new_v4());
+    let input = std::env::temp_dir().join(format!("{}.{}", name, file.extension()));
+    let output = std::env::temp_dir().join(format!("{}.epub", name));
+    std::fs::write(&input, &file.contents)?;
+
+    // placeholders are replaced after splitting so paths with spaces stay one argument
+    let mut args = template.split_whitespace().map(|arg| {
+        arg.replace("{input}", &input.to_string_lossy())
+            .replace("{output}", &output.to_string_lossy())
+    });
+    let program = args
+        .next()
+        .ok_or_else(|| failed("the command is empty".to_string()))?;
+
+    let result = Command::new(&program).args(args).output();
+    let _ = std::fs::remove_file(&input);
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => return Err(failed(format!("unable to run {}: {}", program, e))),
+    };
+    if !result.status.success() {
+        let _ = std::fs::remove_file(&output);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("no output")
+            .trim()
+            .to_string();
+        return Err(failed(format!("{} {}: {}", program, result.status, reason)));
+    }
+
+    let contents = std::fs::read(&output)
+        .map_err(|e| failed(format!("{} didn't write the epub: {}", program, e)))?;
+    let _ = std::fs::remove_file(&output);
+
+    Ok(file.converted(contents))
+}
+
+/// The converter for a file's extension, if there is one.
+pub fn converter<'a>(converters: &'a BTreeMap<String, String>, file: &BookFile) -> Option<&'a str> {
+    converters
+        .get(&file.extension())
+        .map(|template| template.as_str())
+}
@@ -0,0 +1,262 @@
+use crate::api::{self, API_SOCKET_PATH};
+use crate::jobs::{Job, JobKind, JobQueue};
+use crate::settings::Settings;
+use crate::text_index::{TextIndex, TEXT_INDEX_PATH};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream as AsyncUnixStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{broadcast, Notify};
+
+/// Where the daemon listens, next to the database like the other files ereader keeps.
+pub const SOCKET_PATH: &str = "ereader.sock";
+
+/// How many job updates a slow client can fall behind before it misses some.
+const UPDATE_BUFFER: usize = 64;
+
+/// A message from a client, each one is a line of JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    Ping,
+    Enqueue {
+        job: JobKind,
+    },
+    Cancel {
+        id: i64,
+    },
+    /// keeps the connection open and sends an `Update` whenever a job starts or finishes
+    Subscribe,
+    Shutdown,
+}
+
+/// The daemon's answer to a request, also a line of JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Queued { id: i64 },
+    Update { job: Job },
+    Error { message: String },
+}
+
+/// Runs the jobs in the background without the interface so scans keep going after it's closed.
+///
+/// The daemon owns the job queue, any number of interfaces can attach to it over the socket
+/// to queue jobs and follow their progress, and other programs can use the library through `api`.
+/// It runs until it's sent a shutdown.
+pub fn serve<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    if Client::connect(path).is_some() {
+        return Err(Error::DaemonError(format!(
+            "a daemon is already listening on {}",
+            path.display()
+        )));
+    }
+    // a socket left behind by a daemon that didn't exit cleanly would stop the bind
+    let _ = std::fs::remove_file(path);
+
+    let runtime = Runtime::new()?;
+    let pool = runtime.block_on(SqlitePool::connect("ereader.sqlite"))?;
+    let settings = runtime.block_on(Settings::load(&pool))?;
+    let text_index = TextIndex::open(TEXT_INDEX_PATH, settings.index_tuning)?;
+    let jobs = JobQueue::new(pool.clone(), text_index.clone());
+
+    let (updates, _) = broadcast::channel(UPDATE_BUFFER);
+    let sender = updates.clone();
+    jobs.start(&runtime, move |job| {
+        // there's nothing to do when no client is subscribed
+        let _ = sender.send(job);
+    })?;
+
+    // the api is served next to the socket the interfaces use
+    let api_path = path.with_file_name(API_SOCKET_PATH);
+    let api = runtime.spawn(api::listen(api_path.clone(), pool, text_index));
+
+    let result = runtime.block_on(listen(path, jobs, updates));
+    api.abort();
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(api_path);
+    result
+}
+
+async fn listen(path: &Path, jobs: JobQueue, updates: broadcast::Sender<Job>) -> Result<(), Error> {
+    let listener = UnixListener::bind(path)?;
+    let shutdown = Arc::new(Notify::new());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let jobs = jobs.clone();
+                let updates = updates.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    // a client going away mid-request only ends its own connection
+                    let _ = handle(stream, jobs, updates, shutdown).await;
+                });
+            }
+            _ = shutdown.notified() => return Ok(()),
+        }
+    }
+}
+
+async fn handle(
+    stream: AsyncUnixStream,
+    jobs: JobQueue,
+    updates: broadcast::Sender<Job>,
+    shutdown: Arc<Notify>,
+) -> Result<(), Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Subscribe) => {
+                let mut updates = updates.subscribe();
+                send(&mut writer, &Response::Ok).await?;
+                loop {
+                    match updates.recv().await {
+                        Ok(job) => send(&mut writer, &Response::Update { job }).await?,
+                        // the tasks list reads the jobs table so a missed update only delays a notification
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+            Ok(Request::Shutdown) => {
+                send(&mut writer, &Response::Ok).await?;
+                shutdown.notify_one();
+                return Ok(());
+            }
+            Ok(request) => respond(&jobs, request)
+                .await
+                .unwrap_or_else(|e| Response::Error {
+                    message: e.to_string(),
+                }),
+            Err(e) => Response::Error {
+                message: e.to_string(),
+            },
+        };
+        send(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn respond(jobs: &JobQueue, request: Request) -> Result<Response, Error> {
+    Ok(match request {
+        Request::Ping => Response::Ok,
+        Request::Enqueue { job } => Response::Queued {
+            id: jobs.enqueue(job).await?,
+        },
+        Request::Cancel { id } => {
+            jobs.cancel(id).await?;
+            Response::Ok
+        }
+        Request::Subscribe | Request::Shutdown => unreachable!("handled by the connection"),
+    })
+}
+
+async fn send<W: AsyncWriteExt + Unpin>(writer: &mut W, response: &Response) -> Result<(), Error> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// A connection to a running daemon, used by the interface instead of its own job queue.
+///
+/// Requests block since the interface is waiting on them anyway,
+/// each one opens a new connection so a daemon restart only fails the requests made while it's down.
+#[derive(Clone, Debug)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// The daemon listening on the socket, None if there isn't one.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let client = Client {
+            path: path.as_ref().to_path_buf(),
+        };
+        match client.request(&Request::Ping) {
+            Ok(Response::Ok) => Some(client),
+            _ => None,
+        }
+    }
+
+    fn open(&self) -> Result<(UnixStream, BufReader<UnixStream>), Error> {
+        let stream = UnixStream::connect(&self.path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok((stream, reader))
+    }
+
+    fn request(&self, request: &Request) -> Result<Response, Error> {
+        let (mut stream, mut reader) = self.open()?;
+        writeln!(stream, "{}", serde_json::to_string(request)?)?;
+        read_response(&mut reader)
+    }
+
+    pub fn enqueue(&self, job: JobKind) -> Result<i64, Error> {
+        match self.request(&Request::Enqueue { job })? {
+            Response::Queued { id } => Ok(id),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn cancel(&self, id: i64) -> Result<(), Error> {
+        match self.request(&Request::Cancel { id })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn shutdown(&self) -> Result<(), Error> {
+        match self.request(&Request::Shutdown)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Calls `on_update` from a separate thread whenever a job starts or finishes,
+    /// until the daemon exits.
+    pub fn subscribe<F: Fn(Job) + Send + 'static>(&self, on_update: F) -> Result<(), Error> {
+        let (mut stream, mut reader) = self.open()?;
+        writeln!(stream, "{}", serde_json::to_string(&Request::Subscribe)?)?;
+        match read_response(&mut reader)? {
+            Response::Ok => {}
+            response => return Err(unexpected(response)),
+        }
+
+        std::thread::spawn(move || {
+            while let Ok(Response::Update { job }) = read_response(&mut reader) {
+                on_update(job);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn read_response(reader: &mut BufReader<UnixStream>) -> Result<Response, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(Error::DaemonError(
+            "the daemon closed the connection".to_string(),
+        ));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn unexpected(response: Response) -> Error {
+    match response {
+        Response::Error { message } => Error::DaemonError(message),
+        response => Error::DaemonError(format!("unexpected response {:?}", response)),
+    }
+}
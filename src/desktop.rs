@@ -0,0 +1,82 @@
+use crate::Error;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const EPUB_MIME_TYPE: &str = "application/epub+zip";
+
+fn applications_dir() -> Result<PathBuf, Error> {
+    let data_dir = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var("HOME").map_err(|_| Error::MissingHomeDir)?;
+            PathBuf::from(home).join(".local").join("share")
+        }
+    };
+
+    Ok(data_dir.join("applications"))
+}
+
+/// Writes a .desktop entry for opening epubs with ereader and makes it the default for epubs.
+///
+/// The database, index, and epub directory are relative to the working directory,
+/// so the entry runs ereader from the directory this was installed from.
+pub fn install() -> Result<PathBuf, Error> {
+    let exe = std::env::current_exe()?;
+    let working_dir = std::env::current_dir()?;
+
+    let entry = format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name=ereader\n\
+         Comment=Terminal epub reader\n\
+         Exec=\"{}\" %f\n\
+         Path={}\n\
+         Terminal=true\n\
+         MimeType={};\n\
+         Categories=Office;Viewer;\n",
+        exe.display(),
+        working_dir.display(),
+        EPUB_MIME_TYPE,
+    );
+
+    let dir = applications_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("ereader.desktop");
+    std::fs::write(&path, entry)?;
+
+    let status = Command::new("xdg-mime")
+        .args(&["default", "ereader.desktop", EPUB_MIME_TYPE])
+        .status()?;
+    if !status.success() {
+        return Err(Error::CommandFailed("xdg-mime".to_string()));
+    }
+
+    // not every desktop has this, the entry still works without the cache being updated
+    let _ = Command::new("update-desktop-database").arg(&dir).status();
+
+    Ok(path)
+}
+
+/// Shows a desktop notification with notify-send.
+pub fn notify(summary: &str, body: &str) -> Result<(), Error> {
+    let status = Command::new("notify-send")
+        .args(&["--app-name=ereader", summary, body])
+        .status()?;
+    if !status.success() {
+        return Err(Error::CommandFailed("notify-send".to_string()));
+    }
+    Ok(())
+}
+
+/// Opens a url in the default browser with xdg-open.
+pub fn open_url(url: &str) -> Result<(), Error> {
+    let status = Command::new("xdg-open")
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::CommandFailed("xdg-open".to_string()));
+    }
+    Ok(())
+}
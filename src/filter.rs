@@ -0,0 +1,401 @@
+use crate::library::Book;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use regex::{Captures, Regex};
+
+/// A book along with the extra information needed to filter the library.
+#[derive(Clone, Debug)]
+pub struct BookInfo {
+    pub book: Book,
+    pub tags: Vec<String>,
+    /// the authors split out of the book's creator
+    pub authors: Vec<String>,
+    /// the collections a rule added the book to
+    pub collections: Vec<String>,
+    /// has a saved reading position or a bookmark
+    pub started: bool,
+    pub bookmarked: bool,
+    /// fraction of the book's words before the bookmark
+    pub progress: Option<f32>,
+    pub last_read: Option<DateTime<Utc>>,
+    pub rating: Option<i64>,
+    /// none of the paths the book was imported from exist anymore
+    pub missing: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    GreaterEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn compare(&self, a: i64, b: i64) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessEqual => a <= b,
+            Comparison::Equal => a == b,
+            Comparison::GreaterEqual => a >= b,
+            Comparison::Greater => a > b,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Unread,
+    Started,
+    Tag(String),
+    Author(String),
+    /// one of the book's authors is exactly the name, rather than the creator containing it
+    Authored(String),
+    Title(String),
+    Description(String),
+    Collection(String),
+    Text(String),
+    Words(Comparison, i64),
+    Not(Box<Term>),
+    Any(Vec<Term>),
+    All(Vec<Term>),
+}
+
+impl Term {
+    fn matches(&self, info: &BookInfo) -> bool {
+        match self {
+            Term::Unread => !info.started,
+            Term::Started => info.started,
+            Term::Tag(tag) => info.tags.iter().any(|t| t.to_lowercase() == *tag),
+            Term::Author(author) => info
+                .book
+                .creator
+                .as_ref()
+                .map(|creator| creator.to_lowercase().contains(author))
+                .unwrap_or(false),
+            Term::Authored(name) => info
+                .authors
+                .iter()
+                .any(|author| author.to_lowercase() == *name),
+            Term::Title(title) => info.book.title.to_lowercase().contains(title),
+            Term::Description(text) => info
+                .book
+                .description
+                .as_ref()
+                .map(|description| description.to_lowercase().contains(text))
+                .unwrap_or(false),
+            Term::Collection(name) => info
+                .collections
+                .iter()
+                .any(|collection| collection.to_lowercase() == *name),
+            Term::Text(text) => {
+                Term::Title(text.clone()).matches(info)
+                    || Term::Author(text.clone()).matches(info)
+                    || Term::Description(text.clone()).matches(info)
+            }
+            Term::Words(comparison, words) => comparison.compare(info.book.words, *words),
+            Term::Not(term) => !term.matches(info),
+            Term::Any(terms) => terms.iter().any(|term| term.matches(info)),
+            Term::All(terms) => terms.iter().all(|term| term.matches(info)),
+        }
+    }
+}
+
+type TermFn = fn(String) -> Result<(String, Vec<Term>), Error>;
+
+/// A library query using the same syntax as the fimfarchive search.
+///
+/// - `author(name)`: books by any of the given authors, `-author(name)` excludes an author and `+author(name)` requires each of them
+/// - `#(tag)` or `tag(tag)`: required tags, `-#(tag)` excludes a tag and `~#(tag)` requires at least one of them,
+///   the fimfarchive's categories like `genre#(tag)` are matched as plain tags
+/// - `words>100k`: compare the word count with <, <=, =, >=, or >, k and m suffixes are allowed
+/// - `status:unread`/`status:started`: whether the book has been opened (has been opened)
+/// - `collection(name)`: books a rule added to the collection
+/// - `title:"exact phrase"`/`description:dragon`: text that must appear in that field
+///
+/// Saved collections written as "unread AND tag:fantasy AND words>100k" still work:
+/// `AND` is ignored, `NOT`/`-` negate the next term, and `tag:`, `author:`, `unread`, and `started` are understood.
+/// Any other words must appear in the title, author, or description.
+/// Terms that only make sense for fimfarchive stories (likes, ratings, ordering, ...) are ignored.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+impl Filter {
+    pub fn parse(query: &str) -> Result<Self, Error> {
+        let mut input = query.to_string();
+        let mut terms = Vec::new();
+
+        let extractors: Vec<TermFn> = vec![
+            fimfarchive_only,
+            phrases,
+            authors,
+            collections,
+            tags,
+            words,
+            status,
+        ];
+        for extract in extractors {
+            let (new_input, mut new_terms) = extract(input)?;
+            terms.append(&mut new_terms);
+            input = new_input;
+        }
+
+        let mut negate = false;
+        for token in input.split_whitespace() {
+            let lower = token.to_lowercase();
+            match lower.as_str() {
+                "and" => continue,
+                "not" | "-" => {
+                    negate = !negate;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let term = match lower.strip_prefix('-') {
+                Some(rest) => Term::Not(Box::new(parse_term(rest)?)),
+                None => parse_term(&lower)?,
+            };
+            terms.push(if negate {
+                Term::Not(Box::new(term))
+            } else {
+                term
+            });
+            negate = false;
+        }
+
+        Ok(Filter { terms })
+    }
+
+    pub fn matches(&self, info: &BookInfo) -> bool {
+        self.terms.iter().all(|term| term.matches(info))
+    }
+
+    /// The books by an author from the authors list.
+    pub fn by_author(name: &str) -> Self {
+        Filter {
+            terms: vec![Term::Authored(name.to_lowercase())],
+        }
+    }
+
+    /// Also matches the books that were added to the collection by a rule.
+    pub fn or_in_collection(self, name: &str) -> Self {
+        Filter {
+            terms: vec![Term::Any(vec![
+                Term::All(self.terms),
+                Term::Collection(name.to_lowercase()),
+            ])],
+        }
+    }
+}
+
+fn parse_term(term: &str) -> Result<Term, Error> {
+    let prefixed = |prefix: &str| {
+        term.strip_prefix(prefix).map(|rest| {
+            if rest.is_empty() {
+                Err(Error::InvalidFilter(term.to_string()))
+            } else {
+                Ok(rest.to_string())
+            }
+        })
+    };
+
+    if let Some(tag) = prefixed("tag:") {
+        return Ok(Term::Tag(tag?));
+    }
+    if let Some(author) = prefixed("author:") {
+        return Ok(Term::Author(author?));
+    }
+    if let Some(title) = prefixed("title:") {
+        return Ok(Term::Title(title?));
+    }
+    if let Some(description) = prefixed("description:") {
+        return Ok(Term::Description(description?));
+    }
+
+    // the text already matches anywhere in a word so a prefix's `*` isn't needed
+    let term = term.trim_end_matches('*');
+    Ok(match term {
+        "unread" => Term::Unread,
+        "started" | "read" => Term::Started,
+        _ => Term::Text(term.to_string()),
+    })
+}
+
+/// Removes every match of `re` from the input, collecting the unescaped names in the first group.
+fn extract_names(input: String, re: &Regex) -> (String, Vec<String>) {
+    let paren_escape_re = Regex::new(r#"\\(\))"#).unwrap();
+    let mut names = Vec::new();
+
+    let input = re
+        .replace_all(&input, |caps: &Captures| {
+            let name = paren_escape_re.replace_all(&caps[1], |caps: &Captures| caps[1].to_string());
+            names.push(name.trim().to_lowercase());
+            String::new()
+        })
+        .to_string();
+
+    (input, names)
+}
+
+/// Quoted `title:` and `description:` text, the unquoted ones are single words handled by `parse_term`.
+fn phrases(input: String) -> Result<(String, Vec<Term>), Error> {
+    let phrase_re = Regex::new(r#"(?i)(-?)(title|description):"([^"]*)""#).unwrap();
+    let mut terms = Vec::new();
+
+    let input = phrase_re
+        .replace_all(&input, |caps: &Captures| {
+            let phrase = caps[3].trim().to_lowercase();
+            let term = if caps[2].eq_ignore_ascii_case("title") {
+                Term::Title(phrase)
+            } else {
+                Term::Description(phrase)
+            };
+            terms.push(if &caps[1] == "-" {
+                Term::Not(Box::new(term))
+            } else {
+                term
+            });
+            String::new()
+        })
+        .to_string();
+
+    Ok((input, terms))
+}
+
+fn authors(input: String) -> Result<(String, Vec<Term>), Error> {
+    let mut terms = Vec::new();
+
+    let ex_author_re = Regex::new(r#"(?i)-author\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, ex_authors) = extract_names(input, &ex_author_re);
+    for author in ex_authors {
+        terms.push(Term::Not(Box::new(Term::Author(author))));
+    }
+
+    let and_author_re = Regex::new(r#"(?i)\+author\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, and_authors) = extract_names(input, &and_author_re);
+    for author in and_authors {
+        terms.push(Term::Author(author));
+    }
+
+    let author_re = Regex::new(r#"(?i)author\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, authors) = extract_names(input, &author_re);
+    if !authors.is_empty() {
+        terms.push(Term::Any(authors.into_iter().map(Term::Author).collect()));
+    }
+
+    Ok((input, terms))
+}
+
+fn collections(input: String) -> Result<(String, Vec<Term>), Error> {
+    let collection_re = Regex::new(r#"(?i)collection\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, collections) = extract_names(input, &collection_re);
+
+    Ok((
+        input,
+        collections.into_iter().map(Term::Collection).collect(),
+    ))
+}
+
+fn tags(input: String) -> Result<(String, Vec<Term>), Error> {
+    let mut terms = Vec::new();
+
+    let ex_tag_re = Regex::new(r#"-(?:[a-z]*#|tag)\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, ex_tags) = extract_names(input, &ex_tag_re);
+    for tag in ex_tags {
+        terms.push(Term::Not(Box::new(Term::Tag(tag))));
+    }
+
+    let or_tag_re = Regex::new(r#"~(?:[a-z]*#|tag)\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, or_tags) = extract_names(input, &or_tag_re);
+    if !or_tags.is_empty() {
+        terms.push(Term::Any(or_tags.into_iter().map(Term::Tag).collect()));
+    }
+
+    let tag_re = Regex::new(r#"(?:[a-z]*#|tag)\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let (input, tags) = extract_names(input, &tag_re);
+    for tag in tags {
+        terms.push(Term::Tag(tag));
+    }
+
+    Ok((input, terms))
+}
+
+fn words(input: String) -> Result<(String, Vec<Term>), Error> {
+    let word_re = Regex::new(r#"(?i)(-?)words(>=|<=|>|<|=)([0-9]+)([km]?)\b"#).unwrap();
+    let mut terms = Vec::new();
+    let mut invalid = None;
+
+    let input = word_re
+        .replace_all(&input, |caps: &Captures| {
+            let comparison = match &caps[2] {
+                "<" => Comparison::Less,
+                "<=" => Comparison::LessEqual,
+                "=" => Comparison::Equal,
+                ">=" => Comparison::GreaterEqual,
+                ">" => Comparison::Greater,
+                _ => unreachable!(),
+            };
+            let multiplier = match caps[4].to_lowercase().as_str() {
+                "k" => 1_000,
+                "m" => 1_000_000,
+                _ => 1,
+            };
+            match caps[3].parse::<i64>() {
+                Ok(words) => {
+                    let term = Term::Words(comparison, words.saturating_mul(multiplier));
+                    terms.push(if &caps[1] == "-" {
+                        Term::Not(Box::new(term))
+                    } else {
+                        term
+                    });
+                }
+                Err(_) => invalid = Some(caps[0].to_string()),
+            }
+            String::new()
+        })
+        .to_string();
+
+    match invalid {
+        Some(term) => Err(Error::InvalidFilter(term)),
+        None => Ok((input, terms)),
+    }
+}
+
+fn fimfarchive_only(input: String) -> Result<(String, Vec<Term>), Error> {
+    let fimfarchive_re = Regex::new(
+        r#"(?i)((dis)?likes|wilson)(>=|<=|>|<)[0-9.]+|rating:[a-z]+|order:[a-z]+|fuzzy:[0-9]+|status:(incomplete|complete|hiatus|cancelled)"#,
+    )
+    .unwrap();
+
+    Ok((
+        fimfarchive_re.replace_all(&input, "").to_string(),
+        Vec::new(),
+    ))
+}
+
+fn status(input: String) -> Result<(String, Vec<Term>), Error> {
+    let status_re = Regex::new(r#"(?i)status:([a-z]+)"#).unwrap();
+    let mut statuses = Vec::new();
+
+    let input = status_re
+        .replace_all(&input, |caps: &Captures| {
+            statuses.push(caps[1].to_lowercase());
+            String::new()
+        })
+        .to_string();
+
+    let terms = statuses
+        .into_iter()
+        .map(|status| match status.as_str() {
+            "unread" => Ok(Term::Unread),
+            "started" | "read" => Ok(Term::Started),
+            _ => Err(Error::InvalidFilter(format!("status:{}", status))),
+        })
+        .collect::<Result<Vec<Term>, Error>>()?;
+
+    Ok((input, terms))
+}
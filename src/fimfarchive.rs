@@ -1,57 +1,214 @@
+use crate::cli::OutputFormat;
+use crate::text_index::IndexTuning;
 use crate::Error;
 use regex::Captures;
 use regex::Regex;
-use serde::Deserialize;
+use serde::de::{Error as _, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer as _, Serialize};
+use serde_json::value::RawValue;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
+use std::io::{BufReader, Read};
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use tantivy::collector::TopDocs;
-use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tantivy::collector::{FacetCollector, TopDocs};
+use tantivy::query::{
+    AllQuery, BooleanQuery, FuzzyTermQuery, Occur, PhraseQuery, Query, QueryParser, RangeQuery,
+    TermQuery,
+};
 use tantivy::schema::*;
+use tantivy::tokenizer::{TokenStream, TokenizerManager};
 use tantivy::Index;
 use tantivy::IndexReader;
-use tantivy::ReloadPolicy;
 
+/// Bumped whenever `FimfArchiveSchema` changes so an index built with other fields is rebuilt instead of searched.
+const SCHEMA_VERSION: u32 = 4;
+/// Kept in the index directory next to tantivy's files.
+const SCHEMA_VERSION_FILE: &str = "ereader-schema-version";
+
+/// The stories in index.json are committed in batches of this many so an interrupted build only loses the last batch.
+const BATCH_SIZE: usize = 10_000;
+
+/// Builds the index from the index.json in the fimfarchive zip, in an empty directory
+/// or continuing an index whose build was interrupted.
 pub fn load<P: AsRef<Path>>(
     fimfarchive_path: P,
     index_path: P,
-) -> (FimfArchiveSchema, Index, IndexReader) {
+    tuning: IndexTuning,
+) -> Result<IndexStats, Error> {
     let schema = FimfArchiveSchema::new();
 
-    let index = Index::create_in_dir(index_path, schema.schema.clone()).unwrap();
-    // it's really the index.json path right now, need to change it to open the zip and get the index.json
-    import_fimfarchive(fimfarchive_path, &index, &schema).unwrap();
+    let (index, done) = match progress(index_path.as_ref()) {
+        Some(done) => (Index::open_in_dir(&index_path)?, done),
+        None => (Index::create_in_dir(&index_path, schema.schema.clone())?, 0),
+    };
+    let mut archive = zip::ZipArchive::new(File::open(fimfarchive_path)?)?;
+    let reader = BufReader::new(archive.by_name("index.json")?);
+    let stats = import_fimfarchive(reader, done, &index, &schema, tuning)?;
+    std::fs::write(
+        index_path.as_ref().join(SCHEMA_VERSION_FILE),
+        SCHEMA_VERSION.to_string(),
+    )?;
+
+    Ok(stats)
+}
+
+/// Opens the index, the reader's searchers are shared by every search made with it.
+///
+/// An index built with different fields is an `IndexSchemaMismatch` error rather than a panic in the middle of a search.
+pub fn open<P: AsRef<Path>>(
+    path: P,
+    tuning: IndexTuning,
+) -> Result<(FimfArchiveSchema, Index, IndexReader), Error> {
+    let schema = FimfArchiveSchema::new();
 
-    let reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommit)
-        .try_into()
-        .unwrap();
+    let index = Index::open_in_dir(&path)?;
+    if progress(path.as_ref()).is_some() {
+        return Err(Error::IndexIncomplete(path.as_ref().display().to_string()));
+    }
+    check_schema_version(path.as_ref(), &index, &schema)?;
+
+    let reader = tuning.reader(&index)?;
+
+    Ok((schema, index, reader))
+}
 
-    (schema, index, reader)
+fn check_schema_version(
+    path: &Path,
+    index: &Index,
+    schema: &FimfArchiveSchema,
+) -> Result<(), Error> {
+    let marker = path.join(SCHEMA_VERSION_FILE);
+    let mismatch = || Error::IndexSchemaMismatch(path.display().to_string());
+
+    match std::fs::read_to_string(&marker) {
+        Ok(version) if version.trim() == SCHEMA_VERSION.to_string() => Ok(()),
+        Ok(_) => Err(mismatch()),
+        // indexes built before the version was written are fine as long as the fields match
+        Err(_) => {
+            if serde_json::to_string(&index.schema())? == serde_json::to_string(&schema.schema)? {
+                std::fs::write(marker, SCHEMA_VERSION.to_string())?;
+                Ok(())
+            } else {
+                Err(mismatch())
+            }
+        }
+    }
 }
 
-pub fn open<P: AsRef<Path>>(path: P) -> (FimfArchiveSchema, Index, IndexReader) {
+/// How many lines of index.json an interrupted build committed, None if the index isn't a build that can be continued.
+///
+/// Each batch's commit has the line count as its payload so it can't get out of step with what was committed,
+/// the last commit doesn't have one.
+fn progress(index_path: &Path) -> Option<usize> {
+    let index = Index::open_in_dir(index_path).ok()?;
+    // a build from before the fields changed has to start over
     let schema = FimfArchiveSchema::new();
+    if serde_json::to_string(&index.schema()).ok()? != serde_json::to_string(&schema.schema).ok()? {
+        return None;
+    }
+    index.load_metas().ok()?.payload?.parse().ok()
+}
 
-    let index = Index::open_in_dir(path).unwrap();
+/// How many stories a build indexed and how many entries of index.json it couldn't read.
+#[derive(Clone, Debug, Default)]
+pub struct IndexStats {
+    pub indexed: usize,
+    /// the entries whose fields didn't match what a story should have, along with why
+    pub skipped: Vec<(String, String)>,
+}
 
-    let reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommit)
-        .try_into()
-        .unwrap();
+impl std::fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Indexed {} stories.", self.indexed)?;
+        if !self.skipped.is_empty() {
+            write!(
+                f,
+                "\nSkipped {} entries that couldn't be read:",
+                self.skipped.len()
+            )?;
+            for (key, reason) in &self.skipped {
+                write!(f, "\n  {}: {}", key, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deletes the index and builds it again, e.g. after `SCHEMA_VERSION` changed.
+/// An index whose build was interrupted is continued instead.
+pub fn rebuild(
+    fimfarchive_path: &str,
+    index_path: &str,
+    tuning: IndexTuning,
+) -> Result<IndexStats, Error> {
+    if progress(Path::new(index_path)).is_none() {
+        if Path::new(index_path).exists() {
+            std::fs::remove_dir_all(index_path)?;
+        }
+        std::fs::create_dir_all(index_path)?;
+    }
+    load(fimfarchive_path, index_path, tuning)
+}
+
+/// Rebuilds the index from the paths in the settings for the rebuild-index subcommand.
+pub async fn rebuild_command() -> Result<(), Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let settings = crate::settings::Settings::load(&pool).await?;
+    pool.close().await;
+
+    match progress(Path::new(&settings.index_path)) {
+        Some(done) => println!(
+            "Continuing the fimfarchive index from story {} of the index.json in {}...",
+            done, settings.fimfarchive_path
+        ),
+        None => println!(
+            "Rebuilding the fimfarchive index from {}, this can take a while...",
+            settings.fimfarchive_path
+        ),
+    }
+    let stats = rebuild(
+        &settings.fimfarchive_path,
+        &settings.index_path,
+        settings.index_tuning,
+    )?;
+    println!("{}", stats);
+    Ok(())
+}
+
+/// Asks whether to rebuild an index that doesn't match the schema, or finish one that was interrupted,
+/// before the interface starts.
+pub fn confirm_rebuild(path: &str) -> bool {
+    if progress(Path::new(path)).is_some() {
+        print!(
+            "Building the fimfarchive index at {} was interrupted. Continue building it now? [y/N] ",
+            path
+        );
+    } else {
+        print!(
+            "The fimfarchive index at {} was built by a different version of ereader. Rebuild it now? [y/N] ",
+            path
+        );
+    }
+    let _ = std::io::Write::flush(&mut std::io::stdout());
 
-    (schema, index, reader)
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
 }
 
-type FileLines = Lines<BufReader<File>>;
+/// Reads an epub out of the fimfarchive zip.
+pub fn read_epub<P: AsRef<Path>>(archive_path: P, path: &str) -> Result<Vec<u8>, Error> {
+    let mut archive = zip::ZipArchive::new(File::open(archive_path)?)?;
+    let mut file = archive.by_name(path)?;
 
-fn file_lines<P: AsRef<Path>>(path: P) -> Result<FileLines, Error> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    let mut buff = Vec::new();
+    file.read_to_end(&mut buff)?;
 
-    Ok(reader.lines())
+    Ok(buff)
 }
 
 #[derive(Deserialize, Debug)]
@@ -94,6 +251,8 @@ struct FimfArchiveBook {
     #[serde(rename = "num_words")]
     words: i64,
     tags: Vec<FimfArchiveTag>,
+    #[serde(default)]
+    prequel: Option<i64>,
 }
 
 fn wilson_bounds(positive: f64, negative: f64) -> (f64, f64) {
@@ -109,14 +268,92 @@ fn wilson_bounds(positive: f64, negative: f64) -> (f64, f64) {
     ((a - b) / c, (a + b) / c)
 }
 
+/// Parses out `title:"exact phrase"` and `description:dragon` to only search one of the text fields.
+fn fields(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur, Box<dyn Query>)>) {
+    let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    let field_re = Regex::new(r#"(title|description):(?:"([^"]*)"|([^\s"]+))"#).unwrap();
+    let mut scoped = Vec::new();
+
+    input = field_re
+        .replace_all(&input, |caps: &Captures| {
+            let field = match &caps[1] {
+                "title" => schema.title,
+                "description" => schema.description,
+                _ => unreachable!(),
+            };
+            let text = caps.get(2).or_else(|| caps.get(3)).unwrap().as_str();
+            scoped.push((field, text.to_string()));
+            String::new()
+        })
+        .to_string();
+
+    // split the text the same way it was when it was indexed
+    let tokenizer = TokenizerManager::default().get("default").unwrap();
+    for (field, text) in scoped {
+        let mut terms = Vec::new();
+        tokenizer
+            .token_stream(&text)
+            .process(&mut |token| terms.push(Term::from_field_text(field, &token.text)));
+
+        if terms.len() == 1 {
+            let query = TermQuery::new(terms.remove(0), IndexRecordOption::WithFreqs);
+            queries.push((Occur::Must, Box::new(query)));
+        } else if terms.len() > 1 {
+            queries.push((Occur::Must, Box::new(PhraseQuery::new(terms))));
+        }
+    }
+
+    (input, queries)
+}
+
 fn authors(
     mut input: String,
     schema: &FimfArchiveSchema,
 ) -> (String, Vec<(Occur, Box<dyn Query>)>) {
     let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
-    let paren_escape_re = Regex::new(r#"\\\)"#).unwrap();
+    let paren_escape_re = Regex::new(r#"\\(\))"#).unwrap();
+
+    // excluded authors
+    let ex_author_re = Regex::new(r#"-author\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let mut ex_authors = Vec::new();
+
+    input = ex_author_re
+        .replace_all(&input, |caps: &Captures| {
+            let name = paren_escape_re.replace_all(&caps[1], |caps: &Captures| caps[1].to_string());
+            ex_authors.push(name.to_string());
+            String::new()
+        })
+        .to_string();
+
+    for author in ex_authors {
+        let facet = Facet::from_path(&["author", &author]);
+        let term = Term::from_facet(schema.author, &facet);
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        queries.push((Occur::MustNot, Box::new(query)));
+    }
+
+    // required authors, for collabs that are listed under each of them
+    let and_author_re = Regex::new(r#"\+author\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let mut and_authors = Vec::new();
+
+    input = and_author_re
+        .replace_all(&input, |caps: &Captures| {
+            let name = paren_escape_re.replace_all(&caps[1], |caps: &Captures| caps[1].to_string());
+            and_authors.push(name.to_string());
+            String::new()
+        })
+        .to_string();
+
+    for author in and_authors {
+        let facet = Facet::from_path(&["author", &author]);
+        let term = Term::from_facet(schema.author, &facet);
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        queries.push((Occur::Must, Box::new(query)));
+    }
 
+    // any of these authors
     let author_re = Regex::new(r#"author\(((?:\\\)|[^\)])+)\)"#).unwrap();
     let mut authors = Vec::new();
 
@@ -152,7 +389,7 @@ fn authors(
 fn tags(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur, Box<dyn Query>)>) {
     let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
-    let paren_escape_re = Regex::new(r#"\\\)"#).unwrap();
+    let paren_escape_re = Regex::new(r#"\\(\))"#).unwrap();
 
     let mut all_tag_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
     // This first block is for excluded tags
@@ -233,10 +470,42 @@ fn tags(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur, B
     (input, queries)
 }
 
+/// Parses out "character#(Rainbow Dash)" and "genre#(Adventure)" to search on a tag in one category,
+/// "-genre#(Sad)" excludes it.
+fn categories(
+    mut input: String,
+    schema: &FimfArchiveSchema,
+) -> (String, Vec<(Occur, Box<dyn Query>)>) {
+    let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    let paren_escape_re = Regex::new(r#"\\(\))"#).unwrap();
+
+    let category_re = Regex::new(r#"(-?)([a-z]+)#\(((?:\\\)|[^\)])+)\)"#).unwrap();
+    let mut category_tags = Vec::new();
+
+    input = category_re
+        .replace_all(&input, |caps: &Captures| {
+            let name = paren_escape_re.replace_all(&caps[3], |caps: &Captures| caps[1].to_string());
+            category_tags.push((&caps[1] == "-", caps[2].to_string(), name.to_string()));
+            String::new()
+        })
+        .to_string();
+
+    for (exclude, category, tag) in category_tags {
+        let facet = Facet::from_path(&[&category, &tag]);
+        let term = Term::from_facet(schema.category, &facet);
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        let occur = if exclude { Occur::MustNot } else { Occur::Must };
+        queries.push((occur, Box::new(query)));
+    }
+
+    (input, queries)
+}
+
 fn words(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur, Box<dyn Query>)>) {
     let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
-    let word_re = Regex::new(r#"words(>=|<=|>|<)([0-9]+)"#).unwrap();
+    let word_re = Regex::new(r#"words(>=|<=|>|<)([0-9]+)([km]?)"#).unwrap();
 
     let mut lower = 0;
     let mut upper = i64::MAX;
@@ -245,7 +514,12 @@ fn words(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur,
     input = word_re
         .replace_all(&input, |caps: &Captures| {
             filter_words = true;
-            let value = caps[2].parse::<i64>().unwrap();
+            let multiplier = match &caps[3] {
+                "k" => 1_000,
+                "m" => 1_000_000,
+                _ => 1,
+            };
+            let value = caps[2].parse::<i64>().unwrap() * multiplier;
             match &caps[1] {
                 ">=" => {
                     if value > lower {
@@ -457,16 +731,31 @@ fn rating(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur,
         })
         .to_string();
 
+    // a story only has one rating so more than one means any of them
+    let mut rating_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
     for rating in ratings {
         let facet = Facet::from_path(&["rating", &rating]);
         let term = Term::from_facet(schema.rating, &facet);
         let query = TermQuery::new(term, IndexRecordOption::Basic);
-        queries.push((Occur::Must, Box::new(query)));
+        rating_queries.push((Occur::Should, Box::new(query)));
+    }
+    if !rating_queries.is_empty() {
+        queries.push((Occur::Must, Box::new(BooleanQuery::new(rating_queries))));
     }
 
     (input, queries)
 }
 
+/// Removes the terms that only apply to the local library so the same query can be used for both.
+fn library_only(
+    input: String,
+    _schema: &FimfArchiveSchema,
+) -> (String, Vec<(Occur, Box<dyn Query>)>) {
+    let library_re = Regex::new(r#"status:(unread|started|read)"#).unwrap();
+
+    (library_re.replace_all(&input, "").to_string(), Vec::new())
+}
+
 fn status(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur, Box<dyn Query>)>) {
     let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
@@ -480,11 +769,16 @@ fn status(mut input: String, schema: &FimfArchiveSchema) -> (String, Vec<(Occur,
         })
         .to_string();
 
+    // the same goes for statuses
+    let mut status_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
     for status in statuses {
         let facet = Facet::from_path(&["status", &status]);
         let term = Term::from_facet(schema.status, &facet);
         let query = TermQuery::new(term, IndexRecordOption::Basic);
-        queries.push((Occur::Must, Box::new(query)));
+        status_queries.push((Occur::Should, Box::new(query)));
+    }
+    if !status_queries.is_empty() {
+        queries.push((Occur::Must, Box::new(BooleanQuery::new(status_queries))));
     }
 
     (input, queries)
@@ -520,14 +814,167 @@ fn order(mut input: String) -> (String, Order) {
     (input, order)
 }
 
+/// Typos further than two edits from a word would match too many other words to be useful.
+const MAX_FUZZINESS: u8 = 2;
+
+fn fuzziness(mut input: String) -> (String, u8) {
+    let fuzzy_re = Regex::new(r#"fuzzy:([0-9]+)"#).unwrap();
+
+    let mut distance = 0;
+
+    input = fuzzy_re
+        .replace_all(&input, |caps: &Captures| {
+            distance = caps[1]
+                .parse::<u8>()
+                .unwrap_or(MAX_FUZZINESS)
+                .min(MAX_FUZZINESS);
+            String::new()
+        })
+        .to_string();
+
+    (input, distance)
+}
+
+/// Matches each word of the free text in the title or description, allowing `distance` typos
+/// and treating words that end with `*` as prefixes.
+///
+/// The words are split and lowercased the same way tantivy's default tokenizer does for the index.
+fn fuzzy_text(input: &str, distance: u8, schema: &FimfArchiveSchema) -> BooleanQuery {
+    let mut word_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    for word in input.split_whitespace() {
+        let prefix = word.ends_with('*');
+        for part in word
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|part| !part.is_empty())
+        {
+            let part = part.to_lowercase();
+            let mut field_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            for field in [schema.title, schema.description] {
+                let term = Term::from_field_text(field, &part);
+                let query = if prefix {
+                    FuzzyTermQuery::new_prefix(term, distance, true)
+                } else {
+                    FuzzyTermQuery::new(term, distance, true)
+                };
+                field_queries.push((Occur::Should, Box::new(query)));
+            }
+            word_queries.push((Occur::Must, Box::new(BooleanQuery::new(field_queries))));
+        }
+    }
+
+    BooleanQuery::new(word_queries)
+}
+
+pub const RATINGS: [&str; 3] = ["everyone", "teen", "mature"];
+pub const STATUSES: [&str; 4] = ["incomplete", "complete", "hiatus", "cancelled"];
+pub const ORDERS: [&str; 5] = ["relevancy", "words", "likes", "dislikes", "wilson"];
+
+/// The fields of the advanced search form, turned into the same query the search box takes.
+#[derive(Clone, Debug, Default)]
+pub struct AdvancedSearch {
+    pub text: String,
+    /// any of these, all of them when empty
+    pub ratings: Vec<String>,
+    pub statuses: Vec<String>,
+    pub min_words: Option<i64>,
+    pub max_words: Option<i64>,
+    pub min_likes: Option<i64>,
+    pub max_likes: Option<i64>,
+    pub tags: Vec<String>,
+    pub excluded_tags: Vec<String>,
+    /// one of `ORDERS`
+    pub order: String,
+}
+
+impl AdvancedSearch {
+    pub fn query(&self) -> String {
+        let escape = |name: &str| name.replace(')', "\\)");
+        let mut terms = Vec::new();
+
+        for rating in &self.ratings {
+            terms.push(format!("rating:{}", rating));
+        }
+        for status in &self.statuses {
+            terms.push(format!("status:{}", status));
+        }
+        if let Some(words) = self.min_words {
+            terms.push(format!("words>={}", words));
+        }
+        if let Some(words) = self.max_words {
+            terms.push(format!("words<={}", words));
+        }
+        if let Some(likes) = self.min_likes {
+            terms.push(format!("likes>={}", likes));
+        }
+        if let Some(likes) = self.max_likes {
+            terms.push(format!("likes<={}", likes));
+        }
+        for tag in &self.tags {
+            terms.push(format!("#({})", escape(tag)));
+        }
+        for tag in &self.excluded_tags {
+            terms.push(format!("-#({})", escape(tag)));
+        }
+        if !self.order.is_empty() && self.order != "relevancy" {
+            terms.push(format!("order:{}", self.order));
+        }
+        if !self.text.trim().is_empty() {
+            terms.push(self.text.trim().to_string());
+        }
+
+        terms.join(" ")
+    }
+}
+
+/// Every tag in the index as its category and name, for picking tags without knowing their names.
+pub fn tag_names(
+    schema: &FimfArchiveSchema,
+    reader: &IndexReader,
+) -> Result<Vec<(String, String)>, Error> {
+    let searcher = reader.searcher();
+
+    let mut collector = FacetCollector::for_field(schema.category);
+    collector.add_facet(Facet::root());
+    let counts = searcher.search(&AllQuery, &collector)?;
+    let categories = counts
+        .get(Facet::root())
+        .map(|(facet, _count)| facet.clone())
+        .collect::<Vec<Facet>>();
+
+    let mut collector = FacetCollector::for_field(schema.category);
+    for category in &categories {
+        collector.add_facet(category.clone());
+    }
+    let counts = searcher.search(&AllQuery, &collector)?;
+
+    let mut tags = Vec::new();
+    for category in categories {
+        for (facet, _count) in counts.get(category) {
+            if let [category, tag] = facet.to_path().as_slice() {
+                tags.push((category.to_string(), tag.to_string()));
+            }
+        }
+    }
+    Ok(tags)
+}
+
 type FilterFn = fn(String, &FimfArchiveSchema) -> (String, Vec<(Occur, Box<dyn Query>)>);
 
 #[derive(Clone, Debug)]
 pub struct FimfArchiveResult {
+    /// the story's id on fimfiction
+    pub id: i64,
+    /// the id of the story this one continues
+    pub prequel: Option<i64>,
+    /// path of the epub within the fimfarchive zip
+    pub path: String,
     pub title: String,
     pub author: String,
     pub description: String,
     pub tags: Vec<String>,
+    /// the tags grouped by their category (genre, character, series, content, ...)
+    pub categories: BTreeMap<String, Vec<String>>,
     pub words: i64,
     pub likes: i64,
     pub dislikes: i64,
@@ -536,6 +983,83 @@ pub struct FimfArchiveResult {
     pub rating: String,
 }
 
+impl FimfArchiveResult {
+    /// The story's page on fimfiction.net.
+    pub fn url(&self) -> String {
+        format!("https://www.fimfiction.net/story/{}", self.id)
+    }
+}
+
+/// The columns the search subcommand prints for each result.
+#[derive(Serialize)]
+struct SearchRow<'a> {
+    title: &'a str,
+    author: &'a str,
+    words: i64,
+    wilson: f64,
+    path: &'a str,
+    tags: &'a [String],
+}
+
+/// Searches the index from the settings for the search subcommand, formatting the results to be piped elsewhere.
+pub async fn search_command(
+    query: &str,
+    limit: usize,
+    format: OutputFormat,
+) -> Result<String, Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let settings = crate::settings::Settings::load(&pool).await?;
+    pool.close().await;
+
+    let (schema, index, reader) = open(&settings.index_path, settings.index_tuning)?;
+    let results = search(query.to_string(), limit, &index, &schema, &reader);
+    let rows = results.iter().map(|result| SearchRow {
+        title: &result.title,
+        author: &result.author,
+        words: result.words,
+        wilson: result.wilson,
+        path: &result.path,
+        tags: &result.tags,
+    });
+
+    Ok(match format {
+        OutputFormat::Text => rows
+            .map(|row| {
+                format!(
+                    "{} by {} ({} words, {:.2} wilson)\n  {}\n",
+                    row.title, row.author, row.words, row.wilson, row.path
+                )
+            })
+            .collect(),
+        OutputFormat::Json => serde_json::to_string_pretty(&rows.collect::<Vec<SearchRow>>())?,
+        OutputFormat::Csv => {
+            let mut csv = String::from("title,author,words,wilson,path,tags\n");
+            for row in rows {
+                let fields = [
+                    csv_field(row.title),
+                    csv_field(row.author),
+                    row.words.to_string(),
+                    row.wilson.to_string(),
+                    csv_field(row.path),
+                    csv_field(&row.tags.join(";")),
+                ];
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            csv
+        }
+    })
+}
+
+/// Quotes a field if it has a comma, quote, or line break in it.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 pub fn search(
     mut input: String,
     limit: usize,
@@ -548,7 +1072,17 @@ pub fn search(
     let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
     let filters: Vec<FilterFn> = vec![
-        authors, tags, words, likes, dislikes, wilson, rating, status,
+        fields,
+        authors,
+        categories,
+        tags,
+        words,
+        likes,
+        dislikes,
+        wilson,
+        rating,
+        status,
+        library_only,
     ];
 
     for filter in filters {
@@ -558,15 +1092,24 @@ pub fn search(
     }
 
     let (input, order) = order(input);
+    let (input, distance) = fuzziness(input);
 
     let input = input.trim_start().trim_end().to_string();
-    if input.len() != 0 {
+    if input.len() != 0 && (distance > 0 || input.contains('*')) {
+        // the query parser doesn't do fuzzy or prefix terms
+        queries.push((Occur::Must, Box::new(fuzzy_text(&input, distance, schema))));
+    } else if input.len() != 0 {
         let query_parser = QueryParser::for_index(&index, vec![schema.title, schema.description]);
         let text_query = query_parser.parse_query(&input).unwrap();
 
         queries.push((Occur::Must, Box::new(text_query)));
     }
 
+    // a query that only excludes things wouldn't match anything on its own
+    if queries.iter().all(|(occur, _)| *occur == Occur::MustNot) {
+        queries.push((Occur::Must, Box::new(AllQuery)));
+    }
+
     let query = BooleanQuery::new(queries);
     use tantivy::DocAddress;
 
@@ -628,78 +1171,172 @@ pub fn search(
     let mut results = Vec::new();
     for doc_address in docs {
         let retrieved_doc = searcher.doc(doc_address).unwrap();
-
-        let title = retrieved_doc
-            .get_first(schema.title)
-            .unwrap()
-            .text()
-            .unwrap()
-            .to_string();
-        let author = retrieved_doc
-            .get_first(schema.author)
-            .unwrap()
-            .path()
-            .unwrap();
-        let description = retrieved_doc
-            .get_first(schema.description)
-            .unwrap()
-            .text()
-            .unwrap()
-            .to_string();
-        let words = retrieved_doc
-            .get_first(schema.words)
-            .unwrap()
-            .i64_value()
-            .unwrap();
-        let likes = retrieved_doc
-            .get_first(schema.likes)
-            .unwrap()
-            .i64_value()
-            .unwrap();
-        let dislikes = retrieved_doc
-            .get_first(schema.dislikes)
-            .unwrap()
-            .i64_value()
-            .unwrap();
-        let wilson = retrieved_doc
-            .get_first(schema.wilson)
-            .unwrap()
-            .f64_value()
-            .unwrap();
-        let status = retrieved_doc
-            .get_first(schema.status)
-            .unwrap()
-            .path()
-            .unwrap();
-        let rating = retrieved_doc
-            .get_first(schema.rating)
-            .unwrap()
-            .path()
-            .unwrap();
-        let tags = retrieved_doc
-            .get_all(schema.tag)
-            .map(|f| f.path().unwrap())
-            .collect::<Vec<String>>();
-        results.push(FimfArchiveResult {
-            title,
-            author,
-            description,
-            tags,
-            words,
-            likes,
-            dislikes,
-            wilson,
-            status,
-            rating,
-        });
+        results.push(story_result(&retrieved_doc, schema));
     }
 
     results
 }
 
+/// The stored fields of a story's document.
+fn story_result(retrieved_doc: &Document, schema: &FimfArchiveSchema) -> FimfArchiveResult {
+    let title = retrieved_doc
+        .get_first(schema.title)
+        .unwrap()
+        .text()
+        .unwrap()
+        .to_string();
+    let path = retrieved_doc
+        .get_first(schema.path)
+        .unwrap()
+        .text()
+        .unwrap()
+        .to_string();
+    let author = retrieved_doc
+        .get_first(schema.author)
+        .unwrap()
+        .path()
+        .unwrap();
+    let description = retrieved_doc
+        .get_first(schema.description)
+        .unwrap()
+        .text()
+        .unwrap()
+        .to_string();
+    let words = retrieved_doc
+        .get_first(schema.words)
+        .unwrap()
+        .i64_value()
+        .unwrap();
+    let likes = retrieved_doc
+        .get_first(schema.likes)
+        .unwrap()
+        .i64_value()
+        .unwrap();
+    let dislikes = retrieved_doc
+        .get_first(schema.dislikes)
+        .unwrap()
+        .i64_value()
+        .unwrap();
+    let wilson = retrieved_doc
+        .get_first(schema.wilson)
+        .unwrap()
+        .f64_value()
+        .unwrap();
+    let id = retrieved_doc
+        .get_first(schema.id)
+        .unwrap()
+        .i64_value()
+        .unwrap();
+    let prequel = retrieved_doc
+        .get_first(schema.prequel)
+        .and_then(|value| value.i64_value());
+    let status = retrieved_doc
+        .get_first(schema.status)
+        .unwrap()
+        .path()
+        .unwrap();
+    let rating = retrieved_doc
+        .get_first(schema.rating)
+        .unwrap()
+        .path()
+        .unwrap();
+    let tags = retrieved_doc
+        .get_all(schema.tag)
+        .map(|f| f.path().unwrap())
+        .collect::<Vec<String>>();
+    let mut categories = BTreeMap::<String, Vec<String>>::new();
+    for facet in retrieved_doc.get_all(schema.category) {
+        let path = facet.path().unwrap();
+        let mut parts = path.trim_start_matches('/').splitn(2, '/');
+        if let (Some(category), Some(tag)) = (parts.next(), parts.next()) {
+            categories
+                .entry(category.to_string())
+                .or_default()
+                .push(tag.to_string());
+        }
+    }
+    FimfArchiveResult {
+        id,
+        prequel,
+        path,
+        title,
+        author,
+        description,
+        tags,
+        categories,
+        words,
+        likes,
+        dislikes,
+        wilson,
+        status,
+        rating,
+    }
+}
+
+/// Looks up a story by its fimfiction id.
+pub fn story(
+    id: i64,
+    schema: &FimfArchiveSchema,
+    reader: &IndexReader,
+) -> Option<FimfArchiveResult> {
+    let searcher = reader.searcher();
+    let query = TermQuery::new(
+        Term::from_field_i64(schema.id, id),
+        IndexRecordOption::Basic,
+    );
+    let top_docs = searcher.search(&query, &TopDocs::with_limit(1)).ok()?;
+    let (_score, doc_address) = top_docs.into_iter().next()?;
+    let doc = searcher.doc(doc_address).ok()?;
+    Some(story_result(&doc, schema))
+}
+
+/// How a story is related to another one on fimfiction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relation {
+    Prequel,
+    Sequel,
+}
+
+impl Relation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Relation::Prequel => "prequel",
+            Relation::Sequel => "sequel",
+        }
+    }
+}
+
+/// The prequel of a story and its sequels, the stories that name it as their prequel.
+pub fn related_stories(
+    id: i64,
+    schema: &FimfArchiveSchema,
+    reader: &IndexReader,
+) -> Result<Vec<(Relation, FimfArchiveResult)>, Error> {
+    let mut related = Vec::new();
+    if let Some(prequel) = story(id, schema, reader).and_then(|story| story.prequel) {
+        if let Some(prequel) = story(prequel, schema, reader) {
+            related.push((Relation::Prequel, prequel));
+        }
+    }
+
+    let searcher = reader.searcher();
+    let query = TermQuery::new(
+        Term::from_field_i64(schema.prequel, id),
+        IndexRecordOption::Basic,
+    );
+    for (_score, doc_address) in searcher.search(&query, &TopDocs::with_limit(100))? {
+        let doc = searcher.doc(doc_address)?;
+        related.push((Relation::Sequel, story_result(&doc, schema)));
+    }
+    Ok(related)
+}
+
 #[derive(Clone)]
 pub struct FimfArchiveSchema {
     schema: Schema,
+    id: Field,
+    /// only set for stories with a prequel
+    prequel: Field,
     title: Field,
     description: Field,
     author: Field,
@@ -711,11 +1348,15 @@ pub struct FimfArchiveSchema {
     status: Field,
     rating: Field,
     tag: Field,
+    /// the tags again under their category, e.g. "/character/Rainbow Dash"
+    category: Field,
 }
 
 impl FimfArchiveSchema {
     fn new() -> Self {
         let mut schema_builder = Schema::builder();
+        schema_builder.add_i64_field("id", INDEXED | STORED);
+        schema_builder.add_i64_field("prequel", INDEXED | STORED);
         schema_builder.add_text_field("title", TEXT | STORED);
         schema_builder.add_text_field("description", TEXT | STORED);
         schema_builder.add_facet_field("author", INDEXED | STORED);
@@ -727,10 +1368,13 @@ impl FimfArchiveSchema {
         schema_builder.add_facet_field("status", INDEXED | STORED);
         schema_builder.add_facet_field("rating", INDEXED | STORED);
         schema_builder.add_facet_field("tag", INDEXED | STORED);
+        schema_builder.add_facet_field("category", INDEXED | STORED);
         let schema = schema_builder.build();
 
         FimfArchiveSchema {
             schema: schema.clone(),
+            id: schema.get_field("id").unwrap(),
+            prequel: schema.get_field("prequel").unwrap(),
             title: schema.get_field("title").unwrap(),
             description: schema.get_field("description").unwrap(),
             author: schema.get_field("author").unwrap(),
@@ -742,73 +1386,242 @@ impl FimfArchiveSchema {
             status: schema.get_field("status").unwrap(),
             rating: schema.get_field("rating").unwrap(),
             tag: schema.get_field("tag").unwrap(),
+            category: schema.get_field("category").unwrap(),
         }
     }
 }
 
-fn import_fimfarchive<P: AsRef<Path>>(
-    path: P,
+/// Indexes the stories in the fimfarchive's index.json, an object of the stories by their id,
+/// skipping the first `done` stories that an interrupted build already committed.
+///
+/// The json is streamed rather than read into memory, and an entry that isn't a story is skipped instead of
+/// stopping the build. Each entry is only split out as raw json while reading, it's parsed and turned into
+/// a document by a pool of as many threads as the index writer has since that takes about as long as indexing it.
+fn import_fimfarchive<R: Read>(
+    reader: R,
+    done: usize,
     index: &Index,
     schema: &FimfArchiveSchema,
-) -> Result<(), Error> {
-    let mut index_writer = index.writer(16_000_000).unwrap();
-
-    for line in file_lines(path).unwrap() {
-        let line = line.unwrap();
-        if line.len() != 1 {
-            // ignore the object key and trailing comma
-            let mut start = 0;
-            for j in 0..line.len() {
-                if line.as_bytes()[j] == b'{' {
-                    start = j;
-                    break;
+    tuning: IndexTuning,
+) -> Result<IndexStats, Error> {
+    let workers = tuning.writer_threads.max(1);
+    let mut indexer = StoryIndexer::new(tuning.writer(index)?, schema, workers, done);
+
+    let mut error = None;
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let streamed = deserializer.deserialize_map(StoryEntries {
+        done,
+        indexer: &mut indexer,
+        error: &mut error,
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    streamed?;
+    deserializer.end()?;
+
+    indexer.finish()
+}
+
+/// How many entries can wait for a worker per worker, enough to keep them busy without holding much of index.json.
+const QUEUED_PER_WORKER: usize = 64;
+
+/// Parses the entries of index.json on a pool of threads and adds them to the index, committing every `BATCH_SIZE`.
+struct StoryIndexer {
+    index_writer: tantivy::IndexWriter,
+    entries: SyncSender<(String, Box<RawValue>)>,
+    /// a worker that panicked on an entry sends the panic so it's passed on instead of the commit waiting for it
+    documents: Receiver<std::thread::Result<Result<Document, (String, String)>>>,
+    workers: Vec<JoinHandle<()>>,
+    /// entries sent to the workers since the last commit
+    pending: usize,
+    /// entries read including the ones already indexed, the commit payload an interrupted build continues from
+    read: usize,
+    stats: IndexStats,
+}
+
+impl StoryIndexer {
+    fn new(
+        index_writer: tantivy::IndexWriter,
+        schema: &FimfArchiveSchema,
+        workers: usize,
+        done: usize,
+    ) -> Self {
+        let (entries, entry_receiver) = sync_channel(workers * QUEUED_PER_WORKER);
+        let entry_receiver = Arc::new(Mutex::new(entry_receiver));
+        let (documents, document_receiver) = channel();
+
+        let workers = (0..workers)
+            .map(|_| {
+                let entries = entry_receiver.clone();
+                let documents = documents.clone();
+                let schema = schema.clone();
+                std::thread::spawn(move || loop {
+                    let entry = entries.lock().map(|entries| entries.recv());
+                    let (key, value) = match entry {
+                        Ok(Ok(entry)) => entry,
+                        // the indexer is done with the workers
+                        _ => break,
+                    };
+                    let document = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        parse_story(key, &value, &schema)
+                    }));
+                    if documents.send(document).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        StoryIndexer {
+            index_writer,
+            entries,
+            documents: document_receiver,
+            workers,
+            pending: 0,
+            read: done,
+            stats: IndexStats::default(),
+        }
+    }
+
+    fn add(&mut self, key: String, value: Box<RawValue>) -> Result<(), Error> {
+        self.entries
+            .send((key, value))
+            .expect("the stories' workers run until the indexer is finished");
+        self.pending += 1;
+        self.read += 1;
+
+        if self.pending == BATCH_SIZE {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the workers to finish the entries sent since the last commit and commits them.
+    fn commit(&mut self) -> Result<(), Error> {
+        for _ in 0..self.pending {
+            let document = self
+                .documents
+                .recv()
+                .expect("the stories' workers run until the indexer is finished");
+            match document {
+                Ok(Ok(doc)) => {
+                    self.index_writer.add_document(doc);
+                    self.stats.indexed += 1;
                 }
+                Ok(Err(skipped)) => self.stats.skipped.push(skipped),
+                Err(panic) => std::panic::resume_unwind(panic),
             }
-            let end = if line.as_bytes()[line.len() - 1] == b'}' {
-                line.len()
-            } else {
-                line.len() - 1
-            };
-            let object = &line[start..end];
+        }
+        self.pending = 0;
 
-            let book: FimfArchiveBook = serde_json::from_str(object).unwrap();
+        let mut commit = self.index_writer.prepare_commit()?;
+        commit.set_payload(&self.read.to_string());
+        commit.commit()?;
+        Ok(())
+    }
 
-            let mut doc = Document::default();
-            if let Some(t) = book.title {
-                doc.add_text(schema.title, t);
-            } else {
-                doc.add_text(schema.title, "UNTITLED");
-            }
-            if let Some(d) = book.description {
-                doc.add_text(schema.description, d);
-            } else {
-                doc.add_text(schema.description, "");
-            }
+    fn finish(mut self) -> Result<IndexStats, Error> {
+        if self.pending > 0 {
+            self.commit()?;
+        }
+        // without a payload the index is finished
+        self.index_writer.commit()?;
+
+        drop(self.entries);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+        Ok(self.stats)
+    }
+}
+
+/// The document for an entry of index.json, or its key and why it isn't a story.
+fn parse_story(
+    key: String,
+    value: &RawValue,
+    schema: &FimfArchiveSchema,
+) -> Result<Document, (String, String)> {
+    match serde_json::from_str::<FimfArchiveBook>(value.get()) {
+        Ok(book) => Ok(story_document(book, schema)),
+        Err(e) => Err((key, e.to_string())),
+    }
+}
+
+/// Hands the entries of index.json to the `StoryIndexer` as they're read.
+struct StoryEntries<'a> {
+    /// entries at the start that were already indexed and are only read past
+    done: usize,
+    indexer: &'a mut StoryIndexer,
+    /// what the indexer failed with, serde's errors can only carry a message
+    error: &'a mut Option<Error>,
+}
 
-            doc.add_facet(schema.author, &format!("/author/{}", book.author.name));
-            doc.add_text(schema.path, book.archive.path);
-            doc.add_i64(schema.likes, book.likes);
-            doc.add_i64(schema.dislikes, book.dislikes);
-            doc.add_i64(schema.words, book.words);
+impl<'de, 'a> Visitor<'de> for StoryEntries<'a> {
+    type Value = ();
 
-            if book.likes > 0 && book.dislikes >= 0 {
-                let (lower, _upper) = wilson_bounds(book.likes as f64, book.dislikes as f64);
-                doc.add_f64(schema.wilson, lower);
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "an object of fimfarchive stories by their id")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut seen = 0;
+
+        while let Some(key) = map.next_key::<String>()? {
+            if seen < self.done {
+                map.next_value::<IgnoredAny>()?;
             } else {
-                doc.add_f64(schema.wilson, 0.0);
+                // any json is accepted here so a story with unexpected fields only skips that story
+                let value = map.next_value::<Box<RawValue>>()?;
+                if let Err(e) = self.indexer.add(key, value) {
+                    *self.error = Some(e);
+                    return Err(A::Error::custom("unable to index a batch of stories"));
+                }
             }
+            seen += 1;
+        }
+        Ok(())
+    }
+}
 
-            doc.add_facet(schema.status, &format!("/status/{}", book.status));
-            doc.add_facet(schema.rating, &format!("/rating/{}", book.rating));
+/// The document for a story from index.json.
+fn story_document(book: FimfArchiveBook, schema: &FimfArchiveSchema) -> Document {
+    let mut doc = Document::default();
+    doc.add_i64(schema.id, book.id);
+    if let Some(prequel) = book.prequel {
+        doc.add_i64(schema.prequel, prequel);
+    }
+    if let Some(t) = book.title {
+        doc.add_text(schema.title, t);
+    } else {
+        doc.add_text(schema.title, "UNTITLED");
+    }
+    if let Some(d) = book.description {
+        doc.add_text(schema.description, d);
+    } else {
+        doc.add_text(schema.description, "");
+    }
 
-            for t in book.tags {
-                doc.add_facet(schema.tag, &format!("/tag/{}", t.name));
-            }
+    doc.add_facet(schema.author, &format!("/author/{}", book.author.name));
+    doc.add_text(schema.path, book.archive.path);
+    doc.add_i64(schema.likes, book.likes);
+    doc.add_i64(schema.dislikes, book.dislikes);
+    doc.add_i64(schema.words, book.words);
+
+    if book.likes > 0 && book.dislikes >= 0 {
+        let (lower, _upper) = wilson_bounds(book.likes as f64, book.dislikes as f64);
+        doc.add_f64(schema.wilson, lower);
+    } else {
+        doc.add_f64(schema.wilson, 0.0);
+    }
 
-            index_writer.add_document(doc);
-        }
+    doc.add_facet(schema.status, &format!("/status/{}", book.status));
+    doc.add_facet(schema.rating, &format!("/rating/{}", book.rating));
+
+    for t in book.tags {
+        doc.add_facet(schema.tag, &format!("/tag/{}", t.name));
+        doc.add_facet(schema.category, Facet::from_path(&[&t.category, &t.name]));
     }
 
-    index_writer.commit().unwrap();
-    Ok(())
+    doc
 }
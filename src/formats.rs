@@ -0,0 +1,396 @@
+use crate::library::{self, Book, Chapter, Landmark, MediaOverlay, PageTarget, Toc};
+use crate::scan::{split_authors, text, word_count};
+use crate::Error;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use scraper::{Html, Selector};
+use sqlx::{Sqlite, Transaction};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+mod epub;
+mod pdf;
+
+pub use self::epub::EpubImporter;
+pub use self::pdf::PdfImporter;
+
+/// A file read into memory that one of the importers may be able to turn into a book.
+#[derive(Clone, Debug)]
+pub struct BookFile {
+    /// where the file was found, recorded as the book's path
+    pub path: PathBuf,
+    pub hash: String,
+    pub contents: Vec<u8>,
+    id: Uuid,
+}
+
+impl BookFile {
+    pub fn new(path: PathBuf, contents: Vec<u8>) -> Self {
+        let hash = blake3::hash(contents.as_slice()).to_string();
+        // the book id is derived from the file contents so it's the same for every copy
+        let id = Uuid::new_v5(&Uuid::nil(), &contents);
+        BookFile {
+            path,
+            hash,
+            contents,
+            id,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// The same file converted into another format, it keeps the original's hash and id
+    /// so the original is recognized as already imported.
+    pub fn converted(&self, contents: Vec<u8>) -> Self {
+        BookFile {
+            path: self.path.clone(),
+            hash: self.hash.clone(),
+            contents,
+            id: self.id,
+        }
+    }
+
+    /// The lowercase extension of the path.
+    pub fn extension(&self) -> String {
+        self.path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    }
+}
+
+/// Everything that gets inserted for a book.
+///
+/// The chapters and media overlays are uncompressed until `insert_book` or `reprocess::replace_book`
+/// compresses them at the configured level.
+#[derive(Clone, Debug)]
+pub struct ParsedBook {
+    pub book: Book,
+    pub chapters: Vec<Chapter>,
+    pub toc: Vec<Toc>,
+    pub landmarks: Vec<Landmark>,
+    /// where the pages of the print edition start
+    pub page_list: Vec<PageTarget>,
+    /// read-aloud data, only epubs have it
+    pub media_overlays: Vec<MediaOverlay>,
+}
+
+impl ParsedBook {
+    /// Compresses the chapters and media overlays the way they're stored, see `library::compress_chapter`.
+    pub fn compress(&mut self, level: i32) -> Result<(), Error> {
+        for chapter in &mut self.chapters {
+            chapter.content = library::compress_chapter(&chapter.content, level)?;
+        }
+        for overlay in &mut self.media_overlays {
+            overlay.smil = library::compress_chapter(&overlay.smil, level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns files of one format into books.
+///
+/// Chapters are stored as html so formats without markup have to wrap their text in it.
+/// Other crates can implement this and add their importer with `install_registry`,
+/// it's kept object safe so the registry can hold any of them.
+pub trait Importer: Send + Sync {
+    /// The format's name, e.g. "epub".
+    fn name(&self) -> &'static str;
+
+    /// Lowercase extensions without the dot, used to find the files to scan.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Whether the contents look like this format, for files with an unknown or wrong extension.
+    fn sniff(&self, contents: &[u8]) -> bool;
+
+    /// Whether the file can't be read because of DRM, those are skipped and listed in the scan report.
+    fn is_drm_protected(&self, _file: &BookFile) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn parse(&self, file: BookFile) -> Result<ParsedBook, Error>;
+}
+
+/// The importers for every supported format, checked in the order they were registered.
+pub struct Registry {
+    importers: Vec<Box<dyn Importer>>,
+}
+
+impl Registry {
+    /// A registry without any importers, `Registry::default()` has the built in ones.
+    pub fn new() -> Self {
+        Registry {
+            importers: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, importer: Box<dyn Importer>) {
+        self.importers.push(importer);
+    }
+
+    /// Every extension some importer handles.
+    pub fn extensions(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.importers
+            .iter()
+            .flat_map(|importer| importer.extensions().iter().copied())
+    }
+
+    pub fn handles_extension(&self, extension: &str) -> bool {
+        let extension = extension.to_lowercase();
+        self.extensions().any(|handled| handled == extension)
+    }
+
+    /// The importer for a file, picked by its extension and falling back to sniffing the contents.
+    pub fn find(&self, file: &BookFile) -> Option<&dyn Importer> {
+        let extension = file.extension();
+        self.importers
+            .iter()
+            .find(|importer| importer.extensions().contains(&extension.as_str()))
+            .or_else(|| {
+                self.importers
+                    .iter()
+                    .find(|importer| importer.sniff(&file.contents))
+            })
+            .map(|importer| importer.as_ref())
+    }
+}
+
+impl Default for Registry {
+    /// The formats built into ereader.
+    fn default() -> Self {
+        let mut registry = Registry::new();
+        registry.register(Box::new(EpubImporter));
+        registry.register(Box::new(PdfImporter));
+        registry
+    }
+}
+
+static REGISTRY: OnceCell<Registry> = OnceCell::new();
+
+/// The importers used for scans and imports.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Replaces the built in registry, e.g. with `Registry::default()` plus importers from other crates.
+///
+/// It has to be called before `run` since the registry can't change once something has been imported,
+/// the registry is handed back if it's too late.
+pub fn install_registry(registry: Registry) -> Result<(), Registry> {
+    REGISTRY.set(registry)
+}
+
+/// Inserts a parsed book along with its authors, chapters, table of contents, and navigation,
+/// compressing the chapters at `compression_level`.
+pub async fn insert_book(
+    tx: &mut Transaction<'_, Sqlite>,
+    mut parsed: ParsedBook,
+    compression_level: i32,
+) -> Result<(), Error> {
+    parsed.compress(compression_level)?;
+    library::insert_book(tx, &parsed.book).await?;
+    library::set_book_authors(
+        tx,
+        parsed.book.id,
+        &split_authors(parsed.book.creator.as_deref()),
+    )
+    .await?;
+    for chapter in &parsed.chapters {
+        library::insert_chapter(tx, chapter).await?;
+    }
+    for toc in &parsed.toc {
+        library::insert_toc(tx, toc).await?;
+    }
+    for landmark in &parsed.landmarks {
+        library::insert_landmark(tx, landmark).await?;
+    }
+    for page in &parsed.page_list {
+        library::insert_page_target(tx, page).await?;
+    }
+    for overlay in &parsed.media_overlays {
+        library::insert_media_overlay(tx, overlay).await?;
+    }
+    Ok(())
+}
+
+/// Turns the bytes of an html or text file into NFC normalized text without failing on bad encodings.
+///
+/// UTF-8 is used when the bytes are valid UTF-8 or start with a BOM. A file with some valid multi-byte UTF-8
+/// has its invalid bytes decoded as the declared charset, or windows-1252 when it's not declared,
+/// since that's what gets mixed into UTF-8 by editors and copy-paste.
+/// Otherwise the whole file is decoded as the declared charset or a guessed one.
+/// Anything that still can't be decoded becomes replacement characters.
+pub fn decode_text(bytes: &[u8]) -> String {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => match Encoding::for_bom(bytes) {
+            Some((encoding, bom_length)) => encoding
+                .decode_without_bom_handling(&bytes[bom_length..])
+                .0
+                .into_owned(),
+            None => decode_legacy(bytes),
+        },
+    };
+    text.nfc().collect()
+}
+
+fn decode_legacy(bytes: &[u8]) -> String {
+    let declared = declared_charset(bytes).filter(|encoding| *encoding != UTF_8);
+
+    let mut runs = Vec::new();
+    let mut has_utf8 = false;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                has_utf8 |= !valid.is_ascii();
+                runs.push(Ok(valid));
+                rest = &[];
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let valid = std::str::from_utf8(valid).unwrap_or_default();
+                has_utf8 |= !valid.is_ascii();
+                runs.push(Ok(valid));
+                let invalid_length = e.error_len().unwrap_or(after.len());
+                runs.push(Err(&after[..invalid_length]));
+                rest = &after[invalid_length..];
+            }
+        }
+    }
+
+    if has_utf8 {
+        let encoding = declared.unwrap_or(WINDOWS_1252);
+        return runs
+            .into_iter()
+            .map(|run| match run {
+                Ok(valid) => valid.into(),
+                Err(invalid) => encoding.decode_without_bom_handling(invalid).0,
+            })
+            .collect();
+    }
+
+    let encoding = declared.unwrap_or_else(|| {
+        let mut detector = EncodingDetector::new();
+        detector.feed(bytes, true);
+        detector.guess(None, true)
+    });
+    encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+/// The charset from an xml declaration or a meta tag near the start of the file.
+fn declared_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    let charset_re =
+        regex::bytes::Regex::new(r#"(?i)(?:encoding|charset)\s*=\s*["']?([a-z0-9_.:-]+)"#).unwrap();
+
+    let head = &bytes[..bytes.len().min(1024)];
+    let label = charset_re.captures(head)?.get(1)?.as_bytes();
+    Encoding::for_label(label)
+}
+
+/// Builds a chapter from its html the same way for every format.
+///
+/// Chapters within the same book could have the same contents,
+/// so the index is mixed into the id to keep them apart.
+pub fn chapter(book_id: Uuid, index: usize, content: &str) -> Result<Chapter, Error> {
+    let chapter_index_id = Uuid::new_v5(&book_id, &index.to_le_bytes());
+    let chapter_id = Uuid::new_v5(&chapter_index_id, content.as_bytes());
+
+    Ok(Chapter {
+        id: Hyphenated::from(chapter_id),
+        book_id: Hyphenated::from(book_id),
+        index: index as i64 + 1,
+        content: content.as_bytes().to_vec(),
+        words: word_count(content),
+        linear: true,
+        title: first_heading(&[content.to_string()]),
+    })
+}
+
+/// Titles the chapters with the first toc entry pointing at them, keeping the heading from `chapter`
+/// for chapters that aren't in the toc.
+pub fn title_chapters(chapters: &mut [Chapter], toc: &[Toc]) {
+    for chapter in chapters {
+        if let Some(entry) = toc.iter().find(|entry| entry.chapter_id == chapter.id) {
+            chapter.title = Some(entry.title.clone());
+        }
+    }
+}
+
+/// Detects the language from the text of the first few chapters.
+/// Falls back to "und" (undetermined) when there isn't enough text to tell.
+pub fn detect_language(contents: &[String]) -> String {
+    let sample = contents
+        .iter()
+        .take(5)
+        .map(|content| text(content))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    whatlang::detect_lang(&sample)
+        .map(|lang| lang.code().to_string())
+        .unwrap_or_else(|| "und".to_string())
+}
+
+pub fn first_heading(contents: &[String]) -> Option<String> {
+    let heading = Selector::parse("h1, h2, h3").unwrap();
+
+    contents.iter().find_map(|content| {
+        Html::parse_document(content)
+            .select(&heading)
+            .map(|h| h.text().collect::<String>().trim().to_string())
+            .find(|text| !text.is_empty())
+    })
+}
+
+/// The story id in a fimfiction url, e.g. from an epub's identifier or source.
+pub fn fimfiction_story_id(text: &str) -> Option<i64> {
+    let story_re = Regex::new(r#"fimfiction\.net/story/([0-9]+)"#).unwrap();
+    story_re.captures(text)?[1].parse().ok()
+}
+
+/// The title to use when the file doesn't have one.
+pub fn fallback_title(path: &Path, contents: &[String]) -> String {
+    first_heading(contents)
+        .or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| "Untitled".to_string())
+}
+
+/// Wraps plain text in html for formats without markup, blank lines separate the paragraphs.
+pub fn text_html(title: Option<&str>, text: &str) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+
+    let mut html = String::from("<html><body>");
+    if let Some(title) = title {
+        html.push_str(&format!("<h2>{}</h2>", escape(title)));
+    }
+
+    let mut paragraph = Vec::new();
+    for line in text.lines().chain(std::iter::once("")) {
+        let line = line.trim();
+        if line.is_empty() {
+            if !paragraph.is_empty() {
+                html.push_str(&format!("<p>{}</p>", escape(&paragraph.join(" "))));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+
+    html.push_str("</body></html>");
+    html
+}
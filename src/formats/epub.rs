@@ -0,0 +1,498 @@
+use super::{
+    chapter, decode_text, detect_language, fallback_title, fimfiction_story_id, title_chapters,
+    BookFile, Importer, ParsedBook,
+};
+use crate::library::{Book, Chapter, Landmark, MediaOverlay, PageTarget, Toc};
+use crate::Error;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::PathBuf;
+use url::Url;
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+type Epub = ::epub::doc::EpubDoc<std::io::Cursor<Vec<u8>>>;
+
+/// Font obfuscation also uses encryption.xml but doesn't stop the book from being read.
+const FONT_OBFUSCATION_ALGORITHMS: [&str; 2] = [
+    "http://www.idpf.org/2008/embedding",
+    "http://ns.adobe.com/pdf/enc#RC",
+];
+
+/// The mimetype file an epub has to start with.
+const EPUB_MIMETYPE: &[u8] = b"mimetypeapplication/epub+zip";
+
+pub struct EpubImporter;
+
+impl Importer for EpubImporter {
+    fn name(&self) -> &'static str {
+        "epub"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["epub"]
+    }
+
+    fn sniff(&self, contents: &[u8]) -> bool {
+        // the uncompressed mimetype entry starts 30 bytes into the zip
+        contents.starts_with(b"PK\x03\x04")
+            && contents
+                .get(30..30 + EPUB_MIMETYPE.len())
+                .map(|mimetype| mimetype == EPUB_MIMETYPE)
+                .unwrap_or(false)
+    }
+
+    /// A rights.xml or an encryption.xml with anything other than font obfuscation means the
+    /// chapters are encrypted and can't be read.
+    fn is_drm_protected(&self, file: &BookFile) -> Result<bool, Error> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&file.contents))?;
+
+        if archive.by_name("META-INF/rights.xml").is_ok() {
+            return Ok(true);
+        }
+
+        let mut encryption = String::new();
+        match archive.by_name("META-INF/encryption.xml") {
+            Ok(mut file) => {
+                file.read_to_string(&mut encryption)?;
+            }
+            Err(_) => return Ok(false),
+        }
+
+        let algorithm_re = Regex::new(r#"Algorithm\s*=\s*["']([^"']+)["']"#).unwrap();
+        let protected = algorithm_re
+            .captures_iter(&encryption)
+            .any(|caps| !FONT_OBFUSCATION_ALGORITHMS.contains(&&caps[1]));
+
+        Ok(protected)
+    }
+
+    fn parse(&self, file: BookFile) -> Result<ParsedBook, Error> {
+        let book_id = file.id();
+        let BookFile {
+            path,
+            hash,
+            contents: buff,
+            ..
+        } = file;
+
+        let mut doc = ::epub::doc::EpubDoc::from_reader(std::io::Cursor::new(buff))?;
+
+        let spine = doc.spine.clone();
+        let contents = spine
+            .into_iter()
+            .map(|id| Ok(decode_text(&doc.get_resource(&id[..])?)))
+            .collect::<Result<Vec<String>, Error>>()?;
+
+        let mut chapters = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| chapter(book_id, i, content))
+            .collect::<Result<Vec<Chapter>, Error>>()?;
+
+        let package = Package::read(&mut doc)?;
+        for (chapter, id) in chapters.iter_mut().zip(doc.spine.iter()) {
+            chapter.linear = !package.non_linear.contains(id);
+        }
+        let nav = package.nav(&mut doc);
+
+        // the epub3 nav document is preferred since epub3-only books often have an empty or minimal ncx
+        let nav_toc = nav
+            .as_ref()
+            .map(|nav| targets(&doc, &nav.toc))
+            .unwrap_or_default();
+        let toc = if nav_toc.is_empty() {
+            flatten_toc(&doc.toc, 0)
+                .into_iter()
+                .map(|(nav, depth)| {
+                    let url =
+                        Url::parse(&format!("epub:///{}", nav.content.to_string_lossy())[..])?;
+                    let (spine_index, fragment) = spine_target(&doc, url)?;
+                    Ok((nav.label.clone(), depth, spine_index, fragment))
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        } else {
+            nav_toc
+        };
+        let toc = toc
+            .into_iter()
+            .enumerate()
+            .map(|(index, (title, depth, spine_index, fragment))| Toc {
+                id: 0,
+                book_id: Hyphenated::from(book_id),
+                index: index as i64,
+                chapter_id: chapters[spine_index].id,
+                title,
+                depth,
+                fragment,
+            })
+            .collect::<Vec<Toc>>();
+        title_chapters(&mut chapters, &toc);
+
+        let (landmarks, page_list) = match &nav {
+            Some(nav) => (
+                nav.landmarks
+                    .iter()
+                    .filter_map(|(kind, entry)| {
+                        let (spine_index, fragment) = entry_target(&doc, entry)?;
+                        Some(Landmark {
+                            book_id: Hyphenated::from(book_id),
+                            kind: kind.clone(),
+                            title: entry.label.clone(),
+                            chapter_id: chapters[spine_index].id,
+                            fragment,
+                        })
+                    })
+                    .collect(),
+                targets(&doc, &nav.page_list)
+                    .into_iter()
+                    .enumerate()
+                    .map(
+                        |(index, (label, _depth, spine_index, fragment))| PageTarget {
+                            book_id: Hyphenated::from(book_id),
+                            index: index as i64,
+                            label,
+                            chapter_id: chapters[spine_index].id,
+                            fragment,
+                        },
+                    )
+                    .collect(),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        let media_overlays = package.media_overlays(&mut doc, book_id, &chapters)?;
+
+        // missing metadata shouldn't stop the book from being imported,
+        // so fill in what can be guessed and flag the book to have its metadata reviewed
+        let mut metadata_review = false;
+
+        let identifier = doc.mdata("identifier").unwrap_or_else(|| {
+            metadata_review = true;
+            hash.clone()
+        });
+        let language = doc.mdata("language").unwrap_or_else(|| {
+            metadata_review = true;
+            detect_language(&contents)
+        });
+        let title = doc.mdata("title").unwrap_or_else(|| {
+            metadata_review = true;
+            fallback_title(&path, &contents)
+        });
+
+        let words = chapters.iter().map(|chapter| chapter.words).sum::<i64>();
+        // fimfiction puts the story's url in the identifier, older exports only have it in the source
+        let story_id = fimfiction_story_id(&identifier).or_else(|| {
+            doc.mdata("source")
+                .and_then(|source| fimfiction_story_id(&source))
+        });
+
+        Ok(ParsedBook {
+            book: Book {
+                id: Hyphenated::from(book_id),
+                identifier,
+                language,
+                title,
+                creator: doc.mdata("creator"),
+                description: doc.mdata("description"),
+                publisher: doc.mdata("publisher"),
+                series: doc.mdata("calibre:series"),
+                hash,
+                metadata_review,
+                words,
+                story_id,
+            },
+            chapters,
+            toc,
+            landmarks,
+            page_list,
+            media_overlays,
+        })
+    }
+}
+
+/// The spine index and fragment a link inside the epub points to.
+///
+/// Some links have a fragment to jump to a specific spot in the chapter.
+/// It's removed so the link can be turned into a spine index,
+/// but it's kept so the reader can scroll to that spot.
+fn spine_target(doc: &Epub, mut url: Url) -> Result<(usize, Option<String>), Error> {
+    let fragment = url
+        .fragment()
+        .filter(|fragment| !fragment.is_empty())
+        .map(|fragment| percent_decode_str(fragment).decode_utf8_lossy().to_string());
+    url.set_fragment(None);
+
+    let mut content_path = PathBuf::new();
+    content_path.push(archive_path(&url));
+
+    match doc.resource_uri_to_chapter(&content_path) {
+        Some(i) => Ok((i, fragment)),
+        None => Err(Error::EpubMissingTocResource),
+    }
+}
+
+/// The path within the archive of an `epub:///` url.
+fn archive_path(url: &Url) -> String {
+    let absolute_path = url.to_string();
+    let relative_path = absolute_path.trim_start_matches("epub:///");
+    percent_decode_str(relative_path)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+/// A link from the nav document, its url is already resolved against the nav document's path.
+struct NavEntry {
+    label: String,
+    url: Url,
+    depth: i64,
+}
+
+/// The lists in an epub3 nav document.
+#[derive(Default)]
+struct Nav {
+    toc: Vec<NavEntry>,
+    /// each landmark's epub:type, like cover, toc, or bodymatter, with its link
+    landmarks: Vec<(String, NavEntry)>,
+    page_list: Vec<NavEntry>,
+}
+
+fn entry_target(doc: &Epub, entry: &NavEntry) -> Option<(usize, Option<String>)> {
+    spine_target(doc, entry.url.clone()).ok()
+}
+
+/// The entries that point into the spine as (label, depth, spine index, fragment),
+/// links to files outside the spine are skipped.
+fn targets(doc: &Epub, entries: &[NavEntry]) -> Vec<(String, i64, usize, Option<String>)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (spine_index, fragment) = entry_target(doc, entry)?;
+            Some((entry.label.clone(), entry.depth, spine_index, fragment))
+        })
+        .collect()
+}
+
+/// A manifest item from the package document.
+struct ManifestItem {
+    href: String,
+    properties: String,
+    /// the id of the item with the SMIL file read-aloud books use for this item
+    media_overlay: Option<String>,
+}
+
+/// What the epub crate doesn't expose from the package document (the .opf).
+struct Package {
+    /// the url of the package document, manifest hrefs are relative to it
+    url: Url,
+    items: HashMap<String, ManifestItem>,
+    /// media:duration of the media overlays by item id
+    durations: HashMap<String, String>,
+    /// the ids of spine items marked linear="no"
+    non_linear: HashSet<String>,
+}
+
+impl Package {
+    fn read(doc: &mut Epub) -> Result<Self, Error> {
+        let root_file = doc.root_file.clone();
+        let url = Url::parse(&format!("epub:///{}", root_file.to_string_lossy()))?;
+        let opf = doc.get_resource_str_by_path(&root_file)?;
+
+        // the package document is xml so a regex is used rather than the html parser,
+        // which would treat <item/> and <meta> as html elements
+        let item_re = Regex::new(r#"<(?:opf:)?item\s[^>]*>"#).unwrap();
+        let mut items = HashMap::new();
+        for tag in item_re.find_iter(&opf) {
+            let attributes = attributes(tag.as_str());
+            if let (Some(id), Some(href)) = (attributes.get("id"), attributes.get("href")) {
+                items.insert(
+                    id.clone(),
+                    ManifestItem {
+                        href: href.clone(),
+                        properties: attributes.get("properties").cloned().unwrap_or_default(),
+                        media_overlay: attributes.get("media-overlay").cloned(),
+                    },
+                );
+            }
+        }
+
+        let meta_re = Regex::new(r#"<(?:opf:)?meta\s([^>]*)>([^<]*)<"#).unwrap();
+        let mut durations = HashMap::new();
+        for caps in meta_re.captures_iter(&opf) {
+            let attributes = attributes(&caps[1]);
+            if attributes.get("property").map(String::as_str) == Some("media:duration") {
+                if let Some(id) = attributes.get("refines") {
+                    durations.insert(
+                        id.trim_start_matches('#').to_string(),
+                        caps[2].trim().to_string(),
+                    );
+                }
+            }
+        }
+
+        let itemref_re = Regex::new(r#"<(?:opf:)?itemref\s[^>]*>"#).unwrap();
+        let non_linear = itemref_re
+            .find_iter(&opf)
+            .map(|tag| attributes(tag.as_str()))
+            .filter(|attributes| attributes.get("linear").map(String::as_str) == Some("no"))
+            .filter_map(|mut attributes| attributes.remove("idref"))
+            .collect();
+
+        Ok(Package {
+            url,
+            items,
+            durations,
+            non_linear,
+        })
+    }
+
+    /// The nav document, if the epub has one and it can be read.
+    fn nav(&self, doc: &mut Epub) -> Option<Nav> {
+        let item = self.items.values().find(|item| {
+            item.properties
+                .split_whitespace()
+                .any(|property| property == "nav")
+        })?;
+        let url = self.url.join(&item.href).ok()?;
+        let html = decode_text(&doc.get_resource_by_path(archive_path(&url)).ok()?);
+        Some(parse_nav(&html, &url))
+    }
+
+    /// The SMIL files of the chapters with media overlays, kept for aligning the text to speech later.
+    fn media_overlays(
+        &self,
+        doc: &mut Epub,
+        book_id: Uuid,
+        chapters: &[Chapter],
+    ) -> Result<Vec<MediaOverlay>, Error> {
+        let spine = doc.spine.clone();
+        let mut overlays = Vec::new();
+
+        for (spine_index, id) in spine.iter().enumerate() {
+            let overlay_id = match self
+                .items
+                .get(id)
+                .and_then(|item| item.media_overlay.as_ref())
+            {
+                Some(overlay_id) => overlay_id,
+                None => continue,
+            };
+            let smil = match self.items.get(overlay_id) {
+                Some(smil) => smil,
+                None => continue,
+            };
+
+            let url = self.url.join(&smil.href)?;
+            let smil = match doc.get_resource_by_path(archive_path(&url)) {
+                Ok(smil) => smil,
+                // a missing overlay only loses the read-aloud data
+                Err(_) => continue,
+            };
+
+            overlays.push(MediaOverlay {
+                chapter_id: chapters[spine_index].id,
+                book_id: Hyphenated::from(book_id),
+                smil,
+                duration: self.durations.get(overlay_id).cloned(),
+            });
+        }
+
+        Ok(overlays)
+    }
+}
+
+/// The attributes of an xml tag.
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let attribute_re = Regex::new(r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    attribute_re
+        .captures_iter(tag)
+        .map(|caps| {
+            let value = caps.get(2).or_else(|| caps.get(3)).unwrap();
+            (caps[1].to_string(), value.as_str().to_string())
+        })
+        .collect()
+}
+
+fn parse_nav(html: &str, url: &Url) -> Nav {
+    let document = Html::parse_document(html);
+    let navs = Selector::parse("nav").unwrap();
+    let lists = Selector::parse("ol").unwrap();
+    let links = Selector::parse("a").unwrap();
+
+    let mut nav = Nav::default();
+    for element in document.select(&navs) {
+        let kind = element.value().attr("epub:type").unwrap_or_default();
+        let list = match element.select(&lists).next() {
+            Some(list) => list,
+            None => continue,
+        };
+
+        if kind.split_whitespace().any(|kind| kind == "toc") {
+            nav_list(list, url, 0, &mut nav.toc);
+        } else if kind.split_whitespace().any(|kind| kind == "page-list") {
+            nav_list(list, url, 0, &mut nav.page_list);
+        } else if kind.split_whitespace().any(|kind| kind == "landmarks") {
+            for link in list.select(&links) {
+                if let (Some(kind), Some(entry)) =
+                    (link.value().attr("epub:type"), nav_entry(link, url, 0))
+                {
+                    nav.landmarks.push((kind.to_string(), entry));
+                }
+            }
+        }
+    }
+    nav
+}
+
+/// Adds the links of a nav list and its nested lists in reading order.
+fn nav_list(list: ElementRef, url: &Url, depth: i64, entries: &mut Vec<NavEntry>) {
+    fn children(element: ElementRef) -> Vec<ElementRef> {
+        element.children().filter_map(ElementRef::wrap).collect()
+    }
+
+    for item in children(list)
+        .into_iter()
+        .filter(|child| child.value().name() == "li")
+    {
+        let item_children = children(item);
+        if let Some(entry) = item_children
+            .iter()
+            .find(|child| child.value().name() == "a")
+            .and_then(|link| nav_entry(*link, url, depth))
+        {
+            entries.push(entry);
+        }
+        if let Some(nested) = item_children
+            .iter()
+            .find(|child| child.value().name() == "ol")
+        {
+            nav_list(*nested, url, depth + 1, entries);
+        }
+    }
+}
+
+fn nav_entry(link: ElementRef, url: &Url, depth: i64) -> Option<NavEntry> {
+    let href = link.value().attr("href")?;
+    let label = link
+        .text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    Some(NavEntry {
+        label,
+        url: url.join(href).ok()?,
+        depth,
+    })
+}
+
+/// Flattens the nested nav points into a list in reading order along with how deeply each one is nested.
+fn flatten_toc(navs: &[::epub::doc::NavPoint], depth: i64) -> Vec<(&::epub::doc::NavPoint, i64)> {
+    navs.iter()
+        .flat_map(|nav| std::iter::once((nav, depth)).chain(flatten_toc(&nav.children, depth + 1)))
+        .collect()
+}
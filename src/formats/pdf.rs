@@ -0,0 +1,268 @@
+use super::{
+    chapter, detect_language, fallback_title, text_html, title_chapters, BookFile, Importer,
+    ParsedBook,
+};
+use crate::library::{Book, Chapter, Toc};
+use crate::Error;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, HashSet};
+use uuid::adapter::Hyphenated;
+
+/// Pages per chapter for PDFs without an outline.
+const PAGES_PER_CHAPTER: u32 = 10;
+
+/// Imports the text of PDFs.
+///
+/// Only the text is kept, one paragraph per block of lines, so columns, tables, and figures are lost.
+/// Pages are grouped into chapters at the outline entries, or every few pages if there isn't an outline.
+pub struct PdfImporter;
+
+/// An outline entry and the page it points to.
+struct OutlineEntry {
+    title: String,
+    depth: i64,
+    page: u32,
+}
+
+impl Importer for PdfImporter {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["pdf"]
+    }
+
+    fn sniff(&self, contents: &[u8]) -> bool {
+        contents.starts_with(b"%PDF-")
+    }
+
+    fn is_drm_protected(&self, file: &BookFile) -> Result<bool, Error> {
+        let doc = match Document::load_mem(&file.contents) {
+            Ok(doc) => doc,
+            // a malformed pdf fails to parse too, where it's reported with the other files that couldn't be read
+            Err(_) => return Ok(false),
+        };
+        Ok(doc.trailer.get(b"Encrypt").is_ok())
+    }
+
+    fn parse(&self, file: BookFile) -> Result<ParsedBook, Error> {
+        let book_id = file.id();
+        let doc = Document::load_mem(&file.contents)?;
+
+        let pages = doc.get_pages();
+        let page_numbers = pages.keys().copied().collect::<Vec<u32>>();
+        let last_page = page_numbers.last().copied().unwrap_or(1);
+
+        let mut outline = outline(&doc, &pages);
+        outline.sort_by_key(|entry| entry.page);
+
+        // chapters start at the pages the outline points to, and at the first page so nothing before the outline is lost
+        let mut starts = outline.iter().map(|entry| entry.page).collect::<Vec<u32>>();
+        if outline.is_empty() {
+            starts = (1..=last_page)
+                .step_by(PAGES_PER_CHAPTER as usize)
+                .collect();
+        }
+        starts.push(1);
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut contents = Vec::new();
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map(|next| next - 1).unwrap_or(last_page);
+            let chapter_pages = page_numbers
+                .iter()
+                .copied()
+                .filter(|page| (*start..=end).contains(page))
+                .collect::<Vec<u32>>();
+            let text = doc.extract_text(&chapter_pages).unwrap_or_default();
+            let title = outline
+                .iter()
+                .find(|entry| entry.page == *start)
+                .map(|entry| entry.title.as_str());
+            contents.push(text_html(title, &text));
+        }
+
+        let mut chapters = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| chapter(book_id, i, content))
+            .collect::<Result<Vec<Chapter>, Error>>()?;
+
+        let chapter_at = |page: u32| starts.iter().rposition(|start| *start <= page).unwrap_or(0);
+        let toc = if outline.is_empty() {
+            starts
+                .iter()
+                .enumerate()
+                .map(|(i, start)| Toc {
+                    id: 0,
+                    book_id: Hyphenated::from(book_id),
+                    index: i as i64,
+                    chapter_id: chapters[i].id,
+                    title: format!("Page {}", start),
+                    depth: 0,
+                    fragment: None,
+                })
+                .collect()
+        } else {
+            outline
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| Toc {
+                    id: 0,
+                    book_id: Hyphenated::from(book_id),
+                    index: i as i64,
+                    chapter_id: chapters[chapter_at(entry.page)].id,
+                    title: entry.title.clone(),
+                    depth: entry.depth,
+                    fragment: None,
+                })
+                .collect::<Vec<Toc>>()
+        };
+        title_chapters(&mut chapters, &toc);
+
+        let info = doc
+            .trailer
+            .get(b"Info")
+            .ok()
+            .and_then(|info| resolve(&doc, info))
+            .and_then(|info| info.as_dict().ok());
+        let info_text = |key: &[u8]| info.and_then(|info| dictionary_text(&doc, info, key));
+
+        let title = info_text(b"Title");
+        let metadata_review = title.is_none();
+        let title = title.unwrap_or_else(|| fallback_title(&file.path, &contents));
+
+        let words = chapters.iter().map(|chapter| chapter.words).sum::<i64>();
+
+        Ok(ParsedBook {
+            book: Book {
+                id: Hyphenated::from(book_id),
+                identifier: file.hash.clone(),
+                language: detect_language(&contents),
+                title,
+                creator: info_text(b"Author"),
+                description: info_text(b"Subject"),
+                publisher: None,
+                series: None,
+                hash: file.hash,
+                metadata_review,
+                words,
+                story_id: None,
+            },
+            chapters,
+            toc,
+            landmarks: Vec::new(),
+            page_list: Vec::new(),
+            media_overlays: Vec::new(),
+        })
+    }
+}
+
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        object => Some(object),
+    }
+}
+
+/// A text string from a dictionary, PDF strings are either UTF-16 with a byte order mark or Latin-1.
+fn dictionary_text(doc: &Document, dictionary: &Dictionary, key: &[u8]) -> Option<String> {
+    let bytes = match resolve(doc, dictionary.get(key).ok()?)? {
+        Object::String(bytes, _) => bytes,
+        _ => return None,
+    };
+
+    let text = match bytes.strip_prefix(&[0xfe, 0xff]) {
+        Some(utf16) => String::from_utf16_lossy(
+            &utf16
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+                .collect::<Vec<u16>>(),
+        ),
+        None => bytes.iter().map(|b| *b as char).collect(),
+    };
+
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// The entries of the document outline in order, with the pages they point to.
+/// Entries using named destinations are skipped.
+fn outline(doc: &Document, pages: &BTreeMap<u32, ObjectId>) -> Vec<OutlineEntry> {
+    let page_numbers = pages
+        .iter()
+        .map(|(number, id)| (*id, *number))
+        .collect::<BTreeMap<ObjectId, u32>>();
+
+    let first = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Outlines").ok())
+        .and_then(|outlines| resolve(doc, outlines))
+        .and_then(|outlines| outlines.as_dict().ok())
+        .and_then(|outlines| outlines.get(b"First").ok());
+
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    if let Some(first) = first {
+        outline_items(doc, &page_numbers, first, 0, &mut visited, &mut entries);
+    }
+    entries
+}
+
+fn outline_items(
+    doc: &Document,
+    page_numbers: &BTreeMap<ObjectId, u32>,
+    first: &Object,
+    depth: i64,
+    visited: &mut HashSet<ObjectId>,
+    entries: &mut Vec<OutlineEntry>,
+) {
+    let mut next = first.as_reference().ok();
+
+    // broken files can have loops in the outline
+    while let Some(id) = next.filter(|id| visited.insert(*id)) {
+        let item = match doc.get_object(id).and_then(|item| item.as_dict()) {
+            Ok(item) => item,
+            Err(_) => break,
+        };
+
+        let page = destination_page(doc, item).and_then(|page| page_numbers.get(&page).copied());
+        if let (Some(title), Some(page)) = (dictionary_text(doc, item, b"Title"), page) {
+            entries.push(OutlineEntry { title, depth, page });
+        }
+
+        if let Ok(children) = item.get(b"First") {
+            outline_items(doc, page_numbers, children, depth + 1, visited, entries);
+        }
+        next = item
+            .get(b"Next")
+            .ok()
+            .and_then(|next| next.as_reference().ok());
+    }
+}
+
+/// The page object an outline item points to, from either its /Dest or a GoTo action.
+fn destination_page(doc: &Document, item: &Dictionary) -> Option<ObjectId> {
+    let destination = match item.get(b"Dest") {
+        Ok(destination) => destination,
+        Err(_) => resolve(doc, item.get(b"A").ok()?)?
+            .as_dict()
+            .ok()?
+            .get(b"D")
+            .ok()?,
+    };
+
+    resolve(doc, destination)?
+        .as_array()
+        .ok()?
+        .first()?
+        .as_reference()
+        .ok()
+}
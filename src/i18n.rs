@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// The locales that can be picked in the settings, "auto" uses the one from the environment.
+pub const LOCALES: &[&str] = &["auto", "en", "es"];
+
+/// The Spanish catalog, it's a gettext .po file so it can be edited with the usual tools.
+const ES: &str = include_str!("../locales/es.po");
+
+/// Translations are looked up by their English text, gettext style,
+/// so anything that hasn't been translated is shown in English.
+struct Locale {
+    messages: HashMap<&'static str, &'static str>,
+    date_format: &'static str,
+    datetime_format: &'static str,
+    thousands_separator: char,
+}
+
+impl Locale {
+    fn new(name: &str) -> Self {
+        match name {
+            "es" => Locale {
+                messages: catalog(ES),
+                date_format: "%d/%m/%Y",
+                datetime_format: "%d/%m/%Y %H:%M",
+                thousands_separator: '.',
+            },
+            _ => Locale {
+                messages: HashMap::new(),
+                date_format: "%Y-%m-%d",
+                datetime_format: "%Y-%m-%d %H:%M",
+                thousands_separator: ',',
+            },
+        }
+    }
+}
+
+static LOCALE: OnceCell<Locale> = OnceCell::new();
+
+/// Picks the locale from the setting, it's only read once at startup.
+pub fn load(setting: &str) {
+    let name = if setting == "auto" {
+        environment_locale()
+    } else {
+        setting.to_string()
+    };
+    let _ = LOCALE.set(Locale::new(&name));
+}
+
+/// The language of the first of the usual locale variables that's set, e.g. "es" for LANG=es_MX.UTF-8.
+fn environment_locale() -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .split(|c| c == '_' || c == '.' || c == '@')
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        })
+        .unwrap_or_default()
+}
+
+fn locale() -> &'static Locale {
+    LOCALE.get_or_init(|| Locale::new("en"))
+}
+
+/// Reads the msgid/msgstr pairs of a .po file.
+/// Only single line messages are supported and they can't contain escaped quotes.
+fn catalog(po: &'static str) -> HashMap<&'static str, &'static str> {
+    let quoted = |line: &'static str, keyword: &str| {
+        line.strip_prefix(keyword)
+            .map(|rest| rest.trim().trim_start_matches('"').trim_end_matches('"'))
+    };
+
+    let mut messages = HashMap::new();
+    let mut id = None;
+    for line in po.lines().map(str::trim) {
+        if let Some(msgid) = quoted(line, "msgid ") {
+            id = Some(msgid);
+        } else if let (Some(msgid), Some(msgstr)) = (id, quoted(line, "msgstr ")) {
+            // the header has an empty msgid and untranslated messages have an empty msgstr
+            if !msgid.is_empty() && !msgstr.is_empty() {
+                messages.insert(msgid, msgstr);
+            }
+            id = None;
+        }
+    }
+    messages
+}
+
+/// The translation of a message.
+pub fn tr(message: &'static str) -> &'static str {
+    locale().messages.get(message).copied().unwrap_or(message)
+}
+
+/// The translation of a message with each `{}` replaced by the next argument.
+pub fn tr_args(message: &'static str, args: &[&dyn Display]) -> String {
+    let mut parts = tr(message).split("{}");
+    let mut args = args.iter();
+    let mut translated = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            translated.push_str(&arg.to_string());
+        }
+        translated.push_str(part);
+    }
+    translated
+}
+
+/// A number with the locale's thousands separator, e.g. 12,345 or 12.345.
+pub fn format_number(number: i64) -> String {
+    let digits = number.abs().to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            formatted.push(locale().thousands_separator);
+        }
+        formatted.push(digit);
+    }
+    if number < 0 {
+        formatted.insert(0, '-');
+    }
+    formatted
+}
+
+pub fn format_date(date: &DateTime<Utc>) -> String {
+    date.format(locale().date_format).to_string()
+}
+
+pub fn format_datetime(date: &DateTime<Utc>) -> String {
+    date.format(locale().datetime_format).to_string()
+}
@@ -0,0 +1,436 @@
+use crate::library::{self, JobRow};
+use crate::reprocess::{parse_book_ids, reprocess};
+use crate::scan::{rebuild_authors, recount_words, scan, ScanReport};
+use crate::text_index::TextIndex;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use futures::future::{AbortHandle, Abortable};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+
+/// Something slow that runs in the background instead of blocking the interface.
+///
+/// Stored as JSON in the jobs table so interrupted jobs can run again after a restart,
+/// every kind is safe to run twice since they skip whatever is already done.
+/// Importing a single file isn't a job because the book is opened as soon as it's imported.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Scan {
+        path: String,
+        /// only report what would be imported, jobs queued before this existed weren't dry runs
+        #[serde(default)]
+        dry_run: bool,
+    },
+    RecountWords,
+    RebuildAuthors,
+    RebuildTextIndex,
+    ExportSync {
+        path: String,
+    },
+    /// the ids of the books to run through the import pipeline again, the whole library if it's empty
+    Reprocess {
+        book_ids: Vec<String>,
+    },
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobKind::Scan {
+                path,
+                dry_run: false,
+            } => write!(f, "Scan {}", path),
+            JobKind::Scan {
+                path,
+                dry_run: true,
+            } => write!(f, "Dry run scan of {}", path),
+            JobKind::RecountWords => write!(f, "Recount words"),
+            JobKind::RebuildAuthors => write!(f, "Rebuild authors"),
+            JobKind::RebuildTextIndex => write!(f, "Rebuild text index"),
+            JobKind::ExportSync { path } => write!(f, "Export sync to {}", path),
+            JobKind::Reprocess { book_ids } if book_ids.is_empty() => {
+                write!(f, "Reprocess the library")
+            }
+            JobKind::Reprocess { book_ids } => write!(f, "Reprocess {} books", book_ids.len()),
+        }
+    }
+}
+
+impl JobKind {
+    /// Runs the job, returning a report of what it did.
+    async fn run(self, pool: SqlitePool, text_index: TextIndex) -> Result<String, Error> {
+        match self {
+            // a dry run is listed in full since the point is to see what the scan would do with each file
+            JobKind::Scan { path, dry_run } => {
+                let report = scan(&pool, path, dry_run).await?;
+                report.forget_updated(&text_index)?;
+                Ok(scan_report(&report, dry_run))
+            }
+            JobKind::RecountWords => Ok(format!(
+                "Updated the word counts of {} chapters.",
+                recount_words(&pool).await?
+            )),
+            JobKind::RebuildAuthors => Ok(format!(
+                "Found {} authors in the library.",
+                rebuild_authors(&pool).await?
+            )),
+            JobKind::RebuildTextIndex => Ok(format!(
+                "Indexed the text of {} books.",
+                text_index.rebuild(&pool).await?
+            )),
+            JobKind::ExportSync { path } => {
+                let changeset = crate::sync::changeset(&pool).await?;
+                std::fs::write(&path, serde_json::to_string_pretty(&changeset)?)?;
+                Ok(format!("Wrote the reading state to {}.", path))
+            }
+            JobKind::Reprocess { book_ids } => {
+                let book_ids = parse_book_ids(&book_ids)?;
+                Ok(reprocess(&pool, &text_index, &book_ids).await?.to_string())
+            }
+        }
+    }
+}
+
+/// What was imported, skipped, or already in the library after a scan,
+/// `verbose` also lists the files that were already in the library at the same path.
+pub fn scan_report(report: &ScanReport, verbose: bool) -> String {
+    let mut text = if report.dry_run {
+        format!("Would import {} books.\n", report.imported.len())
+    } else {
+        format!("Imported {} books.\n", report.imported.len())
+    };
+    for path in &report.imported {
+        text.push_str(&format!("  {}\n", path));
+    }
+
+    if !report.updated.is_empty() {
+        text.push_str(&format!(
+            "\nUpdated {} books to a newer version of their story:\n",
+            report.updated.len()
+        ));
+        for (path, book_id) in &report.updated {
+            text.push_str(&format!("  {}\n    {}\n", path, book_id));
+        }
+    }
+
+    if !report.duplicates.is_empty() {
+        text.push_str(&format!(
+            "\nFound {} files already in the library:\n",
+            report.duplicates.len()
+        ));
+        for duplicate in &report.duplicates {
+            let kind = if duplicate.same_file {
+                "same file as"
+            } else {
+                "same contents as"
+            };
+            text.push_str(&format!(
+                "  {}\n    {} {}\n",
+                duplicate.path,
+                kind,
+                duplicate.other_paths.join(", ")
+            ));
+        }
+    }
+
+    if verbose && !report.unchanged.is_empty() {
+        text.push_str(&format!(
+            "\nFound {} files already in the library at the same path:\n",
+            report.unchanged.len()
+        ));
+        for path in &report.unchanged {
+            text.push_str(&format!("  {}\n", path));
+        }
+    }
+
+    if !report.superseded.is_empty() {
+        text.push_str(&format!(
+            "\nSkipped {} older versions of stories that are in the library:\n",
+            report.superseded.len()
+        ));
+        for path in &report.superseded {
+            text.push_str(&format!("  {}\n", path));
+        }
+    }
+
+    if !report.drm_protected.is_empty() {
+        text.push_str(&format!(
+            "\nSkipped {} DRM-protected files:\n",
+            report.drm_protected.len()
+        ));
+        for path in &report.drm_protected {
+            text.push_str(&format!("  {}\n", path));
+        }
+    }
+
+    if !report.unsupported.is_empty() {
+        text.push_str(&format!(
+            "\nSkipped {} files in formats that can't be imported:\n",
+            report.unsupported.len()
+        ));
+        for path in &report.unsupported {
+            text.push_str(&format!("  {}\n", path));
+        }
+    }
+
+    if !report.hook_failed.is_empty() {
+        text.push_str(&format!(
+            "\nImported {} files whose on_book_imported hook failed:\n",
+            report.hook_failed.len()
+        ));
+        for (path, reason) in &report.hook_failed {
+            text.push_str(&format!("  {}\n    {}\n", path, reason));
+        }
+    }
+
+    if !report.failed.is_empty() {
+        text.push_str(&format!(
+            "\nUnable to import {} files:\n",
+            report.failed.len()
+        ));
+        for (path, reason) in &report.failed {
+            text.push_str(&format!("  {}\n    {}\n", path, reason));
+        }
+    }
+
+    text
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(state: &str) -> Option<Self> {
+        Some(match state {
+            "queued" => JobState::Queued,
+            "running" => JobState::Running,
+            "done" => JobState::Done,
+            "failed" => JobState::Failed,
+            "cancelled" => JobState::Cancelled,
+            _ => return None,
+        })
+    }
+
+    pub fn finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: i64,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// the report of a job that's done or the error of one that failed
+    pub message: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+impl Job {
+    fn from_row(row: JobRow) -> Result<Self, Error> {
+        Ok(Job {
+            id: row.id,
+            kind: serde_json::from_str(&row.kind)?,
+            state: JobState::parse(&row.state)
+                .ok_or_else(|| Error::DebugMsg(format!("unknown job state {}", row.state)))?,
+            message: row.message,
+            updated: row.updated,
+        })
+    }
+}
+
+/// The jobs that can be read, one queued by a newer version is failed by the worker and left out here.
+pub async fn get_jobs(pool: &SqlitePool) -> Result<Vec<Job>, Error> {
+    Ok(library::get_jobs(pool)
+        .await?
+        .into_iter()
+        .filter_map(|row| Job::from_row(row).ok())
+        .collect())
+}
+
+/// The process that claims jobs in the jobs table.
+fn owner() -> i64 {
+    std::process::id() as i64
+}
+
+/// Whether another process with the pid is still running.
+/// This process hasn't claimed anything when the queue starts, so a job with its pid was left by one that exited.
+fn process_running(pid: i64) -> bool {
+    pid != owner() && std::path::Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Called from the worker whenever a job starts or finishes.
+type OnUpdate = Arc<dyn Fn(Job) + Send + Sync>;
+
+/// Runs the queued jobs one at a time on a task of the runtime.
+#[derive(Clone)]
+pub struct JobQueue {
+    pool: SqlitePool,
+    text_index: TextIndex,
+    /// woken when a job is queued
+    wake: Arc<Notify>,
+    running: Arc<Mutex<Option<(i64, AbortHandle)>>>,
+}
+
+impl JobQueue {
+    pub fn new(pool: SqlitePool, text_index: TextIndex) -> Self {
+        JobQueue {
+            pool,
+            text_index,
+            wake: Arc::new(Notify::new()),
+            running: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts working through the queue, including the jobs that were running when ereader last exited.
+    ///
+    /// Other processes can work on the same queue, e.g. a second ereader started without the daemon,
+    /// each job is only claimed by one of them and the jobs another one is still running are left alone.
+    pub fn start<F: Fn(Job) + Send + Sync + 'static>(
+        &self,
+        runtime: &Runtime,
+        on_update: F,
+    ) -> Result<(), Error> {
+        runtime.block_on(library::requeue_running_jobs(&self.pool, process_running))?;
+
+        let queue = self.clone();
+        runtime.spawn(queue.work(Arc::new(on_update)));
+        Ok(())
+    }
+
+    pub async fn enqueue(&self, kind: JobKind) -> Result<i64, Error> {
+        let id = library::insert_job(&self.pool, &serde_json::to_string(&kind)?).await?;
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    /// Stops a running job or keeps a queued one from starting.
+    pub async fn cancel(&self, id: i64) -> Result<(), Error> {
+        let aborted = match &*self.running.lock().unwrap() {
+            Some((running_id, abort)) if *running_id == id => {
+                abort.abort();
+                true
+            }
+            _ => false,
+        };
+
+        if !aborted {
+            library::cancel_queued_job(&self.pool, id).await?;
+        }
+        Ok(())
+    }
+
+    async fn work(self, on_update: OnUpdate) {
+        loop {
+            let claimed = match library::get_next_queued_job(&self.pool).await {
+                Ok(Some(row)) => match library::claim_job(&self.pool, row.id, owner()).await {
+                    Ok(true) => Ok(row),
+                    // another process started it, move on to the next one
+                    Ok(false) => continue,
+                    Err(e) => Err(e),
+                },
+                // notify_one leaves a permit if nothing is waiting so a job queued before this is still noticed
+                Ok(None) => {
+                    self.wake.notified().await;
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+
+            let row = match claimed {
+                Ok(row) => row,
+                // the database is unusable, wait for something new to be queued before trying again
+                Err(_) => {
+                    self.wake.notified().await;
+                    continue;
+                }
+            };
+
+            let id = row.id;
+            match Job::from_row(row) {
+                Ok(job) => self.run(job, &on_update).await,
+                // e.g. a kind queued by a newer version, it's failed so it isn't picked again ahead of every other job
+                Err(e) => {
+                    let message = format!("unable to read the job: {}", e);
+                    let _ = library::set_job_state(
+                        &self.pool,
+                        id,
+                        JobState::Failed.as_str(),
+                        Some(&message),
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    async fn run(&self, mut job: Job, on_update: &OnUpdate) {
+        let (abort, registration) = AbortHandle::new_pair();
+        *self.running.lock().unwrap() = Some((job.id, abort));
+
+        // claiming the job already marked it as running
+        job.state = JobState::Running;
+        job.updated = Utc::now();
+        on_update(job.clone());
+
+        let task = job
+            .kind
+            .clone()
+            .run(self.pool.clone(), self.text_index.clone());
+        let result = Abortable::new(task, registration).await;
+        *self.running.lock().unwrap() = None;
+
+        let (state, message) = match result {
+            Ok(Ok(report)) => (JobState::Done, Some(report)),
+            Ok(Err(e)) => (JobState::Failed, Some(e.to_string())),
+            Err(_aborted) => (JobState::Cancelled, None),
+        };
+        job.state = state;
+        job.message = message;
+        job.updated = Utc::now();
+
+        if let Err(e) = library::set_job_state(
+            &self.pool,
+            job.id,
+            job.state.as_str(),
+            job.message.as_deref(),
+        )
+        .await
+        {
+            job.state = JobState::Failed;
+            job.message = Some(e.to_string());
+        }
+        on_update(job);
+    }
+}
@@ -0,0 +1,558 @@
+#![allow(dead_code)]
+
+mod api;
+mod citation;
+mod cli;
+mod clipboard;
+mod completions;
+mod convert;
+mod daemon;
+mod desktop;
+mod filter;
+mod fimfarchive;
+mod formats;
+mod i18n;
+mod jobs;
+mod library;
+mod migrate;
+#[cfg(feature = "cursive-frontend")]
+mod new_tui;
+mod pick;
+#[cfg(feature = "ratatui-frontend")]
+mod ratatui_frontend;
+#[cfg(feature = "cursive-frontend")]
+mod render;
+mod reprocess;
+mod rules;
+mod scan;
+mod scripts;
+mod settings;
+mod state;
+mod sync;
+#[cfg(feature = "cursive-frontend")]
+mod terminal;
+mod text_index;
+mod verify;
+mod view_model;
+mod web;
+
+use clap::Parser;
+#[cfg(feature = "cursive-frontend")]
+use cursive::event::Event;
+#[cfg(feature = "cursive-frontend")]
+use cursive::Cursive;
+#[cfg(feature = "cursive-frontend")]
+use new_tui::error_message;
+use thiserror::Error;
+use uuid::adapter::Hyphenated;
+
+// what other crates need to write an importer, see `Importer`
+pub use formats::{
+    chapter, detect_language, fallback_title, first_heading, insert_book, install_registry,
+    text_html, title_chapters, BookFile, EpubImporter, Importer, ParsedBook, PdfImporter, Registry,
+};
+pub use library::{Book, Chapter, Landmark, MediaOverlay, PageTarget, Toc};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("sqlx error {0}")]
+    SqlxError(sqlx::Error),
+    #[error("unable to parse epub")]
+    UnableToParseEpub,
+    #[error("missing metadata tag {0}")]
+    MissingMetadata(String),
+    #[error("unable to get resource")]
+    UnableToGetResource,
+    #[error("invalid spine index: {0}")]
+    InvalidSpineIndex(usize),
+    #[error("anyhow error {0}")]
+    AnyhowError(anyhow::Error),
+    #[error("unable to parse html")]
+    UnableToParseHTML,
+    #[error("unable to find {0} in html")]
+    UnableToFindSelector(String),
+    #[error("io error {0}")]
+    IOError(std::io::Error),
+    #[error("url parse error {0}")]
+    UrlParseError(url::ParseError),
+    #[error("epub missing resource listed in table of contents")]
+    EpubMissingTocResource,
+    #[error("debug message {0}")]
+    DebugMsg(String),
+    #[error("Missing UserData in Cursive")]
+    MissingUserData,
+    #[error("Cursive view not found.")]
+    ViewNotFound,
+    #[error("invalid go to target {0}, expected a percentage like 42%, a chapter like ch 7, or a page like p 12")]
+    InvalidGotoTarget(String),
+    #[error("regex error {0}")]
+    RegexError(regex::Error),
+    #[error("json error {0}")]
+    JsonError(serde_json::Error),
+    #[error("zip error {0}")]
+    ZipError(zip::result::ZipError),
+    #[error("no readable epub found in {0}, it may be DRM-protected")]
+    NoEpubFound(String),
+    #[error("unable to find the home directory")]
+    MissingHomeDir,
+    #[error("{0} failed")]
+    CommandFailed(String),
+    #[error("invalid filter term {0}")]
+    InvalidFilter(String),
+    #[error("invalid value {1} for setting {0}")]
+    InvalidSetting(String, String),
+    #[error("tantivy error {0}")]
+    TantivyError(tantivy::TantivyError),
+    #[error("toml error {0}")]
+    TomlSerializeError(toml::ser::Error),
+    #[error("toml error {0}")]
+    TomlDeserializeError(toml::de::Error),
+    #[error("sync file version {0} is newer than this version of ereader supports")]
+    UnsupportedSyncVersion(u32),
+    #[error("the sync server's changeset kept changing while pushing, try again later")]
+    SyncConflict,
+    #[error("http error {0}")]
+    HttpError(Box<ureq::Error>),
+    #[error("invalid query {0}")]
+    QueryParserError(tantivy::query::QueryParserError),
+    #[error("invalid rule: {0}")]
+    InvalidRule(String),
+    #[error("{0} isn't in a format that can be imported")]
+    UnsupportedFormat(String),
+    #[error("unable to import {0}: {1}")]
+    ConversionFailed(String, String),
+    #[error("pdf error {0}")]
+    PdfError(lopdf::Error),
+    /// for importers from other crates to wrap their errors in
+    #[error("importer error {0}")]
+    ImporterError(Box<dyn std::error::Error + Send + Sync>),
+    #[error("unable to find the note {0} links to")]
+    NoteNotFound(String),
+    #[error("daemon error {0}")]
+    DaemonError(String),
+    #[error("invalid book id {0}")]
+    InvalidBookId(String),
+    #[error("the file book {0} was imported from is gone and its original wasn't kept")]
+    OriginalNotFound(String),
+    #[error("chapter {1} of book {0} is corrupt and can't be decompressed")]
+    CorruptChapter(Hyphenated, i64),
+    #[error("the fimfarchive index at {0} was built by a different version of ereader, rebuild it with `ereader rebuild-index`")]
+    IndexSchemaMismatch(String),
+    #[error("building the fimfarchive index at {0} was interrupted, finish it with `ereader rebuild-index`")]
+    IndexIncomplete(String),
+    #[error("web server error {0}")]
+    WebServerError(String),
+    #[error("script error {0}")]
+    ScriptError(Box<rhai::EvalAltResult>),
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::SqlxError(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::AnyhowError(e)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Error::UrlParseError(e)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::RegexError(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::JsonError(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::ZipError(e)
+    }
+}
+
+impl From<tantivy::TantivyError> for Error {
+    fn from(e: tantivy::TantivyError) -> Self {
+        Error::TantivyError(e)
+    }
+}
+
+impl From<lopdf::Error> for Error {
+    fn from(e: lopdf::Error) -> Self {
+        Error::PdfError(e)
+    }
+}
+
+impl From<Box<rhai::EvalAltResult>> for Error {
+    fn from(e: Box<rhai::EvalAltResult>) -> Self {
+        Error::ScriptError(e)
+    }
+}
+
+impl From<tantivy::query::QueryParserError> for Error {
+    fn from(e: tantivy::query::QueryParserError) -> Self {
+        Error::QueryParserError(e)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::TomlSerializeError(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::TomlDeserializeError(e)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        Error::HttpError(Box::new(e))
+    }
+}
+
+#[cfg(feature = "cursive-frontend")]
+impl From<cursive::view::ViewNotFound> for Error {
+    fn from(_e: cursive::view::ViewNotFound) -> Self {
+        Error::ViewNotFound
+    }
+}
+
+/// Runs ereader with the command line arguments, everything `main` does.
+pub async fn run() {
+    let cli = cli::Cli::parse();
+
+    if let Err(e) = migrate::migrate("ereader.sqlite").await {
+        eprintln!("unable to update the library database: {}", e);
+        std::process::exit(1);
+    }
+
+    // the book chosen with `ereader pick`, opened once the interface is up
+    let mut picked = None;
+
+    match &cli.command {
+        Some(cli::Command::Scan {
+            path,
+            dry_run,
+            verbose,
+        }) => {
+            match scan::scan_command(path, *dry_run).await {
+                Ok(report) => print!("{}", jobs::scan_report(&report, *verbose)),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::Reprocess { book_ids }) => {
+            match reprocess::reprocess_command(book_ids).await {
+                Ok(report) => println!("{}", report),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::RebuildIndex) => {
+            match fimfarchive::rebuild_command().await {
+                Ok(()) => println!("rebuilt the fimfarchive index"),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::Verify { repair }) => {
+            if let Err(e) = verify::verify_command(*repair).await {
+                eprintln!("{}", e);
+            }
+            return;
+        }
+        Some(cli::Command::Search {
+            query,
+            limit,
+            format,
+        }) => {
+            match fimfarchive::search_command(query, *limit, *format).await {
+                Ok(output) => print!("{}", output),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::Pick { list, open, picker }) => {
+            if let Some(line) = open {
+                match pick::book_id(line) {
+                    Some(book_id) => picked = Some(book_id),
+                    None => {
+                        eprintln!("no book id in {}", line);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                let lines = match pick::book_lines().await {
+                    Ok(lines) => lines,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                };
+                if *list {
+                    print!("{}", lines);
+                    return;
+                }
+                match pick::pick(picker, &lines) {
+                    Ok(Some(book_id)) => picked = Some(book_id),
+                    Ok(None) => return,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Some(cli::Command::GenerateCompletions { dir }) => {
+            match completions::generate(dir) {
+                Ok(paths) => {
+                    for path in paths {
+                        println!("wrote {}", path.display());
+                    }
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::InstallDesktop) => {
+            match desktop::install() {
+                Ok(path) => println!("installed {}", path.display()),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::ExportSettings { path }) => {
+            match settings::export(path).await {
+                Ok(()) => println!("exported settings to {}", path),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::ImportSettings { path }) => {
+            match settings::import(path).await {
+                Ok(kept) => {
+                    for (name, imported) in kept {
+                        eprintln!(
+                            "kept the local {} since {} isn't a directory here",
+                            name, imported
+                        );
+                    }
+                    println!("imported settings from {}", path);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::ExportOriginal { book_id, path }) => {
+            match scan::export_original_command(book_id, path).await {
+                Ok(()) => println!("exported the original of {} to {}", book_id, path),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::ExportSync { path }) => {
+            match sync::export(path).await {
+                Ok(()) => println!("exported reading state to {}", path),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::ImportSync { path }) => {
+            match sync::import(path).await {
+                Ok(report) => println!("{}", report),
+                Err(e) => eprintln!("{}", e),
+            }
+            return;
+        }
+        Some(cli::Command::Daemon) => {
+            if let Err(e) = daemon::serve(daemon::SOCKET_PATH) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(cli::Command::Web { address, token }) => {
+            if let Err(e) = web::serve(address, token.clone()) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        #[cfg(feature = "ratatui-frontend")]
+        Some(cli::Command::Ratatui) => {
+            if let Err(e) = ratatui_frontend::run() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(cli::Command::StopDaemon) => {
+            match daemon::Client::connect(daemon::SOCKET_PATH) {
+                Some(client) => match client.shutdown() {
+                    Ok(()) => println!("stopped the daemon"),
+                    Err(e) => eprintln!("{}", e),
+                },
+                None => eprintln!("no daemon is running"),
+            }
+            return;
+        }
+        None => {}
+    }
+
+    // // what is needed for loading the index and what is needed for searching?
+    // // for loading, the location of the fimfarchive.zip and the directory for the index
+    // // for searching, the directory for the index
+
+    // //let (schema, index, reader) = fimfarchive::load("index.json", "index");
+    // let (schema, index, reader) = fimfarchive::open("index");
+
+    // println!("What is your query?");
+
+    // let stdin = std::io::stdin();
+    // let input = stdin.lock().lines().next().unwrap().unwrap();
+
+    // println!("Results limit?");
+
+    // let stdin = std::io::stdin();
+    // let limit_str = stdin.lock().lines().next().unwrap().unwrap();
+    // let limit: usize = limit_str.parse().expect("expected a usize");
+
+    // fimfarchive::search(input, limit, &index, &schema, &reader);
+
+    // let pool = SqlitePool::connect("ereader.sqlite").await.unwrap();
+    // let start = chrono::Utc::now();
+    // scan::scan(&pool, "epub").await.unwrap();
+    // let end = chrono::Utc::now();
+    // println!("start {}\nend {}\ndiff {}", start, end, end - start);
+    // pool.close().await;
+
+    run_interface(cli.path, picked).await;
+}
+
+/// Starts the cursive interface, opening `path` or the `picked` book once it's up.
+#[cfg(feature = "cursive-frontend")]
+async fn run_interface(path: Option<String>, picked: Option<Hyphenated>) {
+    let mut siv = Cursive::new();
+
+    //let model = tui::init().await.unwrap();
+    //tui::view(&mut siv, &model);
+    //siv.set_user_data(model);
+
+    let mut init = new_tui::init().await;
+    // an index from an older version can be rebuilt before the interface starts instead of failing every search
+    // and one whose build was interrupted can be finished
+    if let Err(Error::IndexSchemaMismatch(path)) | Err(Error::IndexIncomplete(path)) = &init {
+        let path = path.clone();
+        if fimfarchive::confirm_rebuild(&path) {
+            init = match fimfarchive::rebuild_command().await {
+                Ok(()) => new_tui::init().await,
+                Err(e) => Err(e),
+            };
+        }
+    }
+    let user_data = match init {
+        Ok(user_data) => user_data,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    state::install_panic_hook(user_data.pool.clone());
+    if let Err(e) = scripts::load(scripts::SCRIPT_PATH) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    siv.set_user_data(user_data);
+    if let Err(e) = new_tui::apply_theme(&mut siv) {
+        error_message(&mut siv, e);
+    }
+    let sync_result = new_tui::sync_pull(&mut siv);
+    if let Err(e) = new_tui::library(&mut siv) {
+        error_message(&mut siv, e);
+    }
+    if new_tui::start_dashboard(&mut siv) {
+        if let Err(e) = new_tui::dashboard(&mut siv) {
+            error_message(&mut siv, e);
+        }
+    }
+    // shown after the library so it isn't hidden behind it
+    if let Err(e) = sync_result {
+        error_message(&mut siv, e);
+    }
+
+    // `ereader path/to/book.epub` opens the book straight away so it can be used as an epub handler
+    if let Some(path) = path {
+        if let Err(e) = new_tui::import(&mut siv, &path) {
+            error_message(&mut siv, e);
+        }
+    }
+    if let Some(book_id) = picked {
+        if let Err(e) = new_tui::open_book(&mut siv, book_id) {
+            error_message(&mut siv, e);
+        }
+    }
+
+    if let Err(e) = new_tui::register_keymap(&mut siv) {
+        error_message(&mut siv, e);
+    }
+    if let Err(e) = new_tui::start_jobs(&mut siv) {
+        error_message(&mut siv, e);
+    }
+    // refresh once a second so the reading position gets saved, auto-scrolling moves while reading,
+    // and the night theme comes on when it's scheduled to
+    siv.set_fps(1);
+    siv.add_global_callback(Event::Refresh, new_tui::refresh);
+    // siv.add_global_callback('l', |s| {
+    //     s.quit();
+    //     //        s.cb_sink()
+    //     //            .send(Box::new(move |s| tui::update_view(s, tui::Msg::GoLibrary)))
+    //     //            .unwrap();
+    // });
+    let low_bandwidth = new_tui::low_bandwidth(&mut siv).unwrap_or(false);
+    terminal::run(&mut siv, || {
+        let backend = cursive::backends::termion::Backend::init()?;
+        if low_bandwidth {
+            // only the cells that changed since the last frame are sent instead of the whole screen
+            Ok(Box::new(cursive_buffered_backend::BufferedBackend::new(
+                backend,
+            )))
+        } else {
+            Ok(backend)
+        }
+    });
+}
+
+/// Without the cursive interface the ratatui one is the default, it doesn't open books from the command line.
+#[cfg(all(not(feature = "cursive-frontend"), feature = "ratatui-frontend"))]
+async fn run_interface(_path: Option<String>, _picked: Option<Hyphenated>) {
+    if let Err(e) = ratatui_frontend::run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(any(feature = "cursive-frontend", feature = "ratatui-frontend")))]
+async fn run_interface(_path: Option<String>, _picked: Option<Hyphenated>) {
+    eprintln!("ereader was built without an interface, enable the cursive-frontend or ratatui-frontend feature");
+    std::process::exit(1);
+}
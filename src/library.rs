@@ -1,9 +1,15 @@
 use crate::Error;
 use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use sqlx::{query, query_as};
+use std::collections::HashMap;
 use uuid::adapter::Hyphenated;
 
+/// zstd's magic number, chapters stored without compression are html so they never start with it.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
 #[derive(Clone, Debug)]
 pub struct Book {
     pub id: Hyphenated,
@@ -13,7 +19,97 @@ pub struct Book {
     pub creator: Option<String>,
     pub description: Option<String>,
     pub publisher: Option<String>,
+    pub series: Option<String>,
+    pub hash: String,
+    /// whether the identifier, language, or title was missing and guessed during import,
+    /// it's only a flag and which of them were guessed isn't recorded
+    pub metadata_review: bool,
+    pub words: i64,
+    /// the fimfiction story id from the book's metadata
+    pub story_id: Option<i64>,
+}
+
+/// The hash of a file with an older version of a book's story.
+#[derive(Clone, Debug)]
+pub struct SupersededHash {
+    pub hash: String,
+    pub book_id: Hyphenated,
+}
+
+#[derive(Clone, Debug)]
+pub struct BookPath {
+    pub book_id: Hyphenated,
+    pub path: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct BookTag {
+    pub book_id: Hyphenated,
+    pub tag: String,
+}
+
+/// An author and how many books in the library they wrote.
+#[derive(Clone, Debug)]
+pub struct AuthorCount {
+    pub id: i64,
+    pub name: String,
+    pub books: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BookAuthor {
+    pub book_id: Hyphenated,
+    pub name: String,
+}
+
+/// A book or fimfarchive story in the read later queue.
+#[derive(Clone, Debug)]
+pub struct ReadLater {
+    pub id: i64,
+    pub book_id: Option<Hyphenated>,
+    pub story_id: Option<i64>,
+    pub title: String,
+    pub added: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Collection {
+    pub name: String,
+    pub query: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CollectionBook {
+    pub collection: String,
+    pub book_id: Hyphenated,
+}
+
+/// A file a book was imported from, kept so the book doesn't depend on the file still being there.
+#[derive(Clone, Debug)]
+pub struct Original {
     pub hash: String,
+    pub name: String,
+    /// compressed with zstd
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub id: i64,
+    pub field: String,
+    pub pattern: String,
+    pub exact: bool,
+    pub action: String,
+    pub target: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct JobRow {
+    pub id: i64,
+    pub kind: String,
+    pub state: String,
+    pub message: Option<String>,
+    pub updated: DateTime<Utc>,
 }
 
 #[derive(Clone, Debug)]
@@ -22,6 +118,30 @@ pub struct Chapter {
     pub book_id: Hyphenated,
     pub index: i64,
     pub content: Vec<u8>,
+    pub words: i64,
+    /// false for spine items marked linear="no" (covers, ads, notes),
+    /// they're skipped when moving to the next or previous chapter but can still be opened from the toc
+    pub linear: bool,
+    /// from the toc entry pointing at the chapter or its first heading, found when the book is imported
+    pub title: Option<String>,
+}
+
+/// A chapter's compressed content and the checksum it was stored with, for finding corrupted chapters.
+#[derive(Clone, Debug)]
+pub struct ChapterChecksum {
+    pub id: Hyphenated,
+    pub book_id: Hyphenated,
+    pub index: i64,
+    pub content: Vec<u8>,
+    pub checksum: Option<String>,
+}
+
+/// Where a chapter is in its book, without the content.
+#[derive(Clone, Copy, Debug)]
+pub struct ChapterIndex {
+    pub id: Hyphenated,
+    pub book_id: Hyphenated,
+    pub index: i64,
 }
 
 #[derive(Clone, Debug)]
@@ -31,6 +151,39 @@ pub struct Toc {
     pub index: i64,
     pub chapter_id: Hyphenated,
     pub title: String,
+    pub depth: i64,
+    pub fragment: Option<String>,
+}
+
+/// A link from the landmarks of an epub3 nav document.
+#[derive(Clone, Debug)]
+pub struct Landmark {
+    pub book_id: Hyphenated,
+    /// the epub:type of the link, e.g. cover, toc, or bodymatter
+    pub kind: String,
+    pub title: String,
+    pub chapter_id: Hyphenated,
+    pub fragment: Option<String>,
+}
+
+/// Where a page of the print edition starts.
+#[derive(Clone, Debug)]
+pub struct PageTarget {
+    pub book_id: Hyphenated,
+    pub index: i64,
+    pub label: String,
+    pub chapter_id: Hyphenated,
+    pub fragment: Option<String>,
+}
+
+/// The SMIL file of a chapter in a read-aloud epub, kept for aligning the text to speech.
+#[derive(Clone, Debug)]
+pub struct MediaOverlay {
+    pub chapter_id: Hyphenated,
+    pub book_id: Hyphenated,
+    /// zstd compressed
+    pub smil: Vec<u8>,
+    pub duration: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -42,11 +195,112 @@ pub struct Bookmark {
     pub created: DateTime<Utc>,
 }
 
+/// A bookmark that was deleted, identified by its book and when it was created.
+#[derive(Clone, Debug)]
+pub struct DeletedBookmark {
+    pub book_id: Hyphenated,
+    pub created: DateTime<Utc>,
+    pub deleted: DateTime<Utc>,
+}
+
+/// Per-book changes to how chapters are rendered.
+#[derive(Clone, Debug)]
+pub struct RenderOverrides {
+    pub book_id: Hyphenated,
+    pub disable_italics: bool,
+    /// turn divs and double line breaks into paragraphs so they get spaced out
+    pub paragraph_spacing: bool,
+    /// css selectors of elements to remove, one per line
+    pub strip_selectors: String,
+}
+
+impl RenderOverrides {
+    pub fn selectors(&self) -> Vec<String> {
+        self.strip_selectors
+            .lines()
+            .map(|selector| selector.trim().to_string())
+            .filter(|selector| !selector.is_empty())
+            .collect()
+    }
+}
+
+/// Where a book was last read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub book_id: Hyphenated,
+    pub chapter_id: Hyphenated,
+    pub progress: f32,
+    pub updated: DateTime<Utc>,
+}
+
+/// A prequel or sequel of a fimfiction story.
+#[derive(Clone, Debug)]
+pub struct RelatedStory {
+    pub story_id: i64,
+    pub related_id: i64,
+    pub relation: String,
+    pub title: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rating {
+    pub book_id: Hyphenated,
+    pub rating: i64,
+}
+
+/// How far into a book its bookmark is.
+#[derive(Clone, Debug)]
+pub struct ReadingProgress {
+    pub book_id: Hyphenated,
+    pub words_read: f64,
+    pub last_read: DateTime<Utc>,
+}
+
+/// Limits on how many bookmarks are kept, `None` means no limit.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BookmarkPruning {
+    /// the most recent bookmarks to keep for each book
+    pub per_book: Option<i64>,
+    /// bookmarks older than this many days are deleted
+    pub max_age_days: Option<i64>,
+}
+
+/// A regex replacement applied to chapter html before it is rendered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContentFilter {
+    pub name: String,
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    pub id: i64,
+    pub book_id: Hyphenated,
+    pub chapter_id: Hyphenated,
+    pub progress: f32,
+    pub passage: String,
+    pub note: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Note {
+    pub book_id: Hyphenated,
+    pub content: String,
+    pub updated: DateTime<Utc>,
+}
+
 pub async fn insert_bookmark(pool: &SqlitePool, bookmark: &Bookmark) -> Result<(), Error> {
-    query!("insert or replace into bookmarks(book_id, chapter_id, progress, created) values (?, ?, ?, ?)",
-    bookmark.book_id, bookmark.chapter_id, bookmark.progress, bookmark.created)
-        .execute(pool)
-        .await?;
+    query!(
+        "insert into bookmarks(book_id, chapter_id, progress, created) values (?, ?, ?, ?)",
+        bookmark.book_id,
+        bookmark.chapter_id,
+        bookmark.progress,
+        bookmark.created
+    )
+    .execute(pool)
+    .await?;
 
     Ok(())
 }
@@ -55,23 +309,44 @@ pub async fn insert_book(
     tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
     book: &Book,
 ) -> Result<(), Error> {
-    query!("insert into books(id, identifier, language, title, creator, description, publisher, hash) values (?, ?, ?, ?, ?, ?, ?, ?)",
-    book.id, book.identifier, book.language, book.title, book.creator, book.description, book.publisher, book.hash)
+    let added = Utc::now();
+    query!("insert into books(id, identifier, language, title, creator, description, publisher, series, hash, metadata_review, words, story_id, added) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    book.id, book.identifier, book.language, book.title, book.creator, book.description, book.publisher, book.series, book.hash, book.metadata_review, book.words, book.story_id, added)
         .execute(tx)
         .await?;
     Ok(())
 }
 
+pub async fn insert_book_path(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    path: &str,
+) -> Result<(), Error> {
+    query!(
+        "insert or ignore into book_paths(book_id, path) values (?, ?)",
+        book_id,
+        path
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 pub async fn insert_chapter(
     tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
     chapter: &Chapter,
 ) -> Result<(), Error> {
+    let checksum = chapter_checksum(&chapter.content);
     query!(
-        "insert into chapters(id, book_id, `index`, content) values (?, ?, ?, ?)",
+        "insert into chapters(id, book_id, `index`, content, words, linear, title, checksum) values (?, ?, ?, ?, ?, ?, ?, ?)",
         chapter.id,
         chapter.book_id,
         chapter.index,
-        chapter.content
+        chapter.content,
+        chapter.words,
+        chapter.linear,
+        chapter.title,
+        checksum
     )
     .execute(tx)
     .await?;
@@ -83,11 +358,63 @@ pub async fn insert_toc(
     toc: &Toc,
 ) -> Result<(), Error> {
     query!(
-        "insert into table_of_contents(book_id, `index`, chapter_id, title) values (?, ?, ?, ?)",
+        "insert into table_of_contents(book_id, `index`, chapter_id, title, depth, fragment) values (?, ?, ?, ?, ?, ?)",
         toc.book_id,
         toc.index,
         toc.chapter_id,
-        toc.title
+        toc.title,
+        toc.depth,
+        toc.fragment
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_landmark(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    landmark: &Landmark,
+) -> Result<(), Error> {
+    query!(
+        "insert into landmarks(book_id, kind, title, chapter_id, fragment) values (?, ?, ?, ?, ?)",
+        landmark.book_id,
+        landmark.kind,
+        landmark.title,
+        landmark.chapter_id,
+        landmark.fragment
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_page_target(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    page: &PageTarget,
+) -> Result<(), Error> {
+    query!(
+        "insert into page_list(book_id, `index`, label, chapter_id, fragment) values (?, ?, ?, ?, ?)",
+        page.book_id,
+        page.index,
+        page.label,
+        page.chapter_id,
+        page.fragment
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn insert_media_overlay(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    overlay: &MediaOverlay,
+) -> Result<(), Error> {
+    query!(
+        "insert into media_overlays(chapter_id, book_id, smil, duration) values (?, ?, ?, ?)",
+        overlay.chapter_id,
+        overlay.book_id,
+        overlay.smil,
+        overlay.duration
     )
     .execute(tx)
     .await?;
@@ -95,17 +422,72 @@ pub async fn insert_toc(
 }
 
 pub async fn get_books(pool: &SqlitePool) -> Result<Vec<Book>, Error> {
-    Ok(query_as!(Book, r#"select id as "id: Hyphenated", identifier, language, title, creator, description, publisher, hash from books order by title"#)
+    Ok(query_as!(Book, r#"select id as "id: Hyphenated", identifier, language, title, creator, description, publisher, series, hash, metadata_review as "metadata_review: bool", words, story_id from books order by title"#)
         .fetch_all(pool)
         .await?)
 }
 
 pub async fn get_book(pool: &SqlitePool, id: Hyphenated) -> Result<Book, Error> {
-    Ok(query_as!(Book, r#"select id as "id: Hyphenated", identifier, language, title, creator, description, publisher, hash from books where id = ?"#, id)
+    Ok(query_as!(Book, r#"select id as "id: Hyphenated", identifier, language, title, creator, description, publisher, series, hash, metadata_review as "metadata_review: bool", words, story_id from books where id = ?"#, id)
         .fetch_one(pool)
         .await?)
 }
 
+/// The most recently imported books, newest first.
+pub async fn get_recent_books(pool: &SqlitePool, limit: i64) -> Result<Vec<Book>, Error> {
+    Ok(query_as!(Book, r#"select id as "id: Hyphenated", identifier, language, title, creator, description, publisher, series, hash, metadata_review as "metadata_review: bool", words, story_id from books where added is not null order by added desc limit ?"#, limit)
+        .fetch_all(pool)
+        .await?)
+}
+
+pub async fn get_book_paths(pool: &SqlitePool, book_id: Hyphenated) -> Result<Vec<String>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select path from book_paths where book_id = ? order by path"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_all_book_paths(pool: &SqlitePool) -> Result<Vec<BookPath>, Error> {
+    Ok(query_as!(
+        BookPath,
+        r#"select book_id as "book_id: Hyphenated", path from book_paths"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Stores a book's original file, a file that's already stored is left alone.
+pub async fn insert_original(pool: &SqlitePool, original: &Original) -> Result<(), Error> {
+    query!(
+        "insert or ignore into originals(hash, name, content) values (?, ?, ?)",
+        original.hash,
+        original.name,
+        original.content
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_original(pool: &SqlitePool, hash: &str) -> Result<Option<Original>, Error> {
+    Ok(query_as!(
+        Original,
+        "select hash, name, content from originals where hash = ?",
+        hash
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// The hashes of the books with a stored original.
+pub async fn get_original_hashes(pool: &SqlitePool) -> Result<Vec<String>, Error> {
+    Ok(sqlx::query_scalar!("select hash from originals")
+        .fetch_all(pool)
+        .await?)
+}
+
 pub async fn get_chapter(
     pool: &SqlitePool,
     book_id: Hyphenated,
@@ -113,7 +495,7 @@ pub async fn get_chapter(
 ) -> Result<Chapter, Error> {
     Ok(query_as!(
         Chapter,
-        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content from chapters where book_id = ? and `index` = ?"#,
+        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content, words, linear as "linear: bool", title from chapters where book_id = ? and `index` = ?"#,
         book_id,
         index
     )
@@ -123,12 +505,25 @@ pub async fn get_chapter(
 
 pub async fn get_chapter_by_id(pool: &SqlitePool, id: Hyphenated) -> Result<Chapter, Error> {
     Ok(
-        query_as!(Chapter, r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content from chapters where id = ?"#, id)
+        query_as!(Chapter, r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content, words, linear as "linear: bool", title from chapters where id = ?"#, id)
             .fetch_one(pool)
             .await?,
     )
 }
 
+pub async fn get_book_chapters(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Vec<Chapter>, Error> {
+    Ok(query_as!(
+        Chapter,
+        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content, words, linear as "linear: bool", title from chapters where book_id = ? order by `index`"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
 pub async fn get_num_chapters(pool: &SqlitePool, id: Hyphenated) -> Result<i32, Error> {
     Ok(
         sqlx::query_scalar!(r#"select count(*) from chapters where book_id = ?"#, id)
@@ -137,24 +532,1128 @@ pub async fn get_num_chapters(pool: &SqlitePool, id: Hyphenated) -> Result<i32,
     )
 }
 
+pub async fn get_chapter_words(pool: &SqlitePool, book_id: Hyphenated) -> Result<Vec<i64>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select words from chapters where book_id = ? order by `index`"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// The closest chapter after (or before) an index that's in the reading order.
+pub async fn get_adjacent_linear_chapter(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    index: i64,
+    forward: bool,
+) -> Result<Option<ChapterIndex>, Error> {
+    if forward {
+        Ok(query_as!(
+            ChapterIndex,
+            r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index` from chapters where book_id = ? and `index` > ? and linear order by `index` limit 1"#,
+            book_id,
+            index
+        )
+        .fetch_optional(pool)
+        .await?)
+    } else {
+        Ok(query_as!(
+            ChapterIndex,
+            r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index` from chapters where book_id = ? and `index` < ? and linear order by `index` desc limit 1"#,
+            book_id,
+            index
+        )
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+pub async fn get_chapter_ids(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Vec<Hyphenated>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select id as "id: Hyphenated" from chapters where book_id = ? order by `index`"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Like `get_chapter_ids` but in a transaction that's about to change them.
+pub async fn get_chapter_ids_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    book_id: Hyphenated,
+) -> Result<Vec<Hyphenated>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select id as "id: Hyphenated" from chapters where book_id = ? order by `index`"#,
+        book_id
+    )
+    .fetch_all(tx)
+    .await?)
+}
+
+/// The stored titles of a book's chapters in order, None for chapters without one.
+pub async fn get_chapter_titles(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Vec<Option<String>>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select title from chapters where book_id = ? order by `index`"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_chapter_indices(pool: &SqlitePool) -> Result<Vec<ChapterIndex>, Error> {
+    Ok(query_as!(
+        ChapterIndex,
+        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index` from chapters"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
 pub async fn get_toc(pool: &SqlitePool, book_id: Hyphenated) -> Result<Vec<Toc>, Error> {
     Ok(query_as!(
         Toc,
-        r#"select id, book_id as "book_id: Hyphenated", `index`, chapter_id as "chapter_id: Hyphenated", title from table_of_contents where book_id = ? order by `index`"#,
+        r#"select id, book_id as "book_id: Hyphenated", `index`, chapter_id as "chapter_id: Hyphenated", title, depth, fragment from table_of_contents where book_id = ? order by `index`"#,
         book_id,
     )
     .fetch_all(pool)
     .await?)
 }
 
-pub async fn get_bookmarks(pool: &SqlitePool) -> Result<Vec<Bookmark>, Error> {
-    Ok(query_as!(Bookmark, r#"select id, book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress, created as "created: DateTime<Utc>" from bookmarks order by created desc"#)
-       .fetch_all(pool)
-       .await?)
+pub async fn get_landmarks(pool: &SqlitePool, book_id: Hyphenated) -> Result<Vec<Landmark>, Error> {
+    Ok(query_as!(
+        Landmark,
+        r#"select book_id as "book_id: Hyphenated", kind, title, chapter_id as "chapter_id: Hyphenated", fragment from landmarks where book_id = ?"#,
+        book_id,
+    )
+    .fetch_all(pool)
+    .await?)
 }
 
-pub async fn delete_bookmark(pool: &SqlitePool, id: i64) -> Result<(), Error> {
-    query!("delete from bookmarks where id = ?", id)
+/// The page of the print edition with a label, e.g. "12" or "xiv".
+pub async fn get_page_target(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    label: &str,
+) -> Result<Option<PageTarget>, Error> {
+    Ok(query_as!(
+        PageTarget,
+        r#"select book_id as "book_id: Hyphenated", `index`, label, chapter_id as "chapter_id: Hyphenated", fragment from page_list where book_id = ? and label = ? collate nocase order by `index` limit 1"#,
+        book_id,
+        label,
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+pub async fn get_media_overlay(
+    pool: &SqlitePool,
+    chapter_id: Hyphenated,
+) -> Result<Option<MediaOverlay>, Error> {
+    Ok(query_as!(
+        MediaOverlay,
+        r#"select chapter_id as "chapter_id: Hyphenated", book_id as "book_id: Hyphenated", smil, duration from media_overlays where chapter_id = ?"#,
+        chapter_id,
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+pub async fn get_bookmarks(pool: &SqlitePool) -> Result<Vec<Bookmark>, Error> {
+    Ok(query_as!(Bookmark, r#"select id, book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress, created as "created: DateTime<Utc>" from bookmarks order by created desc"#)
+       .fetch_all(pool)
+       .await?)
+}
+
+pub async fn get_bookmark(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Option<Bookmark>, Error> {
+    Ok(query_as!(Bookmark, r#"select id, book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress, created as "created: DateTime<Utc>" from bookmarks where book_id = ? order by created desc limit 1"#, book_id)
+       .fetch_optional(pool)
+       .await?)
+}
+
+/// Deletes a bookmark, remembering it so syncing deletes it on other machines too.
+pub async fn delete_bookmark(pool: &SqlitePool, id: i64) -> Result<(), Error> {
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    query!(
+        "insert or ignore into deleted_bookmarks(book_id, created, deleted) select book_id, created, ? from bookmarks where id = ?",
+        now,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    query!("delete from bookmarks where id = ?", id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn get_deleted_bookmarks(pool: &SqlitePool) -> Result<Vec<DeletedBookmark>, Error> {
+    Ok(query_as!(DeletedBookmark, r#"select book_id as "book_id: Hyphenated", created as "created: DateTime<Utc>", deleted as "deleted: DateTime<Utc>" from deleted_bookmarks"#)
+        .fetch_all(pool)
+        .await?)
+}
+
+/// Deletes a bookmark that was deleted somewhere else, returning whether there was one to delete.
+pub async fn insert_deleted_bookmark(
+    pool: &SqlitePool,
+    deleted: &DeletedBookmark,
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+    query!(
+        "insert or ignore into deleted_bookmarks(book_id, created, deleted) values (?, ?, ?)",
+        deleted.book_id,
+        deleted.created,
+        deleted.deleted
+    )
+    .execute(&mut tx)
+    .await?;
+    let rows = query!(
+        "delete from bookmarks where book_id = ? and created = ?",
+        deleted.book_id,
+        deleted.created
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok(rows > 0)
+}
+
+pub async fn get_reading_progress(pool: &SqlitePool) -> Result<Vec<ReadingProgress>, Error> {
+    Ok(query_as!(
+        ReadingProgress,
+        r#"select book_id as "book_id!: Hyphenated", words_read as "words_read!: f64", last_read as "last_read!: DateTime<Utc>" from (
+            select positions.book_id,
+                coalesce((select sum(words) from chapters as previous where previous.book_id = positions.book_id and previous.`index` < chapters.`index`), 0)
+                    + positions.progress * chapters.words as words_read,
+                positions.updated as last_read
+            from positions join chapters on chapters.id = positions.chapter_id
+            union all
+            select bookmarks.book_id,
+                coalesce((select sum(words) from chapters as previous where previous.book_id = bookmarks.book_id and previous.`index` < chapters.`index`), 0)
+                    + bookmarks.progress * chapters.words as words_read,
+                bookmarks.created as last_read
+            from bookmarks join chapters on chapters.id = bookmarks.chapter_id
+            where bookmarks.book_id not in (select book_id from positions)
+                and bookmarks.id = (select id from bookmarks as latest where latest.book_id = bookmarks.book_id order by created desc limit 1)
+        )"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_string_setting(pool: &SqlitePool, key: &str) -> Result<Option<String>, Error> {
+    Ok(
+        sqlx::query_scalar!(r#"select value from settings where key = ?"#, key)
+            .fetch_optional(pool)
+            .await?,
+    )
+}
+
+pub async fn set_string_setting(pool: &SqlitePool, key: &str, value: &str) -> Result<(), Error> {
+    query!(
+        "insert or replace into settings(key, value) values (?, ?)",
+        key,
+        value
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// `set_string_setting` as part of a transaction so several settings can be saved together.
+pub async fn insert_string_setting(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &str,
+    value: &str,
+) -> Result<(), Error> {
+    query!(
+        "insert or replace into settings(key, value) values (?, ?)",
+        key,
+        value
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_content_filters(pool: &SqlitePool) -> Result<Vec<ContentFilter>, Error> {
+    match get_string_setting(pool, "content filters").await? {
+        Some(filters) => Ok(serde_json::from_str(&filters)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub async fn set_content_filters(
+    pool: &SqlitePool,
+    filters: &[ContentFilter],
+) -> Result<(), Error> {
+    set_string_setting(pool, "content filters", &serde_json::to_string(filters)?).await
+}
+
+/// Past search queries by the kind of search they were for, oldest first.
+pub type QueryHistory = HashMap<String, Vec<String>>;
+
+pub async fn get_query_history(pool: &SqlitePool) -> Result<QueryHistory, Error> {
+    match get_string_setting(pool, "query history").await? {
+        Some(history) => Ok(serde_json::from_str(&history)?),
+        None => Ok(QueryHistory::new()),
+    }
+}
+
+pub async fn set_query_history(pool: &SqlitePool, history: &QueryHistory) -> Result<(), Error> {
+    set_string_setting(pool, "query history", &serde_json::to_string(history)?).await
+}
+
+/// Deletes the bookmarks that are over the limits, returning how many were deleted.
+/// Like `delete_bookmark` they're remembered so syncing doesn't add them back.
+pub async fn prune_bookmarks(pool: &SqlitePool, pruning: &BookmarkPruning) -> Result<u64, Error> {
+    let now = Utc::now();
+    let mut deleted = 0;
+    let mut tx = pool.begin().await?;
+
+    if let Some(per_book) = pruning.per_book {
+        query!(
+            "insert or ignore into deleted_bookmarks(book_id, created, deleted) select book_id, created, ? from bookmarks where (select count(*) from bookmarks as newer where newer.book_id = bookmarks.book_id and (newer.created > bookmarks.created or (newer.created = bookmarks.created and newer.id > bookmarks.id))) >= ?",
+            now,
+            per_book
+        )
+        .execute(&mut tx)
+        .await?;
+        deleted += query!(
+            "delete from bookmarks where (select count(*) from bookmarks as newer where newer.book_id = bookmarks.book_id and (newer.created > bookmarks.created or (newer.created = bookmarks.created and newer.id > bookmarks.id))) >= ?",
+            per_book
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    }
+
+    if let Some(max_age_days) = pruning.max_age_days {
+        let cutoff = now - chrono::Duration::days(max_age_days);
+        query!(
+            "insert or ignore into deleted_bookmarks(book_id, created, deleted) select book_id, created, ? from bookmarks where created < ?",
+            now,
+            cutoff
+        )
+        .execute(&mut tx)
+        .await?;
+        deleted += query!("delete from bookmarks where created < ?", cutoff)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    }
+
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+pub async fn get_disabled_content_filters(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Vec<String>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select name from disabled_content_filters where book_id = ?"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn set_content_filter_enabled(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    name: &str,
+    enabled: bool,
+) -> Result<(), Error> {
+    if enabled {
+        query!(
+            "delete from disabled_content_filters where book_id = ? and name = ?",
+            book_id,
+            name
+        )
+        .execute(pool)
+        .await?;
+    } else {
+        query!(
+            "insert or replace into disabled_content_filters(book_id, name) values (?, ?)",
+            book_id,
+            name
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn get_note(pool: &SqlitePool, book_id: Hyphenated) -> Result<Option<String>, Error> {
+    Ok(
+        sqlx::query_scalar!(r#"select content from notes where book_id = ?"#, book_id)
+            .fetch_optional(pool)
+            .await?,
+    )
+}
+
+pub async fn set_note(pool: &SqlitePool, book_id: Hyphenated, content: &str) -> Result<(), Error> {
+    let updated = Utc::now();
+    query!(
+        "insert or replace into notes(book_id, content, updated) values (?, ?, ?)",
+        book_id,
+        content,
+        updated
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_notes(pool: &SqlitePool) -> Result<Vec<Note>, Error> {
+    Ok(query_as!(Note, r#"select book_id as "book_id: Hyphenated", content, updated as "updated: DateTime<Utc>" from notes"#)
+        .fetch_all(pool)
+        .await?)
+}
+
+/// Sets a note keeping its timestamp, for notes that were written somewhere else.
+pub async fn insert_note(pool: &SqlitePool, note: &Note) -> Result<(), Error> {
+    query!(
+        "insert or replace into notes(book_id, content, updated) values (?, ?, ?)",
+        note.book_id,
+        note.content,
+        note.updated
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn search_notes(pool: &SqlitePool, search: &str) -> Result<Vec<Note>, Error> {
+    let pattern = format!("%{}%", search);
+    Ok(query_as!(Note, r#"select book_id as "book_id: Hyphenated", content, updated as "updated: DateTime<Utc>" from notes where content like ? order by updated desc"#, pattern)
+        .fetch_all(pool)
+        .await?)
+}
+
+pub async fn insert_annotation(pool: &SqlitePool, annotation: &Annotation) -> Result<(), Error> {
+    query!("insert into annotations(book_id, chapter_id, progress, passage, note, created) values (?, ?, ?, ?, ?, ?)",
+    annotation.book_id, annotation.chapter_id, annotation.progress, annotation.passage, annotation.note, annotation.created)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_annotations(pool: &SqlitePool) -> Result<Vec<Annotation>, Error> {
+    Ok(query_as!(Annotation, r#"select id, book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress, passage, note, created as "created: DateTime<Utc>" from annotations order by created"#)
+        .fetch_all(pool)
+        .await?)
+}
+
+pub async fn search_annotations(pool: &SqlitePool, search: &str) -> Result<Vec<Annotation>, Error> {
+    let pattern = format!("%{}%", search);
+    Ok(query_as!(Annotation, r#"select id, book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress, passage, note, created as "created: DateTime<Utc>" from annotations where passage like ? or note like ? order by created desc"#, pattern, pattern)
+        .fetch_all(pool)
+        .await?)
+}
+
+pub async fn get_book_tags(pool: &SqlitePool, book_id: Hyphenated) -> Result<Vec<String>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"select tag from book_tags where book_id = ? order by tag"#,
+        book_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_all_book_tags(pool: &SqlitePool) -> Result<Vec<BookTag>, Error> {
+    Ok(query_as!(
+        BookTag,
+        r#"select book_id as "book_id: Hyphenated", tag from book_tags"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn set_book_tags(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    tags: &[String],
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    query!("delete from book_tags where book_id = ?", book_id)
+        .execute(&mut tx)
+        .await?;
+    for tag in tags {
+        query!(
+            "insert or ignore into book_tags(book_id, tag) values (?, ?)",
+            book_id,
+            tag
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn add_book_tag(pool: &SqlitePool, book_id: Hyphenated, tag: &str) -> Result<(), Error> {
+    query!(
+        "insert or ignore into book_tags(book_id, tag) values (?, ?)",
+        book_id,
+        tag
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Replaces the authors of a book, adding any that aren't in the authors table yet.
+pub async fn set_book_authors(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    book_id: Hyphenated,
+    authors: &[String],
+) -> Result<(), Error> {
+    query!("delete from book_authors where book_id = ?", book_id)
+        .execute(&mut *tx)
+        .await?;
+    for author in authors {
+        query!("insert or ignore into authors(name) values (?)", author)
+            .execute(&mut *tx)
+            .await?;
+        query!(
+            "insert or ignore into book_authors(book_id, author_id) select ?, id from authors where name = ?",
+            book_id,
+            author
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Removes the authors that no longer have any books.
+pub async fn delete_unused_authors(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<(), Error> {
+    query!("delete from authors where id not in (select author_id from book_authors)")
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_author_counts(pool: &SqlitePool) -> Result<Vec<AuthorCount>, Error> {
+    Ok(query_as!(
+        AuthorCount,
+        r#"select authors.id, authors.name, count(book_authors.book_id) as "books!: i64" from authors join book_authors on book_authors.author_id = authors.id group by authors.id order by authors.name collate nocase"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_all_book_authors(pool: &SqlitePool) -> Result<Vec<BookAuthor>, Error> {
+    Ok(query_as!(
+        BookAuthor,
+        r#"select book_authors.book_id as "book_id: Hyphenated", authors.name from book_authors join authors on authors.id = book_authors.author_id"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_all_chapters(pool: &SqlitePool) -> Result<Vec<Chapter>, Error> {
+    Ok(query_as!(
+        Chapter,
+        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content, words, linear as "linear: bool", title from chapters"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// A chapter's html the way it's stored at a zstd level from `Settings::compression_level`, 0 leaves it uncompressed.
+pub fn compress_chapter(html: &[u8], level: i32) -> Result<Vec<u8>, Error> {
+    match level {
+        0 => Ok(html.to_vec()),
+        level => Ok(zstd::stream::encode_all(html, level)?),
+    }
+}
+
+/// A chapter's html from its stored content, chapters imported with different levels can be mixed in a library.
+pub fn decompress_chapter(content: &[u8]) -> Result<Vec<u8>, Error> {
+    if content.starts_with(&ZSTD_MAGIC) {
+        Ok(zstd::stream::decode_all(content)?)
+    } else {
+        Ok(content.to_vec())
+    }
+}
+
+/// How much space the chapters take up compared to their html.
+#[derive(Clone, Debug, Default)]
+pub struct StorageStats {
+    pub chapters: usize,
+    /// chapters imported with compression turned off
+    pub uncompressed_chapters: usize,
+    /// bytes in the database
+    pub stored: usize,
+    /// bytes of html
+    pub html: usize,
+}
+
+/// Reads through every chapter to find out how well they compress, so it's only done when asked for.
+pub async fn get_storage_stats(pool: &SqlitePool) -> Result<StorageStats, Error> {
+    let mut stats = StorageStats::default();
+    let mut contents = sqlx::query_scalar!("select content from chapters").fetch(pool);
+
+    while let Some(content) = contents.try_next().await? {
+        stats.chapters += 1;
+        stats.stored += content.len();
+        if !content.starts_with(&ZSTD_MAGIC) {
+            stats.uncompressed_chapters += 1;
+        }
+        stats.html += decompress_chapter(&content)?.len();
+    }
+
+    Ok(stats)
+}
+
+/// The checksum stored with a chapter's content.
+pub fn chapter_checksum(content: &[u8]) -> String {
+    blake3::hash(content).to_string()
+}
+
+pub async fn get_chapter_checksums(pool: &SqlitePool) -> Result<Vec<ChapterChecksum>, Error> {
+    Ok(query_as!(
+        ChapterChecksum,
+        r#"select id as "id: Hyphenated", book_id as "book_id: Hyphenated", `index`, content, checksum from chapters order by book_id, `index`"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Replaces everything about a chapter that comes from the book's file, keeping its id and index.
+pub async fn replace_chapter(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    chapter: &Chapter,
+) -> Result<(), Error> {
+    let checksum = chapter_checksum(&chapter.content);
+    let result = query!(
+        "update chapters set content = ?, words = ?, linear = ?, title = ?, checksum = ? where id = ?",
+        chapter.content,
+        chapter.words,
+        chapter.linear,
+        chapter.title,
+        checksum,
+        chapter.id
+    )
+    .execute(tx)
+    .await?;
+    // a chapter that isn't there would otherwise look replaced
+    if result.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound.into());
+    }
+    Ok(())
+}
+
+/// Moves the bookmarks, annotations, and reading position in a chapter to the end of another one
+/// and deletes it, for chapters a book no longer has after it's reprocessed.
+pub async fn remove_chapter(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    id: Hyphenated,
+    replacement: Hyphenated,
+) -> Result<(), Error> {
+    query!(
+        "update bookmarks set chapter_id = ?, progress = 1.0 where chapter_id = ?",
+        replacement,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    query!(
+        "update annotations set chapter_id = ?, progress = 1.0 where chapter_id = ?",
+        replacement,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    query!(
+        "update positions set chapter_id = ?, progress = 1.0 where chapter_id = ?",
+        replacement,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    query!("delete from chapters where id = ?", id)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+/// Deletes a book's table of contents, landmarks, page list, and media overlays so they can be inserted again.
+pub async fn delete_book_navigation(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    book_id: Hyphenated,
+) -> Result<(), Error> {
+    query!("delete from table_of_contents where book_id = ?", book_id)
+        .execute(&mut *tx)
+        .await?;
+    query!("delete from landmarks where book_id = ?", book_id)
+        .execute(&mut *tx)
+        .await?;
+    query!("delete from page_list where book_id = ?", book_id)
+        .execute(&mut *tx)
+        .await?;
+    query!("delete from media_overlays where book_id = ?", book_id)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+pub async fn set_chapter_words(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    id: Hyphenated,
+    words: i64,
+) -> Result<(), Error> {
+    query!("update chapters set words = ? where id = ?", words, id)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+/// Points a book at the file of a newer version of it, e.g. a fimfiction story that was updated in the archive.
+/// The old file's hash is kept as superseded so it's recognised the next time it's found.
+pub async fn set_book_hash(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    book_id: Hyphenated,
+    hash: &str,
+) -> Result<(), Error> {
+    query!(
+        "insert or ignore into superseded_hashes(hash, book_id) select hash, id from books where id = ?",
+        book_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    query!("update books set hash = ? where id = ?", hash, book_id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// Remembers the file of an older version of a book's story, see `set_book_hash`.
+pub async fn insert_superseded_hash(
+    pool: &SqlitePool,
+    hash: &str,
+    book_id: Hyphenated,
+) -> Result<(), Error> {
+    query!(
+        "insert or ignore into superseded_hashes(hash, book_id) values (?, ?)",
+        hash,
+        book_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_superseded_hashes(pool: &SqlitePool) -> Result<Vec<SupersededHash>, Error> {
+    Ok(query_as!(
+        SupersededHash,
+        r#"select hash, book_id as "book_id: Hyphenated" from superseded_hashes"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// The book with a fimfiction story id, if it's in the library.
+pub async fn get_story_book(pool: &SqlitePool, story_id: i64) -> Result<Option<Hyphenated>, Error> {
+    Ok(query!(
+        r#"select id as "id: Hyphenated" from books where story_id = ?"#,
+        story_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| row.id))
+}
+
+pub async fn get_related_stories(
+    pool: &SqlitePool,
+    story_id: i64,
+) -> Result<Vec<RelatedStory>, Error> {
+    Ok(query_as!(
+        RelatedStory,
+        "select story_id, related_id, relation, title from related_stories where story_id = ? order by relation, title",
+        story_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn insert_related_story(pool: &SqlitePool, related: &RelatedStory) -> Result<(), Error> {
+    query!(
+        "insert or replace into related_stories(story_id, related_id, relation, title) values (?, ?, ?, ?)",
+        related.story_id,
+        related.related_id,
+        related.relation,
+        related.title
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Sets each book's word count to the sum of its chapters' word counts.
+pub async fn update_book_words(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>) -> Result<(), Error> {
+    query!("update books set words = (select coalesce(sum(words), 0) from chapters where chapters.book_id = books.id)")
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_collections(pool: &SqlitePool) -> Result<Vec<Collection>, Error> {
+    Ok(query_as!(
+        Collection,
+        "select name, query from collections order by name"
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn insert_collection(pool: &SqlitePool, collection: &Collection) -> Result<(), Error> {
+    query!(
+        "insert or replace into collections(name, query) values (?, ?)",
+        collection.name,
+        collection.query
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_collection(pool: &SqlitePool, name: &str) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    query!("delete from collection_books where collection = ?", name)
+        .execute(&mut tx)
+        .await?;
+    query!("delete from collections where name = ?", name)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn get_collection_books(pool: &SqlitePool) -> Result<Vec<CollectionBook>, Error> {
+    Ok(query_as!(
+        CollectionBook,
+        r#"select collection, book_id as "book_id: Hyphenated" from collection_books"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn add_collection_book(
+    pool: &SqlitePool,
+    collection: &str,
+    book_id: Hyphenated,
+) -> Result<(), Error> {
+    query!(
+        "insert or ignore into collection_books(collection, book_id) values (?, ?)",
+        collection,
+        book_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_rules(pool: &SqlitePool) -> Result<Vec<Rule>, Error> {
+    Ok(query_as!(
+        Rule,
+        r#"select id, field, pattern, exact as "exact: bool", action, target from rules order by id"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn insert_rule(pool: &SqlitePool, rule: &Rule) -> Result<(), Error> {
+    query!(
+        "insert into rules(field, pattern, exact, action, target) values (?, ?, ?, ?, ?)",
+        rule.field,
+        rule.pattern,
+        rule.exact,
+        rule.action,
+        rule.target
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_rule(pool: &SqlitePool, id: i64) -> Result<(), Error> {
+    query!("delete from rules where id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The read later queue, oldest first.
+///
+/// Stories added from the fimfarchive get the book they were imported as.
+pub async fn get_read_later(pool: &SqlitePool) -> Result<Vec<ReadLater>, Error> {
+    let mut queue = query_as!(
+        ReadLater,
+        r#"select id, book_id as "book_id: Hyphenated", story_id, title, added as "added: DateTime<Utc>" from read_later order by id"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for item in &mut queue {
+        if let (None, Some(story_id)) = (item.book_id, item.story_id) {
+            item.book_id = get_story_book(pool, story_id).await?;
+        }
+    }
+    Ok(queue)
+}
+
+/// Adds a book or story to the end of the read later queue.
+/// Returns false if it was already in it.
+pub async fn add_read_later(
+    pool: &SqlitePool,
+    book_id: Option<Hyphenated>,
+    story_id: Option<i64>,
+    title: &str,
+) -> Result<bool, Error> {
+    let added = Utc::now();
+    let result = query!(
+        "insert into read_later(book_id, story_id, title, added) select ?, ?, ?, ? where not exists (select 1 from read_later where book_id = ? or story_id = ?)",
+        book_id,
+        story_id,
+        title,
+        added,
+        book_id,
+        story_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_read_later(pool: &SqlitePool, id: i64) -> Result<(), Error> {
+    query!("delete from read_later where id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_ratings(pool: &SqlitePool) -> Result<Vec<Rating>, Error> {
+    Ok(query_as!(
+        Rating,
+        r#"select book_id as "book_id: Hyphenated", rating from ratings"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn set_rating(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    rating: Option<i64>,
+) -> Result<(), Error> {
+    match rating {
+        Some(rating) => {
+            query!(
+                "insert or replace into ratings(book_id, rating) values (?, ?)",
+                book_id,
+                rating
+            )
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            query!("delete from ratings where book_id = ?", book_id)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_position(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Option<Position>, Error> {
+    Ok(query_as!(
+        Position,
+        r#"select book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress as "progress: f32", updated as "updated: DateTime<Utc>" from positions where book_id = ?"#,
+        book_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+pub async fn get_positions(pool: &SqlitePool) -> Result<Vec<Position>, Error> {
+    Ok(query_as!(
+        Position,
+        r#"select book_id as "book_id: Hyphenated", chapter_id as "chapter_id: Hyphenated", progress as "progress: f32", updated as "updated: DateTime<Utc>" from positions"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn set_position(pool: &SqlitePool, position: &Position) -> Result<(), Error> {
+    query!(
+        "insert or replace into positions(book_id, chapter_id, progress, updated) values (?, ?, ?, ?)",
+        position.book_id,
+        position.chapter_id,
+        position.progress,
+        position.updated
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_render_overrides(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Option<RenderOverrides>, Error> {
+    Ok(query_as!(
+        RenderOverrides,
+        r#"select book_id as "book_id: Hyphenated", disable_italics as "disable_italics: bool", paragraph_spacing as "paragraph_spacing: bool", strip_selectors from render_overrides where book_id = ?"#,
+        book_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+pub async fn set_render_overrides(
+    pool: &SqlitePool,
+    overrides: &RenderOverrides,
+) -> Result<(), Error> {
+    query!(
+        "insert or replace into render_overrides(book_id, disable_italics, paragraph_spacing, strip_selectors) values (?, ?, ?, ?)",
+        overrides.book_id,
+        overrides.disable_italics,
+        overrides.paragraph_spacing,
+        overrides.strip_selectors
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Queues a job, returning its id.
+pub async fn insert_job(pool: &SqlitePool, kind: &str) -> Result<i64, Error> {
+    let now = Utc::now();
+    let result = query!(
+        "insert into jobs(kind, state, created, updated) values (?, 'queued', ?, ?)",
+        kind,
+        now,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_rowid())
+}
+
+pub async fn get_jobs(pool: &SqlitePool) -> Result<Vec<JobRow>, Error> {
+    Ok(query_as!(
+        JobRow,
+        r#"select id, kind, state, message, updated as "updated: DateTime<Utc>" from jobs order by id desc"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// The oldest job that's still queued.
+pub async fn get_next_queued_job(pool: &SqlitePool) -> Result<Option<JobRow>, Error> {
+    Ok(query_as!(
+        JobRow,
+        r#"select id, kind, state, message, updated as "updated: DateTime<Utc>" from jobs where state = 'queued' order by id limit 1"#
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Marks a queued job as running in the process `owner`.
+/// Returns false if another process claimed it first.
+pub async fn claim_job(pool: &SqlitePool, id: i64, owner: i64) -> Result<bool, Error> {
+    let now = Utc::now();
+    let result = query!(
+        "update jobs set state = 'running', owner = ?, updated = ? where id = ? and state = 'queued'",
+        owner,
+        now,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn set_job_state(
+    pool: &SqlitePool,
+    id: i64,
+    state: &str,
+    message: Option<&str>,
+) -> Result<(), Error> {
+    let now = Utc::now();
+    query!(
+        "update jobs set state = ?, message = ?, updated = ? where id = ?",
+        state,
+        message,
+        now,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Cancels a job if it hasn't started yet.
+pub async fn cancel_queued_job(pool: &SqlitePool, id: i64) -> Result<(), Error> {
+    let now = Utc::now();
+    query!(
+        "update jobs set state = 'cancelled', updated = ? where id = ? and state = 'queued'",
+        now,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Puts the jobs that were interrupted by ereader exiting back in the queue,
+/// the ones whose process is still running are left to it.
+pub async fn requeue_running_jobs<F: Fn(i64) -> bool>(
+    pool: &SqlitePool,
+    is_running: F,
+) -> Result<(), Error> {
+    let jobs = query!("select id, owner from jobs where state = 'running'")
+        .fetch_all(pool)
+        .await?;
+    for job in jobs {
+        // jobs from before the owner was stored have none
+        if job.owner.map_or(false, &is_running) {
+            continue;
+        }
+        query!(
+            "update jobs set state = 'queued', owner = null where id = ? and state = 'running' and owner is ?",
+            job.id,
+            job.owner
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn delete_finished_jobs(pool: &SqlitePool) -> Result<(), Error> {
+    query!("delete from jobs where state in ('done', 'failed', 'cancelled')")
         .execute(pool)
         .await?;
     Ok(())
@@ -0,0 +1,112 @@
+use crate::Error;
+use sqlx::{Executor, SqlitePool};
+use std::path::Path;
+
+/// Columns added to tables older databases already have, with the definition that gives existing rows a value.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("books", "series", "text"),
+    ("books", "metadata_review", "boolean not null default false"),
+    ("books", "words", "integer not null default 0"),
+    ("books", "story_id", "integer"),
+    ("books", "added", "datetime"),
+    ("chapters", "words", "integer not null default 0"),
+    ("chapters", "linear", "boolean not null default true"),
+    ("chapters", "title", "text"),
+    ("chapters", "checksum", "text"),
+    ("table_of_contents", "depth", "integer not null default 0"),
+    ("table_of_contents", "fragment", "text"),
+];
+
+/// Brings a database made from an older schema.sql up to date, run at startup before anything else opens it.
+///
+/// Each step checks whether it's needed so they can all run on every start,
+/// a database made from the current schema.sql has nothing to do.
+pub async fn migrate<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    // commands like completions don't need a library, one is made with reset_db.sh
+    if !path.as_ref().exists() {
+        return Ok(());
+    }
+
+    let pool = SqlitePool::connect(&path.as_ref().to_string_lossy()).await?;
+    let mut added = Vec::new();
+    for (table, column, definition) in ADDED_COLUMNS {
+        if add_column(&pool, table, column, definition).await? {
+            added.push((*table, *column));
+        }
+    }
+    create_missing(&pool).await?;
+    allow_multiple_bookmarks(&pool).await?;
+    // the counts the column was added with are all 0, this is the recount maintenance task
+    if added.contains(&("chapters", "words")) || added.contains(&("books", "words")) {
+        crate::scan::recount_words(&pool).await?;
+    }
+    pool.close().await;
+
+    Ok(())
+}
+
+/// Adds a column if the table doesn't have it yet, returning whether it was added.
+async fn add_column(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, Error> {
+    let columns: Vec<String> = sqlx::query_scalar("select name from pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(pool)
+        .await?;
+    if columns.iter().any(|name| name == column) {
+        return Ok(false);
+    }
+
+    sqlx::query(&format!(
+        "alter table {} add column {} {}",
+        table, column, definition
+    ))
+    .execute(pool)
+    .await?;
+    Ok(true)
+}
+
+/// Creates the tables and indexes from schema.sql that the database doesn't have yet.
+async fn create_missing(pool: &SqlitePool) -> Result<(), Error> {
+    let schema = include_str!("../schema.sql")
+        .replace("create table ", "create table if not exists ")
+        .replace("create index ", "create index if not exists ");
+    pool.execute(schema.as_str()).await?;
+    Ok(())
+}
+
+/// Bookmarks used to be one per book with `unique(book_id)`, sqlite can't drop a constraint so the table is rebuilt without it.
+async fn allow_multiple_bookmarks(pool: &SqlitePool) -> Result<(), Error> {
+    let sql: Option<String> = sqlx::query_scalar(
+        "select sql from sqlite_master where type = 'table' and name = 'bookmarks'",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if !sql.map_or(false, |sql| sql.contains("unique(book_id)")) {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+    for statement in &[
+        "create table bookmarks_migrated (
+            id integer not null primary key autoincrement,
+            book_id text not null,
+            chapter_id text not null,
+            progress real not null,
+            created datetime not null,
+            foreign key (book_id) references books(id),
+            foreign key (chapter_id) references chapters(id)
+        )",
+        "insert into bookmarks_migrated(id, book_id, chapter_id, progress, created) select id, book_id, chapter_id, progress, created from bookmarks",
+        "drop table bookmarks",
+        "alter table bookmarks_migrated rename to bookmarks",
+    ] {
+        sqlx::query(statement).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
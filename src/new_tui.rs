@@ -1,18 +1,49 @@
+use crate::citation::{self, Source};
+use crate::clipboard;
+use crate::daemon::{self, Client};
+use crate::filter::{BookInfo, Filter};
 use crate::fimfarchive::FimfArchiveResult;
 use crate::fimfarchive::FimfArchiveSchema;
+use crate::fimfarchive::{AdvancedSearch, ORDERS, RATINGS, STATUSES};
+use crate::i18n::{format_date, format_datetime, format_number, tr, tr_args, LOCALES};
+use crate::jobs::{get_jobs, Job, JobKind, JobQueue, JobState};
 use crate::library::delete_bookmark;
 use crate::library::*;
+use crate::render::{
+    force_paragraph_spacing, hide_spoilers, note_text, noterefs, strip_selectors, Anchors,
+    ColorRenderer,
+};
+use crate::rules::{apply_rules, describe, validate, RULE_ACTIONS, RULE_FIELDS};
+use crate::scan::{import_epub, import_file};
+use crate::settings::{
+    command_is_night, parse_key, Keymap, Settings, ThemeSchedule, BOLD_EFFECTS, ITALIC_EFFECTS,
+    THEMES,
+};
+use crate::text_index::{IndexTuning, TextIndex, TextMatch, TEXT_INDEX_PATH};
 use crate::Error;
+use cursive::event::{Event, EventResult, Key};
+use cursive::theme::{BaseColor, Color, ColorStyle, Effect, PaletteColor, Theme};
 use cursive::traits::*;
+use cursive::utils::markup::StyledString;
+use cursive::utils::Counter;
+use cursive::view::{SizeConstraint, ViewWrapper};
 use tantivy::{Index, IndexReader};
 //use cursive::view::*;
 use cursive::views::*;
 use cursive::*;
-use cursive_markup::html::RichRenderer;
 use cursive_markup::MarkupView;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use scraper::{Html, Selector};
 use sqlx::SqlitePool;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 use uuid::adapter::Hyphenated;
 
@@ -21,7 +52,50 @@ pub struct Data {
     pub runtime: Runtime,
     schema: FimfArchiveSchema,
     index: Index,
+    /// its pool of searchers is shared by every fimfarchive search, see `IndexTuning::searchers`
     reader: IndexReader,
+    text_index: TextIndex,
+    jobs: JobQueue,
+    /// the daemon running the jobs instead of `jobs`, if one was running at startup
+    daemon: Option<Client>,
+    /// incremented for every fimfarchive search so older searches know they've been superseded
+    search_generation: Arc<AtomicUsize>,
+    /// incremented for every edit of an incremental search query to debounce the searches
+    search_edits: Arc<AtomicUsize>,
+    query_history: QueryHistory,
+    /// how far back in the query history the focused search is, None when it isn't showing a past query
+    history_position: Option<usize>,
+    settings: Settings,
+    library_sort: LibrarySort,
+    /// the filter the library is currently showing, kept so it can be re-sorted
+    library_filter: Option<Filter>,
+    /// the book and chapter open in the reader
+    current_chapter: Option<(Hyphenated, Hyphenated)>,
+    /// whether the open chapter is the last one in its book
+    last_chapter: bool,
+    /// chapters were added after the open one while it was read, said once the end of it is reached
+    new_chapter: bool,
+    /// the title of the open chapter, shown in the reader's title
+    chapter_title: String,
+    /// the last book on_finish_book ran for, so reading the end again doesn't run it again
+    finished_book: Option<Hyphenated>,
+    last_position: Option<(Hyphenated, Hyphenated, f32)>,
+    last_position_save: Instant,
+    page_turns: usize,
+    /// whether spoilers are shown in the open chapter or the selected story's description
+    spoilers_revealed: bool,
+    /// the book opened from a text search and the matched words, marked in the reader's scrollbar
+    search_terms: Option<(Hyphenated, Vec<String>)>,
+    auto_scroll: Option<AutoScroll>,
+    /// the theme showing, the night theme at night if there is one
+    active_theme: String,
+    theme_checked: Instant,
+    /// whether the theme schedule said it's night the last time it was checked
+    night: bool,
+    /// the most recent notifications, oldest first
+    notifications: Vec<Notification>,
+    /// the refresh tasks that are failing and when to retry them, see `refresh`
+    refresh_failures: HashMap<&'static str, Backoff>,
 }
 
 impl Data {
@@ -31,23 +105,150 @@ impl Data {
 }
 
 pub async fn init() -> Result<Data, Error> {
-    let (schema, index, reader) = crate::fimfarchive::open("index");
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let settings = Settings::load(&pool).await?;
+    crate::i18n::load(&settings.locale);
+    let (schema, index, reader) =
+        crate::fimfarchive::open(&settings.index_path, settings.index_tuning)?;
+
+    let query_history = if settings.save_query_history {
+        get_query_history(&pool).await?
+    } else {
+        QueryHistory::new()
+    };
+    let text_index = TextIndex::open(TEXT_INDEX_PATH, settings.index_tuning)?;
+    let jobs = JobQueue::new(pool.clone(), text_index.clone());
+
     Ok(Data {
-        pool: SqlitePool::connect("ereader.sqlite").await?,
+        pool,
         runtime: Runtime::new()?,
         schema,
         index,
         reader,
+        text_index,
+        jobs,
+        daemon: Client::connect(daemon::SOCKET_PATH),
+        search_generation: Arc::new(AtomicUsize::new(0)),
+        search_edits: Arc::new(AtomicUsize::new(0)),
+        query_history,
+        history_position: None,
+        settings,
+        library_sort: LibrarySort {
+            column: LibraryColumn::Title,
+            descending: false,
+        },
+        library_filter: None,
+        current_chapter: None,
+        last_chapter: false,
+        new_chapter: false,
+        chapter_title: String::new(),
+        finished_book: None,
+        last_position: None,
+        last_position_save: Instant::now(),
+        page_turns: 0,
+        spoilers_revealed: false,
+        search_terms: None,
+        auto_scroll: None,
+        active_theme: String::new(),
+        theme_checked: Instant::now(),
+        night: false,
+        notifications: Vec::new(),
+        refresh_failures: HashMap::new(),
     })
 }
 
+/// The longest a failing refresh task waits before it's tried again.
+const MAX_REFRESH_BACKOFF: Duration = Duration::from_secs(60);
+
+/// When a failing refresh task is tried next and how long it waited last time.
+struct Backoff {
+    retry_at: Instant,
+    delay: Duration,
+}
+
+/// Called on every refresh (once a second) to save the reading position, move auto-scrolling along,
+/// and switch to the night theme when it's scheduled to.
+pub fn refresh(s: &mut Cursive) {
+    refresh_task(s, "autosave position", |s| autosave_position(s, false));
+    refresh_task(s, "auto scroll", auto_scroll);
+    refresh_task(s, "scheduled theme", scheduled_theme);
+}
+
+/// Runs one of the refresh tasks, only showing its error the first time it fails.
+/// A failing task waits twice as long as the last time before it's tried again, up to `MAX_REFRESH_BACKOFF`,
+/// so a lasting problem like a locked database doesn't open a dialog every second. It's back to every refresh once it works.
+fn refresh_task(s: &mut Cursive, name: &'static str, task: fn(&mut Cursive) -> Result<(), Error>) {
+    let now = Instant::now();
+    let waiting = data(s)
+        .map(|data| {
+            data.refresh_failures
+                .get(name)
+                .map_or(false, |backoff| now < backoff.retry_at)
+        })
+        .unwrap_or(false);
+    if waiting {
+        return;
+    }
+
+    let result = task(s);
+    let data = match data(s) {
+        Ok(data) => data,
+        Err(e) => return error_message(s, e),
+    };
+    match result {
+        Ok(()) => {
+            data.refresh_failures.remove(name);
+        }
+        Err(e) => {
+            let delay = match data.refresh_failures.get(name) {
+                Some(backoff) => (backoff.delay * 2).min(MAX_REFRESH_BACKOFF),
+                None => Duration::from_secs(1),
+            };
+            let first = data
+                .refresh_failures
+                .insert(
+                    name,
+                    Backoff {
+                        retry_at: now + delay,
+                        delay,
+                    },
+                )
+                .is_none();
+            if first {
+                error_message(s, e);
+            }
+        }
+    }
+}
+
 pub fn cleanup(s: &mut Cursive) -> Result<(), Error> {
-    let data = data(s)?;
-    data.run(data.pool.close());
-    s.quit();
+    autosave_position(s, true)?;
+
+    if let Err(e) = sync_push(s) {
+        s.add_layer(
+            Dialog::around(TextView::new(tr_args(
+                "Unable to push to the sync server: {}",
+                &[&e],
+            )))
+            .title(tr("Sync"))
+            .button(tr("Quit Anyway"), quit)
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+        );
+        return Ok(());
+    }
+
+    quit(s);
     Ok(())
 }
 
+fn quit(s: &mut Cursive) {
+    if let Ok(data) = data(s) {
+        data.run(data.pool.close());
+    }
+    s.quit();
+}
+
 fn data(s: &mut Cursive) -> Result<&mut Data, Error> {
     s.user_data().ok_or(Error::MissingUserData)
 }
@@ -83,7 +284,7 @@ macro_rules! try_view {
 pub fn error_message(s: &mut Cursive, e: Error) {
     s.add_layer(
         Dialog::around(TextView::new(e.to_string()))
-            .dismiss_button("Close")
+            .dismiss_button(tr("Close"))
             .max_width(90),
     );
 }
@@ -102,295 +303,5579 @@ pub fn log(message: String) {
 // ============================== LIBRARY ==============================
 pub fn library(s: &mut Cursive) -> Result<(), Error> {
     let data = data(s)?;
-    let books = data.run(get_books(&data.pool))?;
+    let collections = data.run(get_collections(&data.pool))?;
+
+    let mut collections_list = SelectView::new();
+    collections_list.add_item(tr("All Books"), None);
+    for collection in collections {
+        collections_list.add_item(collection.name.clone(), Some(collection));
+    }
+    collections_list.set_on_select(try_view!(|s, collection: &Option<Collection>| {
+        set_collection(s, collection.as_ref())
+    }));
 
     let mut library = LinearLayout::vertical();
 
     let mut books_list = SelectView::new();
-    books_list.set_on_select(set_book_details);
+    books_list.set_on_select(try_view!(set_book_details));
     books_list.set_on_submit(try_view!(|s, book: &Book| chapter_goto_index(
         s, book.id, 1
     )));
 
-    for book in &books {
-        books_list.add_item(book.title.clone(), book.clone());
-    }
-
     let book_details = Panel::new(ListView::new());
 
-    library.add_child(books_list.scrollable());
+    // typing a letter jumps to the first title starting with it, except for the quit and read later keys
+    let keymap = data(s)?.settings.keymap.clone();
+    let quit = parse_key(&keymap.quit);
+    let read_later_key = parse_key(&keymap.read_later).unwrap_or(Event::Char('+'));
+    let mut books_list = OnEventView::new(books_list.with_name("books"));
+    books_list.set_on_event(
+        read_later_key.clone(),
+        try_view!(read_later_selected_book, button),
+    );
+    for letter in ('a'..='z').chain('0'..='9') {
+        if quit == Some(Event::Char(letter)) || read_later_key == Event::Char(letter) {
+            continue;
+        }
+        books_list.set_on_event_inner(letter, move |books, _| {
+            jump_to_letter(&mut books.get_mut(), letter).map(EventResult::Consumed)
+        });
+    }
+    let books_list = LinearLayout::vertical()
+        .child(TextView::new("").with_name("letter index"))
+        .child(books_list.scrollable());
+
+    let wide = s.screen_size().x >= WIDE_LIBRARY;
+    if wide {
+        library.add_child(
+            LinearLayout::vertical()
+                .child(library_header().with_name("library header"))
+                .child(books_list),
+        );
+    } else {
+        library.add_child(books_list);
+    }
     library.add_child(book_details);
 
+    let layout = LinearLayout::vertical()
+        .child(
+            LinearLayout::horizontal()
+                .child(
+                    Panel::new(collections_list.with_name("collections").scrollable())
+                        .title(tr("Collections")),
+                )
+                .child(library.with_name("library")),
+        )
+        .child(TextView::new(latest_notification(s)?).with_name("notifications"));
+
     s.add_layer(
-        Dialog::around(library.with_name("library"))
-            .title("Library")
-            .button("Bookmarks", try_view!(bookmarks, button))
-            .button("Fimfarchive", fimfarchive)
-            .max_width(90),
+        Dialog::around(layout)
+            .title(tr("Library"))
+            .button(tr("Scan"), try_view!(scan_library, button))
+            .button(tr("Import"), import_prompt)
+            .button(tr("Search"), search_library_prompt)
+            .button(tr("Search All"), search_all_prompt)
+            .button(tr("Search Text"), search_text_prompt)
+            .button(tr("Authors"), try_view!(authors, button))
+            .button(tr("Book"), book_actions)
+            .button(tr("Collections"), try_view!(collections, button))
+            .button(tr("Read Later"), try_view!(read_later, button))
+            .button(tr("Search Notes"), search_notes_prompt)
+            .button(tr("Bookmarks"), try_view!(bookmarks, button))
+            .button(tr("Filters"), try_view!(content_filters, button))
+            .button(tr("Fimfarchive"), fimfarchive)
+            .button(tr("Notifications"), try_view!(notifications, button))
+            .button(tr("Tasks"), try_view!(tasks, button))
+            .button(tr("Maintenance"), maintenance)
+            .max_width(if wide { 180 } else { 120 }),
     );
 
-    if let Some(book) = books.get(0) {
-        set_book_details(s, book);
-    }
+    set_collection(s, None)
+}
 
-    Ok(())
+/// All of the books in the library with the information needed to filter them.
+fn books_info(s: &mut Cursive) -> Result<Vec<BookInfo>, Error> {
+    let data = data(s)?;
+    let books = data.run(get_books(&data.pool))?;
+    let tags = data.run(get_all_book_tags(&data.pool))?;
+    let members = data.run(get_collection_books(&data.pool))?;
+    let authors = data.run(get_all_book_authors(&data.pool))?;
+    let bookmarks = data.run(get_bookmarks(&data.pool))?;
+    let progress = data.run(get_reading_progress(&data.pool))?;
+    let ratings = data.run(get_ratings(&data.pool))?;
+    let paths = data.run(get_all_book_paths(&data.pool))?;
+
+    Ok(books
+        .into_iter()
+        .map(|book| {
+            let progress = progress.iter().find(|progress| progress.book_id == book.id);
+            BookInfo {
+                tags: tags
+                    .iter()
+                    .filter(|tag| tag.book_id == book.id)
+                    .map(|tag| tag.tag.clone())
+                    .collect(),
+                authors: authors
+                    .iter()
+                    .filter(|author| author.book_id == book.id)
+                    .map(|author| author.name.clone())
+                    .collect(),
+                collections: members
+                    .iter()
+                    .filter(|member| member.book_id == book.id)
+                    .map(|member| member.collection.clone())
+                    .collect(),
+                started: progress.is_some(),
+                bookmarked: bookmarks.iter().any(|bookmark| bookmark.book_id == book.id),
+                progress: progress.map(|progress| {
+                    if book.words == 0 {
+                        0.0
+                    } else {
+                        (progress.words_read / book.words as f64).min(1.0) as f32
+                    }
+                }),
+                last_read: progress.map(|progress| progress.last_read),
+                rating: ratings
+                    .iter()
+                    .find(|rating| rating.book_id == book.id)
+                    .map(|rating| rating.rating),
+                missing: !paths
+                    .iter()
+                    .filter(|path| path.book_id == book.id)
+                    .any(|path| book_path_exists(&path.path)),
+                book,
+            }
+        })
+        .collect())
 }
 
-fn set_book_details(s: &mut Cursive, book: &Book) {
-    let mut detail_view = LinearLayout::vertical();
+/// Shows the books in a collection in the library, or all books if there is no collection.
+fn set_collection(s: &mut Cursive, collection: Option<&Collection>) -> Result<(), Error> {
+    let filter = match collection {
+        Some(collection) => {
+            Some(Filter::parse(&collection.query)?.or_in_collection(&collection.name))
+        }
+        None => None,
+    };
 
-    detail_view.add_child(TextView::new(format!("Title: {}", book.title)));
+    show_books(s, filter)
+}
 
-    if let Some(creator) = &book.creator {
-        detail_view.add_child(TextView::new(format!("Author: {}", creator)));
-    }
-    if let Some(publisher) = &book.publisher {
-        detail_view.add_child(TextView::new(format!("Publisher: {}", publisher)));
-    }
-    detail_view.add_child(TextView::new("\n\n"));
-    if let Some(description) = &book.description {
-        detail_view.add_child(MarkupView::html(description));
-    }
+fn search_library_prompt(s: &mut Cursive) {
+    let mut search_view = EditView::new();
+    search_view.set_on_submit(try_view!(search_library));
 
-    let mut library = s.find_name::<LinearLayout>("library").unwrap();
+    s.add_layer(
+        Dialog::around(history_edit_view(search_view, "library", "library query"))
+            .title(tr(
+                "Search Library (e.g. author(name) #(tag) words>100k status:unread)",
+            ))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
 
-    library.remove_child(1);
-    library.add_child(Panel::new(detail_view.scrollable()).title("Details"));
+fn search_library(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    record_query(s, "library", query)?;
+    let filter = Filter::parse(query)?;
+    s.pop_layer();
+    show_books(s, Some(filter))
 }
 
-// ============================== READER ==============================
-fn chapter(s: &mut Cursive, id: Hyphenated, progress: Option<f32>) -> Result<(), Error> {
-    let data = data(s)?;
-    let chapter = data.run(get_chapter_by_id(&data.pool, id))?;
-    let num_chapters = data.run(get_num_chapters(&data.pool, chapter.book_id))?;
+fn search_text_prompt(s: &mut Cursive) {
+    let mut search_view = EditView::new();
+    search_view.set_on_submit(try_view!(search_text));
 
-    let cursor = std::io::Cursor::new(chapter.content.clone());
-    let content = zstd::stream::decode_all(cursor).unwrap();
-    let content_str = String::from_utf8(content).unwrap();
+    s.add_layer(
+        Dialog::around(history_edit_view(search_view, "text", "text query"))
+            .title(tr_args(
+                "Search Text (e.g. {} +required -excluded)",
+                &[&format!("\"{}\"", tr("exact phrase"))],
+            ))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
 
-    let mut chapter_view = if let Some(c) = s.find_name::<Dialog>("reader") {
-        c
-    } else {
-        s.add_layer(Dialog::new().with_name("reader").max_width(90));
-        s.find_name::<Dialog>("reader").unwrap()
-    };
+/// Searches the text of every chapter in the library, indexing any books that were added since the last search.
+fn search_text(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    record_query(s, "text", query)?;
 
-    let mut view = MarkupView::html(&content_str);
-    view.on_link_focus(|_s, _url| {});
-    view.on_link_select(|_s, _url| {});
+    let data = data(s)?;
+    data.run(data.text_index.update(&data.pool))?;
+    let matches = data.text_index.search(query, 50)?;
+    let titles = data
+        .run(get_books(&data.pool))?
+        .into_iter()
+        .map(|book| (book.id, book.title))
+        .collect::<HashMap<Hyphenated, String>>();
 
-    let mut scrollable = view.scrollable();
-    // TODO: this might still be wrong when the bookmark is near the end or at weird screen sizes
-    // write out the calculations and figure out the correct way to do this
-    if let Some(progress) = progress {
-        let x = std::cmp::min(s.screen_size().x - 6, 86);
-        scrollable.layout(XY::new(x, 65));
+    s.pop_layer();
 
-        let size = scrollable.inner_size();
-        let offset_y = (size.y as f32 * progress).round() as usize;
-        scrollable.set_offset(XY::new(0, offset_y));
+    if matches.is_empty() {
+        s.add_layer(
+            Dialog::around(TextView::new(tr_args("Nothing matched {}.", &[&query])))
+                .title(tr("Search Text"))
+                .dismiss_button(tr("Close"))
+                .max_width(90),
+        );
+        return Ok(());
     }
 
-    chapter_view.set_content(scrollable.with_name("reader content"));
-
-    chapter_view.clear_buttons();
-    if chapter.index < num_chapters as i64 {
-        let book_id = chapter.book_id;
-        let index = chapter.index;
-        chapter_view.add_button("Next", try_view!(chapter_goto_index, book_id, index + 1));
+    // each match takes two rows, the book's title and the snippet under it
+    let mut results = SelectView::new();
+    for text_match in matches {
+        let title = titles.get(&text_match.book_id).cloned().unwrap_or_default();
+        results.add_item(
+            StyledString::styled(title, Effect::Bold),
+            text_match.clone(),
+        );
+        results.add_item(snippet_label(&text_match), text_match);
     }
-    if chapter.index > 1 {
-        let book_id = chapter.book_id;
-        let index = chapter.index;
-        chapter_view.add_button("Prev", try_view!(chapter_goto_index, book_id, index - 1));
-    }
-    let book_id = chapter.book_id;
-    chapter_view.add_button("TOC", try_view!(toc, book_id));
-    let book_id = chapter.book_id;
-    let chapter_id = chapter.id;
-    chapter_view.add_button("Bookmark", try_view!(set_bookmark, book_id, chapter_id));
-    chapter_view.add_button("Close", |s| {
+    results.set_on_submit(|s, text_match: &TextMatch| {
         s.pop_layer();
+        if let Err(e) = open_text_match(s, text_match) {
+            error_message(s, e);
+        }
     });
 
+    s.add_layer(
+        Dialog::around(results.scrollable())
+            .title(tr_args("Search Text: {}", &[&query]))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
     Ok(())
 }
 
-fn chapter_goto_index(s: &mut Cursive, id: Hyphenated, index: i64) -> Result<(), Error> {
-    let chapter_id = {
-        let data = data(s)?;
-        let chapter = data.run(get_chapter(&data.pool, id, index))?;
-        chapter.id
-    };
+/// Opens the chapter at the match, remembering the matched words so the reader can mark the other hits.
+fn open_text_match(s: &mut Cursive, text_match: &TextMatch) -> Result<(), Error> {
+    let mut terms = Vec::new();
+    for (start, end) in &text_match.highlights {
+        if let Some(term) = text_match.snippet.get(*start..*end) {
+            let term = term.to_lowercase();
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+    }
+    data(s)?.search_terms = Some((text_match.book_id, terms));
 
-    chapter(s, chapter_id, None)
+    chapter(s, text_match.chapter_id, Some(text_match.progress))
 }
 
-fn chapter_goto_toc(s: &mut Cursive, toc: &Toc) -> Result<(), Error> {
-    s.pop_layer();
-    chapter(s, toc.chapter_id, None)
-}
+/// The snippet of a text match, indented with the matching words highlighted.
+fn snippet_label(text_match: &TextMatch) -> StyledString {
+    let snippet = &text_match.snippet;
+    let mut label = StyledString::plain("  ");
+    let mut end = 0;
 
-fn chapter_goto_bookmark(s: &mut Cursive, bookmark: &Bookmark) -> Result<(), Error> {
-    s.pop_layer();
-    chapter(s, bookmark.chapter_id, Some(bookmark.progress))
+    for (start, stop) in &text_match.highlights {
+        if *start < end || *stop > snippet.len() {
+            continue;
+        }
+        label.append_plain(&snippet[end..*start]);
+        label.append_styled(&snippet[*start..*stop], Effect::Reverse);
+        end = *stop;
+    }
+    label.append_plain(&snippet[end..]);
+
+    label
 }
 
-// ============================== TOC ==============================
-fn toc(s: &mut Cursive, id: Hyphenated) -> Result<(), Error> {
+/// Fills the library with the books matching the filter.
+fn show_books(s: &mut Cursive, filter: Option<Filter>) -> Result<(), Error> {
+    let mut books = books_info(s)?
+        .into_iter()
+        .filter(|info| filter.as_ref().map(|f| f.matches(info)).unwrap_or(true))
+        .collect::<Vec<BookInfo>>();
+
     let data = data(s)?;
-    let toc = data.run(get_toc(&data.pool, id))?;
+    sort_books(&mut books, data.library_sort);
+    data.library_filter = filter;
 
-    let mut toc_list = SelectView::new();
-    for toc in toc {
-        toc_list.add_item(toc.title.clone(), toc.clone());
-    }
+    let wide = s.find_name::<LinearLayout>("library header").is_some();
 
-    toc_list.set_on_submit(try_view!(chapter_goto_toc));
+    let mut books_list = s
+        .find_name::<SelectView<Book>>("books")
+        .ok_or(Error::ViewNotFound)?;
+    books_list.clear();
+    for info in &books {
+        let label = if wide {
+            format!("{} {}", badges(info), library_row(info))
+        } else {
+            format!("{} {}", badges(info), info.book.title)
+        };
+        books_list.add_item(label, info.book.clone());
+    }
+    drop(books_list);
 
-    s.add_layer(
-        Dialog::around(toc_list.scrollable())
-            .title("Table of Contents")
-            .dismiss_button("Close")
-            .max_width(90),
-    );
+    if let Some(mut letter_index) = s.find_name::<TextView>("letter index") {
+        letter_index.set_content(letter_strip(&books));
+    }
 
-    Ok(())
+    match books.get(0) {
+        Some(info) => set_book_details(s, &info.book),
+        None => {
+            let mut library = s
+                .find_name::<LinearLayout>("library")
+                .ok_or(Error::ViewNotFound)?;
+            library.remove_child(1);
+            library.add_child(Panel::new(ListView::new()));
+            Ok(())
+        }
+    }
 }
 
-// ============================== BOOKMARKS ==============================
-fn bookmarks(s: &mut Cursive) -> Result<(), Error> {
-    let data = data(s)?;
-    let bookmarks = data.run(get_bookmarks(&data.pool))?;
+/// The letter a title is grouped under, titles starting with anything else are grouped under '#'.
+fn title_letter(title: &str) -> char {
+    match title.trim().chars().next() {
+        Some(c) if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+        _ => '#',
+    }
+}
 
-    let mut bookmarks_view = SelectView::new();
+/// An A-Z strip above the library with the letters that no title starts with greyed out.
+fn letter_strip(books: &[BookInfo]) -> StyledString {
+    let letters = books
+        .iter()
+        .map(|info| title_letter(&info.book.title))
+        .collect::<HashSet<char>>();
 
-    for bookmark in bookmarks {
-        let book = data.run(get_book(&data.pool, bookmark.book_id))?;
-        bookmarks_view.add_item(book.title.clone(), bookmark);
+    let mut strip = StyledString::new();
+    for letter in std::iter::once('#').chain('A'..='Z') {
+        if letters.contains(&letter) {
+            strip.append_plain(format!("{} ", letter));
+        } else {
+            strip.append_styled(format!("{} ", letter), ColorStyle::secondary());
+        }
     }
+    strip
+}
 
-    bookmarks_view.set_on_submit(try_view!(chapter_goto_bookmark));
+/// Selects the first book in the list whose title starts with the letter.
+/// Returns the callback that shows its details, or None if no title starts with it.
+fn jump_to_letter(books: &mut SelectView<Book>, letter: char) -> Option<Option<Callback>> {
+    let letter = letter.to_ascii_uppercase();
+    let index = books
+        .iter()
+        .position(|(_label, book)| title_letter(&book.title) == letter)?;
+    Some(Some(books.set_selection(index)))
+}
 
-    s.add_layer(
-        Dialog::around(bookmarks_view.with_name("bookmarks"))
-            .title("Bookmarks")
-            .button("Delete", try_view!(delete_selected_bookmark, button))
-            .dismiss_button("Close")
-            .max_width(90),
-    );
+/// Terminals at least this wide show the library as a table.
+const WIDE_LIBRARY: usize = 150;
 
-    Ok(())
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LibraryColumn {
+    Title,
+    Author,
+    Series,
+    Progress,
+    Words,
+    LastRead,
 }
 
-fn delete_selected_bookmark(s: &mut Cursive) -> Result<(), Error> {
-    let bookmarks_view = s.find_name::<SelectView<Bookmark>>("bookmarks").unwrap();
-    let bookmark = bookmarks_view.selection().unwrap();
+const LIBRARY_COLUMNS: [(LibraryColumn, &str, usize); 6] = [
+    (LibraryColumn::Title, "Title", 40),
+    (LibraryColumn::Author, "Author", 24),
+    (LibraryColumn::Series, "Series", 20),
+    (LibraryColumn::Progress, "Progress", 9),
+    (LibraryColumn::Words, "Words", 9),
+    (LibraryColumn::LastRead, "Last Read", 10),
+];
 
-    log(format!("{:?}", bookmark));
-    let data = data(s)?;
-    data.run(delete_bookmark(&data.pool, bookmark.id))?;
+#[derive(Clone, Copy, Debug)]
+struct LibrarySort {
+    column: LibraryColumn,
+    descending: bool,
+}
 
-    s.pop_layer();
-    bookmarks(s)
+fn sort_books(books: &mut Vec<BookInfo>, sort: LibrarySort) {
+    books.sort_by(|a, b| {
+        let ordering = match sort.column {
+            LibraryColumn::Title => a
+                .book
+                .title
+                .to_lowercase()
+                .cmp(&b.book.title.to_lowercase()),
+            LibraryColumn::Author => a.book.creator.cmp(&b.book.creator),
+            LibraryColumn::Series => a.book.series.cmp(&b.book.series),
+            LibraryColumn::Progress => a
+                .progress
+                .partial_cmp(&b.progress)
+                .unwrap_or(std::cmp::Ordering::Equal),
+            LibraryColumn::Words => a.book.words.cmp(&b.book.words),
+            LibraryColumn::LastRead => a.last_read.cmp(&b.last_read),
+        };
+        if sort.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
 }
 
-fn set_bookmark(s: &mut Cursive, book_id: Hyphenated, chapter_id: Hyphenated) -> Result<(), Error> {
-    let reader_content = s
-        .find_name::<ScrollView<MarkupView<RichRenderer>>>("reader content")
-        .unwrap();
+/// Pads or truncates text to exactly `width` characters.
+fn fit_column(text: &str, width: usize) -> String {
+    let mut cell = text.chars().take(width).collect::<String>();
+    if text.chars().count() > width {
+        cell.pop();
+        cell.push('…');
+    }
+    format!("{:<width$}", cell, width = width)
+}
 
-    let viewport = reader_content.content_viewport();
-    let size = reader_content.inner_size();
-    let progress = viewport.top() as f32 / size.y as f32;
+/// Column headers for the wide library, selecting one sorts by it.
+fn library_header() -> LinearLayout {
+    let mut header = LinearLayout::horizontal();
+    // room for the badges in front of each row
+    header.add_child(DummyView.fixed_width(6));
+    for (column, label, width) in LIBRARY_COLUMNS.iter() {
+        let column = *column;
+        header.add_child(Button::new_raw(fit_column(tr(*label), *width), move |s| {
+            if let Err(e) = sort_library(s, column) {
+                error_message(s, e);
+            }
+        }));
+        header.add_child(DummyView.fixed_width(1));
+    }
+    header
+}
+
+fn library_row(info: &BookInfo) -> String {
+    let cells = [
+        info.book.title.clone(),
+        info.book.creator.clone().unwrap_or_default(),
+        info.book.series.clone().unwrap_or_default(),
+        info.progress
+            .map(|progress| format!("{:.0}%", progress * 100.0))
+            .unwrap_or_default(),
+        format_number(info.book.words),
+        info.last_read
+            .map(|last_read| format_date(&last_read))
+            .unwrap_or_default(),
+    ];
+
+    LIBRARY_COLUMNS
+        .iter()
+        .zip(cells.iter())
+        .map(|((_, _, width), cell)| fit_column(cell, *width))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Progress at or above this counts as having finished the book.
+const FINISHED: f32 = 0.99;
+
+/// Compact status indicators for a library row, always the same width so the rows line up.
+/// ✓ finished, ▶ reading, ★ rated, 🔖 bookmarked, ✗ missing file
+fn badges(info: &BookInfo) -> String {
+    let reading = match info.progress {
+        Some(progress) if progress >= FINISHED => "✓",
+        Some(_) => "▶",
+        None => " ",
+    };
+    let rated = if info.rating.is_some() { "★" } else { " " };
+    let bookmarked = if info.bookmarked { "🔖" } else { "  " };
+    let missing = if info.missing { "✗" } else { " " };
 
+    format!("{}{}{}{}", reading, rated, bookmarked, missing)
+}
+
+/// Whether a book path still exists, paths inside a zip count if the zip exists.
+fn book_path_exists(path: &str) -> bool {
+    std::path::Path::new(path)
+        .ancestors()
+        .any(|path| path.is_file())
+}
+
+/// Sorts the library by a column, selecting the column it's already sorted by reverses the order.
+fn sort_library(s: &mut Cursive, column: LibraryColumn) -> Result<(), Error> {
     let data = data(s)?;
-    data.run(insert_bookmark(
-        &data.pool,
-        &Bookmark {
-            id: 0,
-            book_id,
-            chapter_id,
-            progress,
-            created: chrono::Utc::now(),
-        },
-    ))
+    data.library_sort = LibrarySort {
+        column,
+        descending: data.library_sort.column == column && !data.library_sort.descending,
+    };
+    let filter = data.library_filter.clone();
+
+    show_books(s, filter)
 }
 
-// ============================== FIMFARCHIVE ==============================
+type BookAction = fn(&mut Cursive, Hyphenated) -> Result<(), Error>;
 
-fn fimfarchive(s: &mut Cursive) {
-    let mut search_view = EditView::new();
+/// Actions for the book selected in the library.
+fn book_actions(s: &mut Cursive) {
+    let book = match selected_book(s) {
+        Some(book) => book,
+        None => return,
+    };
+    let book_id = book.id;
+
+    let mut actions = SelectView::<BookAction>::new();
+    actions.add_item(tr("Skim"), skim as BookAction);
+    actions.add_item(tr("Notes"), notes as BookAction);
+    actions.add_item(tr("Tags"), tags as BookAction);
+    actions.add_item(tr("Rate"), rate as BookAction);
+    actions.add_item(tr("Reprocess"), reprocess_book as BookAction);
 
-    search_view.set_on_submit(try_view!(search_fimfarchive));
+    actions.set_on_submit(move |s, action: &BookAction| {
+        s.pop_layer();
+        if let Err(e) = action(s, book_id) {
+            error_message(s, e);
+        }
+    });
 
     s.add_layer(
-        Dialog::around(search_view)
-            .title("Fimfarchive Search")
-            .dismiss_button("Close")
+        Dialog::around(actions)
+            .title(book.title.clone())
+            .dismiss_button(tr("Close"))
             .max_width(90),
     );
 }
 
-fn search_fimfarchive(s: &mut Cursive, query: &str) -> Result<(), Error> {
+fn selected_book(s: &mut Cursive) -> Option<Rc<Book>> {
+    s.find_name::<SelectView<Book>>("books")?.selection()
+}
+
+fn set_book_details(s: &mut Cursive, book: &Book) -> Result<(), Error> {
     let data = data(s)?;
-    let books = crate::fimfarchive::search(
-        query.to_string(),
-        50,
-        &data.index,
-        &data.schema,
-        &data.reader,
-    );
+    let paths = data.run(get_book_paths(&data.pool, book.id))?;
 
-    let mut fimfarchive = LinearLayout::vertical();
+    let mut detail_view = LinearLayout::vertical();
 
-    let mut books_list = SelectView::new();
-    books_list.set_on_select(set_fimfarchive_details);
+    detail_view.add_child(TextView::new(tr_args("Title: {}", &[&book.title])));
 
-    for book in &books {
-        books_list.add_item(book.title.clone(), book.clone());
+    if let Some(creator) = &book.creator {
+        detail_view.add_child(TextView::new(tr_args("Author: {}", &[creator])));
+    }
+    if let Some(publisher) = &book.publisher {
+        detail_view.add_child(TextView::new(tr_args("Publisher: {}", &[publisher])));
+    }
+    if let Some(series) = &book.series {
+        detail_view.add_child(TextView::new(tr_args("Series: {}", &[series])));
+    }
+    detail_view.add_child(TextView::new(tr_args(
+        "Words: {}",
+        &[&format_number(book.words)],
+    )));
+    if book.metadata_review {
+        detail_view.add_child(TextView::new(tr(
+            "Some metadata was missing and has been guessed, it may need to be reviewed.",
+        )));
+    }
+    for path in paths {
+        detail_view.add_child(TextView::new(tr_args("File: {}", &[&path])));
+    }
+    if let Some(story_id) = book.story_id {
+        for related in related_stories(data, story_id)? {
+            detail_view.add_child(related_story_button(data, related)?);
+        }
+    }
+    // goes straight to the toc so a chapter in the middle can be opened without opening the book first
+    let book_id = book.id;
+    detail_view.add_child(Button::new(tr("Chapters"), try_view!(toc, book_id)));
+    detail_view.add_child(TextView::new("\n\n"));
+    if let Some(description) = &book.description {
+        detail_view.add_child(MarkupView::html(description));
     }
 
-    let book_details = Panel::new(ListView::new());
+    let mut library = s.find_name::<LinearLayout>("library").unwrap();
 
-    fimfarchive.add_child(books_list.scrollable());
-    fimfarchive.add_child(book_details);
+    library.remove_child(1);
+    library.add_child(Panel::new(detail_view.scrollable()).title(tr("Details")));
 
-    s.add_layer(
-        Dialog::around(fimfarchive.with_name("fimfarchive"))
-            .title("Fimfarchive Results")
-            .dismiss_button("Close")
-            .max_width(90),
-    );
+    Ok(())
+}
 
-    if let Some(book) = books.get(0) {
-        set_fimfarchive_details(s, book);
+/// The prequel and sequels of a fimfiction story.
+///
+/// They're looked up in the fimfarchive index the first time and saved, so they still show
+/// after the index is rebuilt from a fimfarchive that doesn't have them.
+fn related_stories(data: &mut Data, story_id: i64) -> Result<Vec<RelatedStory>, Error> {
+    let stored = data.run(get_related_stories(&data.pool, story_id))?;
+    if !stored.is_empty() {
+        return Ok(stored);
     }
 
-    Ok(())
+    for (relation, story) in
+        crate::fimfarchive::related_stories(story_id, &data.schema, &data.reader)?
+    {
+        let related = RelatedStory {
+            story_id,
+            related_id: story.id,
+            relation: relation.as_str().to_string(),
+            title: story.title,
+        };
+        data.run(insert_related_story(&data.pool, &related))?;
+    }
+    data.run(get_related_stories(&data.pool, story_id))
 }
 
-fn set_fimfarchive_details(s: &mut Cursive, book: &FimfArchiveResult) {
-    let mut detail_view = LinearLayout::vertical();
+/// Opens a related story that's in the library, or imports it from the fimfarchive if it's there.
+fn related_story_button(data: &mut Data, related: RelatedStory) -> Result<Button, Error> {
+    let relation = match related.relation.as_str() {
+        "prequel" => tr("Prequel"),
+        _ => tr("Sequel"),
+    };
 
-    detail_view.add_child(TextView::new(format!(
-        "Title: {}\nAuthor: {}\nWords: {}\nLikes: {}\nDislikes: {}\nWilson: {:.2}%\nTags: {}\n\n",
-        book.title,
-        book.author.split("/").last().unwrap(),
-        book.words,
-        book.likes,
-        book.dislikes,
-        book.wilson * 100.0,
-        book.tags
-            .iter()
-            .map(|tag| tag.split("/").last().unwrap().to_string())
-            .collect::<Vec<String>>()
-            .join(", ")
-    )));
-    detail_view.add_child(MarkupView::html(&book.description));
+    if let Some(book_id) = data.run(get_story_book(&data.pool, related.related_id))? {
+        let label = format!("{}: {} ({})", relation, related.title, tr("in library"));
+        return Ok(Button::new(label, try_view!(open_book, book_id)));
+    }
 
-    let mut fimfarchive = s.find_name::<LinearLayout>("fimfarchive").unwrap();
+    match crate::fimfarchive::story(related.related_id, &data.schema, &data.reader) {
+        Some(story) => {
+            let label = format!("{}: {} ({})", relation, related.title, tr("importable"));
+            Ok(Button::new(label, try_view!(import_fimfarchive, &story)))
+        }
+        None => {
+            let mut button = Button::new(format!("{}: {}", relation, related.title), |_| {});
+            button.disable();
+            Ok(button)
+        }
+    }
+}
 
-    fimfarchive.remove_child(1);
-    fimfarchive.add_child(Panel::new(detail_view.scrollable()).title("Details"));
+/// Queues a scan of the epub directory, the library reloads when it finishes.
+fn scan_library(s: &mut Cursive) -> Result<(), Error> {
+    let path = data(s)?.settings.epub_path.clone();
+    enqueue(
+        s,
+        JobKind::Scan {
+            path,
+            dry_run: false,
+        },
+    )
+}
+
+/// Queues a scan that only reports what it would import, the report is shown when it finishes.
+fn dry_run_scan(s: &mut Cursive) -> Result<(), Error> {
+    let path = data(s)?.settings.epub_path.clone();
+    enqueue(
+        s,
+        JobKind::Scan {
+            path,
+            dry_run: true,
+        },
+    )
+}
+
+fn import_prompt(s: &mut Cursive) {
+    let input = EditView::new().on_submit(|s, path| {
+        s.pop_layer();
+        if let Err(e) = import(s, path) {
+            error_message(s, e);
+        }
+    });
+
+    let input = OnEventView::new(input).on_pre_event_inner(Key::Tab, |input, _| {
+        if let Some(completed) = complete_path(&input.get_content()) {
+            input.set_content(completed);
+        }
+        Some(EventResult::Consumed(None))
+    });
+
+    s.add_layer(
+        Dialog::around(input)
+            .title(tr("Import File (tab to complete)"))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
+
+/// Completes a path as far as it can be done unambiguously, like a shell would.
+fn complete_path(input: &str) -> Option<String> {
+    let path = std::path::Path::new(input);
+    let (dir, prefix) = if input.is_empty() || input.ends_with('/') {
+        (path, "")
+    } else {
+        (path.parent()?, path.file_name()?.to_str()?)
+    };
+
+    let read_dir = if dir.as_os_str().is_empty() {
+        std::path::Path::new(".")
+    } else {
+        dir
+    };
+
+    let matches = std::fs::read_dir(read_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(prefix))
+        .collect::<Vec<String>>();
+
+    let common = matches
+        .iter()
+        .skip(1)
+        .fold(matches.first()?.clone(), |common, name| {
+            common
+                .chars()
+                .zip(name.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        });
+
+    let completed = dir.join(common);
+    if matches.len() == 1 && completed.is_dir() {
+        Some(format!("{}/", completed.to_string_lossy()))
+    } else {
+        Some(completed.to_string_lossy().to_string())
+    }
+}
+
+/// Imports a file if it isn't already in the library and opens it at the last position.
+pub fn import(s: &mut Cursive, path: &str) -> Result<(), Error> {
+    let data = data(s)?;
+    let (book_ids, report) = data.run(import_file(&data.pool, path))?;
+    let book_id = *book_ids.first().ok_or_else(|| report.failure(path))?;
+    report.forget_updated(&data.text_index)?;
+
+    // reload the library so the new book shows up
+    s.pop_layer();
+    library(s)?;
+    if !report.imported.is_empty() {
+        notify(
+            s,
+            tr_args(
+                "Imported {} books from {}",
+                &[&report.imported.len(), &path],
+            ),
+        )?;
+    }
+
+    open_book(s, book_id)
+}
+
+/// Chapters shorter than this can be front matter even when their title doesn't say so, e.g. a cover image.
+const FRONT_MATTER_WORDS: i64 = 150;
+
+/// Where the body of a book starts, past the cover, title page, copyright page, and so on.
+///
+/// The bodymatter landmark is used when the book has one, otherwise the first chapter that's in the reading order,
+/// doesn't have a front matter title in the toc, and has enough text to not be a cover or title page.
+fn body_start(
+    data: &mut Data,
+    book_id: Hyphenated,
+) -> Result<Option<(Hyphenated, Option<String>)>, Error> {
+    let landmarks = data.run(get_landmarks(&data.pool, book_id))?;
+    if let Some(body) = landmarks
+        .into_iter()
+        .find(|landmark| landmark.kind == "bodymatter")
+    {
+        return Ok(Some((body.chapter_id, body.fragment)));
+    }
+
+    let front_matter_re = Regex::new(
+        r"(?i)^\s*(cover|title( page)?|half title|copyright|(table of )?contents|dedication|epigraph|also by|praise for|about the (author|publisher)|imprint|colophon)\b",
+    )
+    .unwrap();
+    let toc = data.run(get_toc(&data.pool, book_id))?;
+    let chapters = data.run(get_book_chapters(&data.pool, book_id))?;
+
+    let body = chapters.into_iter().find(|chapter| {
+        let title = toc.iter().find(|toc| toc.chapter_id == chapter.id);
+        let front_matter_title = title
+            .map(|toc| front_matter_re.is_match(&toc.title))
+            .unwrap_or(false);
+        chapter.linear && !front_matter_title && chapter.words >= FRONT_MATTER_WORDS
+    });
+    Ok(body.map(|chapter| (chapter.id, None)))
+}
+
+/// Opens a book where it was last read, at its bookmark, or at the start.
+/// The front matter is skipped the first time a book is opened unless that's turned off in the settings.
+pub fn open_book(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let (position, bookmark) = {
+        let data = data(s)?;
+        (
+            data.run(get_position(&data.pool, book_id))?,
+            data.run(get_bookmark(&data.pool, book_id))?,
+        )
+    };
+
+    match (position, bookmark) {
+        (Some(position), _) => chapter(s, position.chapter_id, Some(position.progress)),
+        (None, Some(bookmark)) => chapter(s, bookmark.chapter_id, Some(bookmark.progress)),
+        (None, None) => {
+            let data = data(s)?;
+            let start = if data.settings.skip_front_matter {
+                body_start(data, book_id)?
+            } else {
+                None
+            };
+            match start {
+                Some((chapter_id, anchor)) => chapter_at_anchor(s, chapter_id, None, anchor),
+                None => chapter_goto_index(s, book_id, 1),
+            }
+        }
+    }
+}
+
+// ============================== DASHBOARD ==============================
+
+/// How many of the newest books the dashboard lists, each opened with its number.
+const DASHBOARD_RECENT: i64 = 5;
+
+/// Shown over the library at startup with `Settings::start_dashboard`: the book that was read last,
+/// the head of the read later queue, and the newest books, each opened with a single key.
+pub fn dashboard(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let last_read = data
+        .run(get_reading_progress(&data.pool))?
+        .into_iter()
+        .max_by_key(|progress| progress.last_read);
+    let continue_book = match last_read {
+        Some(progress) => Some(data.run(get_book(&data.pool, progress.book_id))?),
+        None => None,
+    };
+    let up_next = data.run(get_read_later(&data.pool))?.into_iter().next();
+    let recent = data.run(get_recent_books(&data.pool, DASHBOARD_RECENT))?;
+
+    let none = || TextView::new(tr("Nothing here yet"));
+    let mut layout = LinearLayout::vertical();
+
+    let mut view = OnEventView::new(Dialog::new());
+    let continue_view = match &continue_book {
+        Some(book) => {
+            let book_id = book.id;
+            view.set_on_event('c', move |s| {
+                s.pop_layer();
+                if let Err(e) = open_book(s, book_id) {
+                    error_message(s, e);
+                }
+            });
+            TextView::new(format!("[c] {}", book.title))
+        }
+        None => none(),
+    };
+    layout.add_child(Panel::new(continue_view).title(tr("Continue reading")));
+
+    let up_next_view = match up_next {
+        Some(item) => {
+            let label = format!("[n] {}", item.title);
+            view.set_on_event('n', move |s| {
+                if let Err(e) = open_read_later(s, &item) {
+                    error_message(s, e);
+                }
+            });
+            TextView::new(label)
+        }
+        None => none(),
+    };
+    layout.add_child(Panel::new(up_next_view).title(tr("Up next")));
+
+    let mut recent_view = LinearLayout::vertical();
+    if recent.is_empty() {
+        recent_view.add_child(none());
+    }
+    for (number, book) in ('1'..='9').zip(recent) {
+        let book_id = book.id;
+        view.set_on_event(number, move |s| {
+            s.pop_layer();
+            if let Err(e) = open_book(s, book_id) {
+                error_message(s, e);
+            }
+        });
+        recent_view.add_child(TextView::new(format!("[{}] {}", number, book.title)));
+    }
+    layout.add_child(Panel::new(recent_view).title(tr("Recently added")));
+
+    let dialog = view.get_inner_mut();
+    dialog.set_content(layout);
+    dialog.set_title(tr("Dashboard"));
+    dialog.add_button(tr("Library"), |s| {
+        s.pop_layer();
+    });
+    s.add_layer(view.max_width(90));
+
+    Ok(())
+}
+
+// ============================== READER ==============================
+/// Explains that a chapter's stored content is damaged instead of opening it,
+/// offering to re-import the book from the file it was imported from.
+fn corrupt_chapter(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    index: i64,
+    id: Hyphenated,
+    progress: Option<f32>,
+    anchor: Option<String>,
+) -> Result<(), Error> {
+    let data = data(s)?;
+    let title = data.run(get_book(&data.pool, book_id))?.title;
+
+    s.add_layer(
+        Dialog::around(TextView::new(tr_args(
+            "Chapter {} of {} is damaged and can't be shown, e.g. by a bad disk or an interrupted write. Re-import the book from the file it was imported from? Bookmarks and highlights stay where they are.",
+            &[&index, &title],
+        )))
+        .title(tr("Corrupt Chapter"))
+        .button(tr("Re-import"), move |s| {
+            s.pop_layer();
+            if let Err(e) = reimport_corrupt_book(s, book_id, id, progress, anchor.clone()) {
+                error_message(s, e);
+            }
+        })
+        .dismiss_button(tr("Close"))
+        .max_width(70),
+    );
+
+    Ok(())
+}
+
+fn reimport_corrupt_book(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    id: Hyphenated,
+    progress: Option<f32>,
+    anchor: Option<String>,
+) -> Result<(), Error> {
+    let data = data(s)?;
+    // matched by position, the reparsed chapters can have different ids if the importer changed since
+    let chapters = data
+        .run(crate::verify::repair(&data.pool, book_id))?
+        .ok_or_else(|| Error::OriginalNotFound(book_id.to_string()))?;
+    // the chapters' text is indexed again on the next search
+    data.text_index.remove_book(book_id)?;
+    let still_there = data
+        .run(get_chapter_ids(&data.pool, book_id))?
+        .contains(&id);
+    notify(s, tr_args("Re-imported {} chapters", &[&chapters]))?;
+
+    // the book can have fewer chapters than before
+    if still_there {
+        chapter_at_anchor(s, id, progress, anchor)
+    } else {
+        open_book(s, book_id)
+    }
+}
+
+fn chapter(s: &mut Cursive, id: Hyphenated, progress: Option<f32>) -> Result<(), Error> {
+    chapter_at_anchor(s, id, progress, None)
+}
+
+/// Opens a chapter scrolled to the element with the `anchor` id, or to `progress` if it isn't found.
+fn chapter_at_anchor(
+    s: &mut Cursive,
+    id: Hyphenated,
+    progress: Option<f32>,
+    anchor: Option<String>,
+) -> Result<(), Error> {
+    let background = s.current_theme().palette[PaletteColor::View];
+    let data = data(s)?;
+    let width = data.settings.reader_width;
+    let close_key = parse_key(&data.settings.keymap.close_reader).unwrap_or(Event::Key(Key::Esc));
+    let spoiler_key = parse_key(&data.settings.keymap.reveal_spoilers).unwrap_or(Event::Char('s'));
+    let next_mark_key = parse_key(&data.settings.keymap.next_mark).unwrap_or(Event::Char('n'));
+    let previous_mark_key =
+        parse_key(&data.settings.keymap.previous_mark).unwrap_or(Event::Char('p'));
+    let focus_key = parse_key(&data.settings.keymap.focus_mode).unwrap_or(Event::Char('f'));
+    let scrolling = Scrolling::new(&data.settings);
+    let auto_scroll_key = parse_key(&data.settings.keymap.auto_scroll).unwrap_or(Event::Char('a'));
+    let chapter = data.run(get_chapter_by_id(&data.pool, id))?;
+    // non-linear chapters are skipped, they can still be opened from the toc
+    let next_chapter = data.run(get_adjacent_linear_chapter(
+        &data.pool,
+        chapter.book_id,
+        chapter.index,
+        true,
+    ))?;
+    let previous_chapter = data.run(get_adjacent_linear_chapter(
+        &data.pool,
+        chapter.book_id,
+        chapter.index,
+        false,
+    ))?;
+    let words = data.run(get_chapter_words(&data.pool, chapter.book_id))?;
+    let (content_str, overrides) = match chapter_html(data, &chapter) {
+        Err(Error::CorruptChapter(book_id, index)) => {
+            return corrupt_chapter(s, book_id, index, id, progress, anchor)
+        }
+        result => result?,
+    };
+
+    // spoilers are hidden again when moving to another chapter
+    data.spoilers_revealed = data.spoilers_revealed
+        && data
+            .current_chapter
+            .map(|(_book_id, chapter_id)| chapter_id)
+            == Some(chapter.id);
+    let content_str = if data.spoilers_revealed {
+        content_str
+    } else {
+        hide_spoilers(&content_str, &data.settings.keymap.reveal_spoilers)
+    };
+    let renderer = chapter_renderer(&data.settings, &content_str, background, overrides);
+
+    data.current_chapter = Some((chapter.book_id, chapter.id));
+    data.last_chapter = next_chapter.is_none();
+    data.chapter_title = chapter
+        .title
+        .clone()
+        .unwrap_or_else(|| tr_args("Chapter {}", &[&chapter.index]));
+    data.page_turns += 1;
+
+    let mut markers = book_marks(s, chapter.book_id)?
+        .into_iter()
+        .filter(|mark| mark.chapter_id == chapter.id)
+        .map(|mark| (mark.progress, mark.kind.symbol()))
+        .collect::<Vec<(f32, &'static str)>>();
+    markers.extend(
+        search_hits(s, chapter.book_id, &content_str)?
+            .into_iter()
+            .map(|position| (position, SEARCH_HIT_SYMBOL)),
+    );
+
+    // the view the reader was opened from stays underneath it so closing the reader goes back to it
+    match s.screen_mut().find_layer_from_name("reader") {
+        Some(position) => s.screen_mut().move_to_front(position),
+        None => s.add_layer(
+            OnEventView::new(
+                Dialog::new()
+                    .with_name("reader")
+                    .max_width(width)
+                    .with_name("reader size"),
+            )
+            .on_event(close_key, close_reader)
+            .on_event(spoiler_key, |s| {
+                if let Err(e) = toggle_reader_spoilers(s) {
+                    error_message(s, e);
+                }
+            })
+            .on_event(next_mark_key, |s| {
+                if let Err(e) = jump_to_mark(s, true) {
+                    error_message(s, e);
+                }
+            })
+            .on_event(previous_mark_key, |s| {
+                if let Err(e) = jump_to_mark(s, false) {
+                    error_message(s, e);
+                }
+            })
+            .on_event(focus_key, |s| {
+                if let Err(e) = focus_mode(s) {
+                    error_message(s, e);
+                }
+            })
+            .on_event(auto_scroll_key, try_view!(toggle_auto_scroll, button))
+            .on_event('+', try_view!(change_auto_scroll_speed, true))
+            .on_event('-', try_view!(change_auto_scroll_speed, false))
+            .on_event(' ', try_view!(pause_auto_scroll, button)),
+        ),
+    }
+    let mut chapter_view = s.find_name::<Dialog>("reader").ok_or(Error::ViewNotFound)?;
+
+    let anchors = renderer.anchors();
+    let mut view = MarkupView::with_renderer(renderer);
+    view.on_link_focus(|_s, _url| {});
+    open_notes_on_select(&mut view, &content_str, &chapter);
+
+    let scrollable = view.scrollable();
+
+    let words_before: i64 = words.iter().take(chapter.index as usize - 1).sum();
+    let total_words: i64 = words.iter().sum();
+    let counter = Counter::new(words_before as usize);
+
+    let mut reader = LinearLayout::vertical();
+    reader.add_child(ReaderContent {
+        view: scrollable.with_name("reader content"),
+        size: XY::zero(),
+        position: progress,
+        anchor,
+        anchors,
+        markers,
+        scrolling,
+        counter: counter.clone(),
+        words_before: words_before as usize,
+        words: chapter.words as usize,
+    });
+    reader.add_child(
+        ProgressBar::new()
+            .range(0, std::cmp::max(total_words as usize, 1))
+            .with_value(counter),
+    );
+
+    chapter_view.set_content(reader);
+
+    chapter_view.clear_buttons();
+    if let Some(next) = next_chapter {
+        chapter_view.add_button(tr("Next"), try_view!(self::chapter, next.id, None));
+    }
+    if let Some(previous) = previous_chapter {
+        chapter_view.add_button(tr("Prev"), try_view!(self::chapter, previous.id, None));
+    }
+    let book_id = chapter.book_id;
+    chapter_view.add_button(tr("TOC"), try_view!(toc, book_id));
+    let book_id = chapter.book_id;
+    chapter_view.add_button(tr("Go To"), try_view!(goto_prompt, book_id));
+    let book_id = chapter.book_id;
+    let chapter_id = chapter.id;
+    chapter_view.add_button(tr("Bookmark"), try_view!(set_bookmark, book_id, chapter_id));
+    let book_id = chapter.book_id;
+    chapter_view.add_button(tr("Marks"), try_view!(marks, book_id));
+    let chapter_id = chapter.id;
+    chapter_view.add_button(tr("Select"), try_view!(select_text, chapter_id));
+    let book_id = chapter.book_id;
+    chapter_view.add_button(tr("Notes"), try_view!(notes, book_id));
+    let book_id = chapter.book_id;
+    let chapter_id = chapter.id;
+    chapter_view.add_button(
+        tr("Filters"),
+        try_view!(book_content_filters, book_id, chapter_id),
+    );
+    let book_id = chapter.book_id;
+    let chapter_id = chapter.id;
+    chapter_view.add_button(
+        tr("Render"),
+        try_view!(render_overrides, book_id, chapter_id),
+    );
+    chapter_view.add_button(tr("Focus"), try_view!(focus_mode, button));
+    chapter_view.add_button(tr("Close"), close_reader);
+    drop(chapter_view);
+
+    show_reader_title(s)
+}
+
+fn toggle_reader_spoilers(s: &mut Cursive) -> Result<(), Error> {
+    let progress = reader_progress(s);
+    let data = data(s)?;
+    data.spoilers_revealed = !data.spoilers_revealed;
+
+    match data.current_chapter {
+        Some((_book_id, chapter_id)) => chapter(s, chapter_id, progress),
+        None => Ok(()),
+    }
+}
+
+/// Closes the reader, going back to the view it was opened from (TOC, bookmarks, search results, ...).
+fn close_reader(s: &mut Cursive) {
+    if let Err(e) = autosave_position(s, true) {
+        error_message(s, e);
+    }
+    if let Ok(data) = data(s) {
+        data.current_chapter = None;
+        data.search_terms = None;
+        data.auto_scroll = None;
+    }
+    s.pop_layer();
+}
+
+/// Save the reading position at least this often while reading.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Or after this many chapter changes, whichever comes first.
+const AUTOSAVE_PAGE_TURNS: usize = 5;
+
+/// Called on every refresh, keeps track of the reader position and saves it in the background
+/// when it has changed and enough time or page turns have passed (or right away when `force`d).
+pub fn autosave_position(s: &mut Cursive, force: bool) -> Result<(), Error> {
+    let progress = reader_progress(s);
+    let at_end = reader_at_end(s);
+    let data = data(s)?;
+
+    let (book_id, chapter_id) = match (data.current_chapter, progress) {
+        (Some(current), Some(_)) => current,
+        _ => return Ok(()),
+    };
+    let progress = progress.unwrap_or(0.0);
+    if progress.is_nan() {
+        return Ok(());
+    }
+
+    if data.last_position != Some((book_id, chapter_id, progress)) {
+        data.last_position = Some((book_id, chapter_id, progress));
+        crate::state::set_pending(Position {
+            book_id,
+            chapter_id,
+            progress,
+            updated: chrono::Utc::now(),
+        });
+    }
+
+    let new_chapter = at_end && data.new_chapter;
+    if new_chapter {
+        data.new_chapter = false;
+    }
+
+    let finished = if at_end && data.last_chapter && data.finished_book != Some(book_id) {
+        data.finished_book = Some(book_id);
+        let book = data.run(get_book(&data.pool, book_id))?;
+        crate::scripts::on_finish_book(&book)?;
+        Some(book)
+    } else {
+        None
+    };
+
+    let due = data.last_position_save.elapsed() >= AUTOSAVE_INTERVAL
+        || data.page_turns >= AUTOSAVE_PAGE_TURNS;
+    let pending = if force || due {
+        crate::state::take_pending()
+    } else {
+        None
+    };
+    if let Some(position) = pending {
+        data.last_position_save = Instant::now();
+        data.page_turns = 0;
+
+        if force {
+            data.run(set_position(&data.pool, &position))?;
+        } else {
+            let pool = data.pool.clone();
+            data.runtime.spawn(async move {
+                if set_position(&pool, &position).await.is_err() {
+                    // try again on the next save
+                    crate::state::set_pending(position);
+                }
+            });
+        }
+    }
+
+    if new_chapter && !force {
+        notify(
+            s,
+            tr("A new chapter is available, Next opens it").to_string(),
+        )?;
+    }
+
+    // a forced save is the reader closing or ereader quitting, there's nothing to show the dialog over
+    match finished {
+        Some(book) if !force => end_of_book(s, &book, chapter_id),
+        _ => Ok(()),
+    }
+}
+
+/// Shown when the end of a book's last chapter is reached, with what to read or do next.
+fn end_of_book(s: &mut Cursive, book: &Book, chapter_id: Hyphenated) -> Result<(), Error> {
+    let book_id = book.id;
+    let data = data(s)?;
+    let sequels = match book.story_id {
+        Some(story_id) => related_stories(data, story_id)?
+            .into_iter()
+            .filter(|related| related.relation == "sequel")
+            .collect(),
+        None => Vec::new(),
+    };
+    let up_next = data
+        .run(get_read_later(&data.pool))?
+        .into_iter()
+        .find(|item| item.book_id != Some(book_id));
+
+    let mut layout = LinearLayout::vertical();
+    layout.add_child(TextView::new(tr_args("You finished {}.", &[&book.title])));
+    layout.add_child(DummyView);
+    layout.add_child(Button::new(
+        tr("Mark finished"),
+        try_view!(mark_finished, book_id, chapter_id),
+    ));
+    layout.add_child(Button::new(tr("Rate"), try_view!(rate, book_id)));
+    layout.add_child(Button::new(tr("Review"), try_view!(notes, book_id)));
+    for sequel in sequels {
+        let story_id = sequel.related_id;
+        layout.add_child(Button::new(
+            format!("{}: {}", tr("Sequel"), sequel.title),
+            try_view!(open_sequel, story_id),
+        ));
+    }
+    if let Some(item) = up_next {
+        let label = format!("{}: {}", tr("Up next"), item.title);
+        // open_read_later closes this dialog
+        layout.add_child(Button::new(label, move |s| {
+            if let Err(e) = open_read_later(s, &item) {
+                error_message(s, e);
+            }
+        }));
+    }
+    layout.add_child(Button::new(tr("Library"), |s| {
+        s.pop_layer();
+        close_reader(s);
+    }));
+
+    s.add_layer(
+        Dialog::around(layout)
+            .title(tr("End of Book"))
+            .dismiss_button(tr("Close"))
+            .max_width(70),
+    );
+
+    Ok(())
+}
+
+/// Saves the position at the very end of the book and takes it off the read later queue.
+fn mark_finished(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+) -> Result<(), Error> {
+    // the reader's own position would overwrite this on the next save
+    crate::state::take_pending();
+
+    let data = data(s)?;
+    data.last_position = Some((book_id, chapter_id, 1.0));
+    data.run(set_position(
+        &data.pool,
+        &Position {
+            book_id,
+            chapter_id,
+            progress: 1.0,
+            updated: chrono::Utc::now(),
+        },
+    ))?;
+    for item in data.run(get_read_later(&data.pool))? {
+        if item.book_id == Some(book_id) {
+            data.run(delete_read_later(&data.pool, item.id))?;
+        }
+    }
+    let title = data.run(get_book(&data.pool, book_id))?.title;
+
+    notify(s, tr_args("Marked {} as finished", &[&title]))
+}
+
+/// Opens a sequel from the end of book dialog, importing it from the fimfarchive if it isn't in the library.
+fn open_sequel(s: &mut Cursive, story_id: i64) -> Result<(), Error> {
+    let data = data(s)?;
+    if let Some(book_id) = data.run(get_story_book(&data.pool, story_id))? {
+        s.pop_layer();
+        return open_book(s, book_id);
+    }
+
+    match crate::fimfarchive::story(story_id, &data.schema, &data.reader) {
+        Some(story) => {
+            s.pop_layer();
+            import_fimfarchive(s, &story)
+        }
+        None => notify(
+            s,
+            tr("The sequel isn't in the library or the fimfarchive").to_string(),
+        ),
+    }
+}
+
+/// Selecting a link to a footnote shows the note in a popup instead of leaving the chapter.
+fn open_notes_on_select(view: &mut MarkupView<ColorRenderer>, html: &str, chapter: &Chapter) {
+    let noterefs = noterefs(html);
+    let book_id = chapter.book_id;
+    let chapter_id = chapter.id;
+
+    view.on_link_select(move |s, url| {
+        if noterefs.contains(url) {
+            if let Err(e) = show_note(s, book_id, chapter_id, url) {
+                error_message(s, e);
+            }
+        }
+    });
+}
+
+/// Finds the note a link points to, looking in the chapter with the link first
+/// since most books keep footnotes with their chapter, then in the rest of the book for endnotes.
+fn show_note(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+    href: &str,
+) -> Result<(), Error> {
+    let id = href
+        .rsplit_once('#')
+        .map(|(_path, fragment)| percent_decode_str(fragment).decode_utf8_lossy().to_string())
+        .ok_or_else(|| Error::NoteNotFound(href.to_string()))?;
+
+    let data = data(s)?;
+    let current = data.run(get_chapter_by_id(&data.pool, chapter_id))?;
+    let text = match note_text(&decode_chapter(&current)?, &id) {
+        Some(text) => Some(text),
+        // a corrupt chapter elsewhere in the book shouldn't stop the note from being found
+        None => data
+            .run(get_book_chapters(&data.pool, book_id))?
+            .iter()
+            .filter(|chapter| chapter.id != chapter_id)
+            .find_map(|chapter| note_text(&decode_chapter(chapter).ok()?, &id)),
+    };
+    let text = text.ok_or_else(|| Error::NoteNotFound(href.to_string()))?;
+
+    s.add_layer(
+        Dialog::around(TextView::new(text).scrollable())
+            .title(tr("Note"))
+            .dismiss_button(tr("Close"))
+            .max_width(70),
+    );
+
+    Ok(())
+}
+
+fn decode_chapter(chapter: &Chapter) -> Result<String, Error> {
+    let content = decompress_chapter(&chapter.content)
+        .map_err(|_| Error::CorruptChapter(chapter.book_id, chapter.index))?;
+    // chapters imported before their encoding was fixed up could still have invalid UTF-8
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// The fraction of the current chapter above the top of the reader viewport.
+fn reader_progress(s: &mut Cursive) -> Option<f32> {
+    // the focus mode view is on top of the reader when it's open
+    let reader_content = s
+        .find_name::<ScrollView<MarkupView<ColorRenderer>>>("focus content")
+        .or_else(|| s.find_name::<ScrollView<MarkupView<ColorRenderer>>>("reader content"))?;
+
+    let viewport = reader_content.content_viewport();
+    let size = reader_content.inner_size();
+    Some(viewport.top() as f32 / size.y as f32)
+}
+
+/// Whether the bottom of the current chapter is showing in the reader.
+fn reader_at_end(s: &mut Cursive) -> bool {
+    s.find_name::<ScrollView<MarkupView<ColorRenderer>>>("focus content")
+        .or_else(|| s.find_name::<ScrollView<MarkupView<ColorRenderer>>>("reader content"))
+        .map(|reader_content| reader_content.is_at_bottom())
+        .unwrap_or(false)
+}
+
+fn chapter_goto_index(s: &mut Cursive, id: Hyphenated, index: i64) -> Result<(), Error> {
+    let chapter_id = {
+        let data = data(s)?;
+        let chapter = data.run(get_chapter(&data.pool, id, index))?;
+        chapter.id
+    };
+
+    chapter(s, chapter_id, None)
+}
+
+fn chapter_goto_toc(s: &mut Cursive, toc: &Toc) -> Result<(), Error> {
+    chapter_at_anchor(s, toc.chapter_id, None, toc.fragment.clone())
+}
+
+fn chapter_goto_bookmark(s: &mut Cursive, bookmark: &Bookmark) -> Result<(), Error> {
+    chapter(s, bookmark.chapter_id, Some(bookmark.progress))
+}
+
+enum GotoTarget {
+    Percent(f32),
+    Chapter(i64),
+    /// a page label of the print edition
+    Page(String),
+}
+
+/// Parses "42%" into a percentage of the book, "ch 7"/"chapter 7" into a chapter number,
+/// and "p 12"/"page 12" into a page of the print edition.
+fn parse_goto(input: &str) -> Option<GotoTarget> {
+    let input = input.trim().to_lowercase();
+
+    if let Some(page) = input
+        .strip_prefix("page")
+        .or_else(|| input.strip_prefix('p'))
+    {
+        let page = page.trim();
+        return if page.is_empty() {
+            None
+        } else {
+            Some(GotoTarget::Page(page.to_string()))
+        };
+    }
+
+    if let Some(percent) = input.strip_suffix('%') {
+        let percent = percent.trim().parse::<f32>().ok()?;
+        return if (0.0..=100.0).contains(&percent) {
+            Some(GotoTarget::Percent(percent))
+        } else {
+            None
+        };
+    }
+
+    let number = input
+        .strip_prefix("chapter")
+        .or_else(|| input.strip_prefix("ch"))?
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+
+    if number >= 1 {
+        Some(GotoTarget::Chapter(number))
+    } else {
+        None
+    }
+}
+
+fn goto_prompt(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let mut input = EditView::new();
+    input.set_on_submit(move |s, text| {
+        s.pop_layer();
+        if let Err(e) = goto(s, book_id, text) {
+            error_message(s, e);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(input)
+            .title(tr("Go To (42%, ch 7, or p 12)"))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn goto(s: &mut Cursive, book_id: Hyphenated, input: &str) -> Result<(), Error> {
+    let target = parse_goto(input).ok_or_else(|| Error::InvalidGotoTarget(input.to_string()))?;
+
+    let (chapter_id, progress, anchor) = {
+        let data = data(s)?;
+        match target {
+            GotoTarget::Percent(percent) => {
+                let words = data.run(get_chapter_words(&data.pool, book_id))?;
+                let total: i64 = words.iter().sum();
+                let target = (total as f32 * percent / 100.0).round() as i64;
+
+                // find the chapter that contains the target word and how far into it the word is
+                let mut words_before = 0;
+                let mut index = words.len();
+                let mut progress = 1.0;
+                for (i, chapter_words) in words.iter().enumerate() {
+                    if words_before + chapter_words >= target {
+                        index = i + 1;
+                        progress = if *chapter_words == 0 {
+                            0.0
+                        } else {
+                            (target - words_before) as f32 / *chapter_words as f32
+                        };
+                        break;
+                    }
+                    words_before += chapter_words;
+                }
+
+                let chapter = data.run(get_chapter(&data.pool, book_id, index as i64))?;
+                (chapter.id, Some(progress), None)
+            }
+            GotoTarget::Chapter(number) => {
+                // chapter numbers refer to the table of contents when the book has one
+                // since spine items also include things like the cover and copyright page
+                let toc = data.run(get_toc(&data.pool, book_id))?;
+                if toc.is_empty() {
+                    let chapter = data.run(get_chapter(&data.pool, book_id, number))?;
+                    (chapter.id, None, None)
+                } else {
+                    let toc = toc
+                        .get(number as usize - 1)
+                        .ok_or_else(|| Error::InvalidGotoTarget(input.to_string()))?;
+                    (toc.chapter_id, None, None)
+                }
+            }
+            GotoTarget::Page(label) => {
+                // only epubs with a page list in their nav document have page numbers
+                let page = data
+                    .run(get_page_target(&data.pool, book_id, &label))?
+                    .ok_or_else(|| Error::InvalidGotoTarget(input.to_string()))?;
+                (page.chapter_id, None, page.fragment)
+            }
+        }
+    };
+
+    chapter_at_anchor(s, chapter_id, progress, anchor)
+}
+
+/// Wraps the reader content to keep the reading position stable and the progress bar up to date.
+///
+/// The position is stored as the fraction of the content above the top of the viewport.
+/// Whenever the size changes (including the first layout) the text is reflowed and the
+/// offset is restored from that fraction so the same paragraph stays on screen.
+/// Progress is weighted by the word counts of the chapters so it reflects the whole book.
+/// When the chapter is opened at an anchor, the first layout scrolls to it instead.
+/// How far the reader moves for each key, from the settings.
+#[derive(Clone, Debug)]
+struct Scrolling {
+    step: usize,
+    page_overlap: usize,
+    half_page_up: Event,
+    half_page_down: Event,
+}
+
+/// The fewest lines the arrow keys move in low bandwidth mode, so fewer redraws are needed to get through a page.
+const LOW_BANDWIDTH_SCROLL_STEP: usize = 5;
+
+impl Scrolling {
+    fn new(settings: &Settings) -> Self {
+        Scrolling {
+            step: if settings.low_bandwidth {
+                settings.scroll_step.max(LOW_BANDWIDTH_SCROLL_STEP)
+            } else {
+                settings.scroll_step
+            },
+            page_overlap: settings.page_overlap,
+            half_page_up: parse_key(&settings.keymap.half_page_up).unwrap_or(Event::CtrlChar('u')),
+            half_page_down: parse_key(&settings.keymap.half_page_down)
+                .unwrap_or(Event::CtrlChar('d')),
+        }
+    }
+
+    /// The lines to scroll for an event (negative is up), None if it isn't a scrolling key.
+    fn lines(&self, event: &Event, height: usize) -> Option<isize> {
+        let page = height.saturating_sub(self.page_overlap).max(1) as isize;
+        let half_page = (height / 2).max(1) as isize;
+
+        if *event == self.half_page_up {
+            return Some(-half_page);
+        }
+        if *event == self.half_page_down {
+            return Some(half_page);
+        }
+        match event {
+            Event::Key(Key::Up) => Some(-(self.step as isize)),
+            Event::Key(Key::Down) => Some(self.step as isize),
+            Event::Key(Key::PageUp) => Some(-page),
+            Event::Key(Key::PageDown) => Some(page),
+            _ => None,
+        }
+    }
+}
+
+struct ReaderContent {
+    view: NamedView<ScrollView<MarkupView<ColorRenderer>>>,
+    size: XY<usize>,
+    position: Option<f32>,
+    anchor: Option<String>,
+    anchors: Anchors,
+    /// positions in the chapter (0 to 1) and the symbols drawn for them next to the scrollbar
+    markers: Vec<(f32, &'static str)>,
+    scrolling: Scrolling,
+    counter: Counter,
+    words_before: usize,
+    words: usize,
+}
+
+impl ViewWrapper for ReaderContent {
+    wrap_impl!(self.view: NamedView<ScrollView<MarkupView<ColorRenderer>>>);
+
+    fn wrap_on_event(&mut self, event: Event) -> EventResult {
+        let mut scrollable = self.view.get_mut();
+        let viewport = scrollable.content_viewport();
+        let inner_height = scrollable.inner_size().y;
+
+        let lines = match self.scrolling.lines(&event, viewport.height()) {
+            Some(lines) => lines,
+            None => {
+                drop(scrollable);
+                return self.view.on_event(event);
+            }
+        };
+
+        // ignored at the ends so the focus can move on to the buttons like it does by default
+        let at_top = viewport.top() == 0;
+        let at_bottom = viewport.bottom() + 1 >= inner_height;
+        if (lines < 0 && at_top) || (lines > 0 && at_bottom) {
+            return EventResult::Ignored;
+        }
+
+        let top = (viewport.top() as isize + lines).max(0) as usize;
+        scrollable.set_offset(XY::new(0, top));
+        EventResult::Consumed(None)
+    }
+
+    fn wrap_draw(&self, printer: &Printer) {
+        self.view.draw(printer);
+
+        if printer.size.x == 0 || printer.size.y == 0 {
+            return;
+        }
+
+        // drawn over the scrollbar's column at the height the scrollbar would be at to show that position
+        let x = printer.size.x - 1;
+        for (position, symbol) in &self.markers {
+            let y = ((printer.size.y - 1) as f32 * position.max(0.0).min(1.0)).round() as usize;
+            printer.with_color(ColorStyle::title_primary(), |printer| {
+                printer.print((x, y), symbol)
+            });
+        }
+    }
+
+    fn wrap_layout(&mut self, size: XY<usize>) {
+        let resized = self.size != size;
+        self.size = size;
+
+        self.view.layout(size);
+
+        let mut scrollable = self.view.get_mut();
+
+        let anchor_line = self
+            .anchor
+            .take()
+            .and_then(|anchor| self.anchors.borrow().get(&anchor).copied());
+
+        if let Some(line) = anchor_line {
+            scrollable.set_offset(XY::new(0, line));
+        } else if resized {
+            if let Some(position) = self.position {
+                let inner_size = scrollable.inner_size();
+                let offset_y = (inner_size.y as f32 * position).round() as usize;
+                scrollable.set_offset(XY::new(0, offset_y));
+            }
+        }
+
+        let viewport = scrollable.content_viewport();
+        let inner_size = scrollable.inner_size();
+
+        if inner_size.y > 0 {
+            self.position = Some(viewport.top() as f32 / inner_size.y as f32);
+        }
+
+        // count everything that has been scrolled past or is currently on screen as read
+        let read = std::cmp::min(viewport.bottom() + 1, inner_size.y);
+        let fraction = if inner_size.y == 0 {
+            1.0
+        } else {
+            read as f32 / inner_size.y as f32
+        };
+
+        self.counter
+            .set(self.words_before + (self.words as f32 * fraction).round() as usize);
+    }
+}
+
+/// Renders a chapter with the text effects from the settings and the book's render overrides.
+fn chapter_renderer(
+    settings: &Settings,
+    html: &str,
+    background: Color,
+    overrides: Option<RenderOverrides>,
+) -> ColorRenderer {
+    let mut renderer = ColorRenderer::new(html, background).with_effects(
+        text_effect(&settings.italic_effect),
+        text_effect(&settings.bold_effect),
+    );
+    if !settings.horizontal_rule.is_empty() {
+        renderer = renderer.with_rule(&settings.horizontal_rule);
+    }
+    if overrides.map(|overrides| overrides.disable_italics) == Some(true) {
+        renderer = renderer.without_italics();
+    }
+    renderer
+}
+
+/// The effect for one of `ITALIC_EFFECTS` or `BOLD_EFFECTS`, None for "none".
+fn text_effect(name: &str) -> Option<Effect> {
+    match name {
+        "italic" => Some(Effect::Italic),
+        "bold" => Some(Effect::Bold),
+        "underline" => Some(Effect::Underline),
+        _ => None,
+    }
+}
+
+/// The chapter's html with the content filters and the book's render overrides applied.
+fn chapter_html(
+    data: &Data,
+    chapter: &Chapter,
+) -> Result<(String, Option<RenderOverrides>), Error> {
+    let filters = data.run(get_content_filters(&data.pool))?;
+    let disabled_filters = data.run(get_disabled_content_filters(&data.pool, chapter.book_id))?;
+    let overrides = data.run(get_render_overrides(&data.pool, chapter.book_id))?;
+
+    let content = apply_content_filters(decode_chapter(chapter)?, &filters, &disabled_filters)?;
+    let content = match &overrides {
+        Some(overrides) => apply_render_overrides(content, overrides)?,
+        None => content,
+    };
+    let book = data.run(get_book(&data.pool, chapter.book_id))?;
+    let content = crate::scripts::on_chapter_render(content, &book)?;
+
+    Ok((content, overrides))
+}
+
+/// Hands-free scrolling of the reader, moved along by the refresh every second.
+#[derive(Clone, Debug)]
+struct AutoScroll {
+    lines: usize,
+    interval: u64,
+    paused: bool,
+    last_step: Instant,
+}
+
+fn toggle_auto_scroll(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    // every step redraws the whole reader
+    if data.settings.low_bandwidth {
+        return notify(
+            s,
+            tr("Auto-scroll is off in low bandwidth mode").to_string(),
+        );
+    }
+    data.auto_scroll = match data.auto_scroll {
+        Some(_) => None,
+        None => Some(AutoScroll {
+            lines: data.settings.auto_scroll_lines,
+            interval: data.settings.auto_scroll_interval,
+            paused: false,
+            last_step: Instant::now(),
+        }),
+    };
+
+    show_reader_title(s)
+}
+
+/// Speeds up by scrolling more often until it's every second, then by scrolling more lines at a time.
+fn change_auto_scroll_speed(s: &mut Cursive, faster: bool) -> Result<(), Error> {
+    if let Some(auto_scroll) = &mut data(s)?.auto_scroll {
+        if faster {
+            if auto_scroll.interval > 1 {
+                auto_scroll.interval -= 1;
+            } else {
+                auto_scroll.lines += 1;
+            }
+        } else if auto_scroll.lines > 1 {
+            auto_scroll.lines -= 1;
+        } else {
+            auto_scroll.interval += 1;
+        }
+    }
+
+    show_reader_title(s)
+}
+
+fn pause_auto_scroll(s: &mut Cursive) -> Result<(), Error> {
+    if let Some(auto_scroll) = &mut data(s)?.auto_scroll {
+        auto_scroll.paused = !auto_scroll.paused;
+        auto_scroll.last_step = Instant::now();
+    }
+
+    show_reader_title(s)
+}
+
+/// Shows the chapter's title and the auto-scrolling speed in the reader's title.
+fn show_reader_title(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let title = match &data.auto_scroll {
+        Some(auto_scroll) => tr_args(
+            if auto_scroll.paused {
+                "{} - Auto-scroll: {} lines every {}s (paused)"
+            } else {
+                "{} - Auto-scroll: {} lines every {}s"
+            },
+            &[
+                &data.chapter_title,
+                &auto_scroll.lines,
+                &auto_scroll.interval,
+            ],
+        ),
+        None => data.chapter_title.clone(),
+    };
+
+    if let Some(mut reader) = s.find_name::<Dialog>("reader") {
+        reader.set_title(title);
+    }
+    Ok(())
+}
+
+/// Scrolls the reader if auto-scrolling is due, continuing into the next chapter at the end of one.
+pub fn auto_scroll(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let lines = match &mut data.auto_scroll {
+        Some(auto_scroll)
+            if !auto_scroll.paused
+                && auto_scroll.last_step.elapsed() >= Duration::from_secs(auto_scroll.interval) =>
+        {
+            auto_scroll.last_step = Instant::now();
+            auto_scroll.lines
+        }
+        _ => return Ok(()),
+    };
+    let (book_id, chapter_id) = match data.current_chapter {
+        Some(current) => current,
+        None => return Ok(()),
+    };
+
+    // focus mode only shows one chapter so it stops at the end instead
+    let focus = s
+        .find_name::<ScrollView<MarkupView<ColorRenderer>>>("focus content")
+        .is_some();
+    let name = if focus {
+        "focus content"
+    } else {
+        "reader content"
+    };
+
+    let at_bottom = {
+        let mut reader_content = s
+            .find_name::<ScrollView<MarkupView<ColorRenderer>>>(name)
+            .ok_or(Error::ViewNotFound)?;
+        let viewport = reader_content.content_viewport();
+        if viewport.bottom() + 1 >= reader_content.inner_size().y {
+            true
+        } else {
+            reader_content.set_offset(XY::new(0, viewport.top() + lines));
+            false
+        }
+    };
+    if !at_bottom {
+        return Ok(());
+    }
+
+    let data = data(s)?;
+    let chapter = data.run(get_chapter_by_id(&data.pool, chapter_id))?;
+    let next = data.run(get_adjacent_linear_chapter(
+        &data.pool,
+        book_id,
+        chapter.index,
+        true,
+    ))?;
+    match next {
+        Some(next) if !focus => self::chapter(s, next.id, None),
+        _ => {
+            data.auto_scroll = None;
+            show_reader_title(s)
+        }
+    }
+}
+
+/// Space on each side of the text in focus mode.
+const FOCUS_MARGIN: usize = 6;
+
+/// Shows the open chapter on its own in the middle of the screen, without the dialog, buttons, or progress bar.
+/// The focus mode or close key goes back to the reader at the same position.
+fn focus_mode(s: &mut Cursive) -> Result<(), Error> {
+    let chapter_id = match data(s)?.current_chapter {
+        Some((_book_id, chapter_id)) => chapter_id,
+        None => return Ok(()),
+    };
+    let progress = reader_progress(s);
+    let background = s.current_theme().palette[PaletteColor::View];
+
+    let data = data(s)?;
+    let width = data.settings.reader_width;
+    let focus_key = parse_key(&data.settings.keymap.focus_mode).unwrap_or(Event::Char('f'));
+    let close_key = parse_key(&data.settings.keymap.close_reader).unwrap_or(Event::Key(Key::Esc));
+    let scrolling = Scrolling::new(&data.settings);
+    let chapter = data.run(get_chapter_by_id(&data.pool, chapter_id))?;
+
+    let (content_str, overrides) = chapter_html(data, &chapter)?;
+    let content_str = if data.spoilers_revealed {
+        content_str
+    } else {
+        hide_spoilers(&content_str, &data.settings.keymap.reveal_spoilers)
+    };
+
+    let renderer = chapter_renderer(&data.settings, &content_str, background, overrides);
+    let anchors = renderer.anchors();
+    let mut view = MarkupView::with_renderer(renderer);
+    view.on_link_focus(|_s, _url| {});
+    open_notes_on_select(&mut view, &content_str, &chapter);
+
+    let content = ReaderContent {
+        view: view.scrollable().with_name("focus content"),
+        size: XY::zero(),
+        position: progress,
+        anchor: None,
+        anchors,
+        markers: Vec::new(),
+        scrolling,
+        counter: Counter::new(0),
+        words_before: 0,
+        words: 0,
+    };
+
+    let page = LinearLayout::horizontal()
+        .child(DummyView.full_width())
+        .child(
+            PaddedView::lrtb(FOCUS_MARGIN, FOCUS_MARGIN, 1, 1, content)
+                .max_width(width + 2 * FOCUS_MARGIN),
+        )
+        .child(DummyView.full_width());
+
+    s.add_fullscreen_layer(
+        OnEventView::new(Layer::new(page))
+            .on_event(focus_key, try_view!(exit_focus_mode, button))
+            .on_event(close_key, try_view!(exit_focus_mode, button)),
+    );
+
+    Ok(())
+}
+
+fn exit_focus_mode(s: &mut Cursive) -> Result<(), Error> {
+    let progress = reader_progress(s);
+    s.pop_layer();
+
+    match data(s)?.current_chapter {
+        Some((_book_id, chapter_id)) => chapter(s, chapter_id, progress),
+        None => Ok(()),
+    }
+}
+
+const SEARCH_HIT_SYMBOL: &str = "•";
+
+/// Where the words of the last text search are in a chapter (0 to 1), if it's from the book the search opened.
+fn search_hits(s: &mut Cursive, book_id: Hyphenated, content: &str) -> Result<Vec<f32>, Error> {
+    let terms = match &data(s)?.search_terms {
+        Some((search_book_id, terms)) if *search_book_id == book_id => terms.clone(),
+        _ => return Ok(Vec::new()),
+    };
+
+    let text = crate::scan::text(content).to_lowercase();
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(terms
+        .iter()
+        .flat_map(|term| text.match_indices(term.as_str()))
+        .map(|(i, _term)| i as f32 / text.len() as f32)
+        .collect())
+}
+
+// ============================== SELECTION ==============================
+/// The text of each paragraph and heading in a chapter.
+fn paragraphs(content: &str) -> Vec<String> {
+    let document = Html::parse_document(content);
+    let paragraph = Selector::parse("p, h1, h2, h3, h4, h5, h6").unwrap();
+
+    document
+        .select(&paragraph)
+        .map(|p| p.text().collect::<String>().trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect()
+}
+
+/// Shows the paragraphs of a chapter so a passage can be selected and copied.
+/// Space marks the start of the selection and the selected paragraph is the end.
+fn select_text(s: &mut Cursive, chapter_id: Hyphenated) -> Result<(), Error> {
+    let progress = reader_progress(s).unwrap_or(0.0);
+
+    let data = data(s)?;
+    let chapter = data.run(get_chapter_by_id(&data.pool, chapter_id))?;
+    let book = data.run(get_book(&data.pool, chapter.book_id))?;
+    let toc = data.run(get_toc(&data.pool, chapter.book_id))?;
+    let paragraphs = Rc::new(paragraphs(&decode_chapter(&chapter)?));
+
+    let source = Rc::new(Source {
+        title: book.title,
+        author: book.creator,
+        publisher: book.publisher,
+        chapter: chapter
+            .title
+            .clone()
+            .or_else(|| {
+                toc.iter()
+                    .find(|toc| toc.chapter_id == chapter.id)
+                    .map(|toc| toc.title.clone())
+            })
+            .unwrap_or_else(|| tr_args("Chapter {}", &[&chapter.index])),
+    });
+
+    let mut paragraphs_view = SelectView::new();
+    for (i, paragraph) in paragraphs.iter().enumerate() {
+        paragraphs_view.add_item(paragraph.clone(), i);
+    }
+    // start at the paragraph that was being read
+    if !paragraphs.is_empty() {
+        let current = (progress * paragraphs.len() as f32) as usize;
+        paragraphs_view.set_selection(std::cmp::min(current, paragraphs.len() - 1));
+    }
+
+    let anchor = Rc::new(Cell::new(None));
+
+    let mark_anchor = anchor.clone();
+    let view =
+        OnEventView::new(paragraphs_view.with_name("selection")).on_pre_event(' ', move |s| {
+            let current = s
+                .find_name::<SelectView<usize>>("selection")
+                .and_then(|view| view.selection());
+            if let Some(current) = current {
+                mark_anchor.set(Some(*current));
+                if let Some(mut dialog) = s.find_name::<Dialog>("selection dialog") {
+                    dialog.set_title(tr_args(
+                        "Select Text (from paragraph {})",
+                        &[&(*current + 1)],
+                    ));
+                }
+            }
+        });
+
+    let copy_anchor = anchor.clone();
+    let copy_paragraphs = paragraphs.clone();
+    s.add_layer(
+        Dialog::around(view.scrollable())
+            .title(tr("Select Text (space to mark the start)"))
+            .button(tr("Copy"), move |s| {
+                if let Some(text) = selected_text(s, &copy_anchor, &copy_paragraphs) {
+                    s.pop_layer();
+                    if let Err(e) = clipboard::copy(&text) {
+                        error_message(s, e);
+                    }
+                }
+            })
+            .button(
+                tr("Annotate"),
+                annotate(
+                    anchor.clone(),
+                    paragraphs.clone(),
+                    chapter.book_id,
+                    chapter.id,
+                ),
+            )
+            .button(
+                tr("Cite (Markdown)"),
+                copy_citation(
+                    anchor.clone(),
+                    paragraphs.clone(),
+                    source.clone(),
+                    citation::markdown,
+                ),
+            )
+            .button(
+                tr("Cite (BibTeX)"),
+                copy_citation(anchor, paragraphs, source, citation::bibtex),
+            )
+            .dismiss_button(tr("Cancel"))
+            .with_name("selection dialog")
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+/// Makes a button callback that copies the selected text formatted as a citation.
+fn copy_citation(
+    anchor: Rc<Cell<Option<usize>>>,
+    paragraphs: Rc<Vec<String>>,
+    source: Rc<Source>,
+    format: fn(&str, &Source) -> String,
+) -> impl Fn(&mut Cursive) {
+    move |s| {
+        if let Some(text) = selected_text(s, &anchor, &paragraphs) {
+            s.pop_layer();
+            if let Err(e) = clipboard::copy(&format(&text, &source)) {
+                error_message(s, e);
+            }
+        }
+    }
+}
+
+/// Makes a button callback that asks for a note and saves it with the selected text.
+fn annotate(
+    anchor: Rc<Cell<Option<usize>>>,
+    paragraphs: Rc<Vec<String>>,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+) -> impl Fn(&mut Cursive) {
+    move |s| {
+        let (start, _) = match selected_range(s, &anchor) {
+            Some(range) => range,
+            None => return,
+        };
+        let passage = match selected_text(s, &anchor, &paragraphs) {
+            Some(passage) => passage,
+            None => return,
+        };
+        // the position of the first paragraph is close enough to the scroll position
+        let progress = start as f32 / paragraphs.len() as f32;
+
+        s.pop_layer();
+        s.add_layer(
+            Dialog::around(TextArea::new().with_name("annotation note").min_height(5))
+                .title(tr("Annotation Note"))
+                .button(tr("Save"), move |s| {
+                    let result = save_annotation(s, book_id, chapter_id, progress, &passage);
+                    if let Err(e) = result {
+                        error_message(s, e);
+                    }
+                })
+                .dismiss_button(tr("Cancel"))
+                .max_width(90),
+        );
+    }
+}
+
+fn save_annotation(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+    progress: f32,
+    passage: &str,
+) -> Result<(), Error> {
+    let note = s
+        .find_name::<TextArea>("annotation note")
+        .ok_or(Error::ViewNotFound)?
+        .get_content()
+        .to_string();
+
+    let data = data(s)?;
+    data.run(insert_annotation(
+        &data.pool,
+        &Annotation {
+            id: 0,
+            book_id,
+            chapter_id,
+            progress,
+            passage: passage.to_string(),
+            note,
+            created: chrono::Utc::now(),
+        },
+    ))?;
+
+    s.pop_layer();
+    Ok(())
+}
+
+/// The indexes of the first and last selected paragraphs.
+fn selected_range(s: &mut Cursive, anchor: &Cell<Option<usize>>) -> Option<(usize, usize)> {
+    let current = *s.find_name::<SelectView<usize>>("selection")?.selection()?;
+    let start = anchor.get().unwrap_or(current);
+
+    if start <= current {
+        Some((start, current))
+    } else {
+        Some((current, start))
+    }
+}
+
+/// The paragraphs between the marked start and the selected paragraph.
+fn selected_text(
+    s: &mut Cursive,
+    anchor: &Cell<Option<usize>>,
+    paragraphs: &[String],
+) -> Option<String> {
+    let (start, end) = selected_range(s, anchor)?;
+    Some(paragraphs[start..=end].join("\n\n"))
+}
+
+// ============================== SKIM ==============================
+fn skim(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let data = data(s)?;
+    let num_chapters = data.run(get_num_chapters(&data.pool, book_id))? as i64;
+
+    let mut chapter_list = SelectView::new();
+    for index in 1..=num_chapters {
+        chapter_list.add_item(tr_args("Chapter {}", &[&index]), index);
+    }
+
+    // chapters are only decoded when selected so large books open quickly
+    chapter_list.set_on_select(move |s, index: &i64| {
+        if let Err(e) = set_skim_preview(s, book_id, *index) {
+            error_message(s, e);
+        }
+    });
+    chapter_list.set_on_submit(move |s, index: &i64| {
+        s.pop_layer();
+        if let Err(e) = chapter_goto_index(s, book_id, *index) {
+            error_message(s, e);
+        }
+    });
+
+    let mut skim = LinearLayout::vertical();
+    skim.add_child(chapter_list.scrollable());
+    skim.add_child(Panel::new(ListView::new()));
+
+    s.add_layer(
+        Dialog::around(skim.with_name("skim"))
+            .title(tr("Skim"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    if num_chapters > 0 {
+        set_skim_preview(s, book_id, 1)?;
+    }
+
+    Ok(())
+}
+
+fn set_skim_preview(s: &mut Cursive, book_id: Hyphenated, index: i64) -> Result<(), Error> {
+    let data = data(s)?;
+    let chapter = data.run(get_chapter(&data.pool, book_id, index))?;
+    let paragraph = first_paragraph(&decode_chapter(&chapter)?)
+        .unwrap_or_else(|| tr("No text in this chapter.").to_string());
+
+    let mut skim = s
+        .find_name::<LinearLayout>("skim")
+        .ok_or(Error::ViewNotFound)?;
+
+    skim.remove_child(1);
+    skim.add_child(Panel::new(TextView::new(paragraph).scrollable()).title(tr("Preview")));
+
+    Ok(())
+}
+
+fn first_paragraph(content: &str) -> Option<String> {
+    let document = Html::parse_document(content);
+    let paragraph = Selector::parse("p").unwrap();
+
+    document
+        .select(&paragraph)
+        .map(|p| p.text().collect::<String>().trim().to_string())
+        .find(|text| !text.is_empty())
+}
+
+// ============================== NOTES ==============================
+fn notes(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let data = data(s)?;
+    let book = data.run(get_book(&data.pool, book_id))?;
+    let note = data.run(get_note(&data.pool, book_id))?.unwrap_or_default();
+
+    s.add_layer(
+        Dialog::around(
+            TextArea::new()
+                .content(note)
+                .with_name("notes")
+                .min_height(10),
+        )
+        .title(tr_args("Notes: {}", &[&book.title]))
+        .button(tr("Save"), try_view!(save_notes, book_id))
+        .dismiss_button(tr("Cancel"))
+        .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_notes(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let content = s
+        .find_name::<TextArea>("notes")
+        .ok_or(Error::ViewNotFound)?
+        .get_content()
+        .to_string();
+
+    let data = data(s)?;
+    data.run(set_note(&data.pool, book_id, &content))?;
+
+    s.pop_layer();
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+enum NoteSearchResult {
+    Annotation(Annotation),
+    Note(Note),
+}
+
+fn excerpt(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.chars().count() > 60 {
+        format!("{}...", text.chars().take(60).collect::<String>())
+    } else {
+        text
+    }
+}
+
+fn search_notes_prompt(s: &mut Cursive) {
+    let mut search_view = EditView::new();
+
+    search_view.set_on_submit(try_view!(search_all_notes));
+
+    s.add_layer(
+        Dialog::around(search_view)
+            .title(tr("Search Notes and Annotations"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+}
+
+fn search_all_notes(s: &mut Cursive, search: &str) -> Result<(), Error> {
+    let data = data(s)?;
+    let annotations = data.run(search_annotations(&data.pool, search))?;
+    let notes = data.run(search_notes(&data.pool, search))?;
+
+    let mut results = SelectView::new();
+    for annotation in annotations {
+        let book = data.run(get_book(&data.pool, annotation.book_id))?;
+        let label = if annotation.note.is_empty() {
+            tr_args(
+                "[Annotation] {}: {}",
+                &[&book.title, &excerpt(&annotation.passage)],
+            )
+        } else {
+            tr_args(
+                "[Annotation] {}: {}",
+                &[&book.title, &excerpt(&annotation.note)],
+            )
+        };
+        results.add_item(label, NoteSearchResult::Annotation(annotation));
+    }
+    for note in notes {
+        let book = data.run(get_book(&data.pool, note.book_id))?;
+        let label = tr_args("[Notes] {}: {}", &[&book.title, &excerpt(&note.content)]);
+        results.add_item(label, NoteSearchResult::Note(note));
+    }
+
+    if results.is_empty() {
+        results.add_item(
+            tr("No matches."),
+            NoteSearchResult::Note(Note {
+                book_id: Hyphenated::from(uuid::Uuid::nil()),
+                content: String::new(),
+                updated: chrono::Utc::now(),
+            }),
+        );
+        results.set_enabled(false);
+    }
+
+    results.set_on_submit(try_view!(goto_note_search_result));
+
+    s.add_layer(
+        Dialog::around(results.scrollable())
+            .title(tr_args("Results for {}", &[&format!("\"{}\"", search)]))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn goto_note_search_result(s: &mut Cursive, result: &NoteSearchResult) -> Result<(), Error> {
+    match result {
+        NoteSearchResult::Annotation(annotation) => {
+            chapter(s, annotation.chapter_id, Some(annotation.progress))
+        }
+        NoteSearchResult::Note(note) => notes(s, note.book_id),
+    }
+}
+
+// ============================== MAINTENANCE ==============================
+type MaintenanceTask = fn(&mut Cursive) -> Result<(), Error>;
+
+fn maintenance(s: &mut Cursive) {
+    let mut tasks = SelectView::<MaintenanceTask>::new();
+    tasks.add_item(
+        tr("Recount Words"),
+        recount_library_words as MaintenanceTask,
+    );
+    tasks.add_item(tr("Scan Dry Run"), dry_run_scan as MaintenanceTask);
+    tasks.add_item(
+        tr("Toggle Saving Query History"),
+        toggle_save_query_history as MaintenanceTask,
+    );
+    tasks.add_item(
+        tr("Clear Query History"),
+        clear_query_history as MaintenanceTask,
+    );
+    tasks.add_item(tr("Settings"), settings as MaintenanceTask);
+    tasks.add_item(
+        tr("Export Settings"),
+        export_settings_prompt as MaintenanceTask,
+    );
+    tasks.add_item(
+        tr("Import Settings"),
+        import_settings_prompt as MaintenanceTask,
+    );
+    tasks.add_item(tr("Bookmark Pruning"), bookmark_pruning as MaintenanceTask);
+    tasks.add_item(
+        tr("Prune Bookmarks"),
+        prune_library_bookmarks as MaintenanceTask,
+    );
+    tasks.add_item(tr("Storage"), storage as MaintenanceTask);
+    tasks.add_item(tr("Index Memory"), index_memory as MaintenanceTask);
+    tasks.add_item(
+        tr("Rebuild Text Index"),
+        rebuild_text_index as MaintenanceTask,
+    );
+    tasks.add_item(
+        tr("Reprocess Library"),
+        reprocess_library as MaintenanceTask,
+    );
+    tasks.add_item(
+        tr("Rebuild Authors"),
+        rebuild_library_authors as MaintenanceTask,
+    );
+    tasks.add_item(tr("Export Sync"), export_sync_prompt as MaintenanceTask);
+
+    tasks.set_on_submit(|s, task: &MaintenanceTask| {
+        s.pop_layer();
+        if let Err(e) = task(s) {
+            error_message(s, e);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(tasks)
+            .title(tr("Maintenance"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+}
+
+fn recount_library_words(s: &mut Cursive) -> Result<(), Error> {
+    enqueue(s, JobKind::RecountWords)
+}
+
+fn rebuild_text_index(s: &mut Cursive) -> Result<(), Error> {
+    enqueue(s, JobKind::RebuildTextIndex)
+}
+
+/// Shows how much space the chapters take compressed and uncompressed, to help pick a compression level.
+fn storage(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let stats = data.run(get_storage_stats(&data.pool))?;
+    let level = data.settings.compression_level;
+
+    let megabytes = |bytes: usize| format!("{:.1} MB", bytes as f64 / 1_000_000.0);
+    let mut lines = vec![
+        tr_args(
+            "Chapters: {} ({} stored uncompressed)",
+            &[
+                &format_number(stats.chapters as i64),
+                &format_number(stats.uncompressed_chapters as i64),
+            ],
+        ),
+        tr_args("Stored: {}", &[&megabytes(stats.stored)]),
+        tr_args("Uncompressed: {}", &[&megabytes(stats.html)]),
+    ];
+    if stats.stored > 0 {
+        let ratio = format!("{:.1}", stats.html as f64 / stats.stored as f64);
+        lines.push(tr_args("Compression ratio: {}x", &[&ratio]));
+    }
+    lines.push(String::new());
+    lines.push(tr_args(
+        "New books are compressed at level {}. Lower levels import faster and take more space, changing the level only affects books imported or reprocessed afterwards.",
+        &[&level],
+    ));
+
+    s.add_layer(
+        Dialog::around(TextView::new(lines.join("\n")))
+            .title(tr("Storage"))
+            .dismiss_button(tr("Close"))
+            .max_width(70),
+    );
+    Ok(())
+}
+
+fn reprocess_book(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    enqueue(
+        s,
+        JobKind::Reprocess {
+            book_ids: vec![book_id.to_string()],
+        },
+    )
+}
+
+fn reprocess_library(s: &mut Cursive) -> Result<(), Error> {
+    enqueue(s, JobKind::Reprocess { book_ids: vec![] })
+}
+
+fn rebuild_library_authors(s: &mut Cursive) -> Result<(), Error> {
+    enqueue(s, JobKind::RebuildAuthors)
+}
+
+fn export_sync_prompt(s: &mut Cursive) -> Result<(), Error> {
+    path_prompt(s, tr("Export Sync To"), "ereader-sync.json", |s, path| {
+        enqueue(
+            s,
+            JobKind::ExportSync {
+                path: path.to_string(),
+            },
+        )
+    });
+    Ok(())
+}
+
+// ============================== TASKS ==============================
+/// Starts running the queued jobs in the background, resuming any that were interrupted.
+///
+/// When a daemon is running it keeps running them and this only follows its updates,
+/// otherwise this process claims jobs from the same table as any other ereader that's open.
+pub fn start_jobs(s: &mut Cursive) -> Result<(), Error> {
+    let cb_sink = s.cb_sink().clone();
+    let on_update = move |job| {
+        let _ = cb_sink.send(Box::new(move |s| {
+            if let Err(e) = job_updated(s, job) {
+                error_message(s, e);
+            }
+        }));
+    };
+
+    let data = data(s)?;
+    match &data.daemon {
+        Some(daemon) => daemon.subscribe(on_update),
+        None => data.jobs.start(&data.runtime, on_update),
+    }
+}
+
+fn enqueue(s: &mut Cursive, kind: JobKind) -> Result<(), Error> {
+    let data = data(s)?;
+    let message = tr_args("Queued {}", &[&kind]);
+    match &data.daemon {
+        Some(daemon) => daemon.enqueue(kind)?,
+        None => data.run(data.jobs.enqueue(kind))?,
+    };
+
+    notify(s, message)?;
+    refresh_tasks(s)
+}
+
+/// Called when a job starts or finishes.
+fn job_updated(s: &mut Cursive, job: Job) -> Result<(), Error> {
+    if job.state.finished() {
+        notify(s, format!("{} {}", job.kind, job.state))?;
+    }
+
+    // the library only changes once the job is done
+    let changes_books = matches!(
+        job.kind,
+        JobKind::Scan { dry_run: false, .. }
+            | JobKind::RecountWords
+            | JobKind::RebuildAuthors
+            | JobKind::Reprocess { .. }
+    );
+    if job.state == JobState::Done
+        && changes_books
+        && s.find_name::<SelectView<Book>>("books").is_some()
+    {
+        let filter = data(s)?.library_filter.clone();
+        show_books(s, filter)?;
+    }
+    if job.state == JobState::Done && changes_books {
+        follow_new_chapters(s)?;
+    }
+
+    // a dry run is only useful for its report
+    if job.state == JobState::Done && matches!(job.kind, JobKind::Scan { dry_run: true, .. }) {
+        show_task(s, &job)?;
+    }
+
+    refresh_tasks(s)
+}
+
+/// Follows a story that's still being written while it's read: when a scan imports a newer version of it
+/// (or it's reprocessed) with chapters after the open one, the reader gets its Next button back
+/// and says so once the end of the chapter is reached.
+fn follow_new_chapters(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let (book_id, chapter_id) = match data.current_chapter {
+        Some(current) if data.last_chapter => current,
+        _ => return Ok(()),
+    };
+    // the chapter can be gone if the new version has fewer chapters
+    let chapter = match data.run(get_chapter_by_id(&data.pool, chapter_id)) {
+        Ok(chapter) => chapter,
+        Err(_) => return Ok(()),
+    };
+    let next = data.run(get_adjacent_linear_chapter(
+        &data.pool,
+        book_id,
+        chapter.index,
+        true,
+    ))?;
+    if next.is_none() {
+        return Ok(());
+    }
+
+    data.new_chapter = true;
+    // finishing what the book had doesn't finish the new chapters
+    data.finished_book = None;
+    rerender_chapter(s)
+}
+
+fn job_label(job: &Job) -> String {
+    format!(
+        "{} [{}] {}",
+        job.updated.with_timezone(&chrono::Local).format("%H:%M"),
+        job.state,
+        job.kind
+    )
+}
+
+/// The queued, running, and finished jobs, newest first.
+fn tasks(s: &mut Cursive) -> Result<(), Error> {
+    let mut list = SelectView::<Job>::new();
+    list.set_on_submit(try_view!(show_task));
+
+    s.add_layer(
+        Dialog::around(list.with_name("task list").scrollable())
+            .title(tr("Tasks"))
+            .button(tr("Cancel Task"), try_view!(cancel_selected_task, button))
+            .button(
+                tr("Clear Finished"),
+                try_view!(clear_finished_tasks, button),
+            )
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    refresh_tasks(s)
+}
+
+/// Reloads the tasks page if it's open.
+fn refresh_tasks(s: &mut Cursive) -> Result<(), Error> {
+    if s.find_name::<SelectView<Job>>("task list").is_none() {
+        return Ok(());
+    }
+
+    let data = data(s)?;
+    let jobs = data.run(get_jobs(&data.pool))?;
+
+    let mut list = s
+        .find_name::<SelectView<Job>>("task list")
+        .ok_or(Error::ViewNotFound)?;
+    let selected = list.selected_id();
+    list.clear();
+    for job in jobs {
+        list.add_item(job_label(&job), job);
+    }
+    if let Some(selected) = selected {
+        list.set_selection(selected);
+    }
+
+    Ok(())
+}
+
+fn show_task(s: &mut Cursive, job: &Job) -> Result<(), Error> {
+    let text = match &job.message {
+        Some(message) => format!("{}\n\n{}", job_label(job), message),
+        None => job_label(job),
+    };
+
+    s.add_layer(
+        Dialog::around(TextView::new(text).scrollable())
+            .title(job.kind.to_string())
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn cancel_selected_task(s: &mut Cursive) -> Result<(), Error> {
+    let job = s
+        .find_name::<SelectView<Job>>("task list")
+        .ok_or(Error::ViewNotFound)?
+        .selection();
+
+    if let Some(job) = job {
+        let data = data(s)?;
+        match &data.daemon {
+            Some(daemon) => daemon.cancel(job.id)?,
+            None => data.run(data.jobs.cancel(job.id))?,
+        }
+    }
+
+    refresh_tasks(s)
+}
+
+fn clear_finished_tasks(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    data.run(delete_finished_jobs(&data.pool))?;
+
+    refresh_tasks(s)
+}
+
+// ============================== NOTIFICATIONS ==============================
+const NOTIFICATION_LIMIT: usize = 100;
+
+#[derive(Clone, Debug)]
+struct Notification {
+    time: chrono::DateTime<chrono::Local>,
+    message: String,
+}
+
+impl std::fmt::Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.time.format("%H:%M"), self.message)
+    }
+}
+
+/// Tells the user something finished, in the line under the library and on the desktop if that's turned on.
+fn notify(s: &mut Cursive, message: String) -> Result<(), Error> {
+    let data = data(s)?;
+    let desktop = data.settings.desktop_notifications;
+    data.notifications.push(Notification {
+        time: chrono::Local::now(),
+        message: message.clone(),
+    });
+    if data.notifications.len() > NOTIFICATION_LIMIT {
+        data.notifications.remove(0);
+    }
+
+    let latest = latest_notification(s)?;
+    if let Some(mut line) = s.find_name::<TextView>("notifications") {
+        line.set_content(latest);
+    }
+
+    if desktop {
+        crate::desktop::notify("ereader", &message)?;
+    }
+    Ok(())
+}
+
+fn latest_notification(s: &mut Cursive) -> Result<String, Error> {
+    Ok(data(s)?
+        .notifications
+        .last()
+        .map(|notification| notification.to_string())
+        .unwrap_or_default())
+}
+
+fn notifications(s: &mut Cursive) -> Result<(), Error> {
+    let text = data(s)?
+        .notifications
+        .iter()
+        .rev()
+        .map(|notification| notification.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    s.add_layer(
+        Dialog::around(
+            TextView::new(text)
+                .with_name("notification list")
+                .scrollable(),
+        )
+        .title(tr("Notifications"))
+        .button(tr("Clear"), try_view!(clear_notifications, button))
+        .dismiss_button(tr("Close"))
+        .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn clear_notifications(s: &mut Cursive) -> Result<(), Error> {
+    data(s)?.notifications.clear();
+
+    if let Some(mut list) = s.find_name::<TextView>("notification list") {
+        list.set_content("");
+    }
+    if let Some(mut line) = s.find_name::<TextView>("notifications") {
+        line.set_content("");
+    }
+    Ok(())
+}
+
+// ============================== QUERY HISTORY ==============================
+const QUERY_HISTORY_LIMIT: usize = 100;
+
+/// Wraps a search EditView so up and down cycle through the past queries of that kind of search.
+fn history_edit_view(
+    view: EditView,
+    kind: &'static str,
+    name: &'static str,
+) -> OnEventView<NamedView<EditView>> {
+    OnEventView::new(view.with_name(name))
+        .on_pre_event(Key::Up, move |s| recall_query(s, kind, name, true))
+        .on_pre_event(Key::Down, move |s| recall_query(s, kind, name, false))
+}
+
+fn recall_query(s: &mut Cursive, kind: &str, name: &str, older: bool) {
+    let data = match data(s) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let history = match data.query_history.get(kind) {
+        Some(history) if !history.is_empty() => history,
+        _ => return,
+    };
+
+    // positions count back from the newest query
+    let position = match (data.history_position, older) {
+        (None, true) => Some(0),
+        (None, false) => None,
+        (Some(position), true) => Some((position + 1).min(history.len() - 1)),
+        (Some(0), false) => None,
+        (Some(position), false) => Some(position - 1),
+    };
+    data.history_position = position;
+
+    let query = position
+        .map(|position| history[history.len() - 1 - position].clone())
+        .unwrap_or_default();
+
+    if let Some(mut view) = s.find_name::<EditView>(name) {
+        view.set_content(query);
+    }
+}
+
+fn record_query(s: &mut Cursive, kind: &str, query: &str) -> Result<(), Error> {
+    let data = data(s)?;
+    data.history_position = None;
+
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(());
+    }
+
+    let history = data.query_history.entry(kind.to_string()).or_default();
+    if history.last().map(|last| last.as_str()) != Some(query) {
+        history.push(query.to_string());
+    }
+    if history.len() > QUERY_HISTORY_LIMIT {
+        history.remove(0);
+    }
+
+    if data.settings.save_query_history {
+        data.run(set_query_history(&data.pool, &data.query_history))?;
+    }
+
+    Ok(())
+}
+
+fn toggle_save_query_history(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let settings = Settings {
+        save_query_history: !data.settings.save_query_history,
+        ..data.settings.clone()
+    };
+    data.run(settings.save(&data.pool))?;
+    data.settings = settings;
+
+    if data.settings.save_query_history {
+        data.run(set_query_history(&data.pool, &data.query_history))?;
+    } else {
+        data.run(set_query_history(&data.pool, &QueryHistory::new()))?;
+    }
+
+    let message = if data.settings.save_query_history {
+        tr("Search queries will be saved between sessions.")
+    } else {
+        tr("Search queries will only be kept for this session.")
+    };
+    s.add_layer(
+        Dialog::around(TextView::new(message))
+            .title(tr("Query History"))
+            .dismiss_button(tr("Close")),
+    );
+
+    Ok(())
+}
+
+fn clear_query_history(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    data.query_history.clear();
+    data.history_position = None;
+    data.run(set_query_history(&data.pool, &data.query_history))?;
+    Ok(())
+}
+
+fn bookmark_pruning(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let pruning = &data.settings.bookmark_pruning;
+
+    let limit = |limit: Option<i64>| limit.map(|limit| limit.to_string()).unwrap_or_default();
+    let fields = ListView::new()
+        .child(
+            tr("Bookmarks per book"),
+            EditView::new()
+                .content(limit(pruning.per_book))
+                .with_name("bookmarks per book"),
+        )
+        .child(
+            tr("Max age in days"),
+            EditView::new()
+                .content(limit(pruning.max_age_days))
+                .with_name("bookmark max age"),
+        );
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(tr(
+                    "Leave a limit empty to keep every bookmark.",
+                )))
+                .child(fields),
+        )
+        .title(tr("Bookmark Pruning"))
+        .button(tr("Save"), try_view!(save_bookmark_pruning, button))
+        .dismiss_button(tr("Cancel"))
+        .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_bookmark_pruning(s: &mut Cursive) -> Result<(), Error> {
+    let mut limit = |name: &str| -> Result<Option<i64>, Error> {
+        let content = s
+            .find_name::<EditView>(name)
+            .ok_or(Error::ViewNotFound)?
+            .get_content();
+        let content = content.trim();
+        if content.is_empty() {
+            return Ok(None);
+        }
+        match content.parse::<i64>() {
+            Ok(limit) if limit >= 0 => Ok(Some(limit)),
+            _ => Err(Error::InvalidSetting(name.to_string(), content.to_string())),
+        }
+    };
+    let pruning = BookmarkPruning {
+        per_book: limit("bookmarks per book")?,
+        max_age_days: limit("bookmark max age")?,
+    };
+
+    let data = data(s)?;
+    let settings = Settings {
+        bookmark_pruning: pruning,
+        ..data.settings.clone()
+    };
+    data.run(settings.save(&data.pool))?;
+    data.settings = settings;
+
+    s.pop_layer();
+    Ok(())
+}
+
+fn index_memory(s: &mut Cursive) -> Result<(), Error> {
+    let tuning = data(s)?.settings.index_tuning;
+
+    let fields = ListView::new()
+        .child(
+            tr("Writer memory (MB)"),
+            EditView::new()
+                .content(tuning.writer_heap_mb.to_string())
+                .with_name("index writer memory"),
+        )
+        .child(
+            tr("Writer threads"),
+            EditView::new()
+                .content(tuning.writer_threads.to_string())
+                .with_name("index writer threads"),
+        )
+        .child(
+            tr("Searchers"),
+            EditView::new()
+                .content(tuning.searchers.to_string())
+                .with_name("index searchers"),
+        );
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(tr(
+                    "Lower these on a machine with little memory. The text index uses them after a restart.",
+                )))
+                .child(fields),
+        )
+        .title(tr("Index Memory"))
+        .button(tr("Save"), try_view!(save_index_memory, button))
+        .dismiss_button(tr("Cancel"))
+        .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_index_memory(s: &mut Cursive) -> Result<(), Error> {
+    let mut field = |name: &str| -> Result<usize, Error> {
+        let content = s
+            .find_name::<EditView>(name)
+            .ok_or(Error::ViewNotFound)?
+            .get_content();
+        let content = content.trim();
+        content
+            .parse()
+            .map_err(|_| Error::InvalidSetting(name.to_string(), content.to_string()))
+    };
+    let tuning = IndexTuning {
+        writer_heap_mb: field("index writer memory")?,
+        writer_threads: field("index writer threads")?,
+        searchers: field("index searchers")?,
+    };
+
+    let data = data(s)?;
+    let old = data.settings.clone();
+    let settings = Settings {
+        index_tuning: tuning,
+        ..old.clone()
+    };
+    replace_settings(data, settings)?;
+
+    s.pop_layer();
+    settings_changed(s, &old)
+}
+
+fn prune_library_bookmarks(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let deleted = data.run(prune_bookmarks(&data.pool, &data.settings.bookmark_pruning))?;
+
+    s.add_layer(
+        Dialog::around(TextView::new(tr_args("Deleted {} bookmarks.", &[&deleted])))
+            .title(tr("Prune Bookmarks"))
+            .dismiss_button(tr("Close")),
+    );
+
+    Ok(())
+}
+
+// ============================== SETTINGS ==============================
+fn theme(name: &str) -> Theme {
+    let mut theme = Theme::default();
+    match name {
+        "dark" => {
+            theme.palette[PaletteColor::Background] = Color::Dark(BaseColor::Black);
+            theme.palette[PaletteColor::View] = Color::Rgb(30, 30, 30);
+            theme.palette[PaletteColor::Primary] = Color::Rgb(220, 220, 220);
+            theme.palette[PaletteColor::Secondary] = Color::Rgb(160, 160, 160);
+            theme.palette[PaletteColor::TitlePrimary] = Color::Light(BaseColor::Blue);
+            theme.palette[PaletteColor::Highlight] = Color::Dark(BaseColor::Blue);
+            theme.palette[PaletteColor::HighlightInactive] = Color::Rgb(60, 60, 60);
+            theme.shadow = false;
+        }
+        "terminal" => {
+            theme.palette[PaletteColor::Background] = Color::TerminalDefault;
+            theme.palette[PaletteColor::View] = Color::TerminalDefault;
+            theme.palette[PaletteColor::Primary] = Color::TerminalDefault;
+            theme.shadow = false;
+        }
+        _ => {}
+    }
+    theme
+}
+
+/// Shows the theme and checks the schedule again, it switches to the night theme once it says it's night.
+pub fn apply_theme(s: &mut Cursive) -> Result<(), Error> {
+    show_theme(s)?;
+    check_night(s)
+}
+
+fn show_theme(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let name = data
+        .settings
+        .theme_schedule
+        .current(&data.settings.theme, data.night)
+        .to_string();
+    data.active_theme = name.clone();
+    s.set_theme(theme(&name));
+    Ok(())
+}
+
+/// How often the theme schedule is checked, its command could be slow so it isn't run on every refresh.
+const THEME_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the theme schedule's command can run before it's killed and counted as day.
+const THEME_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Switches to or from the night theme when the schedule says to.
+pub fn scheduled_theme(s: &mut Cursive) -> Result<(), Error> {
+    if data(s)?.theme_checked.elapsed() < THEME_CHECK_INTERVAL {
+        return Ok(());
+    }
+    check_night(s)
+}
+
+/// Asks the theme schedule whether it's night, its command runs on another thread
+/// so a slow one doesn't freeze the interface and the theme switches once it answers.
+fn check_night(s: &mut Cursive) -> Result<(), Error> {
+    let cb_sink = s.cb_sink().clone();
+    let data = data(s)?;
+    data.theme_checked = Instant::now();
+    let schedule = &data.settings.theme_schedule;
+    if schedule.night_theme.is_none() {
+        return Ok(());
+    }
+
+    match schedule.command.clone() {
+        Some(command) => {
+            std::thread::spawn(move || {
+                let night = command_is_night(&command, THEME_COMMAND_TIMEOUT);
+                let _ = cb_sink.send(Box::new(move |s| {
+                    if let Err(e) = set_night(s, night) {
+                        error_message(s, e);
+                    }
+                }));
+            });
+            Ok(())
+        }
+        None => {
+            let night = schedule.is_night_by_time();
+            set_night(s, night)
+        }
+    }
+}
+
+fn set_night(s: &mut Cursive, night: bool) -> Result<(), Error> {
+    let data = data(s)?;
+    data.night = night;
+    let switched = data
+        .settings
+        .theme_schedule
+        .current(&data.settings.theme, night)
+        != data.active_theme;
+
+    if switched {
+        switch_theme(s)?;
+    }
+    Ok(())
+}
+
+fn switch_theme(s: &mut Cursive) -> Result<(), Error> {
+    show_theme(s)?;
+    // the reader picks text colors that contrast with the background so it has to be re-rendered
+    rerender_chapter(s)
+}
+
+/// Opens the open chapter again at the same position, e.g. to render it with new settings.
+fn rerender_chapter(s: &mut Cursive) -> Result<(), Error> {
+    let progress = reader_progress(s);
+    if let Some((_book_id, chapter_id)) = data(s)?.current_chapter {
+        chapter(s, chapter_id, progress)?;
+    }
+    Ok(())
+}
+
+fn settings(s: &mut Cursive) -> Result<(), Error> {
+    let settings = data(s)?.settings.clone();
+
+    let mut themes = SelectView::new().popup();
+    themes.add_all_str(THEMES.iter().copied());
+    if let Some(i) = THEMES.iter().position(|theme| *theme == settings.theme) {
+        themes.set_selection(i);
+    }
+
+    let effects = |values: &[&str], selected: &str| {
+        let mut view = SelectView::new().popup();
+        view.add_all_str(values.iter().copied());
+        if let Some(i) = values.iter().position(|value| *value == selected) {
+            view.set_selection(i);
+        }
+        view
+    };
+    let italic_effects = effects(ITALIC_EFFECTS, &settings.italic_effect);
+    let bold_effects = effects(BOLD_EFFECTS, &settings.bold_effect);
+
+    let schedule = &settings.theme_schedule;
+    let mut night_themes = SelectView::new().popup();
+    night_themes.add_item(tr("off"), String::new());
+    night_themes.add_all_str(THEMES.iter().copied());
+    if let Some(i) = THEMES
+        .iter()
+        .position(|theme| Some(*theme) == schedule.night_theme.as_deref())
+    {
+        night_themes.set_selection(i + 1);
+    }
+
+    let mut locales = SelectView::new().popup();
+    locales.add_all_str(LOCALES.iter().copied());
+    if let Some(i) = LOCALES.iter().position(|locale| *locale == settings.locale) {
+        locales.set_selection(i);
+    }
+
+    let fields = ListView::new()
+        .child(
+            tr("epub path"),
+            EditView::new()
+                .content(settings.epub_path)
+                .with_name("epub path"),
+        )
+        .child(
+            tr("index path"),
+            EditView::new()
+                .content(settings.index_path)
+                .with_name("index path"),
+        )
+        .child(
+            tr("fimfarchive path"),
+            EditView::new()
+                .content(settings.fimfarchive_path)
+                .with_name("fimfarchive path"),
+        )
+        .child(
+            tr("reader width"),
+            EditView::new()
+                .content(settings.reader_width.to_string())
+                .with_name("reader width"),
+        )
+        .child(
+            tr("scroll step"),
+            EditView::new()
+                .content(settings.scroll_step.to_string())
+                .with_name("scroll step"),
+        )
+        .child(
+            tr("page overlap"),
+            EditView::new()
+                .content(settings.page_overlap.to_string())
+                .with_name("page overlap"),
+        )
+        .child(
+            tr("auto scroll lines"),
+            EditView::new()
+                .content(settings.auto_scroll_lines.to_string())
+                .with_name("auto scroll lines"),
+        )
+        .child(
+            tr("auto scroll interval"),
+            EditView::new()
+                .content(settings.auto_scroll_interval.to_string())
+                .with_name("auto scroll interval"),
+        )
+        .child(
+            tr("compression level (0 for none)"),
+            EditView::new()
+                .content(settings.compression_level.to_string())
+                .with_name("compression level"),
+        )
+        .child(
+            tr("sync endpoint"),
+            EditView::new()
+                .content(settings.sync_endpoint.unwrap_or_default())
+                .with_name("sync endpoint"),
+        )
+        .child(
+            tr("sync token"),
+            EditView::new()
+                .secret()
+                .content(settings.sync_token.unwrap_or_default())
+                .with_name("sync token"),
+        )
+        .child(tr("theme"), themes.with_name("theme"))
+        .child(tr("italic text"), italic_effects.with_name("italic effect"))
+        .child(tr("bold text"), bold_effects.with_name("bold effect"))
+        .child(
+            tr("horizontal rule (empty for a line)"),
+            EditView::new()
+                .content(settings.horizontal_rule.clone())
+                .with_name("horizontal rule"),
+        )
+        .child(tr("night theme"), night_themes.with_name("night theme"))
+        .child(
+            tr("night from"),
+            EditView::new()
+                .content(schedule.night_start.clone())
+                .with_name("night start"),
+        )
+        .child(
+            tr("night until"),
+            EditView::new()
+                .content(schedule.night_end.clone())
+                .with_name("night end"),
+        )
+        .child(
+            tr("night when this command prints dark"),
+            EditView::new()
+                .content(schedule.command.clone().unwrap_or_default())
+                .with_name("night command"),
+        )
+        .child(
+            tr("desktop notifications"),
+            Checkbox::new()
+                .with_checked(settings.desktop_notifications)
+                .with_name("desktop notifications"),
+        )
+        .child(
+            tr("skip front matter"),
+            Checkbox::new()
+                .with_checked(settings.skip_front_matter)
+                .with_name("skip front matter"),
+        )
+        .child(
+            tr("start on the dashboard"),
+            Checkbox::new()
+                .with_checked(settings.start_dashboard)
+                .with_name("start dashboard"),
+        )
+        .child(
+            tr("keep original files"),
+            Checkbox::new()
+                .with_checked(settings.keep_originals)
+                .with_name("keep originals"),
+        )
+        .child(
+            tr("language (after a restart)"),
+            locales.with_name("locale"),
+        )
+        .child(
+            tr("low bandwidth (after a restart)"),
+            Checkbox::new()
+                .with_checked(settings.low_bandwidth)
+                .with_name("low bandwidth"),
+        );
+
+    s.add_layer(
+        Dialog::around(fields)
+            .title(tr("Settings"))
+            .button(tr("Save"), try_view!(save_settings, button))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_settings(s: &mut Cursive) -> Result<(), Error> {
+    let mut field = |name: &str| -> Result<String, Error> {
+        Ok(s.find_name::<EditView>(name)
+            .ok_or(Error::ViewNotFound)?
+            .get_content()
+            .trim()
+            .to_string())
+    };
+    let epub_path = field("epub path")?;
+    let index_path = field("index path")?;
+    let fimfarchive_path = field("fimfarchive path")?;
+    let reader_width = field("reader width")?;
+    let scroll_step = field("scroll step")?;
+    let page_overlap = field("page overlap")?;
+    let auto_scroll_lines = field("auto scroll lines")?;
+    let auto_scroll_interval = field("auto scroll interval")?;
+    let compression_level = field("compression level")?;
+    let optional = |value: String| if value.is_empty() { None } else { Some(value) };
+    let sync_endpoint = optional(field("sync endpoint")?);
+    let sync_token = optional(field("sync token")?);
+    let night_start = field("night start")?;
+    let night_end = field("night end")?;
+    let night_command = optional(field("night command")?);
+    // not trimmed, the spaces in a rule like "* " are part of it
+    let horizontal_rule = s
+        .find_name::<EditView>("horizontal rule")
+        .ok_or(Error::ViewNotFound)?
+        .get_content()
+        .to_string();
+
+    let theme = s
+        .find_name::<SelectView>("theme")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let italic_effect = s
+        .find_name::<SelectView>("italic effect")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let bold_effect = s
+        .find_name::<SelectView>("bold effect")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let night_theme = s
+        .find_name::<SelectView>("night theme")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let desktop_notifications = s
+        .find_name::<Checkbox>("desktop notifications")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    let skip_front_matter = s
+        .find_name::<Checkbox>("skip front matter")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    let start_dashboard = s
+        .find_name::<Checkbox>("start dashboard")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    let low_bandwidth = s
+        .find_name::<Checkbox>("low bandwidth")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    let keep_originals = s
+        .find_name::<Checkbox>("keep originals")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    let locale = s
+        .find_name::<SelectView>("locale")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+
+    let data = data(s)?;
+    let old = data.settings.clone();
+    let settings = Settings {
+        epub_path,
+        index_path,
+        fimfarchive_path,
+        reader_width: reader_width
+            .parse()
+            .map_err(|_| Error::InvalidSetting("reader width".to_string(), reader_width))?,
+        scroll_step: scroll_step
+            .parse()
+            .map_err(|_| Error::InvalidSetting("scroll step".to_string(), scroll_step))?,
+        page_overlap: page_overlap
+            .parse()
+            .map_err(|_| Error::InvalidSetting("page overlap".to_string(), page_overlap))?,
+        auto_scroll_lines: auto_scroll_lines.parse().map_err(|_| {
+            Error::InvalidSetting("auto scroll lines".to_string(), auto_scroll_lines)
+        })?,
+        auto_scroll_interval: auto_scroll_interval.parse().map_err(|_| {
+            Error::InvalidSetting("auto scroll interval".to_string(), auto_scroll_interval)
+        })?,
+        compression_level: compression_level.parse().map_err(|_| {
+            Error::InvalidSetting("compression level".to_string(), compression_level)
+        })?,
+        theme: theme.to_string(),
+        desktop_notifications,
+        skip_front_matter,
+        start_dashboard,
+        locale: locale.to_string(),
+        low_bandwidth,
+        keep_originals,
+        sync_endpoint,
+        sync_token,
+        italic_effect: italic_effect.to_string(),
+        bold_effect: bold_effect.to_string(),
+        horizontal_rule,
+        theme_schedule: ThemeSchedule {
+            night_theme: optional(night_theme.to_string()),
+            night_start,
+            night_end,
+            command: night_command,
+        },
+        ..old.clone()
+    };
+    settings.check_paths(&old)?;
+    replace_settings(data, settings)?;
+
+    s.pop_layer();
+    settings_changed(s, &old)
+}
+
+/// Merges the changes from the sync server if there is one.
+pub fn sync_pull(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    if let Some(endpoint) = &data.settings.sync_endpoint {
+        let report = data.run(crate::sync::pull(
+            &data.pool,
+            endpoint,
+            data.settings.sync_token.as_deref(),
+        ))?;
+        notify(s, tr_args("Synced, {}", &[&report]))?;
+    }
+    Ok(())
+}
+
+/// Sends the library's changes to the sync server if there is one.
+fn sync_push(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    if let Some(endpoint) = &data.settings.sync_endpoint {
+        data.run(crate::sync::push(
+            &data.pool,
+            endpoint,
+            data.settings.sync_token.as_deref(),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Adds the global callbacks for the keys in the keymap.
+/// Whether the dashboard is shown at startup, see `Settings::start_dashboard`.
+pub fn start_dashboard(s: &mut Cursive) -> bool {
+    data(s)
+        .map(|data| data.settings.start_dashboard)
+        .unwrap_or(false)
+}
+
+/// Whether to draw less for a slow connection, see `Settings::low_bandwidth`.
+pub fn low_bandwidth(s: &mut Cursive) -> Result<bool, Error> {
+    Ok(data(s)?.settings.low_bandwidth)
+}
+
+pub fn register_keymap(s: &mut Cursive) -> Result<(), Error> {
+    let keymap = data(s)?.settings.keymap.clone();
+
+    if let Some(quit) = parse_key(&keymap.quit) {
+        s.add_global_callback(quit, try_view!(cleanup, button));
+    }
+    if let Some(suspend) = parse_key(&keymap.suspend) {
+        s.add_global_callback(suspend, crate::terminal::suspend);
+    }
+
+    Ok(())
+}
+
+fn unregister_keymap(s: &mut Cursive, keymap: &Keymap) {
+    for key in &[&keymap.quit, &keymap.suspend] {
+        if let Some(event) = parse_key(key) {
+            s.clear_global_callbacks(event);
+        }
+    }
+}
+
+fn path_prompt(
+    s: &mut Cursive,
+    title: &str,
+    default: &str,
+    action: fn(&mut Cursive, &str) -> Result<(), Error>,
+) {
+    let mut input = EditView::new().content(default);
+    input.set_on_submit(move |s, path| {
+        s.pop_layer();
+        if let Err(e) = action(s, path) {
+            error_message(s, e);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(input)
+            .title(title)
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
+
+fn export_settings_prompt(s: &mut Cursive) -> Result<(), Error> {
+    path_prompt(s, tr("Export Settings To"), "ereader.toml", export_settings);
+    Ok(())
+}
+
+fn import_settings_prompt(s: &mut Cursive) -> Result<(), Error> {
+    path_prompt(
+        s,
+        tr("Import Settings From"),
+        "ereader.toml",
+        import_settings,
+    );
+    Ok(())
+}
+
+fn export_settings(s: &mut Cursive, path: &str) -> Result<(), Error> {
+    data(s)?.settings.write_toml(path)?;
+
+    s.add_layer(
+        Dialog::around(TextView::new(tr_args(
+            "Exported the settings to {}.",
+            &[&path],
+        )))
+        .title(tr("Export Settings"))
+        .dismiss_button(tr("Close")),
+    );
+
+    Ok(())
+}
+
+fn import_settings(s: &mut Cursive, path: &str) -> Result<(), Error> {
+    let mut settings = Settings::read_toml(path)?;
+
+    let data = data(s)?;
+    let old = data.settings.clone();
+    let kept = settings.keep_local(&old);
+    replace_settings(data, settings)?;
+
+    for (name, imported) in kept {
+        notify(
+            s,
+            tr_args(
+                "Kept the local {} since {} isn't a directory here.",
+                &[&tr(name), &imported],
+            ),
+        )?;
+    }
+
+    settings_changed(s, &old)
+}
+
+/// Updates whatever depends on the settings that changed from `old` so they take effect right away.
+/// The epub and fimfarchive paths are read whenever they're used so they don't need anything.
+/// Saves the settings and makes them the current ones.
+///
+/// A changed fimfarchive index is opened first so a path that isn't a usable index
+/// is an error that leaves the settings alone instead of a saved setting every search fails with.
+fn replace_settings(data: &mut Data, settings: Settings) -> Result<(), Error> {
+    let old = &data.settings;
+    let opened =
+        if settings.index_path != old.index_path || settings.index_tuning != old.index_tuning {
+            Some(crate::fimfarchive::open(
+                &settings.index_path,
+                settings.index_tuning,
+            )?)
+        } else {
+            None
+        };
+
+    data.run(settings.save(&data.pool))?;
+    if let Some((schema, index, reader)) = opened {
+        data.schema = schema;
+        data.index = index;
+        data.reader = reader;
+    }
+    data.settings = settings;
+    Ok(())
+}
+
+fn settings_changed(s: &mut Cursive, old: &Settings) -> Result<(), Error> {
+    let settings = data(s)?.settings.clone();
+
+    if settings.keymap != old.keymap {
+        unregister_keymap(s, &old.keymap);
+        register_keymap(s)?;
+    }
+
+    if settings.reader_width != old.reader_width {
+        if let Some(mut reader) = s.find_name::<ResizedView<NamedView<Dialog>>>("reader size") {
+            reader.set_width(SizeConstraint::AtMost(settings.reader_width));
+        }
+    }
+
+    // the open reader keeps the scroll settings it was created with
+    let scrolling_changed = settings.scroll_step != old.scroll_step
+        || settings.page_overlap != old.page_overlap
+        || settings.keymap.half_page_up != old.keymap.half_page_up
+        || settings.keymap.half_page_down != old.keymap.half_page_down;
+    let theme_changed =
+        settings.theme != old.theme || settings.theme_schedule != old.theme_schedule;
+    let effects_changed = settings.italic_effect != old.italic_effect
+        || settings.bold_effect != old.bold_effect
+        || settings.horizontal_rule != old.horizontal_rule;
+    if theme_changed {
+        apply_theme(s)?;
+    }
+    if scrolling_changed || theme_changed || effects_changed {
+        rerender_chapter(s)?;
+    }
+
+    Ok(())
+}
+
+// ============================== RATINGS ==============================
+fn rate(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let mut ratings = SelectView::new();
+    for rating in (1..=5).rev() {
+        ratings.add_item("★".repeat(rating as usize), Some(rating));
+    }
+    ratings.add_item(tr("No Rating"), None);
+
+    ratings.set_on_submit(move |s, rating: &Option<i64>| {
+        if let Err(e) = save_rating(s, book_id, *rating) {
+            error_message(s, e);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(ratings)
+            .title(tr("Rate"))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_rating(s: &mut Cursive, book_id: Hyphenated, rating: Option<i64>) -> Result<(), Error> {
+    let data = data(s)?;
+    data.run(set_rating(&data.pool, book_id, rating))?;
+    let filter = data.library_filter.clone();
+
+    s.pop_layer();
+    show_books(s, filter)
+}
+
+// ============================== TAGS ==============================
+fn tags(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let data = data(s)?;
+    let book = data.run(get_book(&data.pool, book_id))?;
+    let tags = data.run(get_book_tags(&data.pool, book_id))?;
+
+    s.add_layer(
+        Dialog::around(EditView::new().content(tags.join(", ")).with_name("tags"))
+            .title(tr_args("Tags (comma separated): {}", &[&book.title]))
+            .button(tr("Save"), try_view!(save_tags, book_id))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_tags(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let content = s
+        .find_name::<EditView>("tags")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+    let tags = content
+        .split(',')
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<String>>();
+
+    let data = data(s)?;
+    data.run(set_book_tags(&data.pool, book_id, &tags))?;
+
+    s.pop_layer();
+    Ok(())
+}
+
+// ============================== COLLECTIONS ==============================
+fn collections(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let collections = data.run(get_collections(&data.pool))?;
+
+    let mut collections_view = SelectView::new();
+    for collection in collections {
+        collections_view.add_item(
+            format!("{}: {}", collection.name, collection.query),
+            collection,
+        );
+    }
+
+    s.add_layer(
+        Dialog::around(collections_view.with_name("collection list").scrollable())
+            .title(tr("Collections"))
+            .button(tr("New"), new_collection)
+            .button(tr("Delete"), try_view!(delete_selected_collection, button))
+            .button(tr("Rules"), try_view!(rules, button))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn new_collection(s: &mut Cursive) {
+    let fields = ListView::new()
+        .child(tr("Name"), EditView::new().with_name("collection name"))
+        .child(tr("Filter"), EditView::new().with_name("collection query"));
+
+    s.add_layer(
+        Dialog::around(fields)
+            .title(tr(
+                "New Collection (e.g. status:unread #(fantasy) words>100k)",
+            ))
+            .button(tr("Save"), try_view!(save_collection, button))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
+
+fn save_collection(s: &mut Cursive) -> Result<(), Error> {
+    let name = s
+        .find_name::<EditView>("collection name")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+    let query = s
+        .find_name::<EditView>("collection query")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+
+    // make sure the filter is valid before saving it
+    Filter::parse(&query)?;
+
+    let data = data(s)?;
+    data.run(insert_collection(
+        &data.pool,
+        &Collection {
+            name: name.to_string(),
+            query: query.to_string(),
+        },
+    ))?;
+
+    // close the form and collection list and reload the library so the sidebar is up to date
+    s.pop_layer();
+    s.pop_layer();
+    s.pop_layer();
+    library(s)?;
+    collections(s)
+}
+
+fn delete_selected_collection(s: &mut Cursive) -> Result<(), Error> {
+    let collection = match s
+        .find_name::<SelectView<Collection>>("collection list")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+    {
+        Some(collection) => collection,
+        None => return Ok(()),
+    };
+
+    let data = data(s)?;
+    data.run(delete_collection(&data.pool, &collection.name))?;
+
+    s.pop_layer();
+    s.pop_layer();
+    library(s)?;
+    collections(s)
+}
+
+// ============================== READ LATER ==============================
+fn read_later(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let queue = data.run(get_read_later(&data.pool))?;
+
+    let mut queue_view = SelectView::new();
+    for item in queue {
+        let started = match item.book_id {
+            Some(book_id) => data.run(get_position(&data.pool, book_id))?.is_some(),
+            None => false,
+        };
+        let state = if started {
+            tr("started")
+        } else {
+            tr("not started")
+        };
+        queue_view.add_item(format!("{} ({})", item.title, state), item);
+    }
+    queue_view.set_on_submit(try_view!(open_read_later));
+
+    s.add_layer(
+        Dialog::around(queue_view.with_name("read later").scrollable())
+            .title(tr("Read Later"))
+            .button(tr("Remove"), try_view!(remove_selected_read_later, button))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+/// Opens a book from the queue, a story from the fimfarchive is imported first.
+fn open_read_later(s: &mut Cursive, item: &ReadLater) -> Result<(), Error> {
+    if let Some(book_id) = item.book_id {
+        s.pop_layer();
+        return open_book(s, book_id);
+    }
+
+    let data = data(s)?;
+    let story = item
+        .story_id
+        .and_then(|story_id| crate::fimfarchive::story(story_id, &data.schema, &data.reader));
+    match story {
+        Some(story) => {
+            s.pop_layer();
+            import_fimfarchive(s, &story)
+        }
+        None => notify(
+            s,
+            tr_args("{} isn't in the library or the fimfarchive", &[&item.title]),
+        ),
+    }
+}
+
+fn remove_selected_read_later(s: &mut Cursive) -> Result<(), Error> {
+    let item = match s
+        .find_name::<SelectView<ReadLater>>("read later")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+    {
+        Some(item) => item,
+        None => return Ok(()),
+    };
+
+    let data = data(s)?;
+    data.run(delete_read_later(&data.pool, item.id))?;
+
+    s.pop_layer();
+    read_later(s)
+}
+
+fn read_later_selected_book(s: &mut Cursive) -> Result<(), Error> {
+    let book = match selected_book(s) {
+        Some(book) => book,
+        None => return Ok(()),
+    };
+    let data = data(s)?;
+    let added = data.run(add_read_later(
+        &data.pool,
+        Some(book.id),
+        book.story_id,
+        &book.title,
+    ))?;
+    read_later_added(s, &book.title, added)
+}
+
+fn read_later_fimfarchive_story(s: &mut Cursive) -> Result<(), Error> {
+    let story = match selected_fimfarchive_result(s) {
+        Some(story) => story,
+        None => return Ok(()),
+    };
+    let data = data(s)?;
+    let book_id = data.run(get_story_book(&data.pool, story.id))?;
+    let added = data.run(add_read_later(
+        &data.pool,
+        book_id,
+        Some(story.id),
+        &story.title,
+    ))?;
+    read_later_added(s, &story.title, added)
+}
+
+fn read_later_added(s: &mut Cursive, title: &str, added: bool) -> Result<(), Error> {
+    if added {
+        notify(s, tr_args("Added {} to read later", &[&title]))
+    } else {
+        notify(s, tr_args("{} is already in read later", &[&title]))
+    }
+}
+
+// ============================== AUTHORS ==============================
+fn authors(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let authors = data.run(get_author_counts(&data.pool))?;
+
+    let mut authors_view = SelectView::new();
+    for author in authors {
+        authors_view.add_item(format!("{} ({})", author.name, author.books), author);
+    }
+    authors_view.set_on_submit(try_view!(show_author_books));
+
+    s.add_layer(
+        Dialog::around(authors_view.scrollable())
+            .title(tr("Authors"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+/// Filters the library to the books by an author.
+fn show_author_books(s: &mut Cursive, author: &AuthorCount) -> Result<(), Error> {
+    s.pop_layer();
+    show_books(s, Some(Filter::by_author(&author.name)))
+}
+
+// ============================== RULES ==============================
+fn rules(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let rules = data.run(get_rules(&data.pool))?;
+
+    let mut rules_view = SelectView::new();
+    for rule in rules {
+        rules_view.add_item(describe(&rule), rule);
+    }
+
+    s.add_layer(
+        Dialog::around(rules_view.with_name("rule list").scrollable())
+            .title(tr("Rules"))
+            .button(tr("New"), new_rule)
+            .button(tr("Delete"), try_view!(delete_selected_rule, button))
+            .button(tr("Apply"), try_view!(apply_library_rules, button))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn new_rule(s: &mut Cursive) {
+    let mut fields = SelectView::new().popup();
+    fields.add_all_str(RULE_FIELDS.iter().copied());
+
+    let mut comparisons = SelectView::new().popup();
+    comparisons.add_item(tr("contains"), false);
+    comparisons.add_item("=", true);
+
+    let mut actions = SelectView::new().popup();
+    actions.add_item(tr("add tag"), "tag");
+    actions.add_item(tr("add to collection"), "collection");
+
+    let form = ListView::new()
+        .child(tr("If"), fields.with_name("rule field"))
+        .child("", comparisons.with_name("rule comparison"))
+        .child("", EditView::new().with_name("rule pattern"))
+        .child(tr("Then"), actions.with_name("rule action"))
+        .child("", EditView::new().with_name("rule target"));
+
+    s.add_layer(
+        Dialog::around(form)
+            .title(tr("New Rule"))
+            .button(tr("Save"), try_view!(save_rule, button))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
+
+fn save_rule(s: &mut Cursive) -> Result<(), Error> {
+    let field = s
+        .find_name::<SelectView>("rule field")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let exact = *s
+        .find_name::<SelectView<bool>>("rule comparison")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let pattern = s
+        .find_name::<EditView>("rule pattern")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+    let action = *s
+        .find_name::<SelectView<&'static str>>("rule action")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+        .ok_or(Error::ViewNotFound)?;
+    let target = s
+        .find_name::<EditView>("rule target")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+
+    let rule = Rule {
+        id: 0,
+        field: field.to_string(),
+        pattern: pattern.to_string(),
+        exact,
+        action: action.to_string(),
+        target: target.trim().to_string(),
+    };
+    validate(&rule)?;
+
+    let data = data(s)?;
+    data.run(insert_rule(&data.pool, &rule))?;
+
+    // close the form and the old rule list
+    s.pop_layer();
+    s.pop_layer();
+    rules(s)
+}
+
+fn delete_selected_rule(s: &mut Cursive) -> Result<(), Error> {
+    let rule = match s
+        .find_name::<SelectView<Rule>>("rule list")
+        .ok_or(Error::ViewNotFound)?
+        .selection()
+    {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+
+    let data = data(s)?;
+    data.run(delete_rule(&data.pool, rule.id))?;
+
+    s.pop_layer();
+    rules(s)
+}
+
+/// Applies the rules to every book in the library.
+fn apply_library_rules(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let books = data.run(get_books(&data.pool))?;
+    let report = data.run(apply_rules(&data.pool, &books))?;
+
+    // close the rules, collections, and library so the new tags and collections show up
+    s.pop_layer();
+    s.pop_layer();
+    s.pop_layer();
+    library(s)?;
+
+    notify(s, tr_args("Rules {}", &[&report]))?;
+
+    s.add_layer(
+        Dialog::around(TextView::new(tr_args("Rules {}.", &[&report])))
+            .title(tr("Apply Rules"))
+            .dismiss_button(tr("Close")),
+    );
+
+    Ok(())
+}
+
+// ============================== TOC ==============================
+#[derive(Clone, Copy, PartialEq)]
+enum ChapterState {
+    Read,
+    Reading,
+    Unread,
+}
+
+/// The entries of a book's table of contents and how they are shown.
+struct TocTree {
+    entries: Vec<Toc>,
+    /// whether each chapter comes before, at, or after the reading position
+    states: HashMap<Hyphenated, ChapterState>,
+    collapsed: RefCell<HashSet<i64>>,
+}
+
+impl TocTree {
+    fn has_children(&self, i: usize) -> bool {
+        self.entries
+            .get(i + 1)
+            .map(|next| next.depth > self.entries[i].depth)
+            .unwrap_or(false)
+    }
+
+    fn state(&self, entry: &Toc) -> ChapterState {
+        self.states
+            .get(&entry.chapter_id)
+            .copied()
+            .unwrap_or(ChapterState::Unread)
+    }
+}
+
+fn toc(s: &mut Cursive, id: Hyphenated) -> Result<(), Error> {
+    let data = data(s)?;
+    let entries = data.run(get_toc(&data.pool, id))?;
+    // books without a toc can still be opened at any chapter from the chapter list
+    if entries.is_empty() {
+        return skim(s, id);
+    }
+
+    // the chapter open in the reader, or where the book was last read
+    let reading = match data.current_chapter {
+        Some((book_id, chapter_id)) if book_id == id => Some(chapter_id),
+        _ => match data.run(get_position(&data.pool, id))? {
+            Some(position) => Some(position.chapter_id),
+            None => data
+                .run(get_bookmark(&data.pool, id))?
+                .map(|bookmark| bookmark.chapter_id),
+        },
+    };
+
+    let chapter_ids = data.run(get_chapter_ids(&data.pool, id))?;
+    let reading_index =
+        reading.and_then(|reading| chapter_ids.iter().position(|id| *id == reading));
+    let states = chapter_ids
+        .iter()
+        .enumerate()
+        .map(|(i, chapter_id)| {
+            let state = match reading_index {
+                Some(reading) if i < reading => ChapterState::Read,
+                Some(reading) if i == reading => ChapterState::Reading,
+                _ => ChapterState::Unread,
+            };
+            (*chapter_id, state)
+        })
+        .collect();
+
+    let tree = Rc::new(TocTree {
+        entries,
+        states,
+        collapsed: RefCell::new(HashSet::new()),
+    });
+
+    let mut toc_list = SelectView::new();
+    fill_toc(&mut toc_list, &tree);
+    if let Some(row) = toc_list
+        .iter()
+        .position(|(_label, entry)| tree.state(entry) == ChapterState::Reading)
+    {
+        toc_list.set_selection(row);
+    }
+    toc_list.set_on_submit(try_view!(chapter_goto_toc));
+
+    let toc_view = OnEventView::new(toc_list.with_name("toc"))
+        .on_pre_event(Key::Left, {
+            let tree = tree.clone();
+            move |s| toggle_toc_entry(s, &tree, Some(true))
+        })
+        .on_pre_event(Key::Right, {
+            let tree = tree.clone();
+            move |s| toggle_toc_entry(s, &tree, Some(false))
+        })
+        .on_pre_event(' ', move |s| toggle_toc_entry(s, &tree, None));
+
+    s.add_layer(
+        Dialog::around(toc_view.scrollable())
+            .title(tr("Table of Contents (left/right/space to collapse)"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+/// Fills the TOC list with the entries that aren't inside a collapsed entry.
+/// Read chapters are checked off and the one being read is marked and bold.
+fn fill_toc(toc_list: &mut SelectView<Toc>, tree: &TocTree) {
+    toc_list.clear();
+    let collapsed = tree.collapsed.borrow();
+
+    // entries deeper than this are hidden by a collapsed parent
+    let mut hidden_below: Option<i64> = None;
+    for (i, entry) in tree.entries.iter().enumerate() {
+        match hidden_below {
+            Some(depth) if entry.depth > depth => continue,
+            _ => hidden_below = None,
+        }
+
+        let marker = if !tree.has_children(i) {
+            " "
+        } else if collapsed.contains(&entry.id) {
+            "▸"
+        } else {
+            "▾"
+        };
+        if collapsed.contains(&entry.id) {
+            hidden_below = Some(entry.depth);
+        }
+
+        let state = tree.state(entry);
+        let state_marker = match state {
+            ChapterState::Read => "✓",
+            ChapterState::Reading => "▶",
+            ChapterState::Unread => " ",
+        };
+
+        let indent = "  ".repeat(entry.depth as usize);
+        let label = format!("{} {}{} {}", state_marker, indent, marker, entry.title);
+        let label = if state == ChapterState::Reading {
+            StyledString::styled(label, Effect::Bold)
+        } else {
+            StyledString::plain(label)
+        };
+        toc_list.add_item(label, entry.clone());
+    }
+}
+
+/// Collapses (`Some(true)`), expands (`Some(false)`), or toggles (`None`) the selected TOC entry.
+fn toggle_toc_entry(s: &mut Cursive, tree: &TocTree, collapse: Option<bool>) {
+    let mut toc_list = match s.find_name::<SelectView<Toc>>("toc") {
+        Some(toc_list) => toc_list,
+        None => return,
+    };
+    let selected = match toc_list.selection() {
+        Some(selected) => selected,
+        None => return,
+    };
+    let i = match tree
+        .entries
+        .iter()
+        .position(|entry| entry.id == selected.id)
+    {
+        Some(i) => i,
+        None => return,
+    };
+    if !tree.has_children(i) {
+        return;
+    }
+
+    {
+        let mut collapsed = tree.collapsed.borrow_mut();
+        let collapse = collapse.unwrap_or_else(|| !collapsed.contains(&selected.id));
+        if collapse {
+            collapsed.insert(selected.id);
+        } else {
+            collapsed.remove(&selected.id);
+        }
+    }
+
+    fill_toc(&mut toc_list, tree);
+    if let Some(row) = toc_list
+        .iter()
+        .position(|(_label, entry)| entry.id == selected.id)
+    {
+        toc_list.set_selection(row);
+    }
+}
+
+// ============================== BOOKMARKS ==============================
+/// The stored title of a chapter, or the title of the TOC entry it's part of for books imported without titles.
+/// `titles` are the chapter titles in the same order as `chapter_ids`.
+fn chapter_title(
+    toc: &[Toc],
+    chapter_ids: &[Hyphenated],
+    titles: &[Option<String>],
+    chapter_id: Hyphenated,
+) -> Option<String> {
+    let index = chapter_ids.iter().position(|id| *id == chapter_id)?;
+    if let Some(Some(title)) = titles.get(index) {
+        return Some(title.clone());
+    }
+    toc.iter()
+        .filter(|entry| {
+            chapter_ids
+                .iter()
+                .position(|id| *id == entry.chapter_id)
+                .map(|entry_index| entry_index <= index)
+                .unwrap_or(false)
+        })
+        .last()
+        .map(|entry| entry.title.clone())
+}
+
+/// Lists the bookmarks under a heading for their book (along with the start of the book's notes).
+/// The headings have no bookmark so selecting them does nothing.
+fn bookmarks(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let bookmarks = data.run(get_bookmarks(&data.pool))?;
+
+    // books are listed in the order of their most recent bookmark
+    let mut book_ids: Vec<Hyphenated> = Vec::new();
+    for bookmark in &bookmarks {
+        if !book_ids.contains(&bookmark.book_id) {
+            book_ids.push(bookmark.book_id);
+        }
+    }
+
+    let mut bookmarks_view = SelectView::new();
+
+    for book_id in book_ids {
+        let book = data.run(get_book(&data.pool, book_id))?;
+        let note = data.run(get_note(&data.pool, book_id))?;
+        let toc = data.run(get_toc(&data.pool, book_id))?;
+        let chapter_ids = data.run(get_chapter_ids(&data.pool, book_id))?;
+        let titles = data.run(get_chapter_titles(&data.pool, book_id))?;
+        let words = data.run(get_chapter_words(&data.pool, book_id))?;
+        let total_words: i64 = words.iter().sum();
+
+        let heading = match note.as_deref().map(str::trim) {
+            Some(note) if !note.is_empty() => format!("{} - {}", book.title, excerpt(note)),
+            _ => book.title.clone(),
+        };
+        bookmarks_view.add_item(StyledString::styled(heading, Effect::Bold), None);
+
+        for bookmark in bookmarks
+            .iter()
+            .filter(|bookmark| bookmark.book_id == book_id)
+        {
+            let index = chapter_ids
+                .iter()
+                .position(|id| *id == bookmark.chapter_id)
+                .unwrap_or(0);
+            let title = chapter_title(&toc, &chapter_ids, &titles, bookmark.chapter_id)
+                .unwrap_or_else(|| tr_args("Chapter {}", &[&(index + 1)]));
+
+            let words_before: i64 = words.iter().take(index).sum();
+            let chapter_words = words.get(index).copied().unwrap_or(0);
+            let percent = if total_words == 0 {
+                0.0
+            } else {
+                (words_before as f32 + chapter_words as f32 * bookmark.progress)
+                    / total_words as f32
+                    * 100.0
+            };
+
+            bookmarks_view.add_item(
+                format!(
+                    "  {} ({:.0}%) {}",
+                    title,
+                    percent,
+                    format_datetime(&bookmark.created)
+                ),
+                Some(bookmark.clone()),
+            );
+        }
+    }
+
+    bookmarks_view.set_on_submit(|s, bookmark: &Option<Bookmark>| {
+        if let Some(bookmark) = bookmark {
+            if let Err(e) = chapter_goto_bookmark(s, bookmark) {
+                error_message(s, e);
+            }
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(bookmarks_view.with_name("bookmarks").scrollable())
+            .title(tr("Bookmarks"))
+            .button(tr("Delete"), try_view!(delete_selected_bookmark, button))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn delete_selected_bookmark(s: &mut Cursive) -> Result<(), Error> {
+    let bookmarks_view = s
+        .find_name::<SelectView<Option<Bookmark>>>("bookmarks")
+        .ok_or(Error::ViewNotFound)?;
+    let bookmark = match bookmarks_view.selection().as_deref() {
+        Some(Some(bookmark)) => bookmark.clone(),
+        _ => return Ok(()),
+    };
+
+    let data = data(s)?;
+    data.run(delete_bookmark(&data.pool, bookmark.id))?;
+
+    s.pop_layer();
+    bookmarks(s)
+}
+
+fn set_bookmark(s: &mut Cursive, book_id: Hyphenated, chapter_id: Hyphenated) -> Result<(), Error> {
+    let progress = reader_progress(s).ok_or(Error::ViewNotFound)?;
+
+    let data = data(s)?;
+    data.run(insert_bookmark(
+        &data.pool,
+        &Bookmark {
+            id: 0,
+            book_id,
+            chapter_id,
+            progress,
+            created: chrono::Utc::now(),
+        },
+    ))?;
+
+    data.run(prune_bookmarks(&data.pool, &data.settings.bookmark_pruning))?;
+
+    // re-render so the new bookmark shows up next to the scrollbar
+    chapter(s, chapter_id, Some(progress))
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MarkKind {
+    Bookmark,
+    Annotation,
+}
+
+impl MarkKind {
+    /// What marks it next to the reader's scrollbar.
+    fn symbol(self) -> &'static str {
+        match self {
+            MarkKind::Bookmark => "◆",
+            MarkKind::Annotation => "✎",
+        }
+    }
+}
+
+/// A bookmark or annotation in the book open in the reader.
+#[derive(Clone, Debug)]
+struct Mark {
+    kind: MarkKind,
+    chapter_id: Hyphenated,
+    /// where the chapter is in the book so the marks can be put in reading order
+    chapter_index: usize,
+    progress: f32,
+    label: String,
+}
+
+/// The bookmarks and annotations of a book in the order they appear in it.
+fn book_marks(s: &mut Cursive, book_id: Hyphenated) -> Result<Vec<Mark>, Error> {
+    let data = data(s)?;
+    let chapter_ids = data.run(get_chapter_ids(&data.pool, book_id))?;
+    let titles = data.run(get_chapter_titles(&data.pool, book_id))?;
+    let toc = data.run(get_toc(&data.pool, book_id))?;
+    let bookmarks = data.run(get_bookmarks(&data.pool))?;
+    let annotations = data.run(get_annotations(&data.pool))?;
+
+    let location = |chapter_index: usize, chapter_id: Hyphenated, progress: f32| {
+        let title = chapter_title(&toc, &chapter_ids, &titles, chapter_id)
+            .unwrap_or_else(|| tr_args("Chapter {}", &[&(chapter_index + 1)]));
+        format!("{} ({:.0}%)", title, progress * 100.0)
+    };
+    let chapter_index =
+        |chapter_id: Hyphenated| chapter_ids.iter().position(|id| *id == chapter_id);
+
+    let mut marks = Vec::new();
+    for bookmark in bookmarks
+        .iter()
+        .filter(|bookmark| bookmark.book_id == book_id)
+    {
+        if let Some(index) = chapter_index(bookmark.chapter_id) {
+            marks.push(Mark {
+                kind: MarkKind::Bookmark,
+                chapter_id: bookmark.chapter_id,
+                chapter_index: index,
+                progress: bookmark.progress,
+                label: tr_args(
+                    "Bookmark    {}",
+                    &[&location(index, bookmark.chapter_id, bookmark.progress)],
+                ),
+            });
+        }
+    }
+    for annotation in annotations
+        .iter()
+        .filter(|annotation| annotation.book_id == book_id)
+    {
+        if let Some(index) = chapter_index(annotation.chapter_id) {
+            marks.push(Mark {
+                kind: MarkKind::Annotation,
+                chapter_id: annotation.chapter_id,
+                chapter_index: index,
+                progress: annotation.progress,
+                label: tr_args(
+                    "Annotation  {} {}",
+                    &[
+                        &location(index, annotation.chapter_id, annotation.progress),
+                        &excerpt(&annotation.passage),
+                    ],
+                ),
+            });
+        }
+    }
+
+    marks.sort_by(|a, b| {
+        (a.chapter_index, a.progress)
+            .partial_cmp(&(b.chapter_index, b.progress))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(marks)
+}
+
+/// Lists the bookmarks and annotations of the open book in reading order.
+fn marks(s: &mut Cursive, book_id: Hyphenated) -> Result<(), Error> {
+    let marks = book_marks(s, book_id)?;
+
+    let keymap = &data(s)?.settings.keymap;
+    let title = tr_args(
+        "Marks ({}/{} jump between them in the reader)",
+        &[&keymap.next_mark, &keymap.previous_mark],
+    );
+
+    let mut marks_view = SelectView::new();
+    for mark in marks {
+        marks_view.add_item(mark.label.clone(), mark);
+    }
+    marks_view.set_on_submit(|s, mark: &Mark| {
+        s.pop_layer();
+        if let Err(e) = chapter(s, mark.chapter_id, Some(mark.progress)) {
+            error_message(s, e);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(marks_view.scrollable())
+            .title(title)
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+/// Moves the reader to the next (or previous) bookmark or annotation from the current position.
+fn jump_to_mark(s: &mut Cursive, forward: bool) -> Result<(), Error> {
+    let (book_id, chapter_id) = match data(s)?.current_chapter {
+        Some(current) => current,
+        None => return Ok(()),
+    };
+    let progress = reader_progress(s).unwrap_or(0.0);
+    // the reader stops on the line a mark is on, so a mark within a line of the top is the current one
+    let line = s
+        .find_name::<ScrollView<MarkupView<ColorRenderer>>>("reader content")
+        .map(|reader_content| 1.0 / reader_content.inner_size().y.max(1) as f32)
+        .unwrap_or(0.0);
+
+    let marks = book_marks(s, book_id)?;
+    let data = data(s)?;
+    let current_index = data
+        .run(get_chapter_ids(&data.pool, book_id))?
+        .iter()
+        .position(|id| *id == chapter_id)
+        .unwrap_or(0);
+
+    let mark = if forward {
+        marks.iter().find(|mark| {
+            mark.chapter_index > current_index
+                || (mark.chapter_index == current_index && mark.progress > progress + line)
+        })
+    } else {
+        marks.iter().rev().find(|mark| {
+            mark.chapter_index < current_index
+                || (mark.chapter_index == current_index && mark.progress < progress - line)
+        })
+    };
+
+    match mark {
+        Some(mark) => chapter(s, mark.chapter_id, Some(mark.progress)),
+        None => Ok(()),
+    }
+}
+
+// ============================== CONTENT FILTERS ==============================
+fn apply_content_filters(
+    mut content: String,
+    filters: &[ContentFilter],
+    disabled: &[String],
+) -> Result<String, Error> {
+    for filter in filters {
+        if disabled.contains(&filter.name) {
+            continue;
+        }
+
+        let re = Regex::new(&filter.pattern)?;
+        content = re
+            .replace_all(&content, filter.replacement.as_str())
+            .to_string();
+    }
+
+    Ok(content)
+}
+
+fn apply_render_overrides(content: String, overrides: &RenderOverrides) -> Result<String, Error> {
+    let content = strip_selectors(&content, &overrides.selectors())?;
+    Ok(if overrides.paragraph_spacing {
+        force_paragraph_spacing(&content)
+    } else {
+        content
+    })
+}
+
+fn render_overrides(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+) -> Result<(), Error> {
+    let data = data(s)?;
+    let overrides = data
+        .run(get_render_overrides(&data.pool, book_id))?
+        .unwrap_or(RenderOverrides {
+            book_id,
+            disable_italics: false,
+            paragraph_spacing: false,
+            strip_selectors: String::new(),
+        });
+
+    let fields = LinearLayout::vertical()
+        .child(
+            ListView::new()
+                .child(
+                    tr("Disable italics"),
+                    Checkbox::new()
+                        .with_checked(overrides.disable_italics)
+                        .with_name("disable italics"),
+                )
+                .child(
+                    tr("Force paragraph spacing"),
+                    Checkbox::new()
+                        .with_checked(overrides.paragraph_spacing)
+                        .with_name("paragraph spacing"),
+                ),
+        )
+        .child(TextView::new(tr(
+            "Remove elements matching (css selectors, one per line):",
+        )))
+        .child(
+            TextArea::new()
+                .content(overrides.strip_selectors)
+                .with_name("strip selectors")
+                .min_height(3),
+        );
+
+    s.add_layer(
+        Dialog::around(fields)
+            .title(tr("Rendering For This Book"))
+            .button(
+                tr("Save"),
+                try_view!(save_render_overrides, book_id, chapter_id),
+            )
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn save_render_overrides(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+) -> Result<(), Error> {
+    let checked = |s: &mut Cursive, name: &str| {
+        s.find_name::<Checkbox>(name)
+            .map(|checkbox| checkbox.is_checked())
+            .ok_or(Error::ViewNotFound)
+    };
+    let overrides = RenderOverrides {
+        book_id,
+        disable_italics: checked(s, "disable italics")?,
+        paragraph_spacing: checked(s, "paragraph spacing")?,
+        strip_selectors: s
+            .find_name::<TextArea>("strip selectors")
+            .ok_or(Error::ViewNotFound)?
+            .get_content()
+            .to_string(),
+    };
+
+    // make sure the selectors are valid before they get applied to every chapter
+    strip_selectors("", &overrides.selectors())?;
+
+    let data = data(s)?;
+    data.run(set_render_overrides(&data.pool, &overrides))?;
+
+    s.pop_layer();
+    // re-render the chapter so the changes are visible right away
+    let progress = reader_progress(s);
+    chapter(s, chapter_id, progress)
+}
+
+fn content_filters(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let filters = data.run(get_content_filters(&data.pool))?;
+
+    let mut filters_view = SelectView::new();
+    for filter in filters {
+        filters_view.add_item(
+            format!(
+                "{}: s/{}/{}/",
+                filter.name, filter.pattern, filter.replacement
+            ),
+            filter,
+        );
+    }
+
+    s.add_layer(
+        Dialog::around(filters_view.with_name("content filters").scrollable())
+            .title(tr("Content Filters"))
+            .button(tr("Add"), add_content_filter)
+            .button(
+                tr("Delete"),
+                try_view!(delete_selected_content_filter, button),
+            )
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn add_content_filter(s: &mut Cursive) {
+    let fields = ListView::new()
+        .child(tr("Name"), EditView::new().with_name("filter name"))
+        .child(tr("Pattern"), EditView::new().with_name("filter pattern"))
+        .child(
+            tr("Replacement"),
+            EditView::new().with_name("filter replacement"),
+        );
+
+    s.add_layer(
+        Dialog::around(fields)
+            .title(tr("Add Content Filter"))
+            .button(tr("Save"), try_view!(save_content_filter, button))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+}
+
+fn save_content_filter(s: &mut Cursive) -> Result<(), Error> {
+    let name = s
+        .find_name::<EditView>("filter name")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+    let pattern = s
+        .find_name::<EditView>("filter pattern")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+    let replacement = s
+        .find_name::<EditView>("filter replacement")
+        .ok_or(Error::ViewNotFound)?
+        .get_content();
+
+    // make sure the pattern is valid before it gets applied to every chapter
+    Regex::new(&pattern)?;
+
+    let data = data(s)?;
+    let mut filters = data.run(get_content_filters(&data.pool))?;
+    filters.retain(|filter| filter.name != *name);
+    filters.push(ContentFilter {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        replacement: replacement.to_string(),
+    });
+    data.run(set_content_filters(&data.pool, &filters))?;
+
+    s.pop_layer();
+    s.pop_layer();
+    content_filters(s)
+}
+
+fn delete_selected_content_filter(s: &mut Cursive) -> Result<(), Error> {
+    let filters_view = s
+        .find_name::<SelectView<ContentFilter>>("content filters")
+        .ok_or(Error::ViewNotFound)?;
+    let selected = match filters_view.selection() {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+
+    let data = data(s)?;
+    let mut filters = data.run(get_content_filters(&data.pool))?;
+    filters.retain(|filter| filter.name != selected.name);
+    data.run(set_content_filters(&data.pool, &filters))?;
+
+    s.pop_layer();
+    content_filters(s)
+}
+
+fn book_content_filters(
+    s: &mut Cursive,
+    book_id: Hyphenated,
+    chapter_id: Hyphenated,
+) -> Result<(), Error> {
+    let data = data(s)?;
+    let filters = data.run(get_content_filters(&data.pool))?;
+    let disabled = data.run(get_disabled_content_filters(&data.pool, book_id))?;
+
+    let mut filters_view = ListView::new();
+    for filter in filters {
+        let enabled = !disabled.contains(&filter.name);
+        let name = filter.name.clone();
+        filters_view.add_child(
+            &filter.name,
+            Checkbox::new()
+                .with_checked(enabled)
+                .on_change(move |s, checked| {
+                    let result = data(s).and_then(|data| {
+                        data.run(set_content_filter_enabled(
+                            &data.pool, book_id, &name, checked,
+                        ))
+                    });
+                    if let Err(e) = result {
+                        error_message(s, e);
+                    }
+                }),
+        );
+    }
+
+    s.add_layer(
+        Dialog::around(filters_view.scrollable())
+            .title(tr("Content Filters For This Book"))
+            .button(tr("Close"), move |s| {
+                s.pop_layer();
+                // re-render the chapter so the changes are visible right away
+                let progress = reader_progress(s);
+                if let Err(e) = chapter(s, chapter_id, progress) {
+                    error_message(s, e);
+                }
+            })
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+// ============================== FIMFARCHIVE ==============================
+
+fn fimfarchive(s: &mut Cursive) {
+    let mut search_view = EditView::new();
+
+    search_view.set_on_submit(try_view!(submit_fimfarchive));
+    search_view.set_on_edit(|s, query, _cursor| {
+        if let Err(e) = fimfarchive_edited(s, query) {
+            error_message(s, e);
+        }
+    });
+
+    let mut books_list = SelectView::new();
+    books_list.set_on_select(set_fimfarchive_details);
+    books_list.set_on_submit(try_view!(import_fimfarchive));
+
+    let mut results = LinearLayout::vertical();
+    results.add_child(books_list.with_name("fimfarchive results").scrollable());
+    results.add_child(Panel::new(ListView::new()));
+
+    let layout = LinearLayout::vertical()
+        .child(history_edit_view(
+            search_view,
+            "fimfarchive",
+            "fimfarchive query",
+        ))
+        .child(
+            LinearLayout::horizontal()
+                .child(Checkbox::new().with_name("fimfarchive incremental"))
+                .child(TextView::new(format!(" {}", tr("Search as you type")))),
+        )
+        .child(TextView::new("").with_name("fimfarchive status"))
+        .child(results.with_name("fimfarchive"));
+
+    let spoiler_key = data(s)
+        .ok()
+        .and_then(|data| parse_key(&data.settings.keymap.reveal_spoilers))
+        .unwrap_or(Event::Char('s'));
+    let read_later_key = data(s)
+        .ok()
+        .and_then(|data| parse_key(&data.settings.keymap.read_later))
+        .unwrap_or(Event::Char('+'));
+
+    s.add_layer(
+        OnEventView::new(
+            Dialog::around(layout)
+                .title(tr("Fimfarchive Search"))
+                .button(tr("Advanced"), try_view!(advanced_fimfarchive, button))
+                .button(tr("Open Story"), try_view!(open_fimfarchive_story, button))
+                .button(tr("Copy Link"), try_view!(copy_fimfarchive_link, button))
+                .dismiss_button(tr("Close"))
+                .max_width(90),
+        )
+        .on_event(spoiler_key, toggle_fimfarchive_spoilers)
+        .on_event(
+            read_later_key,
+            try_view!(read_later_fimfarchive_story, button),
+        ),
+    );
+}
+
+/// A form that writes the fimfarchive query, for searching without learning the query syntax.
+fn advanced_fimfarchive(s: &mut Cursive) -> Result<(), Error> {
+    let data = data(s)?;
+    let tags = crate::fimfarchive::tag_names(&data.schema, &data.reader)?;
+
+    let checkboxes = |prefix: &str, values: &[&str]| {
+        let mut layout = LinearLayout::horizontal();
+        for value in values {
+            layout.add_child(Checkbox::new().with_name(format!("{} {}", prefix, value)));
+            layout.add_child(TextView::new(format!(" {}  ", value)));
+        }
+        layout
+    };
+    let range = |name: &str| {
+        LinearLayout::horizontal()
+            .child(
+                EditView::new()
+                    .with_name(format!("advanced min {}", name))
+                    .fixed_width(10),
+            )
+            .child(TextView::new(" - "))
+            .child(
+                EditView::new()
+                    .with_name(format!("advanced max {}", name))
+                    .fixed_width(10),
+            )
+    };
+
+    let mut orders = SelectView::new().popup();
+    orders.add_all_str(ORDERS.iter().copied());
+
+    let fields = ListView::new()
+        .child(tr("text"), EditView::new().with_name("advanced text"))
+        .child(tr("ratings"), checkboxes("advanced rating", &RATINGS))
+        .child(tr("status"), checkboxes("advanced status", &STATUSES))
+        .child(tr("words"), range("words"))
+        .child(tr("likes"), range("likes"))
+        .child(tr("order"), orders.with_name("advanced order"));
+
+    let mut tag_picker = SelectView::new();
+    for (category, tag) in tags {
+        tag_picker.add_item(format!("{}: {}", category, tag), tag);
+    }
+    tag_picker.set_on_submit(|s, tag: &String| add_advanced_tag(s, "advanced tags", tag));
+
+    let mut required = SelectView::<String>::new();
+    required.set_on_submit(|s, _: &String| remove_advanced_tag(s, "advanced tags"));
+    let mut excluded = SelectView::<String>::new();
+    excluded.set_on_submit(|s, _: &String| remove_advanced_tag(s, "advanced excluded tags"));
+
+    let tags = LinearLayout::horizontal()
+        .child(
+            Panel::new(
+                tag_picker
+                    .with_name("advanced tag picker")
+                    .scrollable()
+                    .fixed_height(10),
+            )
+            .title(tr("Tags (enter to require)")),
+        )
+        .child(
+            LinearLayout::vertical()
+                .child(
+                    Panel::new(
+                        required
+                            .with_name("advanced tags")
+                            .scrollable()
+                            .fixed_height(3),
+                    )
+                    .title(tr("Required (enter to remove)")),
+                )
+                .child(
+                    Panel::new(
+                        excluded
+                            .with_name("advanced excluded tags")
+                            .scrollable()
+                            .fixed_height(3),
+                    )
+                    .title(tr("Excluded (enter to remove)")),
+                ),
+        );
+
+    s.add_layer(
+        Dialog::around(LinearLayout::vertical().child(fields).child(tags))
+            .title(tr("Advanced Search"))
+            .button(tr("Exclude Tag"), |s| {
+                let tag = s
+                    .find_name::<SelectView<String>>("advanced tag picker")
+                    .and_then(|picker| picker.selection());
+                if let Some(tag) = tag {
+                    add_advanced_tag(s, "advanced excluded tags", &tag);
+                }
+            })
+            .button(tr("Search"), try_view!(search_advanced_fimfarchive, button))
+            .dismiss_button(tr("Cancel"))
+            .max_width(90),
+    );
+
+    Ok(())
+}
+
+fn add_advanced_tag(s: &mut Cursive, list: &str, tag: &str) {
+    if let Some(mut view) = s.find_name::<SelectView<String>>(list) {
+        if !view.iter().any(|(_, added)| added == tag) {
+            view.add_item(tag, tag.to_string());
+        }
+    }
+}
+
+fn remove_advanced_tag(s: &mut Cursive, list: &str) {
+    if let Some(mut view) = s.find_name::<SelectView<String>>(list) {
+        if let Some(id) = view.selected_id() {
+            view.remove_item(id);
+        }
+    }
+}
+
+fn advanced_checked(s: &mut Cursive, name: &str) -> bool {
+    s.find_name::<Checkbox>(name)
+        .map(|checkbox| checkbox.is_checked())
+        .unwrap_or(false)
+}
+
+fn advanced_number(s: &mut Cursive, name: &str) -> Result<Option<i64>, Error> {
+    let value = s
+        .find_name::<EditView>(name)
+        .ok_or(Error::ViewNotFound)?
+        .get_content()
+        .trim()
+        .to_string();
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidFilter(value))
+    }
+}
+
+fn advanced_tags(s: &mut Cursive, name: &str) -> Vec<String> {
+    s.find_name::<SelectView<String>>(name)
+        .map(|view| view.iter().map(|(_, tag)| tag.clone()).collect())
+        .unwrap_or_default()
+}
+
+/// Writes the query from the advanced search form into the search box and searches it.
+fn search_advanced_fimfarchive(s: &mut Cursive) -> Result<(), Error> {
+    let ratings = RATINGS
+        .iter()
+        .filter(|rating| advanced_checked(s, &format!("advanced rating {}", rating)))
+        .map(|rating| rating.to_string())
+        .collect();
+    let statuses = STATUSES
+        .iter()
+        .filter(|status| advanced_checked(s, &format!("advanced status {}", status)))
+        .map(|status| status.to_string())
+        .collect();
+
+    let search = AdvancedSearch {
+        text: s
+            .find_name::<EditView>("advanced text")
+            .ok_or(Error::ViewNotFound)?
+            .get_content()
+            .to_string(),
+        ratings,
+        statuses,
+        min_words: advanced_number(s, "advanced min words")?,
+        max_words: advanced_number(s, "advanced max words")?,
+        min_likes: advanced_number(s, "advanced min likes")?,
+        max_likes: advanced_number(s, "advanced max likes")?,
+        tags: advanced_tags(s, "advanced tags"),
+        excluded_tags: advanced_tags(s, "advanced excluded tags"),
+        order: s
+            .find_name::<SelectView>("advanced order")
+            .ok_or(Error::ViewNotFound)?
+            .selection()
+            .map(|order| order.to_string())
+            .unwrap_or_default(),
+    };
+    let query = search.query();
+
+    s.pop_layer();
+    if let Some(mut view) = s.find_name::<EditView>("fimfarchive query") {
+        view.set_content(query.clone());
+    }
+    submit_fimfarchive(s, &query)
+}
+
+fn submit_fimfarchive(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    record_query(s, "fimfarchive", query)?;
+    search_fimfarchive(s, query)
+}
+
+fn search_fimfarchive(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    search_fimfarchive_background(
+        s,
+        query,
+        "fimfarchive status",
+        Box::new(fimfarchive_results),
+    )
+}
+
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Starts a search once the query hasn't changed for a moment when searching as you type.
+fn fimfarchive_edited(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    let incremental = s
+        .find_name::<Checkbox>("fimfarchive incremental")
+        .ok_or(Error::ViewNotFound)?
+        .is_checked();
+    if !incremental {
+        return Ok(());
+    }
+
+    let data = data(s)?;
+    let edits = data.search_edits.clone();
+    let edit = edits.fetch_add(1, Ordering::SeqCst) + 1;
+    let query = query.to_string();
+    let cb_sink = s.cb_sink().clone();
+
+    std::thread::spawn(move || {
+        std::thread::sleep(SEARCH_DEBOUNCE);
+        if edits.load(Ordering::SeqCst) != edit {
+            return;
+        }
+        let _ = cb_sink.send(Box::new(move |s| {
+            if let Err(e) = search_fimfarchive(s, &query) {
+                error_message(s, e);
+            }
+        }));
+    });
+
+    Ok(())
+}
+
+/// Replaces the results in the fimfarchive search dialog.
+fn fimfarchive_results(s: &mut Cursive, books: Vec<FimfArchiveResult>) -> Result<(), Error> {
+    let mut books_list = s
+        .find_name::<SelectView<FimfArchiveResult>>("fimfarchive results")
+        .ok_or(Error::ViewNotFound)?;
+
+    books_list.clear();
+    for book in &books {
+        books_list.add_item(book.title.clone(), book.clone());
+    }
+    drop(books_list);
+
+    match books.get(0) {
+        Some(book) => set_fimfarchive_details(s, book),
+        None => {
+            let mut fimfarchive = s
+                .find_name::<LinearLayout>("fimfarchive")
+                .ok_or(Error::ViewNotFound)?;
+            fimfarchive.remove_child(1);
+            fimfarchive.add_child(Panel::new(ListView::new()));
+        }
+    }
+
+    Ok(())
+}
+
+type FimfArchiveCallback =
+    Box<dyn FnOnce(&mut Cursive, Vec<FimfArchiveResult>) -> Result<(), Error> + Send>;
+
+const SPINNER: [&str; 4] = ["|", "/", "-", "\\"];
+
+/// Runs a fimfarchive search on another thread so the ui doesn't stall on large indexes.
+/// A spinner is shown in the `status` TextView while it runs and the results are given to `callback`.
+/// The results are dropped if a newer search was started or the status view was closed in the meantime.
+fn search_fimfarchive_background(
+    s: &mut Cursive,
+    query: &str,
+    status: &'static str,
+    callback: FimfArchiveCallback,
+) -> Result<(), Error> {
+    let data = data(s)?;
+    let current = data.search_generation.clone();
+    let generation = current.fetch_add(1, Ordering::SeqCst) + 1;
+    let index = data.index.clone();
+    let schema = data.schema.clone();
+    let reader = data.reader.clone();
+    let query = query.to_string();
+
+    let low_bandwidth = data.settings.low_bandwidth;
+    let cb_sink = s.cb_sink().clone();
+    let done = Arc::new(AtomicBool::new(false));
+
+    // the spinner redraws ten times a second so it's left out on slow connections
+    if low_bandwidth {
+        if let Some(mut status) = s.find_name::<TextView>(status) {
+            status.set_content(tr("Searching..."));
+        }
+    } else {
+        let current = current.clone();
+        let cb_sink = cb_sink.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            for frame in SPINNER.iter().cycle() {
+                if done.load(Ordering::SeqCst) || current.load(Ordering::SeqCst) != generation {
+                    break;
+                }
+                let frame = *frame;
+                let sent = cb_sink.send(Box::new(move |s| {
+                    if let Some(mut status) = s.find_name::<TextView>(status) {
+                        status.set_content(format!("{} {}", frame, tr("Searching...")));
+                    }
+                }));
+                if sent.is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+    }
+
+    std::thread::spawn(move || {
+        let results = crate::fimfarchive::search(query, 50, &index, &schema, &reader);
+        done.store(true, Ordering::SeqCst);
+
+        let _ = cb_sink.send(Box::new(move |s| {
+            if current.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            match s.find_name::<TextView>(status) {
+                Some(mut status) => status.set_content(""),
+                None => return,
+            }
+            if let Err(e) = callback(s, results) {
+                error_message(s, e);
+            }
+        }));
+    });
+
+    Ok(())
+}
+
+/// Shows the details of the selected story, with its spoilers hidden.
+fn set_fimfarchive_details(s: &mut Cursive, book: &FimfArchiveResult) {
+    if let Ok(data) = data(s) {
+        data.spoilers_revealed = false;
+    }
+    show_fimfarchive_details(s, book);
+}
+
+fn toggle_fimfarchive_spoilers(s: &mut Cursive) {
+    let selected = match s.find_name::<SelectView<FimfArchiveResult>>("fimfarchive results") {
+        Some(results) => results.selection(),
+        None => None,
+    };
+    if let (Some(book), Ok(data)) = (selected, data(s)) {
+        data.spoilers_revealed = !data.spoilers_revealed;
+        show_fimfarchive_details(s, &book);
+    }
+}
+
+fn show_fimfarchive_details(s: &mut Cursive, book: &FimfArchiveResult) {
+    let reveal_key = match data(s) {
+        Ok(data) if data.spoilers_revealed => None,
+        Ok(data) => Some(data.settings.keymap.reveal_spoilers.clone()),
+        Err(_) => Some("s".to_string()),
+    };
+    let detail_view = fimfarchive_details(book, reveal_key.as_deref());
+
+    let mut fimfarchive = s.find_name::<LinearLayout>("fimfarchive").unwrap();
+
+    fimfarchive.remove_child(1);
+    fimfarchive.add_child(Panel::new(detail_view.scrollable()).title(tr("Details")));
+}
+
+/// The spoilers in the description are hidden unless `hide_spoilers` is `None`,
+/// otherwise it's the key that reveals them.
+fn fimfarchive_details(book: &FimfArchiveResult, hide_spoilers_key: Option<&str>) -> LinearLayout {
+    let mut detail_view = LinearLayout::vertical();
+
+    let wilson = format!("{:.2}", book.wilson * 100.0);
+    detail_view.add_child(TextView::new(
+        [
+            tr_args("Title: {}", &[&book.title]),
+            tr_args("Author: {}", &[&book.author.split("/").last().unwrap()]),
+            tr_args("Words: {}", &[&book.words]),
+            tr_args("Likes: {}", &[&book.likes]),
+            tr_args("Dislikes: {}", &[&book.dislikes]),
+            tr_args("Wilson: {}%", &[&wilson]),
+            fimfarchive_tags(book),
+            String::new(),
+        ]
+        .join("\n"),
+    ));
+    match hide_spoilers_key {
+        Some(key) => {
+            detail_view.add_child(MarkupView::html(&hide_spoilers(&book.description, key)))
+        }
+        None => detail_view.add_child(MarkupView::html(&book.description)),
+    }
+
+    detail_view
+}
+
+/// A line of tags for each category.
+fn fimfarchive_tags(book: &FimfArchiveResult) -> String {
+    book.categories
+        .iter()
+        .map(|(category, tags)| {
+            let mut chars = category.chars();
+            let category = match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            };
+            format!("{}: {}\n", category, tags.join(", "))
+        })
+        .collect()
+}
+
+fn selected_fimfarchive_result(s: &mut Cursive) -> Option<Rc<FimfArchiveResult>> {
+    s.find_name::<SelectView<FimfArchiveResult>>("fimfarchive results")
+        .and_then(|results| results.selection())
+}
+
+/// Opens the selected story's fimfiction.net page in the browser.
+fn open_fimfarchive_story(s: &mut Cursive) -> Result<(), Error> {
+    if let Some(book) = selected_fimfarchive_result(s) {
+        crate::desktop::open_url(&book.url())?;
+    }
+    Ok(())
+}
+
+fn copy_fimfarchive_link(s: &mut Cursive) -> Result<(), Error> {
+    if let Some(book) = selected_fimfarchive_result(s) {
+        clipboard::copy(&book.url())?;
+        notify(s, tr_args("Copied the link to {}", &[&book.title]))?;
+    }
+    Ok(())
+}
+
+/// Imports a story from the fimfarchive zip into the library and opens it.
+fn import_fimfarchive(s: &mut Cursive, book: &FimfArchiveResult) -> Result<(), Error> {
+    let data = data(s)?;
+    let archive_path = data.settings.fimfarchive_path.clone();
+    let buff = crate::fimfarchive::read_epub(&archive_path, &book.path)?;
+
+    let path = std::path::Path::new(&archive_path).join(&book.path);
+    let (book_id, report) = data.run(import_epub(&data.pool, path, buff))?;
+    let book_id = book_id.ok_or_else(|| report.failure(&book.path))?;
+    report.forget_updated(&data.text_index)?;
+
+    // refresh the library so the new book shows up, the results stay open to come back to
+    show_books(s, None)?;
+    if !report.imported.is_empty() {
+        notify(
+            s,
+            tr_args("Imported {} from the fimfarchive", &[&book.title]),
+        )?;
+    } else if !report.updated.is_empty() {
+        notify(
+            s,
+            tr_args(
+                "Updated {} to the version in the fimfarchive",
+                &[&book.title],
+            ),
+        )?;
+    }
+
+    open_book(s, book_id)
+}
+
+// ============================== SEARCH ALL ==============================
+
+#[derive(Clone, Debug)]
+enum SearchResult {
+    Library(Book),
+    Fimfarchive(FimfArchiveResult),
+}
+
+fn search_all_prompt(s: &mut Cursive) {
+    let mut search_view = EditView::new();
+    search_view.set_on_submit(try_view!(search_all));
+
+    let layout = LinearLayout::vertical()
+        .child(history_edit_view(
+            search_view,
+            "search all",
+            "search all query",
+        ))
+        .child(TextView::new("").with_name("search all status"));
+
+    s.add_layer(
+        Dialog::around(layout)
+            .title(tr("Search Library and Fimfarchive"))
+            .dismiss_button(tr("Close"))
+            .max_width(90),
+    );
+}
+
+fn search_all(s: &mut Cursive, query: &str) -> Result<(), Error> {
+    record_query(s, "search all", query)?;
+    let filter = Filter::parse(query)?;
+    let books = books_info(s)?
+        .into_iter()
+        .filter(|info| filter.matches(info))
+        .map(|info| info.book)
+        .collect::<Vec<Book>>();
+
+    search_fimfarchive_background(
+        s,
+        query,
+        "search all status",
+        Box::new(move |s, stories| search_all_results(s, books, stories)),
+    )
+}
+
+fn search_all_results(
+    s: &mut Cursive,
+    books: Vec<Book>,
+    stories: Vec<FimfArchiveResult>,
+) -> Result<(), Error> {
+    let mut results = SelectView::new();
+    results.set_on_select(set_search_all_details);
+    results.set_on_submit(try_view!(open_search_result));
+
+    for book in books {
+        results.add_item(
+            tr_args("[Library] {}", &[&book.title]),
+            SearchResult::Library(book),
+        );
+    }
+    for story in stories {
+        results.add_item(
+            tr_args("[Fimfarchive] {}", &[&story.title]),
+            SearchResult::Fimfarchive(story),
+        );
+    }
+
+    let first = results.get_item(0).map(|(_label, result)| result.clone());
+
+    let mut layout = LinearLayout::vertical();
+    layout.add_child(results.with_name("search all results").scrollable());
+    layout.add_child(Panel::new(ListView::new()));
+
+    let spoiler_key =
+        parse_key(&data(s)?.settings.keymap.reveal_spoilers).unwrap_or(Event::Char('s'));
+
+    s.add_layer(
+        OnEventView::new(
+            Dialog::around(layout.with_name("search all"))
+                .title(tr("Search Results"))
+                .dismiss_button(tr("Close"))
+                .max_width(90),
+        )
+        .on_event(spoiler_key, toggle_search_all_spoilers),
+    );
+
+    if let Some(result) = first {
+        set_search_all_details(s, &result);
+    }
+
+    Ok(())
+}
+
+/// Shows the details of the selected result, with the spoilers in story descriptions hidden.
+fn set_search_all_details(s: &mut Cursive, result: &SearchResult) {
+    if let Ok(data) = data(s) {
+        data.spoilers_revealed = false;
+    }
+    show_search_all_details(s, result);
+}
+
+fn toggle_search_all_spoilers(s: &mut Cursive) {
+    let selected = match s.find_name::<SelectView<SearchResult>>("search all results") {
+        Some(results) => results.selection(),
+        None => None,
+    };
+    if let (Some(result), Ok(data)) = (selected, data(s)) {
+        data.spoilers_revealed = !data.spoilers_revealed;
+        show_search_all_details(s, &result);
+    }
+}
+
+fn show_search_all_details(s: &mut Cursive, result: &SearchResult) {
+    let reveal_key = match data(s) {
+        Ok(data) if data.spoilers_revealed => None,
+        Ok(data) => Some(data.settings.keymap.reveal_spoilers.clone()),
+        Err(_) => Some("s".to_string()),
+    };
+
+    let detail_view = match result {
+        SearchResult::Library(book) => {
+            let mut detail_view = LinearLayout::vertical();
+            detail_view.add_child(TextView::new(
+                [
+                    tr_args("Title: {}", &[&book.title]),
+                    tr_args(
+                        "Author: {}",
+                        &[&book.creator.as_deref().unwrap_or(tr("Unknown"))],
+                    ),
+                    tr_args("Words: {}", &[&format_number(book.words)]),
+                    tr("In your library, press enter to open it.").to_string(),
+                    String::new(),
+                    String::new(),
+                ]
+                .join("\n"),
+            ));
+            if let Some(description) = &book.description {
+                detail_view.add_child(MarkupView::html(description));
+            }
+            detail_view
+        }
+        SearchResult::Fimfarchive(story) => fimfarchive_details(story, reveal_key.as_deref()),
+    };
+
+    let mut layout = s.find_name::<LinearLayout>("search all").unwrap();
+
+    layout.remove_child(1);
+    layout.add_child(Panel::new(detail_view.scrollable()).title(tr("Details")));
+}
+
+fn open_search_result(s: &mut Cursive, result: &SearchResult) -> Result<(), Error> {
+    match result {
+        SearchResult::Library(book) => open_book(s, book.id),
+        SearchResult::Fimfarchive(story) => import_fimfarchive(s, story),
+    }
 }
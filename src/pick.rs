@@ -0,0 +1,73 @@
+use crate::library::get_books;
+use crate::Error;
+use sqlx::SqlitePool;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+/// The picker `ereader pick` runs unless another one is given,
+/// anything that reads lines on stdin and prints the chosen one works, e.g. "fzf --reverse" or "sk".
+pub const DEFAULT_PICKER: &str = "fzf";
+
+/// A line for each book with its title, author, and id separated by tabs,
+/// so pickers can match on the title and the id can be read back from the chosen line.
+pub async fn book_lines() -> Result<String, Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let mut books = get_books(&pool).await?;
+    pool.close().await;
+    books.sort_by_key(|book| book.title.to_lowercase());
+
+    // tabs and line breaks in a title would split it into more fields or lines
+    let clean = |text: &str| text.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    Ok(books
+        .iter()
+        .map(|book| {
+            format!(
+                "{}\t{}\t{}\n",
+                clean(&book.title),
+                clean(book.creator.as_deref().unwrap_or_default()),
+                book.id
+            )
+        })
+        .collect())
+}
+
+/// The book id at the end of a line from `book_lines`, a bare id works too.
+pub fn book_id(line: &str) -> Option<Hyphenated> {
+    let id = line.trim().rsplit('\t').next()?;
+    Uuid::parse_str(id).ok().map(Hyphenated::from)
+}
+
+/// Runs the picker on the book lines, returning the chosen book or None if nothing was picked.
+pub fn pick(picker: &str, lines: &str) -> Result<Option<Hyphenated>, Error> {
+    let mut args = picker.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| Error::CommandFailed("an empty picker".to_string()))?;
+
+    // the picker draws its interface on stderr or the tty so only the choice comes back on stdout
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(lines.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+
+    // fzf exits with 130 when it's cancelled and 1 when nothing matched
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let chosen = String::from_utf8_lossy(&output.stdout);
+    match chosen.lines().next() {
+        Some(line) => book_id(line)
+            .map(Some)
+            .ok_or_else(|| Error::DebugMsg(format!("no book id in the picked line {}", line))),
+        None => Ok(None),
+    }
+}
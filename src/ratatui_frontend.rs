@@ -0,0 +1,393 @@
+use crate::library::{decompress_chapter, Chapter};
+use crate::view_model::{self, update, Backend, Msg, Page};
+use crate::Error;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::{Backend as TerminalBackend, CrosstermBackend};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Spans;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use sqlx::SqlitePool;
+use tokio::runtime::Runtime;
+use uuid::adapter::Hyphenated;
+
+/// Lines moved by page up and page down in the reader.
+const PAGE_LINES: u16 = 20;
+
+/// The ratatui interface, it draws the pages from `view_model` and renders every frame itself.
+///
+/// Only the basics are here: the library, reading, the table of contents, bookmarks, and fimfarchive search.
+struct App {
+    runtime: Runtime,
+    backend: Backend,
+    model: view_model::Model,
+    /// the selection of whichever list the page shows
+    list: ListState,
+    /// how many lines down the reader is scrolled
+    scroll: u16,
+    /// where to scroll once the chapter has been wrapped, from a bookmark
+    pending_progress: Option<f32>,
+    /// the open chapter wrapped to the reader's width, kept until the chapter or width changes
+    chapter_lines: Option<(Hyphenated, u16, Vec<String>)>,
+    /// the fimfarchive query being typed
+    query: String,
+    /// next was pressed on the last chapter, asking whether to mark the book finished
+    end_of_book: bool,
+    quit: bool,
+}
+
+pub fn run() -> Result<(), Error> {
+    let runtime = Runtime::new()?;
+    let pool = runtime.block_on(SqlitePool::connect("ereader.sqlite"))?;
+    let settings = runtime.block_on(crate::settings::Settings::load(&pool))?;
+    let books = runtime.block_on(crate::library::get_books(&pool))?;
+    let (schema, index, reader) =
+        crate::fimfarchive::open(&settings.index_path, settings.index_tuning)?;
+
+    let mut app = App {
+        runtime,
+        backend: Backend {
+            pool,
+            schema,
+            index,
+            reader,
+            scan_path: settings.epub_path.clone(),
+        },
+        model: view_model::Model::new(books),
+        list: ListState::default(),
+        scroll: 0,
+        pending_progress: None,
+        chapter_lines: None,
+        query: String::new(),
+        end_of_book: false,
+        quit: false,
+    };
+    app.list.select(Some(0));
+
+    enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = app.event_loop(&mut terminal);
+
+    // the terminal is put back even when something failed so the error can be read
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+impl App {
+    fn event_loop<B: TerminalBackend>(&mut self, terminal: &mut Terminal<B>) -> Result<(), Error> {
+        while !self.quit {
+            terminal.draw(|f| self.draw(f))?;
+
+            if let Event::Key(key) = event::read()? {
+                // some terminals also report releases
+                if key.kind == KeyEventKind::Press {
+                    self.key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a message and everything it loads.
+    fn dispatch(&mut self, msg: Msg) {
+        let mut effect = update(&mut self.model, msg);
+        while let Some(next) = effect {
+            let msg = self.runtime.block_on(self.backend.perform(next));
+            if let Msg::Loaded(page) = &msg {
+                // a new page starts at the top, a chapter scrolls to its bookmark once it's wrapped
+                self.list.select(Some(0));
+                self.scroll = 0;
+                self.pending_progress = match page {
+                    Page::Chapter(_, progress) => *progress,
+                    _ => None,
+                };
+            }
+            effect = update(&mut self.model, msg);
+        }
+    }
+
+    fn key(&mut self, code: KeyCode) {
+        if self.model.error.is_some() {
+            self.dispatch(Msg::DismissError);
+            return;
+        }
+
+        if self.end_of_book {
+            self.end_of_book = false;
+            if code == KeyCode::Char('y') {
+                self.dispatch(Msg::MarkFinished);
+            }
+            return;
+        }
+
+        if let Page::FimfArchiveSearch = self.model.page {
+            match code {
+                KeyCode::Char(c) => self.query.push(c),
+                KeyCode::Backspace => {
+                    self.query.pop();
+                }
+                KeyCode::Enter => self.dispatch(Msg::FimfArchiveSearch(self.query.clone())),
+                KeyCode::Esc => self.dispatch(Msg::GoLibrary),
+                _ => {}
+            }
+            return;
+        }
+
+        match code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Esc => self.dispatch(Msg::GoLibrary),
+            KeyCode::Char('b') => self.dispatch(Msg::GoBookmarks),
+            KeyCode::Char('s') => self.dispatch(Msg::Scan),
+            KeyCode::Char('f') => {
+                self.query.clear();
+                self.dispatch(Msg::GoFimfArchiveSearch);
+            }
+            _ if matches!(self.model.page, Page::Chapter(_, _)) => self.reader_key(code),
+            _ => self.list_key(code),
+        }
+    }
+
+    fn reader_key(&mut self, code: KeyCode) {
+        let (book_id, chapter_id) = match &self.model.page {
+            Page::Chapter(chapter, _) => (chapter.book_id, chapter.id),
+            _ => return,
+        };
+
+        match code {
+            KeyCode::Down | KeyCode::Char('j') => self.scroll_by(1),
+            KeyCode::Up | KeyCode::Char('k') => self.scroll_by(-1),
+            KeyCode::PageDown | KeyCode::Char(' ') => self.scroll_by(PAGE_LINES as i32),
+            KeyCode::PageUp => self.scroll_by(-(PAGE_LINES as i32)),
+            // there's no next chapter to load
+            KeyCode::Char('n') if self.model.is_last_chapter() => self.end_of_book = true,
+            KeyCode::Char('n') => self.dispatch(Msg::NextChapter),
+            KeyCode::Char('p') => self.dispatch(Msg::PrevChapter),
+            KeyCode::Char('t') => self.dispatch(Msg::GoTOC),
+            // measured from the top of the screen like the cursive reader
+            KeyCode::Char('m') => {
+                let progress = self.scroll as f32 / self.chapter_len().max(1) as f32;
+                self.dispatch(Msg::SetBookmark(book_id, chapter_id, progress));
+            }
+            _ => {}
+        }
+    }
+
+    fn list_key(&mut self, code: KeyCode) {
+        let len = self.list_len();
+        let selected = self.list.selected().unwrap_or(0);
+
+        match code {
+            KeyCode::Down | KeyCode::Char('j') if selected + 1 < len => {
+                self.list.select(Some(selected + 1))
+            }
+            KeyCode::Up | KeyCode::Char('k') if selected > 0 => {
+                self.list.select(Some(selected - 1))
+            }
+            KeyCode::Enter => {
+                if let Some(msg) = self.submit(selected) {
+                    self.dispatch(msg);
+                }
+            }
+            KeyCode::Char('d') => {
+                let bookmark_id = match &self.model.page {
+                    Page::Bookmarks(bookmarks, _) => {
+                        bookmarks.get(selected).map(|bookmark| bookmark.id)
+                    }
+                    _ => None,
+                };
+                if let Some(id) = bookmark_id {
+                    self.dispatch(Msg::DeleteBookmark(id));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// What picking the selected item of the page's list does.
+    fn submit(&self, selected: usize) -> Option<Msg> {
+        match &self.model.page {
+            Page::Library(books) => books
+                .get(selected)
+                .map(|book| Msg::GoChapterIndex(book.id, 1)),
+            Page::TableOfContents(toc, book_id) => Some(match toc.get(selected) {
+                Some(entry) => Msg::GoChapterId(entry.chapter_id),
+                // an empty toc only offers the start of the book
+                None => Msg::GoChapterIndex(*book_id, 1),
+            }),
+            Page::Bookmarks(bookmarks, _) => bookmarks
+                .get(selected)
+                .map(|bookmark| Msg::GoChapterIdBookmark(bookmark.chapter_id, bookmark.progress)),
+            _ => None,
+        }
+    }
+
+    fn list_len(&self) -> usize {
+        match &self.model.page {
+            Page::Library(books) => books.len(),
+            Page::TableOfContents(toc, _) => toc.len().max(1),
+            Page::Bookmarks(bookmarks, _) => bookmarks.len(),
+            Page::FimfArchiveResults(results) => results.len(),
+            _ => 0,
+        }
+    }
+
+    fn chapter_len(&self) -> usize {
+        self.chapter_lines
+            .as_ref()
+            .map(|(_, _, lines)| lines.len())
+            .unwrap_or(0)
+    }
+
+    fn scroll_by(&mut self, lines: i32) {
+        let last = self.chapter_len().saturating_sub(1) as i32;
+        self.scroll = (self.scroll as i32 + lines).clamp(0, last) as u16;
+    }
+
+    fn draw<B: TerminalBackend>(&mut self, f: &mut Frame<B>) {
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(f.size());
+        let (body, footer) = (areas[0], areas[1]);
+
+        match self.model.page.clone() {
+            Page::Library(books) => self.draw_list(
+                f,
+                body,
+                "Library",
+                books.iter().map(|book| book.title.clone()).collect(),
+            ),
+            Page::Chapter(chapter, _) => self.draw_chapter(f, body, &chapter),
+            Page::TableOfContents(toc, _) => {
+                let mut items = toc
+                    .iter()
+                    .map(|entry| format!("{}{}", "  ".repeat(entry.depth as usize), entry.title))
+                    .collect::<Vec<String>>();
+                if items.is_empty() {
+                    items.push("No table of contents. Go to start.".to_string());
+                }
+                self.draw_list(f, body, "Table of Contents", items)
+            }
+            Page::Bookmarks(bookmarks, books) => self.draw_list(
+                f,
+                body,
+                "Bookmarks",
+                bookmarks
+                    .iter()
+                    .zip(books.iter())
+                    .map(|(bookmark, book)| {
+                        format!("{} ({:.0}%)", book.title, bookmark.progress * 100.0)
+                    })
+                    .collect(),
+            ),
+            Page::FimfArchiveSearch => {
+                let input = Paragraph::new(format!("{}_", self.query)).block(
+                    Block::default()
+                        .title("fimfarchive search")
+                        .borders(Borders::ALL),
+                );
+                f.render_widget(input, body);
+            }
+            Page::FimfArchiveResults(results) => self.draw_list(
+                f,
+                body,
+                "fimfarchive results",
+                results
+                    .iter()
+                    .map(|result| format!("{} by {}", result.title, result.author))
+                    .collect(),
+            ),
+        }
+
+        let status = match &self.model.error {
+            Some(error) => format!("Error: {} (press any key)", error),
+            None if self.end_of_book => "End of book — mark finished? y/n".to_string(),
+            None => self.help().to_string(),
+        };
+        f.render_widget(Paragraph::new(status), footer);
+    }
+
+    fn help(&self) -> &'static str {
+        match self.model.page {
+            Page::Chapter(_, _) => "j/k scroll  space page  n/p next/prev  t toc  m bookmark  b bookmarks  esc library  q quit",
+            Page::Bookmarks(_, _) => "enter open  d delete  esc library  q quit",
+            Page::FimfArchiveSearch => "enter search  esc library",
+            _ => "enter open  b bookmarks  s scan  f fimfarchive  esc library  q quit",
+        }
+    }
+
+    fn draw_list<B: TerminalBackend>(
+        &mut self,
+        f: &mut Frame<B>,
+        area: Rect,
+        title: &str,
+        items: Vec<String>,
+    ) {
+        let list = List::new(items.into_iter().map(ListItem::new).collect::<Vec<_>>())
+            .block(
+                Block::default()
+                    .title(title.to_string())
+                    .borders(Borders::ALL),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, area, &mut self.list);
+    }
+
+    fn draw_chapter<B: TerminalBackend>(
+        &mut self,
+        f: &mut Frame<B>,
+        area: Rect,
+        chapter: &Chapter,
+    ) {
+        let width = area.width.saturating_sub(2).max(1);
+        let wrapped =
+            matches!(&self.chapter_lines, Some((id, w, _)) if *id == chapter.id && *w == width);
+        if !wrapped {
+            let lines = chapter_text(chapter, width)
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<String>>();
+            self.chapter_lines = Some((chapter.id, width, lines));
+        }
+        if let Some(progress) = self.pending_progress.take() {
+            self.scroll = (self.chapter_len() as f32 * progress) as u16;
+        }
+
+        let title = match &chapter.title {
+            Some(title) => title.clone(),
+            None => format!("Chapter {}", chapter.index),
+        };
+        let lines = self
+            .chapter_lines
+            .as_ref()
+            .map(|(_, _, lines)| {
+                lines
+                    .iter()
+                    .map(|line| Spans::from(line.clone()))
+                    .collect::<Vec<Spans>>()
+            })
+            .unwrap_or_default();
+        let reader = Paragraph::new(lines)
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .scroll((self.scroll, 0));
+        f.render_widget(reader, area);
+    }
+}
+
+/// The chapter's html as plain text wrapped to the width.
+fn chapter_text(chapter: &Chapter, width: u16) -> String {
+    match decompress_chapter(&chapter.content) {
+        Ok(html) => html2text::from_read(html.as_slice(), width as usize),
+        Err(e) => format!("unable to read the chapter: {}", e),
+    }
+}
@@ -0,0 +1,533 @@
+use crate::Error;
+use cursive::theme::{BaseColor, Color, Effect, Style};
+use cursive::XY;
+use cursive_markup::{Element, RenderedDocument, Renderer};
+use html2text::render::text_renderer::{RichAnnotation, TaggedLineElement};
+use regex::Regex;
+use scraper::{Html, Node, Selector};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Marks the start of colored text, the palette index is added to it.
+const COLOR_START: u32 = 0xE000;
+/// Marks the end of the most recently started color.
+const COLOR_END: char = '\u{E0FF}';
+const MAX_COLORS: usize = 0xFF;
+
+/// Text colors with less contrast than this against the background are adjusted so they stay readable.
+const MIN_CONTRAST: f32 = 3.0;
+
+/// The line each element id starts on in the last render.
+pub type Anchors = Rc<RefCell<HashMap<String, usize>>>;
+
+/// Renders chapter html like `RichRenderer` but keeps the text colors set with css.
+///
+/// html2text drops colors, so before rendering, colored elements have their text wrapped in
+/// private use characters that index into a palette and those are turned into styles afterwards.
+pub struct ColorRenderer {
+    html: String,
+    palette: Vec<Color>,
+    anchors: Anchors,
+    /// what emphasized and strong text are drawn with, None draws them like the rest of the text
+    emphasis: Option<Effect>,
+    strong: Option<Effect>,
+    /// drawn across horizontal rules instead of box drawing lines
+    rule: Option<String>,
+}
+
+impl ColorRenderer {
+    /// `background` is the color the text is drawn on, used to keep colored text readable.
+    pub fn new(html: &str, background: Color) -> Self {
+        let (html, palette) = mark_colors(html, background);
+        ColorRenderer {
+            html,
+            palette,
+            anchors: Anchors::default(),
+            emphasis: Some(Effect::Italic),
+            strong: Some(Effect::Bold),
+            rule: None,
+        }
+    }
+
+    /// Renders emphasized text like normal text.
+    pub fn without_italics(mut self) -> Self {
+        self.emphasis = None;
+        self
+    }
+
+    /// Draws emphasized and strong text with other effects, e.g. underlined for terminals that can't do italics.
+    pub fn with_effects(mut self, emphasis: Option<Effect>, strong: Option<Effect>) -> Self {
+        self.emphasis = emphasis;
+        self.strong = strong;
+        self
+    }
+
+    /// Draws horizontal rules with the characters of `rule` repeated across the line.
+    pub fn with_rule(mut self, rule: &str) -> Self {
+        self.rule = Some(rule.to_string());
+        self
+    }
+
+    /// Where the elements with ids ended up, updated every time the document is rendered.
+    pub fn anchors(&self) -> Anchors {
+        self.anchors.clone()
+    }
+
+    /// The same styles `RichRenderer` uses, except for the effects of emphasized and strong text.
+    fn annotation_style(&self, annotation: &RichAnnotation) -> Option<Style> {
+        match annotation {
+            RichAnnotation::Link(_) => Some(Effect::Underline.into()),
+            RichAnnotation::Emphasis => self.emphasis.map(Style::from),
+            RichAnnotation::Strong => self.strong.map(Style::from),
+            RichAnnotation::Strikeout => Some(Effect::Strikethrough.into()),
+            _ => None,
+        }
+    }
+}
+
+impl Renderer for ColorRenderer {
+    fn render(&self, constraint: XY<usize>) -> RenderedDocument {
+        let mut doc = RenderedDocument::new(constraint);
+        let mut colors: Vec<Color> = Vec::new();
+        let mut anchors = self.anchors.borrow_mut();
+        anchors.clear();
+
+        let lines = html2text::from_read_rich(self.html.as_bytes(), constraint.x);
+        for (line_number, line) in lines.into_iter().enumerate() {
+            let mut elements = Vec::new();
+
+            for element in line.iter() {
+                if let TaggedLineElement::FragmentStart(fragment) = element {
+                    anchors.entry(fragment.clone()).or_insert(line_number);
+                }
+                if let TaggedLineElement::Str(ts) = element {
+                    let mut link_target = None;
+                    let mut style = Style::none();
+                    for annotation in &ts.tag {
+                        if let RichAnnotation::Link(target) = annotation {
+                            link_target = Some(target.clone());
+                        }
+                        if let Some(annotation_style) = self.annotation_style(annotation) {
+                            style = style.combine(annotation_style);
+                        }
+                    }
+
+                    let rule;
+                    let s = match &self.rule {
+                        Some(chars) if !ts.s.is_empty() && ts.s.chars().all(|c| c == '─') => {
+                            rule = chars
+                                .chars()
+                                .cycle()
+                                .take(ts.s.chars().count())
+                                .collect::<String>();
+                            &rule
+                        }
+                        _ => &ts.s,
+                    };
+
+                    let mut text = String::new();
+                    for c in s.chars() {
+                        let marker = c as u32;
+                        if c == COLOR_END
+                            || (COLOR_START..COLOR_START + MAX_COLORS as u32).contains(&marker)
+                        {
+                            if !text.is_empty() {
+                                elements.push(element_with_color(
+                                    std::mem::take(&mut text),
+                                    style,
+                                    colors.last(),
+                                    &link_target,
+                                ));
+                            }
+                            if c == COLOR_END {
+                                colors.pop();
+                            } else if let Some(color) =
+                                self.palette.get((marker - COLOR_START) as usize)
+                            {
+                                colors.push(*color);
+                            }
+                        } else {
+                            text.push(c);
+                        }
+                    }
+                    if !text.is_empty() {
+                        elements.push(element_with_color(text, style, colors.last(), &link_target));
+                    }
+                }
+            }
+
+            doc.push_line(elements);
+        }
+
+        doc
+    }
+}
+
+fn element_with_color(
+    text: String,
+    style: Style,
+    color: Option<&Color>,
+    link_target: &Option<String>,
+) -> Element {
+    let style = match color {
+        Some(color) => style.combine(*color),
+        None => style,
+    };
+    Element::new(text, style, link_target.clone())
+}
+
+/// Wraps the text of every element with a css color in color markers.
+/// Returns the marked html and the palette the markers index into.
+fn mark_colors(html: &str, background: Color) -> (String, Vec<Color>) {
+    let mut document = Html::parse_document(html);
+    let class_colors = stylesheet_colors(&document);
+
+    let mut palette = Vec::new();
+    let mut marked = Vec::new();
+    for node in document.tree.nodes() {
+        let element = match node.value() {
+            Node::Element(element) => element,
+            _ => continue,
+        };
+
+        let color = element
+            .attr("style")
+            .and_then(style_color)
+            .or_else(|| element.attr("color").and_then(parse_color))
+            .or_else(|| {
+                element
+                    .classes()
+                    .find_map(|class| class_colors.get(class).copied())
+            });
+
+        if let Some(color) = color {
+            if palette.len() >= MAX_COLORS {
+                break;
+            }
+            let color = readable(color, background);
+            let index = palette
+                .iter()
+                .position(|existing| *existing == color)
+                .unwrap_or_else(|| {
+                    palette.push(color);
+                    palette.len() - 1
+                });
+            marked.push((node.id(), index));
+        }
+    }
+
+    if marked.is_empty() {
+        return (html.to_string(), palette);
+    }
+
+    for (id, index) in marked {
+        let start = std::char::from_u32(COLOR_START + index as u32).unwrap_or(COLOR_END);
+        let mut node = document.tree.get_mut(id).unwrap();
+        node.prepend(Node::Text(scraper::node::Text {
+            text: start.to_string().as_str().into(),
+        }));
+        node.append(Node::Text(scraper::node::Text {
+            text: COLOR_END.to_string().as_str().into(),
+        }));
+    }
+
+    (document.root_element().html(), palette)
+}
+
+/// Replaces the contents of spoilers (`.spoiler` elements and `<details>` sections)
+/// with a note saying which key reveals them. The summary of a `<details>` section is kept.
+pub fn hide_spoilers(html: &str, reveal_key: &str) -> String {
+    let mut document = Html::parse_document(html);
+    let selector = Selector::parse(".spoiler, details").unwrap();
+
+    let spoilers = document
+        .select(&selector)
+        .map(|element| element.id())
+        .collect::<Vec<_>>();
+    if spoilers.is_empty() {
+        return html.to_string();
+    }
+
+    for id in spoilers {
+        let hidden = match document.tree.get(id) {
+            Some(node) => node
+                .children()
+                .filter(|child| {
+                    child
+                        .value()
+                        .as_element()
+                        .map(|element| element.name() != "summary")
+                        .unwrap_or(true)
+                })
+                .map(|child| child.id())
+                .collect::<Vec<_>>(),
+            None => continue,
+        };
+        for child in hidden {
+            if let Some(mut child) = document.tree.get_mut(child) {
+                child.detach();
+            }
+        }
+
+        if let Some(mut node) = document.tree.get_mut(id) {
+            node.append(Node::Text(scraper::node::Text {
+                text: format!("[spoiler, press {} to reveal]", reveal_key)
+                    .as_str()
+                    .into(),
+            }));
+        }
+    }
+
+    document.root_element().html()
+}
+
+/// The epub:type values of elements holding the text of a note.
+const NOTE_TYPES: [&str; 4] = ["footnote", "endnote", "rearnote", "note"];
+
+fn has_epub_type(element: &scraper::node::Element, types: &[&str]) -> bool {
+    element
+        .attr("epub:type")
+        .map(|value| value.split_whitespace().any(|kind| types.contains(&kind)))
+        .unwrap_or(false)
+}
+
+/// The hrefs of the links to notes, either marked as noterefs or pointing at a footnote in the same chapter.
+pub fn noterefs(html: &str) -> HashSet<String> {
+    let document = Html::parse_document(html);
+    let links = Selector::parse("a[href]").unwrap();
+    let notes = Selector::parse("[id]").unwrap();
+
+    let note_ids = document
+        .select(&notes)
+        .filter(|element| has_epub_type(element.value(), &NOTE_TYPES))
+        .filter_map(|element| element.value().id())
+        .collect::<HashSet<&str>>();
+
+    document
+        .select(&links)
+        .filter(|link| {
+            let href = link.value().attr("href").unwrap_or_default();
+            has_epub_type(link.value(), &["noteref"])
+                || href
+                    .strip_prefix('#')
+                    .map(|id| note_ids.contains(id))
+                    .unwrap_or(false)
+        })
+        .filter_map(|link| link.value().attr("href"))
+        .map(|href| href.to_string())
+        .collect()
+}
+
+/// The text of the note with an id, or None if the chapter doesn't have it.
+///
+/// Older books mark the note with an anchor at its start rather than putting the id on the note,
+/// so for inline elements the text of the surrounding block is used.
+pub fn note_text(html: &str, id: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let with_id = Selector::parse("[id]").unwrap();
+
+    let element = document
+        .select(&with_id)
+        .find(|element| element.value().id() == Some(id))?;
+    let element = match element.value().name() {
+        "a" | "span" | "sup" => element
+            .parent()
+            .and_then(scraper::ElementRef::wrap)
+            .unwrap_or(element),
+        _ => element,
+    };
+
+    let text = element
+        .text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Removes the elements matching any of the css selectors.
+pub fn strip_selectors(html: &str, selectors: &[String]) -> Result<String, Error> {
+    let mut document = Html::parse_document(html);
+
+    let mut ids = Vec::new();
+    for selector in selectors {
+        let parsed = Selector::parse(selector)
+            .map_err(|_| Error::InvalidSetting("strip selectors".to_string(), selector.clone()))?;
+        ids.extend(document.select(&parsed).map(|element| element.id()));
+    }
+
+    if ids.is_empty() {
+        return Ok(html.to_string());
+    }
+
+    for id in ids {
+        if let Some(mut node) = document.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+
+    Ok(document.root_element().html())
+}
+
+/// Turns divs and double line breaks into paragraphs so books that don't use paragraphs get blank lines between them.
+pub fn force_paragraph_spacing(html: &str) -> String {
+    let div_re = Regex::new(r#"(?i)<(/?)div\b"#).unwrap();
+    let double_br_re = Regex::new(r#"(?i)<br\s*/?>\s*<br\s*/?>"#).unwrap();
+
+    let html = div_re.replace_all(html, "<${1}p");
+    double_br_re.replace_all(&html, "</p><p>").to_string()
+}
+
+/// Colors set on simple `.class` selectors in the chapter's style elements.
+fn stylesheet_colors(document: &Html) -> HashMap<String, (u8, u8, u8)> {
+    let style_selector = scraper::Selector::parse("style").unwrap();
+    let rule_re = Regex::new(r#"\.([A-Za-z0-9_-]+)\s*\{([^}]*)\}"#).unwrap();
+
+    let mut colors = HashMap::new();
+    for style in document.select(&style_selector) {
+        let css = style.text().collect::<String>();
+        for caps in rule_re.captures_iter(&css) {
+            if let Some(color) = style_color(&caps[2]) {
+                colors.insert(caps[1].to_string(), color);
+            }
+        }
+    }
+    colors
+}
+
+/// Finds the `color` declaration in a css declaration block.
+fn style_color(style: &str) -> Option<(u8, u8, u8)> {
+    style.split(';').find_map(|declaration| {
+        let mut parts = declaration.splitn(2, ':');
+        let property = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if property.eq_ignore_ascii_case("color") {
+            parse_color(value.trim_end_matches("!important").trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_color(value: &str) -> Option<(u8, u8, u8)> {
+    let value = value.trim().to_lowercase();
+
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+        return match hex.len() {
+            3 => {
+                let mut digits = hex.chars().map(|c| channel(&c.to_string()).map(|v| v * 17));
+                Some((digits.next()??, digits.next()??, digits.next()??))
+            }
+            6 => Some((
+                channel(&hex[0..2])?,
+                channel(&hex[2..4])?,
+                channel(&hex[4..6])?,
+            )),
+            _ => None,
+        };
+    }
+
+    let rgb_re = Regex::new(r#"^rgba?\(\s*(\d+)\s*,\s*(\d+)\s*,\s*(\d+)"#).unwrap();
+    if let Some(caps) = rgb_re.captures(&value) {
+        let channel = |i: usize| caps[i].parse::<u16>().ok().map(|v| v.min(255) as u8);
+        return Some((channel(1)?, channel(2)?, channel(3)?));
+    }
+
+    match value.as_str() {
+        "black" => Some((0, 0, 0)),
+        "white" => Some((255, 255, 255)),
+        "red" => Some((255, 0, 0)),
+        "lime" => Some((0, 255, 0)),
+        "green" => Some((0, 128, 0)),
+        "blue" => Some((0, 0, 255)),
+        "yellow" => Some((255, 255, 0)),
+        "cyan" | "aqua" => Some((0, 255, 255)),
+        "magenta" | "fuchsia" => Some((255, 0, 255)),
+        "gray" | "grey" => Some((128, 128, 128)),
+        "silver" => Some((192, 192, 192)),
+        "maroon" => Some((128, 0, 0)),
+        "olive" => Some((128, 128, 0)),
+        "navy" => Some((0, 0, 128)),
+        "purple" => Some((128, 0, 128)),
+        "teal" => Some((0, 128, 128)),
+        "orange" => Some((255, 165, 0)),
+        "pink" => Some((255, 192, 203)),
+        "brown" => Some((165, 42, 42)),
+        "gold" => Some((255, 215, 0)),
+        "indigo" => Some((75, 0, 130)),
+        "violet" => Some((238, 130, 238)),
+        _ => None,
+    }
+}
+
+/// Approximate rgb values for the terminal colors.
+fn approximate_rgb(color: Color) -> (u8, u8, u8) {
+    let base = |base: BaseColor, light: bool| {
+        let on = if light { 255 } else { 170 };
+        let off = if light { 85 } else { 0 };
+        match base {
+            BaseColor::Black => (off, off, off),
+            BaseColor::Red => (on, off, off),
+            BaseColor::Green => (off, on, off),
+            BaseColor::Yellow => (on, on, off),
+            BaseColor::Blue => (off, off, on),
+            BaseColor::Magenta => (on, off, on),
+            BaseColor::Cyan => (off, on, on),
+            BaseColor::White => (on, on, on),
+        }
+    };
+
+    match color {
+        Color::Rgb(r, g, b) => (r, g, b),
+        Color::Dark(color) => base(color, false),
+        Color::Light(color) => base(color, true),
+        // assume the common case of a dark terminal
+        _ => (0, 0, 0),
+    }
+}
+
+fn luminance((r, g, b): (u8, u8, u8)) -> f32 {
+    let channel = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(r) + 0.7152 * channel(g) + 0.0722 * channel(b)
+}
+
+fn contrast(a: (u8, u8, u8), b: (u8, u8, u8)) -> f32 {
+    let (a, b) = (luminance(a), luminance(b));
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+/// Moves a color towards black or white (whichever is further from the background)
+/// until it is readable on the background.
+fn readable(color: (u8, u8, u8), background: Color) -> Color {
+    let background = approximate_rgb(background);
+    let target = if luminance(background) > 0.5 {
+        0.0
+    } else {
+        255.0
+    };
+
+    let mut adjusted = color;
+    for step in 1..=10 {
+        if contrast(adjusted, background) >= MIN_CONTRAST {
+            break;
+        }
+        let mix = |c: u8| (c as f32 + (target - c as f32) * step as f32 / 10.0).round() as u8;
+        adjusted = (mix(color.0), mix(color.1), mix(color.2));
+    }
+
+    Color::Rgb(adjusted.0, adjusted.1, adjusted.2)
+}
@@ -0,0 +1,198 @@
+use crate::formats::ParsedBook;
+use crate::library;
+use crate::scan::reparse;
+use crate::text_index::{TextIndex, TEXT_INDEX_PATH};
+use crate::Error;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Default)]
+pub struct ReprocessReport {
+    pub reprocessed: usize,
+    /// books whose original wasn't kept and whose files are gone
+    pub missing: Vec<Hyphenated>,
+}
+
+impl std::fmt::Display for ReprocessReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Reprocessed {} books.", self.reprocessed)?;
+        if !self.missing.is_empty() {
+            write!(
+                f,
+                "\nUnable to find the files of {} books:",
+                self.missing.len()
+            )?;
+            for book_id in &self.missing {
+                write!(f, "\n  {}", book_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs books through the import pipeline again, e.g. after the html cleanup or the toc parsing improves.
+///
+/// An empty `book_ids` reprocesses the whole library.
+pub async fn reprocess(
+    pool: &SqlitePool,
+    text_index: &TextIndex,
+    book_ids: &[Hyphenated],
+) -> Result<ReprocessReport, Error> {
+    let book_ids = if book_ids.is_empty() {
+        library::get_books(pool)
+            .await?
+            .into_iter()
+            .map(|book| book.id)
+            .collect()
+    } else {
+        book_ids.to_vec()
+    };
+
+    let level = crate::settings::Settings::load(pool)
+        .await?
+        .compression_level;
+    let mut report = ReprocessReport::default();
+    for book_id in book_ids {
+        if reprocess_book(pool, book_id, level).await? {
+            text_index.remove_book(book_id)?;
+            report.reprocessed += 1;
+        } else {
+            report.missing.push(book_id);
+        }
+    }
+    // the text of the reprocessed books is indexed again
+    text_index.update(pool).await?;
+
+    Ok(report)
+}
+
+/// Replaces a book's chapters and navigation with the ones parsed again from its original file.
+///
+/// Chapters keep their ids by their position in the book since the ids come from the content,
+/// so bookmarks, annotations, and the reading position stay where they were.
+/// The metadata is left alone in case it was edited.
+/// Returns false if the original file is gone.
+pub async fn reprocess_book(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    compression_level: i32,
+) -> Result<bool, Error> {
+    match reparse(pool, book_id).await? {
+        Some(parsed) => {
+            replace_book(pool, book_id, parsed, compression_level).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Replaces a book's chapters and navigation with the ones from `parsed`, keeping the chapter ids by position.
+///
+/// `parsed` can be a different file than the book was imported from, e.g. a newer version of a fimfiction story,
+/// everything in it is moved to `book_id`. The chapters are compressed at `compression_level`.
+pub async fn replace_book(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    parsed: ParsedBook,
+    compression_level: i32,
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    replace_book_tx(&mut tx, book_id, parsed, compression_level).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Like `replace_book` but in a transaction the caller commits, so other changes to the book go with it.
+pub async fn replace_book_tx(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    book_id: Hyphenated,
+    mut parsed: ParsedBook,
+    compression_level: i32,
+) -> Result<(), Error> {
+    parsed.compress(compression_level)?;
+    let old_ids = library::get_chapter_ids_tx(tx, book_id).await?;
+
+    let mut ids = HashMap::new();
+    for (chapter, old_id) in parsed.chapters.iter_mut().zip(&old_ids) {
+        ids.insert(chapter.id, *old_id);
+        chapter.id = *old_id;
+    }
+    for chapter in &mut parsed.chapters {
+        chapter.book_id = book_id;
+    }
+    let chapter_id = |id: Hyphenated| ids.get(&id).copied().unwrap_or(id);
+    for toc in &mut parsed.toc {
+        toc.book_id = book_id;
+        toc.chapter_id = chapter_id(toc.chapter_id);
+    }
+    for landmark in &mut parsed.landmarks {
+        landmark.book_id = book_id;
+        landmark.chapter_id = chapter_id(landmark.chapter_id);
+    }
+    for page in &mut parsed.page_list {
+        page.book_id = book_id;
+        page.chapter_id = chapter_id(page.chapter_id);
+    }
+    for overlay in &mut parsed.media_overlays {
+        overlay.book_id = book_id;
+        overlay.chapter_id = chapter_id(overlay.chapter_id);
+    }
+
+    library::delete_book_navigation(tx, book_id).await?;
+
+    for (i, chapter) in parsed.chapters.iter().enumerate() {
+        if i < old_ids.len() {
+            library::replace_chapter(tx, chapter).await?;
+        } else {
+            library::insert_chapter(tx, chapter).await?;
+        }
+    }
+    // anything in the chapters that are gone moves to the end of the new last chapter
+    if let Some(last) = parsed.chapters.last().map(|chapter| chapter.id) {
+        for old_id in old_ids.iter().skip(parsed.chapters.len()) {
+            library::remove_chapter(tx, *old_id, last).await?;
+        }
+    }
+
+    for toc in &parsed.toc {
+        library::insert_toc(tx, toc).await?;
+    }
+    for landmark in &parsed.landmarks {
+        library::insert_landmark(tx, landmark).await?;
+    }
+    for page in &parsed.page_list {
+        library::insert_page_target(tx, page).await?;
+    }
+    for overlay in &parsed.media_overlays {
+        library::insert_media_overlay(tx, overlay).await?;
+    }
+    library::update_book_words(tx).await?;
+
+    Ok(())
+}
+
+/// Book ids from the command line or a job.
+pub fn parse_book_ids(book_ids: &[String]) -> Result<Vec<Hyphenated>, Error> {
+    book_ids
+        .iter()
+        .map(|id| {
+            Uuid::parse_str(id)
+                .map(Hyphenated::from)
+                .map_err(|_| Error::InvalidBookId(id.to_string()))
+        })
+        .collect()
+}
+
+/// Reprocesses books for the reprocess subcommand, all of them if no ids are given.
+pub async fn reprocess_command(book_ids: &[String]) -> Result<ReprocessReport, Error> {
+    let book_ids = parse_book_ids(book_ids)?;
+
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let tuning = crate::settings::Settings::load(&pool).await?.index_tuning;
+    let text_index = TextIndex::open(TEXT_INDEX_PATH, tuning)?;
+    let report = reprocess(&pool, &text_index, &book_ids).await;
+    pool.close().await;
+    report
+}
@@ -0,0 +1,149 @@
+use crate::library::*;
+use crate::Error;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+use std::fmt;
+use uuid::adapter::Hyphenated;
+
+/// The book fields a rule can match on.
+pub const RULE_FIELDS: &[&str] = &["author", "title", "publisher", "series", "language"];
+/// `tag` adds the target as a tag, `collection` adds the book to the target collection.
+pub const RULE_ACTIONS: &[&str] = &["tag", "collection"];
+
+/// Checks that a rule only uses known fields and actions and has something to match and add.
+pub fn validate(rule: &Rule) -> Result<(), Error> {
+    if !RULE_FIELDS.contains(&rule.field.as_str()) {
+        return Err(Error::InvalidRule(format!("unknown field {}", rule.field)));
+    }
+    if !RULE_ACTIONS.contains(&rule.action.as_str()) {
+        return Err(Error::InvalidRule(format!(
+            "unknown action {}",
+            rule.action
+        )));
+    }
+    if rule.pattern.trim().is_empty() {
+        return Err(Error::InvalidRule("the pattern is empty".to_string()));
+    }
+    if rule.target.trim().is_empty() {
+        return Err(Error::InvalidRule(format!("the {} is empty", rule.action)));
+    }
+    Ok(())
+}
+
+fn field<'a>(book: &'a Book, field: &str) -> Option<&'a str> {
+    match field {
+        "author" => book.creator.as_deref(),
+        "title" => Some(&book.title),
+        "publisher" => book.publisher.as_deref(),
+        "series" => book.series.as_deref(),
+        "language" => Some(&book.language),
+        _ => None,
+    }
+}
+
+/// Whether the rule's field contains (or equals) its pattern, ignoring case.
+pub fn matches(rule: &Rule, book: &Book) -> bool {
+    let value = match field(book, &rule.field) {
+        Some(value) => value.to_lowercase(),
+        None => return false,
+    };
+    let pattern = rule.pattern.to_lowercase();
+
+    if rule.exact {
+        value.trim() == pattern.trim()
+    } else {
+        value.contains(&pattern)
+    }
+}
+
+/// The rule written out like "if author contains X, add tag Y".
+pub fn describe(rule: &Rule) -> String {
+    let comparison = if rule.exact { "=" } else { "contains" };
+    let action = match rule.action.as_str() {
+        "collection" => "add to collection",
+        _ => "add tag",
+    };
+    format!(
+        "if {} {} {}, {} {}",
+        rule.field, comparison, rule.pattern, action, rule.target
+    )
+}
+
+/// What applying the rules added.
+#[derive(Clone, Debug, Default)]
+pub struct RuleReport {
+    pub tags: usize,
+    pub collections: usize,
+}
+
+impl fmt::Display for RuleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "added {} tags and {} books to collections",
+            self.tags, self.collections
+        )
+    }
+}
+
+/// Applies every rule to the books.
+///
+/// Rules only ever add, tags and collections the books already have are left alone.
+/// A collection that doesn't exist yet is created with a filter that only shows the books added to it.
+pub async fn apply_rules(pool: &SqlitePool, books: &[Book]) -> Result<RuleReport, Error> {
+    let rules = get_rules(pool).await?;
+    let mut report = RuleReport::default();
+    if rules.is_empty() {
+        return Ok(report);
+    }
+
+    let mut tags = get_all_book_tags(pool)
+        .await?
+        .into_iter()
+        .map(|tag| (tag.book_id, tag.tag))
+        .collect::<HashSet<(Hyphenated, String)>>();
+    let mut members = get_collection_books(pool)
+        .await?
+        .into_iter()
+        .map(|member| (member.collection, member.book_id))
+        .collect::<HashSet<(String, Hyphenated)>>();
+    let mut collections = get_collections(pool)
+        .await?
+        .into_iter()
+        .map(|collection| collection.name)
+        .collect::<HashSet<String>>();
+
+    for book in books {
+        for rule in rules.iter().filter(|rule| matches(rule, book)) {
+            let target = rule.target.trim().to_string();
+
+            match rule.action.as_str() {
+                "tag" => {
+                    if tags.insert((book.id, target.clone())) {
+                        add_book_tag(pool, book.id, &target).await?;
+                        report.tags += 1;
+                    }
+                }
+                "collection" => {
+                    if collections.insert(target.clone()) {
+                        insert_collection(
+                            pool,
+                            &Collection {
+                                name: target.clone(),
+                                query: format!("collection({})", target.replace(')', "\\)")),
+                            },
+                        )
+                        .await?;
+                    }
+                    if members.insert((target.clone(), book.id)) {
+                        add_collection_book(pool, &target, book.id).await?;
+                        report.collections += 1;
+                    }
+                }
+                action => return Err(Error::InvalidRule(format!("unknown action {}", action))),
+            }
+        }
+    }
+
+    Ok(report)
+}
@@ -1,159 +1,693 @@
-use crate::library::{self, Book, Chapter, Toc};
+use crate::convert::{convert, converter};
+use crate::formats::{insert_book, registry, BookFile, EpubImporter, Importer, ParsedBook};
+use crate::library;
+use crate::text_index::{TextIndex, TEXT_INDEX_PATH};
 use crate::Error;
-use futures::{stream, StreamExt, TryStreamExt};
-use percent_encoding::percent_decode_str;
+use futures::{stream, StreamExt};
+use regex::Regex;
+use scraper::{Html, Selector};
 use sqlx::SqlitePool;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 use uuid::adapter::Hyphenated;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-fn entries<P: AsRef<Path>>(path: P) -> impl Iterator<Item = walkdir::DirEntry> {
+/// Whether one of the importers can read the extension or it can be converted into an epub.
+fn importable(extension: &str, converters: &BTreeMap<String, String>) -> bool {
+    registry().handles_extension(extension) || converters.contains_key(&extension.to_lowercase())
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// The files that can be imported, and zip archives that might contain them.
+fn entries<'a, P: AsRef<Path>>(
+    path: P,
+    converters: &'a BTreeMap<String, String>,
+) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
     WalkDir::new(&path)
         .follow_links(true)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| e.path().extension().unwrap_or_default() == "epub")
+        .filter(move |e| {
+            let extension = e.path().extension().unwrap_or_default().to_string_lossy();
+            is_zip(e.path()) || importable(&extension, converters)
+        })
 }
 
 async fn get_file<P: AsRef<async_std::path::Path>>(path: P) -> Result<Vec<u8>, Error> {
     Ok(async_std::fs::read(path).await?)
 }
 
-fn hash(buff: Vec<u8>) -> (String, Vec<u8>) {
-    let hash = blake3::hash(buff.as_slice()).to_string();
-    (hash, buff)
-}
+/// Reads the books at a path.
+/// Zip archives are searched for books so downloads don't need to be extracted first,
+/// entries that can't be read are returned with the reason instead of failing the whole archive.
+async fn get_book_files(
+    path: PathBuf,
+    converters: &BTreeMap<String, String>,
+) -> Result<(Vec<BookFile>, Vec<(String, String)>), Error> {
+    let buff = get_file(path.as_path()).await?;
 
-fn process_epub(hash: String, buff: Vec<u8>) -> Result<(Book, Vec<Chapter>, Vec<Toc>), Error> {
-    let book_id = Uuid::new_v5(&Uuid::nil(), &buff);
+    if !is_zip(&path) {
+        return Ok((vec![BookFile::new(path, buff)], Vec::new()));
+    }
 
-    let mut doc = epub::doc::EpubDoc::from_reader(std::io::Cursor::new(buff))?;
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(buff))?;
+    let mut files = Vec::new();
+    let mut failed = Vec::new();
 
-    let spine = doc.spine.clone();
-    let chapters = spine
-        .into_iter()
-        .enumerate()
-        .map(|(i, id)| {
-            let content = doc.get_resource_str(&id[..])?;
-            // chapters within the same book could have the same contents
-            // using another level of uuid with the chapter index to avoid that
-            let chapter_index_id = Uuid::new_v5(&book_id, &i.to_le_bytes());
-            let chapter_id = Uuid::new_v5(&chapter_index_id, content.as_bytes());
-
-            Ok(Chapter {
-                id: Hyphenated::from(chapter_id),
-                book_id: Hyphenated::from(book_id),
-                index: i as i64 + 1,
-                content: zstd::stream::encode_all(content.as_bytes(), 8)?,
-            })
-        })
-        .collect::<Result<Vec<Chapter>, Error>>()?;
-
-    let toc = doc
-        .toc
-        .iter()
-        .enumerate()
-        .map(|(index, nav)| {
-            // Some TOC links have a fragment to jump to a specific spot in the chapter.
-            // I need to remove that so the link can be turned into a spine index.
-            let mut url =
-                url::Url::parse(&format!("epub:///{}", nav.content.to_string_lossy())[..])?;
-            url.set_fragment(None);
-
-            let absolute_path = url.to_string();
-            let relative_path = absolute_path.trim_start_matches("epub:///");
-            let decoded_path = percent_decode_str(relative_path)
-                .decode_utf8_lossy()
-                .to_string();
-
-            let mut content_path = PathBuf::new();
-            content_path.push(decoded_path);
-
-            let spine_index = match doc.resource_uri_to_chapter(&content_path) {
-                Some(i) => Ok(i),
-                None => Err(Error::EpubMissingTocResource),
-            }? as i64;
-
-            Ok(Toc {
-                id: 0,
-                book_id: Hyphenated::from(book_id),
-                index: index as i64,
-                chapter_id: chapters[spine_index as usize].id,
-                title: nav.label.clone(),
-            })
-        })
-        .collect::<Result<Vec<Toc>, Error>>()?;
-
-    Ok((
-        Book {
-            id: Hyphenated::from(book_id),
-            identifier: get_metadata(&doc, "identifier")?,
-            language: get_metadata(&doc, "language")?,
-            title: get_metadata(&doc, "title")?,
-            creator: doc.mdata("creator"),
-            description: doc.mdata("description"),
-            publisher: doc.mdata("publisher"),
-            hash,
-        },
-        chapters,
-        toc,
-    ))
+    for i in 0..archive.len() {
+        let mut file = match archive.by_index(i) {
+            Ok(file) => file,
+            Err(e) => {
+                failed.push((format!("{} entry {}", path.display(), i), e.to_string()));
+                continue;
+            }
+        };
+        let name = file.name().to_string();
+        let extension = Path::new(&name)
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if file.is_dir() || !importable(&extension, converters) {
+            continue;
+        }
+
+        // the archive path is kept as a prefix so it's clear where the book came from
+        let book_path = path.join(name);
+        let mut contents = Vec::new();
+        if let Err(e) = file.read_to_end(&mut contents) {
+            failed.push((book_path.to_string_lossy().to_string(), e.to_string()));
+            continue;
+        }
+        files.push(BookFile::new(book_path, contents));
+    }
+
+    Ok((files, failed))
 }
 
-type Epub = epub::doc::EpubDoc<std::io::Cursor<Vec<u8>>>;
+/// The text of a chapter's body without any markup.
+pub fn text(content: &str) -> String {
+    let document = Html::parse_document(content);
+    let body = Selector::parse("body").unwrap();
 
-fn get_metadata(doc: &Epub, tag: &str) -> Result<String, Error> {
-    doc.mdata(tag)
-        .ok_or_else(|| Error::MissingMetadata(tag.to_string()))
+    document
+        .select(&body)
+        .flat_map(|e| e.text())
+        .collect::<Vec<&str>>()
+        .join(" ")
 }
 
-async fn library_hashes(pool: &SqlitePool) -> Result<HashSet<String>, Error> {
+pub fn word_count(content: &str) -> i64 {
+    text(content).split_whitespace().count() as i64
+}
+
+/// The books in the library by their hash.
+///
+/// A book updated to a newer version of its story keeps its id, so it isn't always the id of the file with its hash.
+async fn library_hashes(pool: &SqlitePool) -> Result<HashMap<String, Hyphenated>, Error> {
     let library_books = library::get_books(pool).await?;
 
     Ok(library_books
         .into_iter()
-        .fold(HashSet::new(), |mut set, book| {
-            set.insert(book.hash);
-            set
+        .fold(HashMap::new(), |mut map, book| {
+            map.insert(book.hash, book.id);
+            map
         }))
 }
 
-pub async fn scan<P: AsRef<Path>>(pool: &SqlitePool, path: P) -> Result<(), Error> {
-    let library_hashes = library_hashes(pool).await?;
-    let mut new_hashes = HashSet::<String>::new();
+/// Whether a version of a story is newer than another, by its (chapters, words).
+/// Stories on fimfiction only gain chapters and words as they're written.
+fn is_newer_version(version: (i64, i64), than: (i64, i64)) -> bool {
+    version > than
+}
 
-    stream::iter(entries(path))
-        .map(|e| async move { get_file(e.path()).await })
-        // buffering a few so there isn't a delay in reads
-        .buffer_unordered(4)
-        .and_then(|buff| async move { Ok(hash(buff)) })
-        .try_filter_map(|(hash, buff)| {
-            let result = if !library_hashes.contains(&hash) && !new_hashes.contains(&hash) {
-                new_hashes.insert(hash.clone());
-                Some((hash, buff))
-            } else {
-                None
-            };
-            async move { Ok(result) }
+/// A file whose contents are already in the library under a different path.
+#[derive(Clone, Debug)]
+pub struct Duplicate {
+    pub path: String,
+    pub book_id: Hyphenated,
+    pub other_paths: Vec<String>,
+    /// true when the paths resolve to the same file (e.g. a symlink) rather than a copy
+    pub same_file: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScanReport {
+    /// true when nothing was written and the report is what a scan would do
+    pub dry_run: bool,
+    pub imported: Vec<String>,
+    /// files with a newer version of a fimfiction story that's already in the library and the book they replaced
+    pub updated: Vec<(String, Hyphenated)>,
+    pub duplicates: Vec<Duplicate>,
+    /// files that are already in the library at the same path
+    pub unchanged: Vec<String>,
+    /// files with an older version of a fimfiction story whose book has a newer one, they're skipped
+    pub superseded: Vec<String>,
+    /// files that were skipped because they are DRM-protected
+    pub drm_protected: Vec<String>,
+    /// files that no importer or converter handles
+    pub unsupported: Vec<String>,
+    /// files that couldn't be converted or parsed and why
+    pub failed: Vec<(String, String)>,
+    /// files that were imported but whose on_book_imported hook failed, and why
+    pub hook_failed: Vec<(String, String)>,
+}
+
+impl ScanReport {
+    /// Removes the books that were updated to a newer version from the text index
+    /// so the next update indexes their new chapters.
+    pub fn forget_updated(&self, text_index: &TextIndex) -> Result<(), Error> {
+        for (_path, book_id) in &self.updated {
+            text_index.remove_book(*book_id)?;
+        }
+        Ok(())
+    }
+
+    /// Why nothing was imported from a single file.
+    pub fn failure(&self, path: &str) -> Error {
+        if let Some((path, reason)) = self.failed.first() {
+            Error::ConversionFailed(path.clone(), reason.clone())
+        } else if let Some(path) = self.unsupported.first() {
+            Error::UnsupportedFormat(path.clone())
+        } else {
+            Error::NoEpubFound(path.to_string())
+        }
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Tracks what is already in the library while books are imported.
+struct ImportBatch {
+    hashes: HashMap<String, Hyphenated>,
+    book_paths: HashMap<Hyphenated, Vec<String>>,
+    /// the command templates for converting other formats into epubs, from the settings
+    converters: BTreeMap<String, String>,
+    /// only fill in the report without writing anything
+    dry_run: bool,
+    /// from the settings, also store the files books are imported from
+    keep_originals: bool,
+    /// the hashes of the books with a stored original
+    originals: HashSet<String>,
+    /// from the settings, the zstd level chapters and originals are stored at
+    compression_level: i32,
+    /// the books in the library by their fimfiction story id
+    stories: HashMap<i64, Hyphenated>,
+    /// the hashes of files with an older version of a story in the library
+    superseded: HashSet<String>,
+    /// the (chapters, words) of the stories' books that were compared this scan, so a dry run compares against what it would have imported
+    versions: HashMap<Hyphenated, (i64, i64)>,
+    report: ScanReport,
+}
+
+impl ImportBatch {
+    async fn new(pool: &SqlitePool, dry_run: bool) -> Result<Self, Error> {
+        let hashes = library_hashes(pool).await?;
+        let stories = library::get_books(pool)
+            .await?
+            .into_iter()
+            .filter_map(|book| Some((book.story_id?, book.id)))
+            .collect();
+        let superseded = library::get_superseded_hashes(pool)
+            .await?
+            .into_iter()
+            .map(|superseded| superseded.hash)
+            .collect();
+        let book_paths = library::get_all_book_paths(pool).await?.into_iter().fold(
+            HashMap::<Hyphenated, Vec<String>>::new(),
+            |mut map, book_path| {
+                map.entry(book_path.book_id)
+                    .or_default()
+                    .push(book_path.path);
+                map
+            },
+        );
+
+        let settings = crate::settings::Settings::load(pool).await?;
+        let originals = if settings.keep_originals {
+            library::get_original_hashes(pool)
+                .await?
+                .into_iter()
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        Ok(ImportBatch {
+            hashes,
+            book_paths,
+            converters: settings.converters,
+            dry_run,
+            keep_originals: settings.keep_originals,
+            compression_level: settings.compression_level,
+            originals,
+            stories,
+            superseded,
+            versions: HashMap::new(),
+            report: ScanReport {
+                dry_run,
+                ..ScanReport::default()
+            },
         })
-        .map_ok(|(hash, buff)| process_epub(hash, buff))
-        .try_for_each(|result| async move {
-            let (book, chapters, toc) = result?;
-            let mut tx = pool.begin().await?;
-            library::insert_book(&mut tx, &book).await?;
-            for chapter in chapters {
-                library::insert_chapter(&mut tx, &chapter).await?;
+    }
+
+    /// Imports a book if it isn't already in the library and records the path it was found at.
+    /// Returns the id of the book or None if it was skipped.
+    ///
+    /// Files that can't be imported are added to the report with the reason so one bad file doesn't stop a scan.
+    async fn import(
+        &mut self,
+        pool: &SqlitePool,
+        file: BookFile,
+    ) -> Result<Option<Hyphenated>, Error> {
+        if self.superseded.contains(&file.hash) {
+            self.report
+                .superseded
+                .push(file.path.to_string_lossy().to_string());
+            return Ok(None);
+        }
+
+        let book_id = match self.hashes.get(&file.hash) {
+            Some(book_id) => *book_id,
+            None => Hyphenated::from(file.id()),
+        };
+        let path_str = file.path.to_string_lossy().to_string();
+
+        if !self.hashes.contains_key(&file.hash) {
+            // kept from before it's converted so it's imported the same way if it's parsed again
+            let original = self.wants_original(&file).then(|| file.clone());
+            let (importer, file): (&dyn Importer, BookFile) = match registry().find(&file) {
+                Some(importer) => (importer, file),
+                None => {
+                    let template = match converter(&self.converters, &file) {
+                        Some(template) => template,
+                        None => {
+                            self.report.unsupported.push(path_str);
+                            return Ok(None);
+                        }
+                    };
+                    match convert(template, &file) {
+                        Ok(converted) => (&EpubImporter, converted),
+                        Err(e) => {
+                            self.report.failed.push((path_str, e.to_string()));
+                            return Ok(None);
+                        }
+                    }
+                }
+            };
+            match importer.is_drm_protected(&file) {
+                Ok(true) => {
+                    self.report.drm_protected.push(path_str);
+                    return Ok(None);
+                }
+                Ok(false) => {}
+                // e.g. a truncated epub that isn't a readable zip
+                Err(e) => {
+                    self.report.failed.push((path_str, e.to_string()));
+                    return Ok(None);
+                }
             }
-            for toc in toc {
-                library::insert_toc(&mut tx, &toc).await?;
+
+            let hash = file.hash.clone();
+            let parsed = match importer.parse(file) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    self.report.failed.push((path_str, e.to_string()));
+                    return Ok(None);
+                }
+            };
+            let story = parsed.book.story_id;
+            let existing = story.and_then(|story| self.stories.get(&story).copied());
+            if let Some(existing) = existing {
+                return self
+                    .update(pool, existing, parsed, &hash, path_str, original)
+                    .await;
+            }
+            self.hashes.insert(hash.clone(), book_id);
+            if let Some(story) = story {
+                self.stories.insert(story, book_id);
+            }
+
+            if !self.dry_run {
+                let mut tx = pool.begin().await?;
+                let book = parsed.book.clone();
+                insert_book(&mut tx, parsed, self.compression_level).await?;
+                tx.commit().await?;
+
+                // the book is already imported, a broken hook only loses its tags
+                match crate::scripts::on_book_imported(&book) {
+                    Ok(tags) => {
+                        for tag in tags {
+                            library::add_book_tag(pool, book.id, &tag).await?;
+                        }
+                    }
+                    Err(e) => self
+                        .report
+                        .hook_failed
+                        .push((path_str.clone(), e.to_string())),
+                }
+                crate::rules::apply_rules(pool, &[book]).await?;
+            }
+            if let Some(original) = original {
+                self.store_original(pool, &original).await?;
+            }
+
+            self.report.imported.push(path_str.clone());
+        } else if self.wants_original(&file) {
+            // books imported before the setting was turned on are kept the next time they're found
+            self.store_original(pool, &file).await?;
+        }
+
+        let paths = self.book_paths.entry(book_id).or_default();
+        if paths.contains(&path_str) {
+            self.report.unchanged.push(path_str);
+        } else {
+            if !paths.is_empty() {
+                self.report.duplicates.push(Duplicate {
+                    path: path_str.clone(),
+                    book_id,
+                    other_paths: paths.clone(),
+                    same_file: paths.iter().any(|other| same_file(other, &path_str)),
+                });
+            }
+
+            if !self.dry_run {
+                library::insert_book_path(pool, book_id, &path_str).await?;
+            }
+            paths.push(path_str);
+        }
+
+        Ok(Some(book_id))
+    }
+
+    /// Replaces the contents of a book with a newer version of the same fimfiction story,
+    /// keeping the book's id so its bookmarks, annotations, and reading position stay with it.
+    ///
+    /// An older version is skipped and its hash remembered, so with several versions of a story
+    /// in the epub directory the book ends up on the newest one whichever order they're found in.
+    async fn update(
+        &mut self,
+        pool: &SqlitePool,
+        book_id: Hyphenated,
+        parsed: ParsedBook,
+        hash: &str,
+        path_str: String,
+        original: Option<BookFile>,
+    ) -> Result<Option<Hyphenated>, Error> {
+        let version = (parsed.chapters.len() as i64, parsed.book.words);
+        let current = match self.versions.get(&book_id) {
+            Some(current) => *current,
+            None => (
+                library::get_num_chapters(pool, book_id).await? as i64,
+                library::get_book(pool, book_id).await?.words,
+            ),
+        };
+        if !is_newer_version(version, current) {
+            if !self.dry_run {
+                library::insert_superseded_hash(pool, hash, book_id).await?;
             }
+            self.superseded.insert(hash.to_string());
+            self.report.superseded.push(path_str);
+            return Ok(None);
+        }
+        self.versions.insert(book_id, version);
+        self.hashes.insert(hash.to_string(), book_id);
+
+        if !self.dry_run {
+            // the new contents and the hash they're found by are saved together so a failure in between
+            // doesn't leave the new contents under the old hash to be imported again by the next scan
+            let mut tx = pool.begin().await?;
+            crate::reprocess::replace_book_tx(&mut tx, book_id, parsed, self.compression_level)
+                .await?;
+            library::set_book_hash(&mut tx, book_id, hash).await?;
             tx.commit().await?;
-            Ok(())
-        })
+        }
+        if let Some(original) = original {
+            self.store_original(pool, &original).await?;
+        }
+
+        let paths = self.book_paths.entry(book_id).or_default();
+        if !paths.contains(&path_str) {
+            if !self.dry_run {
+                library::insert_book_path(pool, book_id, &path_str).await?;
+            }
+            paths.push(path_str.clone());
+        }
+        self.report.updated.push((path_str, book_id));
+
+        Ok(Some(book_id))
+    }
+
+    fn wants_original(&self, file: &BookFile) -> bool {
+        self.keep_originals && !self.dry_run && !self.originals.contains(&file.hash)
+    }
+
+    async fn store_original(&mut self, pool: &SqlitePool, file: &BookFile) -> Result<(), Error> {
+        let name = file
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        library::insert_original(
+            pool,
+            &library::Original {
+                hash: file.hash.clone(),
+                name,
+                // stored like a chapter so it can be told apart from an uncompressed one the same way
+                content: library::compress_chapter(&file.contents, self.compression_level)?,
+            },
+        )
         .await?;
+        self.originals.insert(file.hash.clone());
+        Ok(())
+    }
+}
+
+/// Imports the books at a path, or with `dry_run` only reports what would be imported and what would be skipped.
+pub async fn scan<P: AsRef<Path>>(
+    pool: &SqlitePool,
+    path: P,
+    dry_run: bool,
+) -> Result<ScanReport, Error> {
+    let mut batch = ImportBatch::new(pool, dry_run).await?;
+    let converters = batch.converters.clone();
+    let converters = &converters;
+
+    let files = stream::iter(entries(path, converters))
+        .map(|e| async move {
+            let path = e.path().to_path_buf();
+            (path.clone(), get_book_files(path, converters).await)
+        })
+        // buffering a few so there isn't a delay in reads
+        .buffer_unordered(4);
+    futures::pin_mut!(files);
+
+    // a file or archive that can't be read is reported like a book that can't be parsed so it doesn't stop the scan
+    while let Some((path, files)) = files.next().await {
+        match files {
+            Ok((files, failed)) => {
+                batch.report.failed.extend(failed);
+                for file in files {
+                    batch.import(pool, file).await?;
+                }
+            }
+            Err(e) => batch
+                .report
+                .failed
+                .push((path.to_string_lossy().to_string(), e.to_string())),
+        }
+    }
+
+    Ok(batch.report)
+}
+
+/// Imports a single file (a book or a zip containing books) into the library.
+/// Returns the ids of the books in the file, including ones that were already in the library.
+pub async fn import_file<P: AsRef<Path>>(
+    pool: &SqlitePool,
+    path: P,
+) -> Result<(Vec<Hyphenated>, ScanReport), Error> {
+    let mut batch = ImportBatch::new(pool, false).await?;
+    let mut book_ids = Vec::new();
+
+    let (files, failed) = get_book_files(path.as_ref().to_path_buf(), &batch.converters).await?;
+    batch.report.failed.extend(failed);
+    for file in files {
+        if let Some(book_id) = batch.import(pool, file).await? {
+            book_ids.push(book_id);
+        }
+    }
+
+    Ok((book_ids, batch.report))
+}
+
+/// Imports an epub that has already been read into memory, e.g. one from the fimfarchive.
+/// `path` is recorded as where the book was found.
+pub async fn import_epub(
+    pool: &SqlitePool,
+    path: PathBuf,
+    buff: Vec<u8>,
+) -> Result<(Option<Hyphenated>, ScanReport), Error> {
+    let mut batch = ImportBatch::new(pool, false).await?;
+    let book_id = batch.import(pool, BookFile::new(path, buff)).await?;
+
+    Ok((book_id, batch.report))
+}
+
+/// The file a book was imported from, the stored original if it was kept
+/// or else the first of the book's paths that still has the same file.
+pub async fn original_file(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+) -> Result<Option<BookFile>, Error> {
+    let book = library::get_book(pool, book_id).await?;
+    if let Some(original) = library::get_original(pool, &book.hash).await? {
+        // a damaged original falls back to the paths
+        if let Ok(contents) = library::decompress_chapter(&original.content) {
+            let file = BookFile::new(PathBuf::from(original.name), contents);
+            if file.hash == book.hash {
+                return Ok(Some(file));
+            }
+        }
+    }
+
+    let converters = crate::settings::Settings::load(pool).await?.converters;
+    for path in library::get_book_paths(pool, book_id).await? {
+        // the archive path is a prefix of the paths of the books in it
+        let file_path = match Path::new(&path)
+            .ancestors()
+            .find(|ancestor| ancestor.is_file())
+        {
+            Some(file_path) => file_path.to_path_buf(),
+            None => continue,
+        };
+        let (files, _failed) = get_book_files(file_path, &converters).await?;
+        for file in files {
+            // a book updated to a newer version of its story has the newer file's hash but keeps its old id
+            if Hyphenated::from(file.id()) == book_id || file.hash == book.hash {
+                return Ok(Some(file));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Parses a book again from its original file, e.g. to restore chapters that were corrupted in the database.
+/// Returns None if the original wasn't kept and none of the book's paths still have it.
+pub async fn reparse(pool: &SqlitePool, book_id: Hyphenated) -> Result<Option<ParsedBook>, Error> {
+    let file = match original_file(pool, book_id).await? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let settings = crate::settings::Settings::load(pool).await?;
+
+    let parsed = match registry().find(&file) {
+        Some(importer) => importer.parse(file)?,
+        None => match converter(&settings.converters, &file) {
+            Some(template) => EpubImporter.parse(convert(template, &file)?)?,
+            None => {
+                return Err(Error::UnsupportedFormat(
+                    file.path.to_string_lossy().to_string(),
+                ))
+            }
+        },
+    };
+    Ok(Some(parsed))
+}
+
+/// Writes the file a book was imported from for the export-original subcommand.
+pub async fn export_original_command(book_id: &str, path: &str) -> Result<(), Error> {
+    let book_id = Uuid::parse_str(book_id)
+        .map(Hyphenated::from)
+        .map_err(|_| Error::InvalidBookId(book_id.to_string()))?;
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let file = original_file(&pool, book_id).await;
+    pool.close().await;
+
+    match file? {
+        Some(file) => Ok(std::fs::write(path, file.contents)?),
+        None => Err(Error::OriginalNotFound(book_id.to_string())),
+    }
+}
+
+/// Scans a path for the scan subcommand.
+pub async fn scan_command(path: &str, dry_run: bool) -> Result<ScanReport, Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let report = scan(&pool, path, dry_run).await;
+    if let Ok(report) = &report {
+        if !report.updated.is_empty() {
+            let tuning = crate::settings::Settings::load(&pool).await?.index_tuning;
+            let text_index = TextIndex::open(TEXT_INDEX_PATH, tuning)?;
+            report.forget_updated(&text_index)?;
+        }
+    }
+    pool.close().await;
+    report
+}
+
+/// Splits a creator field like "A, B & C" or "A and B" into the separate authors.
+pub fn split_authors(creator: Option<&str>) -> Vec<String> {
+    let separator_re = Regex::new(r#"(?i)\s*(?:&|,|;|\band\b)\s*"#).unwrap();
+
+    let mut authors = Vec::new();
+    for author in separator_re.split(creator.unwrap_or_default()) {
+        let author = author.trim().to_string();
+        if !author.is_empty() && !authors.contains(&author) {
+            authors.push(author);
+        }
+    }
+    authors
+}
+
+/// Fills the authors table from the creator field of every book, e.g. for libraries imported before it existed.
+/// Returns the number of authors.
+pub async fn rebuild_authors(pool: &SqlitePool) -> Result<usize, Error> {
+    let books = library::get_books(pool).await?;
+
+    let mut tx = pool.begin().await?;
+    let mut authors = HashSet::new();
+    for book in books {
+        let book_authors = split_authors(book.creator.as_deref());
+        library::set_book_authors(&mut tx, book.id, &book_authors).await?;
+        authors.extend(book_authors);
+    }
+    library::delete_unused_authors(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(authors.len())
+}
+
+/// Recounts the words of every chapter in the library and updates the book totals.
+/// Returns the number of chapters whose count changed.
+pub async fn recount_words(pool: &SqlitePool) -> Result<usize, Error> {
+    let chapters = library::get_all_chapters(pool).await?;
+
+    let mut tx = pool.begin().await?;
+    let mut changed = 0;
+    for chapter in chapters {
+        let content = library::decompress_chapter(&chapter.content)?;
+        let words = word_count(&String::from_utf8_lossy(&content));
+        if words != chapter.words {
+            library::set_chapter_words(&mut tx, chapter.id, words).await?;
+            changed += 1;
+        }
+    }
+    library::update_book_words(&mut tx).await?;
+    tx.commit().await?;
 
-    Ok(())
+    Ok(changed)
 }
@@ -0,0 +1,147 @@
+use crate::library::Book;
+use crate::Error;
+use once_cell::sync::OnceCell;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::path::Path;
+use std::time::Duration;
+
+/// The user's hook script, any of the hooks can be left out.
+///
+/// ```rhai
+/// // return tags to add them to the book
+/// fn on_book_imported(book) { if book.publisher == "Fimfiction" { ["fimfiction"] } }
+/// // return the html to show, e.g. after cleaning it up
+/// fn on_chapter_render(html, book) { html.replace("<hr/>", "") }
+/// fn on_finish_book(book) { http_post("https://tracker.example/finished", book.title); }
+/// ```
+pub const SCRIPT_PATH: &str = "hooks.rhai";
+
+/// How many operations a hook can run before it's stopped, hooks run while importing and rendering
+/// so one stuck in a loop would freeze the interface.
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// How deep hooks can call functions, enough for any reasonable hook but not for runaway recursion.
+const MAX_CALL_LEVELS: usize = 32;
+/// How long `http_post` waits to connect and for the request and response.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Hooks {
+    engine: Engine,
+    ast: AST,
+}
+
+static HOOKS: OnceCell<Hooks> = OnceCell::new();
+
+/// Compiles the hook script if there is one, it's only read once at startup.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let mut engine = Engine::new();
+    // printing would draw over the interface
+    engine.on_print(|_| {});
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(MAX_CALL_LEVELS);
+    engine.register_result_fn("http_post", http_post);
+
+    let ast = engine.compile_file(path.to_path_buf())?;
+    let _ = HOOKS.set(Hooks { engine, ast });
+    Ok(())
+}
+
+/// Posts the body to a url and returns the status code, so scripts can tell other services what happened.
+fn http_post(url: &str, body: &str) -> Result<i64, Box<EvalAltResult>> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(HTTP_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build();
+    match agent.post(url).send_string(body) {
+        Ok(response) => Ok(response.status() as i64),
+        Err(ureq::Error::Status(status, _)) => Ok(status as i64),
+        Err(e) => Err(e.to_string().into()),
+    }
+}
+
+/// Calls a hook if the script defines it, None if it doesn't.
+fn call(name: &str, args: Vec<Dynamic>) -> Result<Option<Dynamic>, Error> {
+    let hooks = match HOOKS.get() {
+        Some(hooks) => hooks,
+        None => return Ok(None),
+    };
+    if !hooks
+        .ast
+        .iter_functions()
+        .any(|f| f.name == name && f.params.len() == args.len())
+    {
+        return Ok(None);
+    }
+
+    let result =
+        hooks
+            .engine
+            .call_fn_dynamic(&mut Scope::new(), &hooks.ast, false, name, None, args)?;
+    Ok(Some(result))
+}
+
+/// The book as a map with the same field names, missing values are ().
+fn book_map(book: &Book) -> Dynamic {
+    let optional = |value: &Option<String>| match value {
+        Some(value) => Dynamic::from(value.clone()),
+        None => Dynamic::UNIT,
+    };
+
+    let mut map = Map::new();
+    map.insert("id".into(), Dynamic::from(book.id.to_string()));
+    map.insert("identifier".into(), Dynamic::from(book.identifier.clone()));
+    map.insert("language".into(), Dynamic::from(book.language.clone()));
+    map.insert("title".into(), Dynamic::from(book.title.clone()));
+    map.insert("creator".into(), optional(&book.creator));
+    map.insert("description".into(), optional(&book.description));
+    map.insert("publisher".into(), optional(&book.publisher));
+    map.insert("series".into(), optional(&book.series));
+    map.insert("words".into(), Dynamic::from(book.words));
+    Dynamic::from(map)
+}
+
+/// Runs `on_book_imported`, returning the tags it asked for.
+pub fn on_book_imported(book: &Book) -> Result<Vec<String>, Error> {
+    let result = match call("on_book_imported", vec![book_map(book)])? {
+        Some(result) => result,
+        None => return Ok(Vec::new()),
+    };
+
+    if result.is::<String>() {
+        return Ok(vec![result.cast::<String>()]);
+    }
+    match result.try_cast::<Array>() {
+        Some(tags) => Ok(tags
+            .into_iter()
+            .filter_map(|tag| tag.try_cast::<String>())
+            .collect()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Runs `on_chapter_render`, returning the html it gave back or the original if there's no hook.
+pub fn on_chapter_render(html: String, book: &Book) -> Result<String, Error> {
+    match call(
+        "on_chapter_render",
+        vec![Dynamic::from(html.clone()), book_map(book)],
+    )? {
+        Some(result) => result.try_cast::<String>().ok_or_else(|| {
+            Error::ScriptError(
+                "on_chapter_render has to return a string"
+                    .to_string()
+                    .into(),
+            )
+        }),
+        None => Ok(html),
+    }
+}
+
+/// Runs `on_finish_book` when the end of the last chapter is reached.
+pub fn on_finish_book(book: &Book) -> Result<(), Error> {
+    call("on_finish_book", vec![book_map(book)])?;
+    Ok(())
+}
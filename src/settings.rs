@@ -0,0 +1,757 @@
+use crate::convert::{default_converters, valid_template};
+use crate::i18n::LOCALES;
+use crate::library::{get_string_setting, insert_string_setting, BookmarkPruning};
+use crate::text_index::IndexTuning;
+use crate::Error;
+#[cfg(feature = "cursive-frontend")]
+use cursive::event::{Event, Key};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const THEMES: &[&str] = &["default", "dark", "terminal"];
+pub const ITALIC_EFFECTS: &[&str] = &["italic", "underline", "none"];
+pub const BOLD_EFFECTS: &[&str] = &["bold", "underline", "none"];
+const READER_WIDTHS: std::ops::RangeInclusive<usize> = 20..=500;
+const SCROLL_STEPS: std::ops::RangeInclusive<usize> = 1..=100;
+const PAGE_OVERLAPS: std::ops::RangeInclusive<usize> = 0..=50;
+const AUTO_SCROLL_LINES: std::ops::RangeInclusive<usize> = 1..=50;
+const AUTO_SCROLL_INTERVALS: std::ops::RangeInclusive<u64> = 1..=60;
+const INDEX_WRITER_THREADS: std::ops::RangeInclusive<usize> = 1..=8;
+const MIN_HEAP_MB_PER_THREAD: usize = 4;
+const INDEX_SEARCHERS: std::ops::RangeInclusive<usize> = 1..=32;
+const COMPRESSION_LEVELS: std::ops::RangeInclusive<i32> = 0..=22;
+const DEFAULT_COMPRESSION_LEVEL: i32 = 8;
+
+/// The keys for the actions that work everywhere.
+///
+/// Keys are written like `q`, `ctrl-z`, `alt-x`, `esc`, `pagedown`, or `f5`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Keymap {
+    pub quit: String,
+    pub suspend: String,
+    pub close_reader: String,
+    /// shows the spoilers in the reader and in story descriptions
+    pub reveal_spoilers: String,
+    /// jump to the next or previous bookmark or annotation in the reader
+    pub next_mark: String,
+    pub previous_mark: String,
+    /// shows only the text of the chapter, pressed again to bring the dialog back
+    pub focus_mode: String,
+    pub half_page_up: String,
+    pub half_page_down: String,
+    /// starts and stops auto-scrolling, + and - change the speed and space pauses it
+    pub auto_scroll: String,
+    /// adds the selected book or fimfarchive story to the read later queue
+    pub read_later: String,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            quit: "q".to_string(),
+            suspend: "ctrl-z".to_string(),
+            close_reader: "esc".to_string(),
+            reveal_spoilers: "s".to_string(),
+            next_mark: "n".to_string(),
+            previous_mark: "p".to_string(),
+            focus_mode: "f".to_string(),
+            half_page_up: "ctrl-u".to_string(),
+            half_page_down: "ctrl-d".to_string(),
+            auto_scroll: "a".to_string(),
+            read_later: "+".to_string(),
+        }
+    }
+}
+
+impl Keymap {
+    /// The actions and their keys, for validating them all at once.
+    fn keys(&self) -> [(&'static str, &str); 11] {
+        [
+            ("quit", &self.quit),
+            ("suspend", &self.suspend),
+            ("close_reader", &self.close_reader),
+            ("reveal_spoilers", &self.reveal_spoilers),
+            ("next_mark", &self.next_mark),
+            ("previous_mark", &self.previous_mark),
+            ("focus_mode", &self.focus_mode),
+            ("half_page_up", &self.half_page_up),
+            ("half_page_down", &self.half_page_down),
+            ("auto_scroll", &self.auto_scroll),
+            ("read_later", &self.read_later),
+        ]
+    }
+}
+
+/// Switches to a darker theme in the evening so reading in the dark isn't blinding.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeSchedule {
+    /// one of `THEMES`, used instead of the theme at night, None turns the switching off
+    pub night_theme: Option<String>,
+    /// the local times the night starts and ends, like "20:00" and "07:00"
+    pub night_start: String,
+    pub night_end: String,
+    /// decides when it's night instead of the times, it's night when the output contains "dark",
+    /// e.g. `gsettings get org.gnome.desktop.interface color-scheme`
+    pub command: Option<String>,
+}
+
+impl Default for ThemeSchedule {
+    fn default() -> Self {
+        ThemeSchedule {
+            night_theme: None,
+            night_start: "20:00".to_string(),
+            night_end: "07:00".to_string(),
+            command: None,
+        }
+    }
+}
+
+impl ThemeSchedule {
+    /// The theme to use, `theme` unless it's night.
+    pub fn current<'a>(&'a self, theme: &'a str, night: bool) -> &'a str {
+        match &self.night_theme {
+            Some(night_theme) if night => night_theme,
+            _ => theme,
+        }
+    }
+
+    /// Whether it's night by the start and end times, `command_is_night` is used instead when there's a command.
+    pub fn is_night_by_time(&self) -> bool {
+        let now = chrono::Local::now().time();
+        match (parse_time(&self.night_start), parse_time(&self.night_end)) {
+            (Some(start), Some(end)) if start <= end => start <= now && now < end,
+            // the night goes past midnight
+            (Some(start), Some(end)) => now >= start || now < end,
+            _ => false,
+        }
+    }
+}
+
+/// Runs a theme schedule command, it's night when the output contains "dark".
+/// A command that hasn't finished within `timeout` is killed and counts as day.
+pub fn command_is_night(command: &str, timeout: Duration) -> bool {
+    let mut child = match Command::new("sh")
+        .args(&["-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+
+    // read on another thread so the wait for it can time out
+    let (sender, receiver) = std::sync::mpsc::channel();
+    if let Some(mut stdout) = child.stdout.take() {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            let _ = sender.send(output);
+        });
+    }
+
+    match receiver.recv_timeout(timeout) {
+        Ok(output) => {
+            let _ = child.wait();
+            output.to_lowercase().contains("dark")
+        }
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            false
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<chrono::NaiveTime> {
+    chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()
+}
+
+/// A key from the keymap, whichever interface is handling it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPress {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    /// F1 to F12
+    F(u8),
+    Named(NamedKey),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NamedKey {
+    Esc,
+    Enter,
+    Tab,
+    Backspace,
+    Del,
+    Ins,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// Turns a key from the keymap into the event cursive sends for it.
+#[cfg(feature = "cursive-frontend")]
+pub fn parse_key(key: &str) -> Option<Event> {
+    Some(match parse_key_press(key)? {
+        KeyPress::Char(c) => Event::Char(c),
+        KeyPress::Ctrl(c) => Event::CtrlChar(c),
+        KeyPress::Alt(c) => Event::AltChar(c),
+        KeyPress::F(n) => Event::Key(Key::from_f(n)),
+        KeyPress::Named(key) => Event::Key(match key {
+            NamedKey::Esc => Key::Esc,
+            NamedKey::Enter => Key::Enter,
+            NamedKey::Tab => Key::Tab,
+            NamedKey::Backspace => Key::Backspace,
+            NamedKey::Del => Key::Del,
+            NamedKey::Ins => Key::Ins,
+            NamedKey::Home => Key::Home,
+            NamedKey::End => Key::End,
+            NamedKey::PageUp => Key::PageUp,
+            NamedKey::PageDown => Key::PageDown,
+            NamedKey::Up => Key::Up,
+            NamedKey::Down => Key::Down,
+            NamedKey::Left => Key::Left,
+            NamedKey::Right => Key::Right,
+        }),
+    })
+}
+
+/// Reads a key from the keymap like "q", "ctrl-c", "f5", or "pagedown".
+pub fn parse_key_press(key: &str) -> Option<KeyPress> {
+    let key = key.trim();
+    let lower = key.to_lowercase();
+
+    let single = |rest: &str| {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+
+    if let Some(c) = single(key) {
+        return Some(KeyPress::Char(c));
+    }
+    if let Some(rest) = lower.strip_prefix("ctrl-") {
+        return single(rest).map(KeyPress::Ctrl);
+    }
+    if let Some(rest) = lower.strip_prefix("alt-") {
+        return single(rest).map(KeyPress::Alt);
+    }
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return if (1..=12).contains(&n) {
+            Some(KeyPress::F(n))
+        } else {
+            None
+        };
+    }
+
+    let key = match lower.as_str() {
+        "esc" | "escape" => NamedKey::Esc,
+        "enter" => NamedKey::Enter,
+        "tab" => NamedKey::Tab,
+        "backspace" => NamedKey::Backspace,
+        "del" | "delete" => NamedKey::Del,
+        "ins" | "insert" => NamedKey::Ins,
+        "home" => NamedKey::Home,
+        "end" => NamedKey::End,
+        "pageup" => NamedKey::PageUp,
+        "pagedown" => NamedKey::PageDown,
+        "up" => NamedKey::Up,
+        "down" => NamedKey::Down,
+        "left" => NamedKey::Left,
+        "right" => NamedKey::Right,
+        _ => return None,
+    };
+    Some(KeyPress::Named(key))
+}
+
+/// Everything the user can configure, loaded once at startup and kept in `Data`.
+///
+/// Each field is stored under its own key in the settings table,
+/// this is the only place that knows the keys and the defaults.
+/// The whole struct can also be written to and read from a TOML file, missing fields get their defaults.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// the directory scanned for epubs
+    pub epub_path: String,
+    /// the directory of the fimfarchive search index
+    pub index_path: String,
+    pub fimfarchive_path: String,
+    pub reader_width: usize,
+    /// lines moved by the arrow keys in the reader
+    pub scroll_step: usize,
+    /// lines of the previous page still shown after page up or page down
+    pub page_overlap: usize,
+    /// how many lines auto-scrolling moves every interval
+    pub auto_scroll_lines: usize,
+    /// seconds between auto-scrolling steps
+    pub auto_scroll_interval: u64,
+    /// one of `THEMES`
+    pub theme: String,
+    /// how emphasized text is drawn, one of `ITALIC_EFFECTS`, for terminals or fonts that draw italics badly
+    pub italic_effect: String,
+    /// how strong text is drawn, one of `BOLD_EFFECTS`
+    pub bold_effect: String,
+    /// repeated across horizontal rules instead of drawing a line, e.g. "* " for fonts without box drawing characters
+    pub horizontal_rule: String,
+    pub save_query_history: bool,
+    /// also send notifications to the desktop with notify-send
+    pub desktop_notifications: bool,
+    /// open books at the first chapter of the body instead of the cover the first time they're read
+    pub skip_front_matter: bool,
+    /// start on a dashboard of the book being read, the read later queue, and new books instead of the library
+    pub start_dashboard: bool,
+    /// one of `LOCALES`, only changed at startup
+    pub locale: String,
+    /// draw less for slow connections like ssh or mosh, only changed at startup
+    pub low_bandwidth: bool,
+    /// the zstd level chapters are compressed with when they're imported, 0 stores them uncompressed,
+    /// lower levels import faster (e.g. on a low-power ARM board) and take more space
+    pub compression_level: i32,
+    /// also store the files books are imported from so they can be exported and re-processed after the files are gone
+    pub keep_originals: bool,
+    /// the sync server to pull from on startup and push to on shutdown
+    pub sync_endpoint: Option<String>,
+    /// left out of exported settings so the credential isn't written in plain text, imports keep the local one
+    #[serde(skip)]
+    pub sync_token: Option<String>,
+    // the tables have to come after the plain values for TOML
+    pub bookmark_pruning: BookmarkPruning,
+    pub index_tuning: IndexTuning,
+    pub theme_schedule: ThemeSchedule,
+    pub keymap: Keymap,
+    /// commands that convert other formats into epubs when they're imported, keyed by extension,
+    /// e.g. `djvu = "ebook-convert {input} {output}"`
+    pub converters: BTreeMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            epub_path: "epub".to_string(),
+            index_path: "index".to_string(),
+            fimfarchive_path: "fimfarchive.zip".to_string(),
+            reader_width: 90,
+            scroll_step: 1,
+            page_overlap: 2,
+            auto_scroll_lines: 1,
+            auto_scroll_interval: 2,
+            theme: THEMES[0].to_string(),
+            italic_effect: ITALIC_EFFECTS[0].to_string(),
+            bold_effect: BOLD_EFFECTS[0].to_string(),
+            horizontal_rule: String::new(),
+            save_query_history: false,
+            desktop_notifications: false,
+            skip_front_matter: true,
+            start_dashboard: false,
+            locale: LOCALES[0].to_string(),
+            low_bandwidth: false,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            keep_originals: false,
+            sync_endpoint: None,
+            sync_token: None,
+            bookmark_pruning: BookmarkPruning::default(),
+            index_tuning: IndexTuning::default(),
+            theme_schedule: ThemeSchedule::default(),
+            keymap: Keymap::default(),
+            converters: default_converters(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the saved settings, using the defaults for any that haven't been set.
+    pub async fn load(pool: &SqlitePool) -> Result<Self, Error> {
+        let defaults = Settings::default();
+
+        let bookmark_pruning = match get_string_setting(pool, "bookmark pruning").await? {
+            Some(pruning) => serde_json::from_str(&pruning)?,
+            None => defaults.bookmark_pruning,
+        };
+        let index_tuning = match get_string_setting(pool, "index tuning").await? {
+            Some(tuning) => serde_json::from_str(&tuning)?,
+            None => defaults.index_tuning,
+        };
+        let theme_schedule = match get_string_setting(pool, "theme schedule").await? {
+            Some(schedule) => serde_json::from_str(&schedule)?,
+            None => defaults.theme_schedule,
+        };
+        let keymap = match get_string_setting(pool, "keymap").await? {
+            Some(keymap) => serde_json::from_str(&keymap)?,
+            None => defaults.keymap,
+        };
+        let converters = match get_string_setting(pool, "converters").await? {
+            Some(converters) => serde_json::from_str(&converters)?,
+            None => defaults.converters,
+        };
+
+        Ok(Settings {
+            epub_path: setting(pool, "epub path")
+                .await?
+                .unwrap_or(defaults.epub_path),
+            index_path: setting(pool, "index path")
+                .await?
+                .unwrap_or(defaults.index_path),
+            fimfarchive_path: setting(pool, "fimfarchive path")
+                .await?
+                .unwrap_or(defaults.fimfarchive_path),
+            reader_width: setting(pool, "reader width")
+                .await?
+                .unwrap_or(defaults.reader_width),
+            scroll_step: setting(pool, "scroll step")
+                .await?
+                .unwrap_or(defaults.scroll_step),
+            page_overlap: setting(pool, "page overlap")
+                .await?
+                .unwrap_or(defaults.page_overlap),
+            auto_scroll_lines: setting(pool, "auto scroll lines")
+                .await?
+                .unwrap_or(defaults.auto_scroll_lines),
+            auto_scroll_interval: setting(pool, "auto scroll interval")
+                .await?
+                .unwrap_or(defaults.auto_scroll_interval),
+            theme: setting(pool, "theme").await?.unwrap_or(defaults.theme),
+            italic_effect: setting(pool, "italic effect")
+                .await?
+                .unwrap_or(defaults.italic_effect),
+            bold_effect: setting(pool, "bold effect")
+                .await?
+                .unwrap_or(defaults.bold_effect),
+            horizontal_rule: setting(pool, "horizontal rule")
+                .await?
+                .unwrap_or(defaults.horizontal_rule),
+            save_query_history: setting(pool, "save query history")
+                .await?
+                .unwrap_or(defaults.save_query_history),
+            desktop_notifications: setting(pool, "desktop notifications")
+                .await?
+                .unwrap_or(defaults.desktop_notifications),
+            skip_front_matter: setting(pool, "skip front matter")
+                .await?
+                .unwrap_or(defaults.skip_front_matter),
+            start_dashboard: setting(pool, "start dashboard")
+                .await?
+                .unwrap_or(defaults.start_dashboard),
+            locale: setting(pool, "locale").await?.unwrap_or(defaults.locale),
+            low_bandwidth: setting(pool, "low bandwidth")
+                .await?
+                .unwrap_or(defaults.low_bandwidth),
+            compression_level: setting(pool, "compression level")
+                .await?
+                .unwrap_or(defaults.compression_level),
+            keep_originals: setting(pool, "keep originals")
+                .await?
+                .unwrap_or(defaults.keep_originals),
+            sync_endpoint: optional_setting(pool, "sync endpoint").await?,
+            sync_token: optional_setting(pool, "sync token").await?,
+            bookmark_pruning,
+            index_tuning,
+            theme_schedule,
+            keymap,
+            converters,
+        })
+    }
+
+    /// Checks that the paths are set and the numbers are in range.
+    /// Whether the directories exist is left to `check_paths` so a missing one doesn't keep unrelated settings from being saved.
+    /// The fimfarchive zip is optional so it's only checked when a story is imported from it.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |key: &str, value: &dyn ToString| {
+            Err(Error::InvalidSetting(key.to_string(), value.to_string()))
+        };
+
+        if self.epub_path.trim().is_empty() {
+            return invalid("epub path", &self.epub_path);
+        }
+        if self.index_path.trim().is_empty() {
+            return invalid("index path", &self.index_path);
+        }
+        if self.fimfarchive_path.trim().is_empty() {
+            return invalid("fimfarchive path", &self.fimfarchive_path);
+        }
+        if !READER_WIDTHS.contains(&self.reader_width) {
+            return invalid("reader width", &self.reader_width);
+        }
+        if !SCROLL_STEPS.contains(&self.scroll_step) {
+            return invalid("scroll step", &self.scroll_step);
+        }
+        if !PAGE_OVERLAPS.contains(&self.page_overlap) {
+            return invalid("page overlap", &self.page_overlap);
+        }
+        if !AUTO_SCROLL_LINES.contains(&self.auto_scroll_lines) {
+            return invalid("auto scroll lines", &self.auto_scroll_lines);
+        }
+        if !AUTO_SCROLL_INTERVALS.contains(&self.auto_scroll_interval) {
+            return invalid("auto scroll interval", &self.auto_scroll_interval);
+        }
+        if !COMPRESSION_LEVELS.contains(&self.compression_level) {
+            return invalid("compression level", &self.compression_level);
+        }
+        if !THEMES.contains(&self.theme.as_str()) {
+            return invalid("theme", &self.theme);
+        }
+        if !ITALIC_EFFECTS.contains(&self.italic_effect.as_str()) {
+            return invalid("italic effect", &self.italic_effect);
+        }
+        if !BOLD_EFFECTS.contains(&self.bold_effect.as_str()) {
+            return invalid("bold effect", &self.bold_effect);
+        }
+        if !LOCALES.contains(&self.locale.as_str()) {
+            return invalid("locale", &self.locale);
+        }
+        let schedule = &self.theme_schedule;
+        if let Some(night_theme) = &schedule.night_theme {
+            if !THEMES.contains(&night_theme.as_str()) {
+                return invalid("night theme", night_theme);
+            }
+        }
+        if parse_time(&schedule.night_start).is_none() {
+            return invalid("night start", &schedule.night_start);
+        }
+        if parse_time(&schedule.night_end).is_none() {
+            return invalid("night end", &schedule.night_end);
+        }
+        if let Some(endpoint) = &self.sync_endpoint {
+            if !(endpoint.starts_with("http://") || endpoint.starts_with("https://")) {
+                return invalid("sync endpoint", endpoint);
+            }
+        }
+        if let Some(per_book) = self.bookmark_pruning.per_book {
+            if per_book < 0 {
+                return invalid("bookmarks per book", &per_book);
+            }
+        }
+        if let Some(max_age_days) = self.bookmark_pruning.max_age_days {
+            if max_age_days < 0 {
+                return invalid("bookmark max age", &max_age_days);
+            }
+        }
+        let tuning = &self.index_tuning;
+        if !INDEX_WRITER_THREADS.contains(&tuning.writer_threads) {
+            return invalid("index writer threads", &tuning.writer_threads);
+        }
+        // tantivy needs a few megabytes for each writer thread
+        if tuning.writer_heap_mb < MIN_HEAP_MB_PER_THREAD * tuning.writer_threads {
+            return invalid("index writer memory", &tuning.writer_heap_mb);
+        }
+        if !INDEX_SEARCHERS.contains(&tuning.searchers) {
+            return invalid("index searchers", &tuning.searchers);
+        }
+        for (action, key) in self.keymap.keys().iter() {
+            if parse_key_press(key).is_none() {
+                return invalid(action, key);
+            }
+        }
+        for (extension, template) in &self.converters {
+            if extension.is_empty() || !valid_template(template) {
+                return invalid(&format!("{} converter", extension), template);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the epub and index paths that are different from `old`'s are directories.
+    pub fn check_paths(&self, old: &Settings) -> Result<(), Error> {
+        if self.epub_path != old.epub_path && !Path::new(&self.epub_path).is_dir() {
+            return Err(Error::InvalidSetting(
+                "epub path".to_string(),
+                self.epub_path.clone(),
+            ));
+        }
+        if self.index_path != old.index_path && !Path::new(&self.index_path).is_dir() {
+            return Err(Error::InvalidSetting(
+                "index path".to_string(),
+                self.index_path.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Validates and saves every setting in one transaction.
+    pub async fn save(&self, pool: &SqlitePool) -> Result<(), Error> {
+        self.validate()?;
+
+        let mut tx = pool.begin().await?;
+
+        insert_string_setting(&mut tx, "epub path", &self.epub_path).await?;
+        insert_string_setting(&mut tx, "index path", &self.index_path).await?;
+        insert_string_setting(&mut tx, "fimfarchive path", &self.fimfarchive_path).await?;
+        insert_string_setting(&mut tx, "reader width", &self.reader_width.to_string()).await?;
+        insert_string_setting(&mut tx, "scroll step", &self.scroll_step.to_string()).await?;
+        insert_string_setting(&mut tx, "page overlap", &self.page_overlap.to_string()).await?;
+        insert_string_setting(
+            &mut tx,
+            "auto scroll lines",
+            &self.auto_scroll_lines.to_string(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "auto scroll interval",
+            &self.auto_scroll_interval.to_string(),
+        )
+        .await?;
+        insert_string_setting(&mut tx, "theme", &self.theme).await?;
+        insert_string_setting(&mut tx, "italic effect", &self.italic_effect).await?;
+        insert_string_setting(&mut tx, "bold effect", &self.bold_effect).await?;
+        insert_string_setting(&mut tx, "horizontal rule", &self.horizontal_rule).await?;
+        insert_string_setting(
+            &mut tx,
+            "save query history",
+            &self.save_query_history.to_string(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "desktop notifications",
+            &self.desktop_notifications.to_string(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "skip front matter",
+            &self.skip_front_matter.to_string(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "start dashboard",
+            &self.start_dashboard.to_string(),
+        )
+        .await?;
+        insert_string_setting(&mut tx, "locale", &self.locale).await?;
+        insert_string_setting(&mut tx, "low bandwidth", &self.low_bandwidth.to_string()).await?;
+        insert_string_setting(
+            &mut tx,
+            "compression level",
+            &self.compression_level.to_string(),
+        )
+        .await?;
+        insert_string_setting(&mut tx, "keep originals", &self.keep_originals.to_string()).await?;
+        // an empty value means the setting isn't set
+        insert_string_setting(
+            &mut tx,
+            "sync endpoint",
+            self.sync_endpoint.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "sync token",
+            self.sync_token.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "bookmark pruning",
+            &serde_json::to_string(&self.bookmark_pruning)?,
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "index tuning",
+            &serde_json::to_string(&self.index_tuning)?,
+        )
+        .await?;
+        insert_string_setting(
+            &mut tx,
+            "theme schedule",
+            &serde_json::to_string(&self.theme_schedule)?,
+        )
+        .await?;
+        insert_string_setting(&mut tx, "keymap", &serde_json::to_string(&self.keymap)?).await?;
+        insert_string_setting(
+            &mut tx,
+            "converters",
+            &serde_json::to_string(&self.converters)?,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub fn write_toml<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Reads settings written by `write_toml`, they have to be saved to be used.
+    /// The paths may not exist here if they were exported on another machine, see `keep_local`.
+    pub fn read_toml<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let settings: Settings = toml::from_str(&std::fs::read_to_string(path)?)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Keeps `local`'s epub and index paths in place of any that aren't directories here, and its sync token since it isn't exported.
+    /// Returns the name and imported value of each path that was kept so it can be reported.
+    pub fn keep_local(&mut self, local: &Settings) -> Vec<(&'static str, String)> {
+        let mut kept = Vec::new();
+        self.sync_token = local.sync_token.clone();
+        if !Path::new(&self.epub_path).is_dir() {
+            let imported = std::mem::replace(&mut self.epub_path, local.epub_path.clone());
+            kept.push(("epub path", imported));
+        }
+        if !Path::new(&self.index_path).is_dir() {
+            let imported = std::mem::replace(&mut self.index_path, local.index_path.clone());
+            kept.push(("index path", imported));
+        }
+        kept
+    }
+}
+
+/// Writes the saved settings to a TOML file.
+pub async fn export(path: &str) -> Result<(), Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    Settings::load(&pool).await?.write_toml(path)?;
+    pool.close().await;
+    Ok(())
+}
+
+/// Saves the settings from a TOML file, returning the paths that were kept from `keep_local`.
+pub async fn import(path: &str) -> Result<Vec<(&'static str, String)>, Error> {
+    let mut settings = Settings::read_toml(path)?;
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let kept = settings.keep_local(&Settings::load(&pool).await?);
+    settings.save(&pool).await?;
+    pool.close().await;
+    Ok(kept)
+}
+
+/// A saved setting parsed into its type.
+async fn setting<T: FromStr>(pool: &SqlitePool, key: &str) -> Result<Option<T>, Error> {
+    match get_string_setting(pool, key).await? {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(Error::InvalidSetting(key.to_string(), value)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// A setting that can be left empty.
+async fn optional_setting(pool: &SqlitePool, key: &str) -> Result<Option<String>, Error> {
+    Ok(get_string_setting(pool, key)
+        .await?
+        .filter(|value| !value.is_empty()))
+}
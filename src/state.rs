@@ -0,0 +1,36 @@
+use crate::library::{self, Position};
+use once_cell::sync::{Lazy, OnceCell};
+use sqlx::SqlitePool;
+use std::sync::Mutex;
+
+/// The latest reading position that hasn't been written to the database yet.
+static PENDING: Lazy<Mutex<Option<Position>>> = Lazy::new(|| Mutex::new(None));
+static POOL: OnceCell<SqlitePool> = OnceCell::new();
+
+pub fn set_pending(position: Position) {
+    if let Ok(mut pending) = PENDING.lock() {
+        *pending = Some(position);
+    }
+}
+
+pub fn take_pending() -> Option<Position> {
+    PENDING.lock().ok().and_then(|mut pending| pending.take())
+}
+
+/// Installs a panic hook that writes the pending reading position and puts the terminal back
+/// into a usable state before the panic message is printed.
+pub fn install_panic_hook(pool: SqlitePool) {
+    let _ = POOL.set(pool);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let (Some(pool), Some(position)) = (POOL.get(), take_pending()) {
+            // the tokio runtime may be what panicked so this doesn't go through it
+            let _ = futures::executor::block_on(library::set_position(pool, &position));
+        }
+
+        #[cfg(feature = "cursive-frontend")]
+        crate::terminal::restore();
+        default_hook(info);
+    }));
+}
@@ -0,0 +1,529 @@
+use crate::library::*;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use uuid::adapter::Hyphenated;
+
+/// Bumped whenever the format changes in a way older versions can't read.
+const SYNC_VERSION: u32 = 1;
+
+/// How long a request to the sync server waits to connect and for each read or write.
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How many times a push merges the server's changeset again after another machine pushed in between.
+const PUSH_ATTEMPTS: usize = 3;
+
+/// The reading state of a library, written to a file so another machine can merge it.
+///
+/// Books are identified by the hash of their file and chapters by their index in the book
+/// since ids aren't the same between libraries.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Changeset {
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    pub positions: Vec<SyncPosition>,
+    pub bookmarks: Vec<SyncBookmark>,
+    pub annotations: Vec<SyncAnnotation>,
+    pub notes: Vec<SyncNote>,
+    /// empty in changesets from versions that didn't sync deletions
+    #[serde(default)]
+    pub deleted_bookmarks: Vec<SyncDeletedBookmark>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncPosition {
+    pub book: String,
+    pub chapter: i64,
+    pub progress: f32,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncBookmark {
+    pub book: String,
+    /// the fimfiction story id, finds the book when the other library has a different version of the story
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub story: Option<i64>,
+    pub chapter: i64,
+    pub progress: f32,
+    pub created: DateTime<Utc>,
+}
+
+/// A deleted bookmark, merging it deletes the bookmark and keeps it from being added back.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncDeletedBookmark {
+    pub book: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub story: Option<i64>,
+    /// when the bookmark was created, which identifies it along with the book
+    pub created: DateTime<Utc>,
+    pub deleted: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncAnnotation {
+    pub book: String,
+    pub chapter: i64,
+    pub progress: f32,
+    pub passage: String,
+    pub note: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncNote {
+    pub book: String,
+    pub content: String,
+    pub updated: DateTime<Utc>,
+}
+
+/// What changed in the library when a changeset was merged.
+#[derive(Clone, Debug, Default)]
+pub struct MergeReport {
+    pub positions: usize,
+    pub bookmarks: usize,
+    pub annotations: usize,
+    pub notes: usize,
+    pub deleted_bookmarks: usize,
+    /// changes for books or chapters that aren't in this library
+    pub skipped: usize,
+}
+
+impl fmt::Display for MergeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "updated {} positions, added {} bookmarks and {} annotations, updated {} notes, deleted {} bookmarks, skipped {} changes for books that aren't in the library",
+            self.positions,
+            self.bookmarks,
+            self.annotations,
+            self.notes,
+            self.deleted_bookmarks,
+            self.skipped
+        )
+    }
+}
+
+/// Maps between the ids in this library and the hashes and indices in a changeset.
+struct Ids {
+    hashes: HashMap<Hyphenated, String>,
+    books: HashMap<String, Hyphenated>,
+    stories: HashMap<Hyphenated, i64>,
+    story_books: HashMap<i64, Hyphenated>,
+    chapter_indices: HashMap<Hyphenated, i64>,
+    chapters: HashMap<(Hyphenated, i64), Hyphenated>,
+}
+
+impl Ids {
+    async fn load(pool: &SqlitePool) -> Result<Self, Error> {
+        let books = get_books(pool).await?;
+        let chapters = get_chapter_indices(pool).await?;
+
+        Ok(Ids {
+            hashes: books
+                .iter()
+                .map(|book| (book.id, book.hash.clone()))
+                .collect(),
+            books: books
+                .iter()
+                .map(|book| (book.hash.clone(), book.id))
+                .collect(),
+            stories: books
+                .iter()
+                .filter_map(|book| Some((book.id, book.story_id?)))
+                .collect(),
+            story_books: books
+                .iter()
+                .filter_map(|book| Some((book.story_id?, book.id)))
+                .collect(),
+            chapter_indices: chapters
+                .iter()
+                .map(|chapter| (chapter.id, chapter.index))
+                .collect(),
+            chapters: chapters
+                .iter()
+                .map(|chapter| ((chapter.book_id, chapter.index), chapter.id))
+                .collect(),
+        })
+    }
+
+    fn location(&self, book_id: Hyphenated, chapter_id: Hyphenated) -> Option<(String, i64)> {
+        Some((
+            self.hashes.get(&book_id)?.clone(),
+            *self.chapter_indices.get(&chapter_id)?,
+        ))
+    }
+
+    fn ids(&self, book: &str, chapter: i64) -> Option<(Hyphenated, Hyphenated)> {
+        let book_id = *self.books.get(book)?;
+        Some((book_id, *self.chapters.get(&(book_id, chapter))?))
+    }
+
+    /// The book with a hash, or with a fimfiction story id when this library has a different version of the story.
+    fn book(&self, book: &str, story: Option<i64>) -> Option<Hyphenated> {
+        self.books
+            .get(book)
+            .or_else(|| self.story_books.get(&story?))
+            .copied()
+    }
+
+    /// Like `ids` but finds the book by its fimfiction story id.
+    fn story_ids(&self, story: i64, chapter: i64) -> Option<(Hyphenated, Hyphenated)> {
+        let book_id = *self.story_books.get(&story)?;
+        Some((book_id, *self.chapters.get(&(book_id, chapter))?))
+    }
+}
+
+pub async fn changeset(pool: &SqlitePool) -> Result<Changeset, Error> {
+    let ids = Ids::load(pool).await?;
+
+    let positions = get_positions(pool)
+        .await?
+        .into_iter()
+        .filter_map(|position| {
+            let (book, chapter) = ids.location(position.book_id, position.chapter_id)?;
+            Some(SyncPosition {
+                book,
+                chapter,
+                progress: position.progress,
+                updated: position.updated,
+            })
+        })
+        .collect();
+
+    let bookmarks = get_bookmarks(pool)
+        .await?
+        .into_iter()
+        .filter_map(|bookmark| {
+            let (book, chapter) = ids.location(bookmark.book_id, bookmark.chapter_id)?;
+            Some(SyncBookmark {
+                book,
+                story: ids.stories.get(&bookmark.book_id).copied(),
+                chapter,
+                progress: bookmark.progress,
+                created: bookmark.created,
+            })
+        })
+        .collect();
+
+    let annotations = get_annotations(pool)
+        .await?
+        .into_iter()
+        .filter_map(|annotation| {
+            let (book, chapter) = ids.location(annotation.book_id, annotation.chapter_id)?;
+            Some(SyncAnnotation {
+                book,
+                chapter,
+                progress: annotation.progress,
+                passage: annotation.passage,
+                note: annotation.note,
+                created: annotation.created,
+            })
+        })
+        .collect();
+
+    let notes = get_notes(pool)
+        .await?
+        .into_iter()
+        .filter_map(|note| {
+            Some(SyncNote {
+                book: ids.hashes.get(&note.book_id)?.clone(),
+                content: note.content,
+                updated: note.updated,
+            })
+        })
+        .collect();
+
+    let deleted_bookmarks = get_deleted_bookmarks(pool)
+        .await?
+        .into_iter()
+        .filter_map(|deleted| {
+            Some(SyncDeletedBookmark {
+                book: ids.hashes.get(&deleted.book_id)?.clone(),
+                story: ids.stories.get(&deleted.book_id).copied(),
+                created: deleted.created,
+                deleted: deleted.deleted,
+            })
+        })
+        .collect();
+
+    Ok(Changeset {
+        version: SYNC_VERSION,
+        exported: Utc::now(),
+        positions,
+        bookmarks,
+        annotations,
+        notes,
+        deleted_bookmarks,
+    })
+}
+
+/// Merges a changeset into the library.
+///
+/// Positions and notes are replaced when the changeset's are newer,
+/// bookmarks and annotations are added unless there's already one created at the same time,
+/// and deleted bookmarks are deleted here too and not added back by later merges.
+pub async fn merge(pool: &SqlitePool, changeset: &Changeset) -> Result<MergeReport, Error> {
+    if changeset.version > SYNC_VERSION {
+        return Err(Error::UnsupportedSyncVersion(changeset.version));
+    }
+
+    let ids = Ids::load(pool).await?;
+    let mut report = MergeReport::default();
+
+    for position in &changeset.positions {
+        let (book_id, chapter_id) = match ids.ids(&position.book, position.chapter) {
+            Some(ids) => ids,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let newer = match get_position(pool, book_id).await? {
+            Some(local) => position.updated > local.updated,
+            None => true,
+        };
+        if newer {
+            set_position(
+                pool,
+                &Position {
+                    book_id,
+                    chapter_id,
+                    progress: position.progress,
+                    updated: position.updated,
+                },
+            )
+            .await?;
+            report.positions += 1;
+        }
+    }
+
+    for deleted in &changeset.deleted_bookmarks {
+        let book_id = match ids.book(&deleted.book, deleted.story) {
+            Some(book_id) => book_id,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let existed = insert_deleted_bookmark(
+            pool,
+            &DeletedBookmark {
+                book_id,
+                created: deleted.created,
+                deleted: deleted.deleted,
+            },
+        )
+        .await?;
+        if existed {
+            report.deleted_bookmarks += 1;
+        }
+    }
+
+    let bookmarks = get_bookmarks(pool).await?;
+    let deleted_bookmarks = get_deleted_bookmarks(pool).await?;
+    for bookmark in &changeset.bookmarks {
+        let found = ids.ids(&bookmark.book, bookmark.chapter).or_else(|| {
+            bookmark
+                .story
+                .and_then(|story| ids.story_ids(story, bookmark.chapter))
+        });
+        let (book_id, chapter_id) = match found {
+            Some(ids) => ids,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let exists = bookmarks
+            .iter()
+            .any(|local| local.book_id == book_id && local.created == bookmark.created);
+        let deleted = deleted_bookmarks
+            .iter()
+            .any(|local| local.book_id == book_id && local.created == bookmark.created);
+        if !exists && !deleted {
+            insert_bookmark(
+                pool,
+                &Bookmark {
+                    id: 0,
+                    book_id,
+                    chapter_id,
+                    progress: bookmark.progress,
+                    created: bookmark.created,
+                },
+            )
+            .await?;
+            report.bookmarks += 1;
+        }
+    }
+
+    let annotations = get_annotations(pool).await?;
+    for annotation in &changeset.annotations {
+        let (book_id, chapter_id) = match ids.ids(&annotation.book, annotation.chapter) {
+            Some(ids) => ids,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let exists = annotations.iter().any(|local| {
+            local.book_id == book_id
+                && local.created == annotation.created
+                && local.passage == annotation.passage
+        });
+        if !exists {
+            insert_annotation(
+                pool,
+                &Annotation {
+                    id: 0,
+                    book_id,
+                    chapter_id,
+                    progress: annotation.progress,
+                    passage: annotation.passage.clone(),
+                    note: annotation.note.clone(),
+                    created: annotation.created,
+                },
+            )
+            .await?;
+            report.annotations += 1;
+        }
+    }
+
+    let notes = get_notes(pool).await?;
+    for note in &changeset.notes {
+        let book_id = match ids.books.get(&note.book) {
+            Some(book_id) => *book_id,
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let newer = match notes.iter().find(|local| local.book_id == book_id) {
+            Some(local) => note.updated > local.updated,
+            None => true,
+        };
+        if newer {
+            insert_note(
+                pool,
+                &Note {
+                    book_id,
+                    content: note.content.clone(),
+                    updated: note.updated,
+                },
+            )
+            .await?;
+            report.notes += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(TIMEOUT)
+        .timeout_read(TIMEOUT)
+        .timeout_write(TIMEOUT)
+        .build()
+}
+
+fn request(request: ureq::Request, token: Option<&str>) -> ureq::Request {
+    match token {
+        Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+/// The changeset on the sync server and its ETag if the server sent one, `None` if nothing has been pushed yet.
+fn fetch(
+    agent: &ureq::Agent,
+    url: &str,
+    token: Option<&str>,
+) -> Result<Option<(Changeset, Option<String>)>, Error> {
+    match request(agent.get(url), token).call() {
+        Ok(response) => {
+            let etag = response.header("ETag").map(str::to_string);
+            Ok(Some((response.into_json()?, etag)))
+        }
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Gets the changeset from a sync server with `GET <endpoint>/changeset` and merges it.
+pub async fn pull(
+    pool: &SqlitePool,
+    endpoint: &str,
+    token: Option<&str>,
+) -> Result<MergeReport, Error> {
+    let url = format!("{}/changeset", endpoint.trim_end_matches('/'));
+
+    match fetch(&agent(), &url, token)? {
+        Some((changeset, _)) => merge(pool, &changeset).await,
+        None => Ok(MergeReport::default()),
+    }
+}
+
+/// Sends the library's changeset to a sync server with `PUT <endpoint>/changeset`.
+///
+/// The server's changeset is merged first so what other machines pushed isn't lost,
+/// and the PUT is conditional on it not changing since (`If-Match` with its ETag, or `If-None-Match: *` when nothing has been pushed yet).
+/// A `412 Precondition Failed` means another machine pushed in between so it's merged again.
+pub async fn push(pool: &SqlitePool, endpoint: &str, token: Option<&str>) -> Result<(), Error> {
+    let agent = agent();
+    let url = format!("{}/changeset", endpoint.trim_end_matches('/'));
+
+    for _ in 0..PUSH_ATTEMPTS {
+        let precondition = match fetch(&agent, &url, token)? {
+            Some((remote, etag)) => {
+                merge(pool, &remote).await?;
+                etag.map(|etag| ("If-Match", etag))
+            }
+            None => Some(("If-None-Match", "*".to_string())),
+        };
+
+        let changeset = changeset(pool).await?;
+        let mut put = request(agent.put(&url), token);
+        if let Some((header, value)) = &precondition {
+            put = put.set(header, value);
+        }
+
+        match put.send_json(serde_json::to_value(&changeset)?) {
+            Ok(_) => return Ok(()),
+            Err(ureq::Error::Status(412, _)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(Error::SyncConflict)
+}
+
+/// Writes the library's reading state to a sync file.
+pub async fn export(path: &str) -> Result<(), Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let changeset = changeset(&pool).await?;
+    pool.close().await;
+
+    std::fs::write(path, serde_json::to_string_pretty(&changeset)?)?;
+    Ok(())
+}
+
+/// Merges a sync file into the library.
+pub async fn import(path: &str) -> Result<MergeReport, Error> {
+    let changeset: Changeset = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let report = merge(&pool, &changeset).await?;
+    pool.close().await;
+
+    Ok(report)
+}
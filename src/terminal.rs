@@ -0,0 +1,81 @@
+use cursive::backend::Backend;
+use cursive::Cursive;
+use once_cell::sync::OnceCell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The terminal settings from before the interface started, put back by `restore`.
+static ORIGINAL: OnceCell<libc::termios> = OnceCell::new();
+
+/// Set by the suspend key so `run` stops the event loop and suspends the process.
+static SUSPEND: AtomicBool = AtomicBool::new(false);
+
+/// Saves the terminal settings so `restore` can put them back if the backend doesn't get to.
+fn save() {
+    let tty = match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(_) => return,
+    };
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut termios) } == 0 {
+        let _ = ORIGINAL.set(termios);
+    }
+}
+
+/// Puts the terminal back the way the shell expects it: main screen, visible cursor,
+/// no mouse reporting, and the settings it had before the interface started.
+pub fn restore() {
+    write_escapes(b"\x1b[0m\x1b[?1000l\x1b[?1002l\x1b[?1015l\x1b[?1006l\x1b[?25h\x1b[?1049l");
+    if let (Some(termios), Ok(tty)) = (
+        ORIGINAL.get(),
+        OpenOptions::new().read(true).write(true).open("/dev/tty"),
+    ) {
+        unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, termios) };
+    }
+}
+
+fn write_escapes(escapes: &[u8]) {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(escapes);
+    let _ = stdout.flush();
+}
+
+/// Handles Ctrl-Z, the backend has the terminal in raw mode so it arrives as a key instead of a signal.
+/// The event loop in `run` sees the flag after this step and does the suspending.
+pub fn suspend(_s: &mut Cursive) {
+    SUSPEND.store(true, Ordering::SeqCst);
+}
+
+/// Runs the event loop on a backend from `init` until the interface quits.
+///
+/// On suspend the backend is dropped, which puts the terminal back the way it found it,
+/// the process stops itself with SIGTSTP like the shell would, and once it's continued
+/// (e.g. by `fg`) a new backend is made and the loop picks up where it left off.
+pub fn run(siv: &mut Cursive, init: impl Fn() -> std::io::Result<Box<dyn Backend>>) {
+    save();
+    loop {
+        let backend = match init() {
+            Ok(backend) => backend,
+            Err(e) => {
+                restore();
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut runner = siv.runner(backend);
+        runner.refresh();
+        while runner.is_running() && !SUSPEND.load(Ordering::SeqCst) {
+            runner.step();
+        }
+        drop(runner);
+
+        if !SUSPEND.swap(false, Ordering::SeqCst) {
+            break;
+        }
+        // execution continues here once the process gets SIGCONT
+        unsafe { libc::raise(libc::SIGTSTP) };
+    }
+}
@@ -0,0 +1,241 @@
+use crate::library::{decompress_chapter, get_book_chapters, get_books};
+use crate::scan::text;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::Path;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{QueryParser, TermQuery};
+use tantivy::schema::*;
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, SnippetGenerator, Term};
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+pub const TEXT_INDEX_PATH: &str = "text_index";
+const SNIPPET_CHARS: usize = 80;
+
+/// How much memory the fimfarchive and text indexes use, e.g. lowered on a Raspberry Pi running other services.
+///
+/// The indexes are opened from their directories so the segment files are memory mapped,
+/// only the parts being searched are paged in and the OS can drop them under memory pressure.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexTuning {
+    /// the memory an index writer fills before writing a segment, split between its threads
+    pub writer_heap_mb: usize,
+    pub writer_threads: usize,
+    /// the searchers kept by each index's reader and shared by every search, how many searches can run at once
+    pub searchers: usize,
+}
+
+impl Default for IndexTuning {
+    fn default() -> Self {
+        IndexTuning {
+            writer_heap_mb: 50,
+            writer_threads: 2,
+            searchers: 4,
+        }
+    }
+}
+
+impl IndexTuning {
+    pub fn writer(&self, index: &Index) -> Result<IndexWriter, Error> {
+        Ok(index.writer_with_num_threads(self.writer_threads, self.writer_heap_mb * 1_000_000)?)
+    }
+
+    /// A reader that reloads whenever the index is committed to.
+    pub fn reader(&self, index: &Index) -> Result<IndexReader, Error> {
+        Ok(index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .num_searchers(self.searchers)
+            .try_into()?)
+    }
+}
+
+/// A full-text index of the chapters in the library, separate from the fimfarchive index.
+#[derive(Clone)]
+pub struct TextIndex {
+    index: Index,
+    reader: IndexReader,
+    tuning: IndexTuning,
+    book_id: Field,
+    chapter_id: Field,
+    content: Field,
+}
+
+/// A chapter that matched a text search.
+#[derive(Clone, Debug)]
+pub struct TextMatch {
+    pub book_id: Hyphenated,
+    pub chapter_id: Hyphenated,
+    /// the sentences around the match on a single line
+    pub snippet: String,
+    /// byte ranges of the matched words in the snippet
+    pub highlights: Vec<(usize, usize)>,
+    /// how far into the chapter's text the first match is
+    pub progress: f32,
+}
+
+impl TextIndex {
+    /// Opens the index, creating it if it doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P, tuning: IndexTuning) -> Result<Self, Error> {
+        let mut builder = Schema::builder();
+        let book_id = builder.add_text_field("book_id", STRING | STORED);
+        let chapter_id = builder.add_text_field("chapter_id", STRING | STORED);
+        let content = builder.add_text_field("content", TEXT | STORED);
+        let schema = builder.build();
+
+        let path = path.as_ref();
+        let index = if path.join("meta.json").exists() {
+            Index::open_in_dir(path)?
+        } else {
+            std::fs::create_dir_all(path)?;
+            Index::create_in_dir(path, schema)?
+        };
+
+        let reader = tuning.reader(&index)?;
+
+        Ok(TextIndex {
+            index,
+            reader,
+            tuning,
+            book_id,
+            chapter_id,
+            content,
+        })
+    }
+
+    /// Indexes the chapters of the books that aren't in the index yet, returns how many books were added.
+    pub async fn update(&self, pool: &SqlitePool) -> Result<usize, Error> {
+        let searcher = self.reader.searcher();
+
+        let mut missing = Vec::new();
+        for book in get_books(pool).await? {
+            let term = Term::from_field_text(self.book_id, &book.id.to_string());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            if searcher.search(&query, &Count)? == 0 {
+                missing.push(book.id);
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(0);
+        }
+
+        let mut writer = self.tuning.writer(&self.index)?;
+        self.add_books(&mut writer, pool, &missing).await?;
+        writer.commit()?;
+        self.reader.reload()?;
+
+        Ok(missing.len())
+    }
+
+    /// Indexes the whole library again, returns how many books were indexed.
+    ///
+    /// The old documents are deleted in the same commit the new ones are added in, so searches keep using the old index
+    /// until it's done and a rebuild that's cancelled or fails leaves it as it was.
+    pub async fn rebuild(&self, pool: &SqlitePool) -> Result<usize, Error> {
+        let book_ids = get_books(pool)
+            .await?
+            .into_iter()
+            .map(|book| book.id)
+            .collect::<Vec<Hyphenated>>();
+
+        let mut writer = self.tuning.writer(&self.index)?;
+        writer.delete_all_documents()?;
+        self.add_books(&mut writer, pool, &book_ids).await?;
+        writer.commit()?;
+        self.reader.reload()?;
+
+        Ok(book_ids.len())
+    }
+
+    async fn add_books(
+        &self,
+        writer: &mut IndexWriter,
+        pool: &SqlitePool,
+        book_ids: &[Hyphenated],
+    ) -> Result<(), Error> {
+        for book_id in book_ids {
+            for chapter in get_book_chapters(pool, *book_id).await? {
+                let content = decompress_chapter(&chapter.content)?;
+                writer.add_document(doc!(
+                    self.book_id => book_id.to_string(),
+                    self.chapter_id => chapter.id.to_string(),
+                    self.content => text(&String::from_utf8_lossy(&content)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a book from the index so the next update indexes its chapters again, e.g. after it's reprocessed.
+    pub fn remove_book(&self, book_id: Hyphenated) -> Result<(), Error> {
+        let mut writer = self.tuning.writer(&self.index)?;
+        writer.delete_term(Term::from_field_text(self.book_id, &book_id.to_string()));
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Finds the chapters matching a tantivy query along with a snippet of the matching text.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<TextMatch>, Error> {
+        let searcher = self.reader.searcher();
+        let query_parser = QueryParser::for_index(&self.index, vec![self.content]);
+        let query = query_parser.parse_query(query)?;
+
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
+
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &*query, self.content)?;
+        snippet_generator.set_max_num_chars(SNIPPET_CHARS);
+
+        let mut matches = Vec::new();
+        for (_score, address) in top_docs {
+            let doc = searcher.doc(address)?;
+            let field = |field| {
+                doc.get_first(field)
+                    .and_then(|value| value.text())
+                    .unwrap_or_default()
+            };
+
+            let (book_id, chapter_id) = match (id(field(self.book_id)), id(field(self.chapter_id)))
+            {
+                (Some(book_id), Some(chapter_id)) => (book_id, chapter_id),
+                _ => continue,
+            };
+
+            let content = field(self.content);
+            let snippet = snippet_generator.snippet(content);
+            let fragment = snippet.fragments();
+            let highlights = snippet
+                .highlighted()
+                .iter()
+                .map(|highlight| highlight.bounds())
+                .collect::<Vec<(usize, usize)>>();
+
+            let start = content.find(fragment).unwrap_or(0)
+                + highlights.first().map(|(start, _)| *start).unwrap_or(0);
+            let progress = if content.is_empty() {
+                0.0
+            } else {
+                start as f32 / content.len() as f32
+            };
+
+            matches.push(TextMatch {
+                book_id,
+                chapter_id,
+                // single byte replacements so the highlight ranges still line up
+                snippet: fragment.replace(|c: char| c == '\n' || c == '\r' || c == '\t', " "),
+                highlights,
+                progress,
+            });
+        }
+
+        Ok(matches)
+    }
+}
+
+fn id(value: &str) -> Option<Hyphenated> {
+    Uuid::parse_str(value).ok().map(Hyphenated::from)
+}
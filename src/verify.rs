@@ -0,0 +1,159 @@
+use crate::library::{self, chapter_checksum};
+use crate::reprocess::replace_book;
+use crate::scan::reparse;
+use crate::Error;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use uuid::adapter::Hyphenated;
+
+/// A chapter whose stored content doesn't match its checksum or can't be decompressed.
+#[derive(Clone, Debug)]
+pub struct CorruptChapter {
+    pub id: Hyphenated,
+    pub book_id: Hyphenated,
+    pub index: i64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    /// chapters imported before checksums were stored, they're only checked for decompressing
+    pub unverified: usize,
+    pub corrupt: Vec<CorruptChapter>,
+}
+
+impl VerifyReport {
+    /// The books with corrupt chapters, each one only once.
+    pub fn book_ids(&self) -> Vec<Hyphenated> {
+        let mut book_ids = self
+            .corrupt
+            .iter()
+            .map(|chapter| chapter.book_id)
+            .collect::<Vec<Hyphenated>>();
+        book_ids.dedup();
+        book_ids
+    }
+}
+
+/// Checks every chapter's content against the checksum it was stored with,
+/// e.g. to find chapters damaged by a bad disk or a write that was interrupted.
+pub async fn verify(pool: &SqlitePool) -> Result<VerifyReport, Error> {
+    let mut report = VerifyReport::default();
+
+    for chapter in library::get_chapter_checksums(pool).await? {
+        report.checked += 1;
+        let intact = match &chapter.checksum {
+            Some(checksum) => chapter_checksum(&chapter.content) == *checksum,
+            None => {
+                report.unverified += 1;
+                library::decompress_chapter(&chapter.content).is_ok()
+            }
+        };
+
+        if !intact {
+            report.corrupt.push(CorruptChapter {
+                id: chapter.id,
+                book_id: chapter.book_id,
+                index: chapter.index,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Replaces a book's chapters with the ones parsed again from its original file.
+///
+/// The chapters are matched by their position like `reprocess::replace_book` does, their ids come from their content
+/// so a reparse with a newer importer (e.g. one that fixes up the encoding) gives them different ids,
+/// and bookmarks and highlights stay where they are.
+/// Returns how many chapters were replaced, None if the original file is gone.
+pub async fn repair(pool: &SqlitePool, book_id: Hyphenated) -> Result<Option<usize>, Error> {
+    let parsed = match reparse(pool, book_id).await? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+
+    let level = crate::settings::Settings::load(pool)
+        .await?
+        .compression_level;
+    let chapters = parsed.chapters.len();
+    replace_book(pool, book_id, parsed, level).await?;
+
+    Ok(Some(chapters))
+}
+
+/// What `verify` found, for the verify subcommand.
+pub fn verify_report(report: &VerifyReport) -> String {
+    let mut text = format!("Checked {} chapters", report.checked);
+    if report.unverified > 0 {
+        let _ = write!(
+            text,
+            ", {} from before checksums were stored were only checked for decompressing",
+            report.unverified
+        );
+    }
+    text.push('\n');
+
+    let mut books = BTreeMap::<String, Vec<i64>>::new();
+    for chapter in &report.corrupt {
+        books
+            .entry(chapter.book_id.to_string())
+            .or_default()
+            .push(chapter.index);
+    }
+    for (book_id, indexes) in books {
+        let indexes = indexes
+            .iter()
+            .map(|index| index.to_string())
+            .collect::<Vec<String>>();
+        let _ = writeln!(
+            text,
+            "Corrupt: book {} chapters {}",
+            book_id,
+            indexes.join(", ")
+        );
+    }
+
+    if report.corrupt.is_empty() {
+        text.push_str("No corrupt chapters found\n");
+    }
+    text
+}
+
+/// Verifies the library for the verify subcommand, asking before re-importing books with corrupt chapters
+/// unless `repair_books` says to go ahead.
+pub async fn verify_command(repair_books: bool) -> Result<(), Error> {
+    let pool = SqlitePool::connect("ereader.sqlite").await?;
+    let report = verify(&pool).await?;
+    print!("{}", verify_report(&report));
+
+    let book_ids = report.book_ids();
+    if !book_ids.is_empty() && (repair_books || confirm(book_ids.len())?) {
+        for book_id in book_ids {
+            match repair(&pool, book_id).await? {
+                Some(chapters) => println!("Re-imported {} chapters of {}", chapters, book_id),
+                None => println!(
+                    "Unable to re-import {}, its original wasn't kept and none of its files still have the same contents",
+                    book_id
+                ),
+            }
+        }
+    }
+
+    pool.close().await;
+    Ok(())
+}
+
+fn confirm(books: usize) -> Result<bool, Error> {
+    print!(
+        "Re-import {} books from their original files? [y/N] ",
+        books
+    );
+    std::io::Write::flush(&mut std::io::stdout())?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
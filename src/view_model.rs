@@ -0,0 +1,522 @@
+use crate::fimfarchive::{search, FimfArchiveResult, FimfArchiveSchema};
+use crate::library::*;
+use crate::scan::scan;
+use crate::Error;
+use sqlx::SqlitePool;
+use tantivy::{Index, IndexReader};
+use uuid::adapter::Hyphenated;
+
+/// How many fimfarchive results a search shows.
+const SEARCH_LIMIT: usize = 20;
+
+/// What a frontend is showing.
+#[derive(Clone, Debug)]
+pub enum Page {
+    Library(Vec<Book>),
+    /// the chapter and how far into it to scroll
+    Chapter(Chapter, Option<f32>),
+    TableOfContents(Vec<Toc>, Hyphenated),
+    /// each bookmark with the book it's in
+    Bookmarks(Vec<Bookmark>, Vec<Book>),
+    FimfArchiveSearch,
+    FimfArchiveResults(Vec<FimfArchiveResult>),
+}
+
+/// Everything a frontend needs to draw, without any of its types.
+///
+/// `update` only changes this and says what to load, `Backend::perform` does the loading,
+/// so the transitions between pages can be tested without a database or a terminal.
+#[derive(Clone, Debug)]
+pub struct Model {
+    pub page: Page,
+    /// the last thing that went wrong, shown until it's dismissed
+    pub error: Option<String>,
+    /// the book that was last read and how many chapters it has, so the reader can stop at the end
+    pub chapters: Option<(Hyphenated, i64)>,
+}
+
+impl Model {
+    pub fn new(books: Vec<Book>) -> Self {
+        Model {
+            page: Page::Library(books),
+            error: None,
+            chapters: None,
+        }
+    }
+
+    /// Whether the open chapter is the book's last, false if the chapter count isn't loaded yet.
+    pub fn is_last_chapter(&self) -> bool {
+        match (&self.page, self.chapters) {
+            (Page::Chapter(chapter, _), Some((book_id, chapters))) => {
+                chapter.book_id == book_id && chapter.index >= chapters
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Something the user did, or the result of an `Effect`.
+#[derive(Clone, Debug)]
+pub enum Msg {
+    GoLibrary,
+    GoChapterIndex(Hyphenated, i64),
+    GoChapterId(Hyphenated),
+    GoChapterIdBookmark(Hyphenated, f32),
+    NextChapter,
+    PrevChapter,
+    /// sets the position to the end of the last chapter
+    MarkFinished,
+    GoTOC,
+    Scan,
+    GoBookmarks,
+    DeleteBookmark(i64),
+    SetBookmark(Hyphenated, Hyphenated, f32),
+    GoFimfArchiveSearch,
+    FimfArchiveSearch(String),
+    DismissError,
+    Loaded(Page),
+    /// a bookmark was saved at this progress
+    BookmarkSet(f32),
+    /// how many chapters a book has
+    ChapterCount(Hyphenated, i64),
+    /// a scan that didn't need to reload anything finished
+    Scanned,
+    Failed(String),
+}
+
+/// Loading or saving that has to happen before the page can change.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Effect {
+    LoadBooks,
+    LoadChapter {
+        book_id: Hyphenated,
+        index: i64,
+    },
+    LoadChapterId {
+        id: Hyphenated,
+        progress: Option<f32>,
+    },
+    LoadToc(Hyphenated),
+    LoadChapterCount(Hyphenated),
+    LoadBookmarks,
+    /// scans for new books, reloading the library if it's showing
+    Scan {
+        reload_library: bool,
+    },
+    InsertBookmark {
+        book_id: Hyphenated,
+        chapter_id: Hyphenated,
+        progress: f32,
+    },
+    DeleteBookmark(i64),
+    MarkFinished {
+        book_id: Hyphenated,
+        chapter_id: Hyphenated,
+    },
+    SearchFimfArchive(String),
+}
+
+/// Applies a message to the model, returning what needs to be loaded next if anything.
+///
+/// Messages that don't make sense on the current page, like going to the next chapter from the library, are ignored.
+pub fn update(model: &mut Model, msg: Msg) -> Option<Effect> {
+    let last_chapter = model.is_last_chapter();
+    let reading = match &model.page {
+        Page::Chapter(chapter, _) => Some(chapter),
+        _ => None,
+    };
+
+    match msg {
+        Msg::GoLibrary => Some(Effect::LoadBooks),
+        Msg::GoChapterIndex(book_id, index) => Some(Effect::LoadChapter { book_id, index }),
+        // there's no chapter after the last one to load
+        Msg::NextChapter => reading
+            .filter(|_| !last_chapter)
+            .map(|chapter| Effect::LoadChapter {
+                book_id: chapter.book_id,
+                index: chapter.index + 1,
+            }),
+        Msg::PrevChapter => {
+            reading
+                .filter(|chapter| chapter.index > 1)
+                .map(|chapter| Effect::LoadChapter {
+                    book_id: chapter.book_id,
+                    index: chapter.index - 1,
+                })
+        }
+        Msg::MarkFinished => reading
+            .filter(|_| last_chapter)
+            .map(|chapter| Effect::MarkFinished {
+                book_id: chapter.book_id,
+                chapter_id: chapter.id,
+            }),
+        Msg::GoTOC => reading.map(|chapter| Effect::LoadToc(chapter.book_id)),
+        Msg::GoChapterId(id) => Some(Effect::LoadChapterId { id, progress: None }),
+        Msg::GoChapterIdBookmark(id, progress) => Some(Effect::LoadChapterId {
+            id,
+            progress: Some(progress),
+        }),
+        // scanning can be done from any page, only the library needs to be reloaded after
+        Msg::Scan => Some(Effect::Scan {
+            reload_library: matches!(model.page, Page::Library(_)),
+        }),
+        Msg::GoBookmarks => Some(Effect::LoadBookmarks),
+        Msg::SetBookmark(book_id, chapter_id, progress) => {
+            reading.map(|_| Effect::InsertBookmark {
+                book_id,
+                chapter_id,
+                progress,
+            })
+        }
+        Msg::DeleteBookmark(id) => match model.page {
+            Page::Bookmarks(_, _) => Some(Effect::DeleteBookmark(id)),
+            _ => None,
+        },
+        Msg::GoFimfArchiveSearch => {
+            model.page = Page::FimfArchiveSearch;
+            None
+        }
+        Msg::FimfArchiveSearch(query) => Some(Effect::SearchFimfArchive(query)),
+        Msg::DismissError => {
+            model.error = None;
+            None
+        }
+        Msg::Loaded(page) => {
+            // the chapter count is only loaded once for each book that's read
+            let count = match &page {
+                Page::Chapter(chapter, _)
+                    if model.chapters.map(|(book_id, _)| book_id) != Some(chapter.book_id) =>
+                {
+                    Some(Effect::LoadChapterCount(chapter.book_id))
+                }
+                _ => None,
+            };
+            model.page = page;
+            model.error = None;
+            count
+        }
+        Msg::BookmarkSet(progress) => {
+            // the reader may have moved on while the bookmark was saved
+            if let Page::Chapter(_, bookmark) = &mut model.page {
+                *bookmark = Some(progress);
+            }
+            None
+        }
+        Msg::ChapterCount(book_id, chapters) => {
+            model.chapters = Some((book_id, chapters));
+            None
+        }
+        Msg::Scanned => None,
+        Msg::Failed(message) => {
+            model.error = Some(message);
+            None
+        }
+    }
+}
+
+/// Runs the effects against the library and the fimfarchive index, turning the results back into messages.
+#[derive(Clone)]
+pub struct Backend {
+    pub pool: SqlitePool,
+    pub schema: FimfArchiveSchema,
+    pub index: Index,
+    pub reader: IndexReader,
+    /// the directory `Effect::Scan` imports from, the epub path in the settings
+    pub scan_path: String,
+}
+
+impl Backend {
+    pub async fn perform(&self, effect: Effect) -> Msg {
+        match self.load(effect).await {
+            Ok(msg) => msg,
+            Err(e) => Msg::Failed(e.to_string()),
+        }
+    }
+
+    async fn load(&self, effect: Effect) -> Result<Msg, Error> {
+        let pool = &self.pool;
+        let page = match effect {
+            Effect::LoadBooks => Page::Library(get_books(pool).await?),
+            Effect::LoadChapter { book_id, index } => {
+                Page::Chapter(get_chapter(pool, book_id, index).await?, None)
+            }
+            Effect::LoadChapterId { id, progress } => {
+                Page::Chapter(get_chapter_by_id(pool, id).await?, progress)
+            }
+            Effect::LoadToc(book_id) => {
+                Page::TableOfContents(get_toc(pool, book_id).await?, book_id)
+            }
+            Effect::LoadChapterCount(book_id) => {
+                let chapters = get_num_chapters(pool, book_id).await? as i64;
+                return Ok(Msg::ChapterCount(book_id, chapters));
+            }
+            Effect::LoadBookmarks => self.bookmarks().await?,
+            Effect::Scan { reload_library } => {
+                scan(pool, &self.scan_path, false).await?;
+                if !reload_library {
+                    return Ok(Msg::Scanned);
+                }
+                Page::Library(get_books(pool).await?)
+            }
+            Effect::InsertBookmark {
+                book_id,
+                chapter_id,
+                progress,
+            } => {
+                insert_bookmark(
+                    pool,
+                    &Bookmark {
+                        id: 0,
+                        book_id,
+                        chapter_id,
+                        progress,
+                        created: chrono::Utc::now(),
+                    },
+                )
+                .await?;
+                return Ok(Msg::BookmarkSet(progress));
+            }
+            Effect::DeleteBookmark(id) => {
+                delete_bookmark(pool, id).await?;
+                self.bookmarks().await?
+            }
+            Effect::MarkFinished {
+                book_id,
+                chapter_id,
+            } => {
+                set_position(
+                    pool,
+                    &Position {
+                        book_id,
+                        chapter_id,
+                        progress: 1.0,
+                        updated: chrono::Utc::now(),
+                    },
+                )
+                .await?;
+                crate::scripts::on_finish_book(&get_book(pool, book_id).await?)?;
+                Page::Library(get_books(pool).await?)
+            }
+            Effect::SearchFimfArchive(query) => Page::FimfArchiveResults(search(
+                query,
+                SEARCH_LIMIT,
+                &self.index,
+                &self.schema,
+                &self.reader,
+            )),
+        };
+
+        Ok(Msg::Loaded(page))
+    }
+
+    async fn bookmarks(&self) -> Result<Page, Error> {
+        let bookmarks = get_bookmarks(&self.pool).await?;
+        let mut books = Vec::new();
+        for bookmark in &bookmarks {
+            books.push(get_book(&self.pool, bookmark.book_id).await?);
+        }
+        Ok(Page::Bookmarks(bookmarks, books))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn id(n: u8) -> Hyphenated {
+        Hyphenated::from(Uuid::from_bytes([n; 16]))
+    }
+
+    fn chapter(index: i64) -> Chapter {
+        Chapter {
+            id: id(index as u8),
+            book_id: id(0),
+            index,
+            content: Vec::new(),
+            words: 0,
+            linear: true,
+            title: None,
+        }
+    }
+
+    fn reading(index: i64) -> Model {
+        Model {
+            page: Page::Chapter(chapter(index), None),
+            error: None,
+            chapters: Some((id(0), 10)),
+        }
+    }
+
+    #[test]
+    fn next_chapter_loads_the_following_index() {
+        let mut model = reading(3);
+        assert_eq!(
+            update(&mut model, Msg::NextChapter),
+            Some(Effect::LoadChapter {
+                book_id: id(0),
+                index: 4
+            })
+        );
+    }
+
+    #[test]
+    fn next_chapter_stops_at_the_last_chapter() {
+        let mut model = reading(10);
+        assert!(model.is_last_chapter());
+        assert_eq!(update(&mut model, Msg::NextChapter), None);
+        assert_eq!(
+            update(&mut model, Msg::MarkFinished),
+            Some(Effect::MarkFinished {
+                book_id: id(0),
+                chapter_id: id(10)
+            })
+        );
+
+        let mut model = reading(9);
+        assert!(!model.is_last_chapter());
+        assert_eq!(update(&mut model, Msg::MarkFinished), None);
+    }
+
+    #[test]
+    fn chapter_count_is_loaded_once_for_each_book() {
+        let mut model = Model::new(Vec::new());
+        assert_eq!(
+            update(&mut model, Msg::Loaded(Page::Chapter(chapter(1), None))),
+            Some(Effect::LoadChapterCount(id(0)))
+        );
+        // the count isn't known yet so the next chapter can still be tried
+        assert!(!model.is_last_chapter());
+
+        update(&mut model, Msg::ChapterCount(id(0), 1));
+        assert!(model.is_last_chapter());
+        assert_eq!(
+            update(&mut model, Msg::Loaded(Page::Chapter(chapter(1), None))),
+            None
+        );
+    }
+
+    #[test]
+    fn prev_chapter_stops_at_the_first_chapter() {
+        let mut model = reading(2);
+        assert_eq!(
+            update(&mut model, Msg::PrevChapter),
+            Some(Effect::LoadChapter {
+                book_id: id(0),
+                index: 1
+            })
+        );
+
+        let mut model = reading(1);
+        assert_eq!(update(&mut model, Msg::PrevChapter), None);
+    }
+
+    #[test]
+    fn chapter_messages_are_ignored_outside_the_reader() {
+        let mut model = Model::new(Vec::new());
+        assert_eq!(update(&mut model, Msg::NextChapter), None);
+        assert_eq!(update(&mut model, Msg::GoTOC), None);
+        assert_eq!(
+            update(&mut model, Msg::SetBookmark(id(0), id(1), 0.5)),
+            None
+        );
+        assert!(matches!(model.page, Page::Library(_)));
+    }
+
+    #[test]
+    fn toc_is_loaded_for_the_open_book() {
+        let mut model = reading(2);
+        assert_eq!(update(&mut model, Msg::GoTOC), Some(Effect::LoadToc(id(0))));
+    }
+
+    #[test]
+    fn scan_only_reloads_the_library_page() {
+        let mut model = Model::new(Vec::new());
+        assert_eq!(
+            update(&mut model, Msg::Scan),
+            Some(Effect::Scan {
+                reload_library: true
+            })
+        );
+
+        let mut model = reading(1);
+        assert_eq!(
+            update(&mut model, Msg::Scan),
+            Some(Effect::Scan {
+                reload_library: false
+            })
+        );
+        assert_eq!(update(&mut model, Msg::Scanned), None);
+        assert!(matches!(model.page, Page::Chapter(_, None)));
+    }
+
+    #[test]
+    fn bookmarking_keeps_the_chapter_at_the_bookmark() {
+        let mut model = reading(5);
+        assert_eq!(
+            update(&mut model, Msg::SetBookmark(id(0), id(5), 0.25)),
+            Some(Effect::InsertBookmark {
+                book_id: id(0),
+                chapter_id: id(5),
+                progress: 0.25
+            })
+        );
+
+        update(&mut model, Msg::BookmarkSet(0.25));
+        match &model.page {
+            Page::Chapter(chapter, progress) => {
+                assert_eq!(chapter.index, 5);
+                assert_eq!(*progress, Some(0.25));
+            }
+            page => panic!("expected the chapter, got {:?}", page),
+        }
+    }
+
+    #[test]
+    fn bookmarks_can_only_be_deleted_from_the_bookmarks_page() {
+        let mut model = reading(1);
+        assert_eq!(update(&mut model, Msg::DeleteBookmark(7)), None);
+
+        let mut model = Model {
+            page: Page::Bookmarks(Vec::new(), Vec::new()),
+            error: None,
+            chapters: None,
+        };
+        assert_eq!(
+            update(&mut model, Msg::DeleteBookmark(7)),
+            Some(Effect::DeleteBookmark(7))
+        );
+    }
+
+    #[test]
+    fn fimfarchive_search_page_opens_without_loading() {
+        let mut model = Model::new(Vec::new());
+        assert_eq!(update(&mut model, Msg::GoFimfArchiveSearch), None);
+        assert!(matches!(model.page, Page::FimfArchiveSearch));
+        assert_eq!(
+            update(&mut model, Msg::FimfArchiveSearch("twilight".to_string())),
+            Some(Effect::SearchFimfArchive("twilight".to_string()))
+        );
+    }
+
+    #[test]
+    fn loading_replaces_the_page_and_clears_the_error() {
+        let mut model = Model::new(Vec::new());
+        update(&mut model, Msg::Failed("no such chapter".to_string()));
+        assert_eq!(model.error.as_deref(), Some("no such chapter"));
+        assert!(matches!(model.page, Page::Library(_)));
+
+        update(&mut model, Msg::Loaded(Page::Chapter(chapter(2), None)));
+        assert_eq!(model.error, None);
+        assert!(matches!(model.page, Page::Chapter(_, None)));
+    }
+
+    #[test]
+    fn errors_can_be_dismissed() {
+        let mut model = reading(1);
+        update(&mut model, Msg::Failed("scan failed".to_string()));
+        update(&mut model, Msg::DismissError);
+        assert_eq!(model.error, None);
+        assert!(matches!(model.page, Page::Chapter(_, _)));
+    }
+}
@@ -0,0 +1,416 @@
+use crate::library::{self, Bookmark};
+use crate::settings::Settings;
+use crate::Error;
+use scraper::{Html, Selector};
+use sqlx::SqlitePool;
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::runtime::Runtime;
+use uuid::adapter::Hyphenated;
+use uuid::Uuid;
+
+/// Where the web reader listens unless another address is given, only this machine can reach it.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+/// Holds the token after it's been given once in the address so the links don't need it.
+const TOKEN_COOKIE: &str = "ereader_token";
+
+/// Keeps the pages readable on a phone or tablet without any stylesheet from the book.
+const STYLE: &str =
+    "body { max-width: 40em; margin: auto; padding: 1em; font-family: serif; line-height: 1.5; }
+img { max-width: 100%; }
+nav { display: flex; justify-content: space-between; margin: 1em 0; }
+ul { padding-left: 1.2em; }";
+
+/// Bookmarks the spot at the top of the window, the same way the reader measures progress.
+const BOOKMARK_SCRIPT: &str =
+    "document.getElementById('bookmark').addEventListener('submit', function () {
+    var height = document.documentElement.scrollHeight || 1;
+    this.progress.value = Math.min(1, window.scrollY / height);
+});
+var progress = parseFloat(new URLSearchParams(window.location.search).get('progress'));
+if (progress) {
+    window.addEventListener('load', function () {
+        window.scrollTo(0, progress * document.documentElement.scrollHeight);
+    });
+}";
+
+enum Page {
+    Html(String),
+    Redirect(String),
+    NotFound,
+}
+
+/// Serves a minimal html reader for the library, e.g. for reading on a tablet from the desktop's library.
+///
+/// It lists the books, shows their chapters, and sets bookmarks, everything else is left to the terminal interface.
+/// Requests are handled one at a time since there's only ever a reader or two.
+///
+/// Every request needs `token` when it's given, one is made up when the address can be reached from other machines.
+pub fn serve(address: &str, token: Option<String>) -> Result<(), Error> {
+    let token = match token {
+        Some(token) => Some(token),
+        None if !is_loopback(address)? => Some(Uuid::new_v4().to_simple().to_string()),
+        None => None,
+    };
+
+    let runtime = Runtime::new()?;
+    let pool = runtime.block_on(SqlitePool::connect("ereader.sqlite"))?;
+    let server = Server::http(address).map_err(|e| Error::WebServerError(e.to_string()))?;
+    match &token {
+        Some(token) => println!("serving the library on http://{}/?token={}", address, token),
+        None => println!("serving the library on http://{}", address),
+    }
+
+    for mut request in server.incoming_requests() {
+        let (authorized, from_query) = match &token {
+            Some(token) => authorization(&request, token),
+            None => (true, false),
+        };
+        if !authorized {
+            let response =
+                Response::from_string("open the address with the token ereader web printed")
+                    .with_status_code(401)
+                    .with_header(header("text/plain"));
+            let _ = request.respond(response);
+            continue;
+        }
+
+        // only the scripts and styles of the page itself are run, a book's own can't get through
+        let nonce = Uuid::new_v4().to_simple().to_string();
+        let mut response = match runtime.block_on(route(&pool, &mut request, &nonce)) {
+            Ok(Page::Html(html)) => Response::from_string(html)
+                .with_header(header("text/html"))
+                .with_header(content_security_policy(&nonce)),
+            Ok(Page::Redirect(location)) => Response::from_string("")
+                .with_status_code(303)
+                .with_header(Header::from_bytes(&b"Location"[..], location.as_bytes()).unwrap()),
+            Ok(Page::NotFound) => Response::from_string("not found")
+                .with_status_code(404)
+                .with_header(header("text/plain")),
+            Err(e) => Response::from_string(e.to_string())
+                .with_status_code(500)
+                .with_header(header("text/plain")),
+        };
+        if let (true, Some(token)) = (from_query, &token) {
+            let cookie = format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                TOKEN_COOKIE, token
+            );
+            response.add_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+        }
+        // the browser going away before the page is sent only loses that page
+        let _ = request.respond(response);
+    }
+
+    Ok(())
+}
+
+/// Whether only this machine can reach the address.
+fn is_loopback(address: &str) -> Result<bool, Error> {
+    let mut addresses = address.to_socket_addrs()?.peekable();
+    if addresses.peek().is_none() {
+        return Ok(false);
+    }
+    Ok(addresses.all(|address| address.ip().is_loopback()))
+}
+
+/// Whether the request has the token in its address or its cookie, and if it was in the address.
+fn authorization(request: &Request, token: &str) -> (bool, bool) {
+    let from_query = request
+        .url()
+        .split_once('?')
+        .map(|(_, query)| {
+            query
+                .split('&')
+                .filter_map(|field| field.split_once('='))
+                .any(|(name, value)| name == "token" && value == token)
+        })
+        .unwrap_or(false);
+    let from_cookie = request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Cookie"))
+        .flat_map(|header| header.value.as_str().split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .any(|(name, value)| name == TOKEN_COOKIE && value == token);
+
+    (from_query || from_cookie, from_query)
+}
+
+fn header(content_type: &str) -> Header {
+    Header::from_bytes(
+        &b"Content-Type"[..],
+        format!("{}; charset=utf-8", content_type).as_bytes(),
+    )
+    .unwrap()
+}
+
+fn content_security_policy(nonce: &str) -> Header {
+    let policy = format!(
+        "default-src 'none'; img-src 'self' data:; style-src 'nonce-{nonce}'; script-src 'nonce-{nonce}'; form-action 'self'; base-uri 'none'; frame-ancestors 'none'",
+        nonce = nonce
+    );
+    Header::from_bytes(&b"Content-Security-Policy"[..], policy.as_bytes()).unwrap()
+}
+
+async fn route(pool: &SqlitePool, request: &mut Request, nonce: &str) -> Result<Page, Error> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<&str>>();
+
+    // cloned so the body can still be read
+    let method = request.method().clone();
+
+    match (&method, segments.as_slice()) {
+        (Method::Get, []) => books_page(pool, nonce).await,
+        (Method::Get, ["book", book_id]) => match id(book_id) {
+            Some(book_id) => book_page(pool, book_id, nonce).await,
+            None => Ok(Page::NotFound),
+        },
+        (Method::Get, ["book", book_id, index]) => match (id(book_id), index.parse()) {
+            (Some(book_id), Ok(index)) => chapter_page(pool, book_id, index, nonce).await,
+            _ => Ok(Page::NotFound),
+        },
+        (Method::Post, ["book", book_id, index, "bookmark"]) => {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body)?;
+            match (id(book_id), index.parse()) {
+                (Some(book_id), Ok(index)) => {
+                    add_bookmark(pool, book_id, index, form_progress(&body)).await
+                }
+                _ => Ok(Page::NotFound),
+            }
+        }
+        _ => Ok(Page::NotFound),
+    }
+}
+
+fn id(id: &str) -> Option<Hyphenated> {
+    Uuid::parse_str(id).ok().map(Hyphenated::from)
+}
+
+/// The progress field of the bookmark form, the top of the chapter if it's missing.
+fn form_progress(body: &str) -> f32 {
+    body.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(name, _)| *name == "progress")
+        .and_then(|(_, value)| value.parse::<f32>().ok())
+        .map(|progress| progress.clamp(0.0, 1.0))
+        .unwrap_or(0.0)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn page(title: &str, body: &str, nonce: &str) -> Page {
+    Page::Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{}</title><style nonce=\"{}\">{}</style></head><body>{}</body></html>",
+        escape(title),
+        nonce,
+        STYLE,
+        body
+    ))
+}
+
+async fn books_page(pool: &SqlitePool, nonce: &str) -> Result<Page, Error> {
+    let mut books = library::get_books(pool).await?;
+    books.sort_by_key(|book| book.title.to_lowercase());
+
+    let mut body = String::from("<h1>Library</h1><ul>");
+    for book in books {
+        body.push_str(&format!(
+            "<li><a href=\"/book/{}\">{}</a>",
+            book.id,
+            escape(&book.title)
+        ));
+        if let Some(creator) = &book.creator {
+            body.push_str(&format!(" by {}", escape(creator)));
+        }
+        body.push_str("</li>");
+    }
+    body.push_str("</ul>");
+
+    Ok(page("Library", &body, nonce))
+}
+
+async fn book_page(pool: &SqlitePool, book_id: Hyphenated, nonce: &str) -> Result<Page, Error> {
+    let book = match library::get_book(pool, book_id).await {
+        Ok(book) => book,
+        Err(Error::SqlxError(sqlx::Error::RowNotFound)) => return Ok(Page::NotFound),
+        Err(e) => return Err(e),
+    };
+    let chapter_ids = library::get_chapter_ids(pool, book_id).await?;
+    let titles = library::get_chapter_titles(pool, book_id).await?;
+    // chapter links use the index so they stay short
+    let chapter_index = |chapter_id: Hyphenated| {
+        chapter_ids
+            .iter()
+            .position(|id| *id == chapter_id)
+            .map(|i| i + 1)
+    };
+
+    let mut body = format!(
+        "<p><a href=\"/\">Library</a></p><h1>{}</h1>",
+        escape(&book.title)
+    );
+
+    if let Some(position) = library::get_position(pool, book_id).await? {
+        if let Some(index) = chapter_index(position.chapter_id) {
+            body.push_str(&format!(
+                "<p><a href=\"/book/{}/{}?progress={}\">Continue reading</a></p>",
+                book_id, index, position.progress
+            ));
+        }
+    }
+
+    let bookmarks = library::get_bookmarks(pool)
+        .await?
+        .into_iter()
+        .filter(|bookmark| bookmark.book_id == book_id)
+        .collect::<Vec<Bookmark>>();
+    if !bookmarks.is_empty() {
+        body.push_str("<h2>Bookmarks</h2><ul>");
+        for bookmark in bookmarks {
+            if let Some(index) = chapter_index(bookmark.chapter_id) {
+                body.push_str(&format!(
+                    "<li><a href=\"/book/{}/{}?progress={}\">Chapter {} at {:.0}%</a> {}</li>",
+                    book_id,
+                    index,
+                    bookmark.progress,
+                    index,
+                    bookmark.progress * 100.0,
+                    bookmark.created.format("%Y-%m-%d %H:%M")
+                ));
+            }
+        }
+        body.push_str("</ul>");
+    }
+
+    body.push_str("<h2>Chapters</h2><ol>");
+    for (i, title) in titles.iter().enumerate() {
+        let title = match title {
+            Some(title) => escape(title),
+            None => format!("Chapter {}", i + 1),
+        };
+        body.push_str(&format!(
+            "<li><a href=\"/book/{}/{}\">{}</a></li>",
+            book_id,
+            i + 1,
+            title
+        ));
+    }
+    body.push_str("</ol>");
+
+    Ok(page(&book.title, &body, nonce))
+}
+
+async fn chapter_page(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    index: i64,
+    nonce: &str,
+) -> Result<Page, Error> {
+    let book = match library::get_book(pool, book_id).await {
+        Ok(book) => book,
+        Err(Error::SqlxError(sqlx::Error::RowNotFound)) => return Ok(Page::NotFound),
+        Err(e) => return Err(e),
+    };
+    let chapter = match library::get_chapter(pool, book_id, index).await {
+        Ok(chapter) => chapter,
+        Err(Error::SqlxError(sqlx::Error::RowNotFound)) => return Ok(Page::NotFound),
+        Err(e) => return Err(e),
+    };
+    let chapters = library::get_num_chapters(pool, book_id).await? as i64;
+
+    let content = library::decompress_chapter(&chapter.content)?;
+    let content = chapter_body(&String::from_utf8_lossy(&content));
+
+    let mut nav = String::from("<nav>");
+    nav.push_str(&if index > 1 {
+        format!("<a href=\"/book/{}/{}\">Previous</a>", book_id, index - 1)
+    } else {
+        "<span></span>".to_string()
+    });
+    nav.push_str(&format!(
+        "<a href=\"/book/{}\">{}</a>",
+        book_id,
+        escape(&book.title)
+    ));
+    nav.push_str(&if index < chapters {
+        format!("<a href=\"/book/{}/{}\">Next</a>", book_id, index + 1)
+    } else {
+        "<span></span>".to_string()
+    });
+    nav.push_str("</nav>");
+
+    let body = format!(
+        "{nav}{content}{nav}<form id=\"bookmark\" method=\"post\" action=\"/book/{book_id}/{index}/bookmark\"><input type=\"hidden\" name=\"progress\" value=\"0\"><button>Bookmark</button></form><script nonce=\"{nonce}\">{script}</script>",
+        nav = nav,
+        content = content,
+        book_id = book_id,
+        index = index,
+        nonce = nonce,
+        script = BOOKMARK_SCRIPT
+    );
+
+    let title = chapter
+        .title
+        .unwrap_or_else(|| format!("Chapter {}", index));
+    Ok(page(&format!("{} - {}", title, book.title), &body, nonce))
+}
+
+/// The inside of a chapter's body cut down to ammonia's allowlist of tags, attributes, and url schemes,
+/// so scripts, event handlers, `javascript:` links, and embedded frames or objects from the book never reach the browser.
+/// The book's own styles and links to its other files aren't served so they're left to fail quietly.
+fn chapter_body(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let body = Selector::parse("body").unwrap();
+    let inner = document
+        .select(&body)
+        .next()
+        .map(|body| body.inner_html())
+        .unwrap_or_default();
+
+    ammonia::clean(&inner)
+}
+
+async fn add_bookmark(
+    pool: &SqlitePool,
+    book_id: Hyphenated,
+    index: i64,
+    progress: f32,
+) -> Result<Page, Error> {
+    let chapter = match library::get_chapter(pool, book_id, index).await {
+        Ok(chapter) => chapter,
+        Err(Error::SqlxError(sqlx::Error::RowNotFound)) => return Ok(Page::NotFound),
+        Err(e) => return Err(e),
+    };
+
+    library::insert_bookmark(
+        pool,
+        &Bookmark {
+            id: 0,
+            book_id,
+            chapter_id: chapter.id,
+            progress,
+            created: chrono::Utc::now(),
+        },
+    )
+    .await?;
+    let pruning = Settings::load(pool).await?.bookmark_pruning;
+    library::prune_bookmarks(pool, &pruning).await?;
+
+    Ok(Page::Redirect(format!(
+        "/book/{}/{}?progress={}",
+        book_id, index, progress
+    )))
+}